@@ -0,0 +1,43 @@
+//! Getting a captured 1080p frame out of the PipeWire buffer into a frame the encoder takes. Run
+//! with `cargo bench --bench frame_copy`.
+
+use auto_screen_recorder::pw_capture::frame_copy::copy_plane;
+use criterion::{criterion_group, criterion_main, Criterion};
+use ffmpeg_next::{self as ffmpeg, format::Pixel};
+
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+
+/// Compositors commonly pad rows to 256 bytes
+const SOURCE_STRIDE: usize = (WIDTH as usize * 4).next_multiple_of(256);
+
+fn copy_captured_frame(c: &mut Criterion) {
+    ffmpeg::init().unwrap();
+    let source = vec![0x80; SOURCE_STRIDE * HEIGHT as usize];
+    let mut group = c.benchmark_group("copy_captured_frame");
+
+    // What the capture does now, copying the buffer straight into the encoder's frame
+    group.bench_function("into_frame", |b| {
+        b.iter(|| {
+            let mut frame = ffmpeg::util::frame::video::Video::new(Pixel::BGRA, WIDTH, HEIGHT);
+            copy_plane(&source, 0, SOURCE_STRIDE, &mut frame, 0).unwrap();
+            frame
+        })
+    });
+
+    // What it did before, copying the whole buffer into a `Vec` on the PipeWire thread that the
+    // encoder then copied into its frame
+    group.bench_function("through_vec", |b| {
+        b.iter(|| {
+            let bytes = source.to_vec();
+            let mut frame = ffmpeg::util::frame::video::Video::new(Pixel::BGRA, WIDTH, HEIGHT);
+            copy_plane(&bytes, 0, SOURCE_STRIDE, &mut frame, 0).unwrap();
+            frame
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, copy_captured_frame);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+//! Inserting into a full replay buffer, which trims the oldest GOP as new ones complete. Run with
+//! `cargo bench --bench video_buffer`.
+
+use std::hint::black_box;
+
+use auto_screen_recorder::{
+    application_config::BufferLimit,
+    encoders::buffer::{VideoBuffer, VideoFrameData, ONE_MICROS},
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+/// Capture rate and keyframe interval of the recorder
+const FPS: i64 = 60;
+const GOP_SIZE: i64 = 30;
+
+const BUFFER_SECONDS: u32 = 30;
+
+/// Frames inserted per iteration, enough for a few GOPs to be trimmed
+const INSERTED_FRAMES: i64 = GOP_SIZE * 4;
+
+fn frame(index: i64) -> (i64, VideoFrameData) {
+    let pts = index * ONE_MICROS as i64 / FPS;
+    let frame = VideoFrameData::new(vec![0; 64], index % GOP_SIZE == 0, pts);
+    (pts, frame)
+}
+
+/// A buffer at its limit, along with the index of the next frame
+fn full_buffer() -> (VideoBuffer, i64) {
+    let mut buffer = VideoBuffer::new(BufferLimit::ByDuration {
+        seconds: BUFFER_SECONDS,
+    });
+    let frames = BUFFER_SECONDS as i64 * FPS + GOP_SIZE;
+    for index in 0..frames {
+        let (dts, frame) = frame(index);
+        buffer.insert(dts, frame);
+    }
+    (buffer, frames)
+}
+
+fn insert_into_full_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_into_full_buffer");
+
+    // What `insert` does now, checking the limit and trimming one GOP only on key frames
+    group.bench_function("trim_per_keyframe", |b| {
+        b.iter_batched(
+            full_buffer,
+            |(mut buffer, next)| {
+                for index in next..next + INSERTED_FRAMES {
+                    let (dts, frame) = frame(index);
+                    buffer.insert(dts, frame);
+                }
+                buffer
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    // What `insert` did before, measuring the buffered span after every frame. Trimming itself
+    // is the same, whole GOPs once the limit is reached.
+    group.bench_function("check_per_frame", |b| {
+        b.iter_batched(
+            full_buffer,
+            |(mut buffer, next)| {
+                for index in next..next + INSERTED_FRAMES {
+                    let (dts, frame) = frame(index);
+                    buffer.insert(dts, frame);
+                    black_box(buffer.duration_us());
+                }
+                buffer
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, insert_into_full_buffer);
+criterion_main!(benches);
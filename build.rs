@@ -0,0 +1,81 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Must match `DOMAIN` in `src/i18n.rs`
+const DOMAIN: &str = "auto-screen-recorder";
+
+/// Catalogs in `locales/`, one `<lang>.po` each
+const LANGUAGES: [&str; 4] = ["de", "es", "fr", "ja"];
+
+/// Set `GIT_HASH` for `GetVersion` and compile the translations with `msgfmt` when building
+/// with the `i18n` feature.
+///
+/// The catalogs are compiled into `OUT_DIR` so `cargo run --features i18n` finds them. With
+/// `PREFIX` set the binary loads them from `$PREFIX/share/locale` instead, where
+/// `install-locales.sh` puts them. The build itself never writes outside of `OUT_DIR`.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=locales");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=PREFIX");
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash());
+
+    if env::var_os("CARGO_FEATURE_I18N").is_none() {
+        return;
+    }
+
+    let build_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("locale");
+
+    for language in LANGUAGES {
+        let catalog = build_dir
+            .join(language)
+            .join("LC_MESSAGES")
+            .join(format!("{}.mo", DOMAIN));
+        if let Err(e) = compile(language, &catalog) {
+            println!(
+                "cargo:warning=Could not compile locales/{}.po, is gettext installed? {}",
+                language, e
+            );
+        }
+    }
+
+    let locale_dir = env::var("PREFIX")
+        .map(|prefix| Path::new(&prefix).join("share/locale"))
+        .unwrap_or(build_dir);
+    println!("cargo:rustc-env=LOCALE_DIR={}", locale_dir.display());
+}
+
+/// Short hash of the checked out commit, `unknown` when not building from a git checkout
+fn git_hash() -> String {
+    Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn compile(language: &str, catalog: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(catalog.parent().unwrap())?;
+
+    let status = Command::new("msgfmt")
+        .arg("--check-format")
+        .arg("-o")
+        .arg(catalog)
+        .arg(format!("locales/{}.po", language))
+        .status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "msgfmt exited with {}",
+            status
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+#!/bin/bash
+
+# Installs the translations compiled by `cargo build --features i18n` to $PREFIX/share/locale
+#
+# Build with the same PREFIX so the binary looks for them there, then run this as the user that
+# may write to PREFIX, e.g. as part of packaging
+#
+# EXAMPLE USAGE:
+# PREFIX=/usr/local cargo build --release --features i18n
+# sudo PREFIX=/usr/local ./install-locales.sh
+
+set -euo pipefail
+
+DOMAIN="auto-screen-recorder"
+PROFILE="release"
+
+while getopts ":p:" opt; do
+  case ${opt} in
+    p)
+      PROFILE=$OPTARG
+      ;;
+    \?)
+      echo "Usage: $0 [-p profile]" >&2
+      exit 1
+      ;;
+  esac
+done
+
+if [ -z "${PREFIX:-}" ]; then
+  echo "Set PREFIX to where the translations should go, e.g. PREFIX=/usr/local" >&2
+  exit 1
+fi
+
+# build.rs compiles them into the package's OUT_DIR, take the most recent build's
+BUILD_DIR=$(ls -td target/"$PROFILE"/build/auto-screen-recorder-*/out/locale 2>/dev/null | head -n 1)
+if [ -z "$BUILD_DIR" ]; then
+  echo "No compiled translations in target/$PROFILE, build with --features i18n first" >&2
+  exit 1
+fi
+
+for CATALOG in "$BUILD_DIR"/*/LC_MESSAGES/"$DOMAIN".mo; do
+  LANGUAGE=$(basename "$(dirname "$(dirname "$CATALOG")")")
+  install -Dm644 "$CATALOG" "$PREFIX/share/locale/$LANGUAGE/LC_MESSAGES/$DOMAIN.mo"
+  echo "Installed $LANGUAGE to $PREFIX/share/locale"
+done
@@ -95,6 +95,8 @@ pub struct ScreenCast {
     multiple: bool,
     source_types: Option<SourceType>,
     cursor_mode: Option<CursorMode>,
+    persist_mode: Option<PersistMode>,
+    restore_token: Option<String>,
 }
 
 impl ScreenCast {
@@ -133,6 +135,8 @@ impl ScreenCast {
             multiple: false,
             source_types: None,
             cursor_mode: None,
+            persist_mode: None,
+            restore_token: None,
         })
     }
 
@@ -142,6 +146,12 @@ impl ScreenCast {
         Ok(SourceType::from_bits_truncate(types))
     }
 
+    /// Get the supported cursor modes for this connection
+    pub fn cursor_modes(&self) -> Result<CursorMode, PortalError> {
+        let modes = self.state.desktop_proxy().available_cursor_modes()?;
+        Ok(CursorMode::from_bits_truncate(modes))
+    }
+
     /// Set the source types to capture. This should be a subset of
     /// those from `source_types()`.
     pub fn set_source_types(&mut self, types: SourceType) {
@@ -153,6 +163,19 @@ impl ScreenCast {
         self.cursor_mode = Some(mode);
     }
 
+    /// Set how long the portal should remember the selection. Anything but
+    /// `PersistMode::DoNot` gets a token from `ActiveScreenCast::restore_token()`.
+    pub fn set_persist_mode(&mut self, mode: PersistMode) {
+        self.persist_mode = Some(mode);
+    }
+
+    /// Restore a previous selection with a token from
+    /// `ActiveScreenCast::restore_token()`. The portal skips prompting if it is
+    /// still valid.
+    pub fn set_restore_token(&mut self, token: &str) {
+        self.restore_token = Some(token.to_owned());
+    }
+
     /// Enable multi-stream selection. This allows the user to choose more than
     /// one thing to share. Each will be a separate item in the
     /// `ActiveScreenCast::streams()` iterator.
@@ -188,17 +211,28 @@ impl ScreenCast {
                     None => CursorMode::HIDDEN.bits(),
                 })),
             );
+            if let Some(mode) = self.persist_mode {
+                select_args.insert("persist_mode".into(), Variant(Box::new(mode as u32)));
+            }
+            if let Some(token) = &self.restore_token {
+                select_args.insert("restore_token".into(), Variant(Box::new(token.clone())));
+            }
 
             desktop_proxy.select_sources(session, select_args)?;
             request.wait_response()?;
         }
 
-        let streams = {
+        let (streams, restore_token) = {
             let request = Request::with_handler(&self.state, |response| {
                 if response.response != 0 {
                     return Err(PortalError::Cancelled);
                 }
-                match response.results.get("streams") {
+                let restore_token = response
+                    .results
+                    .get("restore_token")
+                    .and_then(|token| token.as_str())
+                    .map(String::from);
+                let streams: Result<Vec<ScreenCastStream>, _> = match response.results.get("streams") {
                     Some(streams) => match streams.as_iter() {
                         Some(streams) => streams
                             .flat_map(|s| {
@@ -210,7 +244,8 @@ impl ScreenCast {
                         None => Err(PortalError::Parse),
                     },
                     None => Err(PortalError::Parse),
-                }
+                };
+                streams.map(|streams| (streams, restore_token))
             })?;
             let session = dbus::Path::from(&self.session);
             let mut select_args = HashMap::<String, Variant<Box<dyn RefArg>>>::new();
@@ -230,6 +265,7 @@ impl ScreenCast {
             session_path: self.session,
             pipewire_fd,
             streams,
+            restore_token,
         })
     }
 }
@@ -241,6 +277,7 @@ pub struct ActiveScreenCast {
     session_path: String,
     pipewire_fd: OwnedFd,
     streams: Vec<ScreenCastStream>,
+    restore_token: Option<String>,
 }
 
 impl ActiveScreenCast {
@@ -254,6 +291,13 @@ impl ActiveScreenCast {
         self.streams.iter()
     }
 
+    /// Get the token to restore this selection with, if the portal handed one
+    /// out. Only given when a persist mode other than `PersistMode::DoNot` was
+    /// set.
+    pub fn restore_token(&self) -> Option<&str> {
+        self.restore_token.as_deref()
+    }
+
     /// Close the ScreenCast session. This ends the cast.
     pub fn close(&self) -> Result<(), PortalError> {
         // Open a handle to the active session, and close it.
@@ -373,6 +417,19 @@ bitflags! {
     }
 }
 
+/// Persist Mode
+///
+/// Refer to the freedesktop [docs](https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.ScreenCast.html#org-freedesktop-portal-screencast-selectsources)
+/// for how long each keeps the selection
+///
+/// Default: DoNot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistMode {
+    DoNot = 0,
+    Application = 1,
+    Permanent = 2,
+}
+
 // - - - - - - - - - - - - - -  Private Implementation - - - - - - - - - - - -
 
 /// D-Bus connection state. Used to access the Desktop portal
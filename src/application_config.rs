@@ -1,10 +1,18 @@
-use std::{fs, path::Path};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 use config::{Config, File};
-use directories::ProjectDirs;
+use directories::{BaseDirs, ProjectDirs};
+use ffmpeg_next::format::{sample::Type, Pixel, Sample};
+use log::warn;
 use serde::{Deserialize, Serialize};
 
+use crate::encoders::buffer::{GOP_SIZE, MIN_SAVE_GOPS};
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all="UPPERCASE")]
 pub enum QualityPreset {
@@ -14,13 +22,473 @@ pub enum QualityPreset {
     HIGHEST
 }
 
+impl QualityPreset {
+    /// Target video bitrate of the preset
+    pub fn bitrate_kbps(&self) -> u32 {
+        match self {
+            QualityPreset::LOW => 20_000,
+            QualityPreset::MEDIUM => 40_000,
+            QualityPreset::HIGH => 80_000,
+            QualityPreset::HIGHEST => 120_000,
+        }
+    }
+}
+
+/// Pixel format frames are handed to the video encoder in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChromaFormat {
+    NV12,
+    YUV420P,
+    YUV444P,
+}
+
+impl ChromaFormat {
+    pub fn pixel_format(&self) -> Pixel {
+        match self {
+            ChromaFormat::NV12 => Pixel::NV12,
+            ChromaFormat::YUV420P => Pixel::YUV420P,
+            ChromaFormat::YUV444P => Pixel::YUV444P,
+        }
+    }
+
+    /// Whether chroma is stored at half resolution (4:2:0)
+    pub fn is_subsampled(&self) -> bool {
+        *self != ChromaFormat::YUV444P
+    }
+}
+
+/// How subtitles added with `AddSubtitle` end up in saved clips
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubtitleMode {
+    /// Subtitles are ignored
+    None,
+
+    /// Drawn into the video, which re-encodes the clip
+    Burned,
+
+    /// Muxed as a `mov_text` track players can toggle
+    SoftSubtitle,
+}
+
+/// Little endian sample format audio is captured from PipeWire in, see `pw_sample_format`, or
+/// handed to the Opus encoder in, see `audio_sample_format`. Either way it's converted from or to
+/// the `f32` samples mixed in between.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleFormat {
+    /// 32 bit float
+    F32,
+
+    /// 16 bit signed integer
+    #[serde(alias = "s16le")]
+    S16,
+
+    /// 24 bit signed integer. PipeWire packs it in 3 bytes, FFmpeg has no packed 24 bit format so
+    /// the encoder gets it in the top of 32 bit integers.
+    #[serde(alias = "s24le")]
+    S24,
+}
+
+impl SampleFormat {
+    /// FFmpeg sample format the encoder is opened with
+    pub fn sample(&self) -> Sample {
+        match self {
+            SampleFormat::F32 => Sample::F32(Type::Packed),
+            SampleFormat::S16 => Sample::I16(Type::Packed),
+            SampleFormat::S24 => Sample::I32(Type::Packed),
+        }
+    }
+}
+
+/// Where the timeline of a saved clip starts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FirstFrameOffset {
+    /// The first frame shown is at 0. With B-frames the first decode timestamps are negative,
+    /// which MP4 covers with an edit list.
+    Zero,
+
+    /// The first frame decoded is at 0, so the first frame shown starts after the encoder's
+    /// reorder delay
+    Preserve,
+}
+
+/// What saved clips do when the buffered audio ends before the video
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortAudio {
+    /// Leave the audio stream shorter, the end of the clip plays without sound
+    Accept,
+
+    /// Fill the rest of the clip with encoded silence so both streams end together
+    PadSilence,
+}
+
+/// What the encoder threads do when a frame fails to encode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnEncodeError {
+    /// Stop recording and exit with the error, to fail loudly when testing
+    Abort,
+
+    /// Drop the frame, count it and keep recording
+    Skip,
+}
+
+/// What happens when video capture never becomes ready
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnVideoReadyTimeout {
+    /// Keep going and buffer audio without video, so at least `SaveAudioOnly` works
+    AudioOnly,
+
+    /// Stop recording and exit with an error
+    Shutdown,
+}
+
+/// How long the screen cast portal remembers the picked monitor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortalPersist {
+    /// Show the picker every time the recorder starts or reconnects to the portal
+    None,
+
+    /// Remember it until the recorder exits, so reconnecting doesn't show the picker
+    App,
+
+    /// Remember it until revoked in the desktop's settings, across restarts and reboots
+    Permanent,
+}
+
+/// How much video the replay buffer holds before its oldest GOP is dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BufferLimit {
+    /// Seconds between the oldest and newest buffered frame, what `max_seconds` sets by default
+    ByDuration { seconds: u32 },
+
+    /// Bytes of encoded video, to bound memory whatever the bitrate
+    BySize { max_bytes: u64 },
+
+    /// Number of buffered video frames
+    ByFrameCount { max_frames: usize },
+}
+
+impl BufferLimit {
+    fn is_zero(&self) -> bool {
+        match *self {
+            BufferLimit::ByDuration { seconds } => seconds == 0,
+            BufferLimit::BySize { max_bytes } => max_bytes == 0,
+            BufferLimit::ByFrameCount { max_frames } => max_frames == 0,
+        }
+    }
+
+    /// Whether the limit leaves room for the [`MIN_SAVE_GOPS`] GOPs a save needs. Sizes are
+    /// estimated at `quality`'s bitrate with [`GOP_SIZE`] frames a second.
+    fn holds_save(&self, quality: &QualityPreset) -> bool {
+        match *self {
+            BufferLimit::ByDuration { .. } => true,
+            BufferLimit::BySize { max_bytes } => {
+                max_bytes >= MIN_SAVE_GOPS as u64 * quality.bitrate_kbps() as u64 * 1000 / 8
+            }
+            BufferLimit::ByFrameCount { max_frames } => {
+                max_frames >= MIN_SAVE_GOPS * GOP_SIZE as usize
+            }
+        }
+    }
+}
+
+/// Encoder settings for a single save made with `SaveWithPreset`. Anything left unset keeps the
+/// encoder's default.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EncodePreset {
+    pub video_bitrate_kbps: Option<u32>,
+
+    /// Constant quality level, passed as `cq` to NVENC and `crf` to other encoders
+    pub video_crf: Option<u32>,
+
+    /// Re-encode the audio at this bitrate. Unset copies the audio as is, unless the container
+    /// needs another codec.
+    pub audio_bitrate_kbps: Option<u32>,
+
+    /// Container to save to, one of `mp4`, `mkv` or `ts`. Defaults to `mp4`. MPEG-TS clips have
+    /// their audio re-encoded as AAC.
+    pub output_format: Option<String>,
+
+    /// Scale the video by this factor, e.g. `0.5` for half the resolution
+    pub scale_factor: Option<f32>,
+}
+
+/// Where and how the live HLS output is written, see `hls_output`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HlsConfig {
+    /// A new segment starts at the first keyframe after this many seconds
+    pub segment_duration_secs: u32,
+
+    /// The `m3u8` playlist, segments are written next to it
+    pub playlist_path: PathBuf,
+}
+
+impl Default for HlsConfig {
+    fn default() -> Self {
+        Self {
+            segment_duration_secs: DEFAULT_HLS_SEGMENT_DURATION_SECS,
+            playlist_path: std::env::temp_dir()
+                .join("screen-recorder-hls")
+                .join("live.m3u8"),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(default)]
 pub struct AppConfig {
     pub encoder: String,
     pub max_seconds: u32,
+
+    /// Limit the video buffer by size or frame count instead of `max_seconds`. The video still
+    /// never goes back further than `max_seconds`, as audio isn't kept any longer. A `by_duration`
+    /// limit replaces `max_seconds`.
+    pub buffer_limit: Option<BufferLimit>,
+
     pub use_mic: bool,
+
+    /// Audio nodes to capture and mix instead of the default sink or microphone, each a node id or
+    /// `node.name`, e.g. a game's playback stream to leave out voice chat
+    pub audio_sources: Vec<String>,
+
     pub quality: QualityPreset,
+
+    /// Pixel format to encode in, `yuv444p` keeps coloured text sharp if the encoder (and for
+    /// NVENC, the GPU) supports it. Unset encodes 4:2:0 from whatever format the capture delivers.
+    pub chroma_format: Option<ChromaFormat>,
+
+    /// Silence microphone audio quieter than this many dBFS, e.g. `-40.0`. Unset disables the gate.
+    pub mic_noise_gate_db: Option<f32>,
+
+    /// How long the noise gate takes to open once the microphone is loud enough
+    pub mic_noise_gate_attack_ms: u32,
+
+    /// How long the noise gate takes to close once the microphone drops below the threshold
+    pub mic_noise_gate_release_ms: u32,
+
+    /// Adjust the microphone gain so it stays around `mic_target_db`, by at most 20dB either way
+    pub mic_agc: bool,
+
+    /// RMS level in dBFS the microphone gain control aims for
+    pub mic_target_db: f32,
+
+    /// Force a keyframe right before saving so the clip ends as close to the save request as
+    /// possible. Adds roughly one frame of encode latency to every save.
+    pub keyframe_on_save: bool,
+
+    /// Keep every GOP closed, so no frame references one before the GOP's keyframe and the buffer
+    /// can be cut cleanly at any keyframe
+    pub closed_gop: bool,
+
+    /// Force a keyframe when a frame differs enough from the previous one, e.g. when switching
+    /// windows, so clips and buffer trimming can cut right at the change
+    pub scene_detection: bool,
+
+    /// Fraction of the picture, from 0 to 1, that has to change for `scene_detection` to count
+    /// it as a cut
+    pub scene_cut_threshold: f32,
+
+    /// Write clips as variable frame rate: every frame keeps its capture time and lasts until the
+    /// next one, and the stream advertises no frame rate so players go by the timestamps
+    pub vfr_output: bool,
+
+    /// Where saved clips start, `zero` puts the first frame shown at 0 so players don't show a
+    /// black lead-in, `preserve` keeps the encoder's reorder delay before it
+    pub first_frame_offset: FirstFrameOffset,
+
+    /// What saved clips do when the audio ends before the video, `accept` leaves it shorter,
+    /// `pad_silence` fills the gap with silence
+    pub short_audio: ShortAudio,
+
+    /// Denominator of the time base the encoders timestamp frames in, e.g. `90000` for the MPEG
+    /// clock. Buffers and the capture clock stay in micro seconds either way.
+    pub time_base_den: u32,
+
+    /// Denominator of the video stream time base in saved clips, e.g. `90000`. Only the muxer uses
+    /// it, buffered timestamps are rescaled to it when saving.
+    pub output_time_base_den: u32,
+
+    /// Ignore save requests arriving within this many milliseconds of the previous one, e.g. when
+    /// a hotkey fires twice. 0 disables the debounce.
+    pub save_debounce_ms: u64,
+
+    /// How long `SaveClipSync` waits for the clip to be written before failing. The save carries
+    /// on in the background.
+    pub save_sync_timeout_secs: u64,
+
+    /// How subtitles added over D-Bus are saved, `none`, `burned` or `soft_subtitle`
+    pub subtitle_mode: SubtitleMode,
+
+    /// Warn at startup if the replay buffer would use more than half of the available memory
+    pub memory_check: bool,
+
+    /// Start buffering audio as soon as its stream is up instead of waiting for video, so audio
+    /// right at the start of capture isn't lost
+    pub audio_prebuffer: bool,
+
+    /// PipeWire node latency for the audio stream as `quantum/rate`, e.g. `1024/48000`
+    pub audio_node_latency: String,
+
+    /// Length in ms of the Opus frames audio is encoded and buffered in, one of 2.5, 5, 10, 20, 40
+    /// or 60. Clips can only start and end on a frame, independent of the PipeWire quantum.
+    pub audio_frame_ms: f32,
+
+    /// Sample format to ask PipeWire for, `f32`, `s16` or `s24`. Asking for the device's native
+    /// format saves PipeWire converting every sample.
+    pub pw_sample_format: SampleFormat,
+
+    /// Sample format handed to the Opus encoder, `f32`, `s16` or `s24`. Some Opus builds convert
+    /// from `s16` more efficiently. Falls back to `f32` if the encoder won't take it.
+    pub audio_sample_format: SampleFormat,
+
+    /// PipeWire node latency for the video stream as `quantum/rate`. Unset lets PipeWire decide.
+    pub video_node_latency: Option<String>,
+
+    /// Number of buffers to ask the compositor for on the video stream, 2 to 8. Fewer buffers
+    /// lower the latency but frames get dropped as soon as the encoder falls behind, more smooth
+    /// out jitter at the cost of memory (one full frame each).
+    pub pw_video_buffers: u32,
+
+    /// Keep the replay buffer across daemon restarts by writing it to `persist_buffer_path` on a
+    /// clean shutdown and restoring it on the next start.
+    pub persistent_buffer: bool,
+
+    /// Also rewrite the persisted buffer every this many seconds while capturing, so a crash or
+    /// power loss only loses what was captured since. 0 only writes it on shutdown.
+    pub persist_interval_secs: u64,
+
+    /// Where the persisted buffer is written. This file can get large, up to roughly
+    /// `max_seconds` worth of video at the quality preset's bitrate.
+    pub persist_buffer_path: PathBuf,
+
+    /// Where `ExportConcatList` writes the buffer's segments, in a directory named after the
+    /// manifest. They're deleted with `RemoveConcatList`.
+    pub segment_dir: PathBuf,
+
+    /// Encrypt saved clips with a passphrase, written as e.g. `clip_1700000000.mp4.enc`. Decrypt
+    /// them with the `decrypt` subcommand.
+    pub encrypt_clips: bool,
+
+    /// File holding the passphrase clips are encrypted with. Unset reads it from the
+    /// `SCREEN_RECORDER_KEY` environment variable.
+    pub encryption_key_file: Option<PathBuf>,
+
+    /// Only capture this part of the monitor, given as `x,y WxH` (the format `slurp` prints).
+    /// Can be overridden with `--region`.
+    pub capture_region: Option<String>,
+
+    /// Draw the cursor ourselves from the position and bitmap the compositor sends alongside each
+    /// frame instead of having it baked in, which looks sharper on some compositors. Falls back to
+    /// the embedded cursor if the portal can't provide cursor metadata.
+    pub cursor_metadata: bool,
+
+    /// With `cursor_metadata`, keep the cursor beside the buffered frames instead of drawing it
+    /// into them, so each save can choose whether to include it. Saves with the cursor are
+    /// re-encoded.
+    pub cursor_on_save: bool,
+
+    /// Show the screen picker again after this many seconds if it's cancelled, so an accidental
+    /// cancel at autostart doesn't leave the recorder stopped. 0 exits instead.
+    pub portal_cancel_retry_secs: u64,
+
+    /// Whether the portal remembers the picked monitor until the recorder exits (`app`) or until
+    /// revoked (`permanent`), instead of showing the picker each time (`none`)
+    pub portal_persist: PortalPersist,
+
+    /// Where the token to restore a `permanent` selection is kept between runs
+    pub portal_restore_token_path: PathBuf,
+
+    /// Restart the video capture if no frames arrive for this many seconds. 0 disables the check.
+    pub video_timeout_secs: u64,
+
+    /// Audio is only buffered once video is streaming. If it isn't after this many seconds, e.g.
+    /// because the video stream failed at startup, `on_video_ready_timeout` decides what happens.
+    /// 0 waits forever.
+    pub video_ready_timeout_secs: u64,
+
+    /// Whether to buffer audio alone (`audio_only`) or exit (`shutdown`) when video capture isn't
+    /// ready within `video_ready_timeout_secs`
+    pub on_video_ready_timeout: OnVideoReadyTimeout,
+
+    /// Exit with an error when a capture thread fails or panics. Otherwise it's only logged and
+    /// reported through `StatusChanged`, and `video_timeout_secs` restarts a failed video capture.
+    pub exit_on_capture_failure: bool,
+
+    /// Log how much audio and video is buffered every this many seconds. 0 disables it.
+    pub buffer_heartbeat_secs: u64,
+
+    /// Also write the video and audio packets of every saved clip as they were buffered to
+    /// `<clip>.packets`, to debug clips that come out broken. Not written for encrypted clips.
+    pub dump_raw_packets: bool,
+
+    /// Write the SHA-256 of every saved clip to `<clip>.sha256` once it's complete, to detect bit
+    /// rot or incomplete transfers later. Hashing adds to how long large saves take.
+    pub clip_checksums: bool,
+
+    /// Run `save_sound_command` once a clip is saved, to hear that it worked without looking
+    pub sound_on_save: bool,
+
+    /// Shell command for `sound_on_save`, e.g. to play a different sound file
+    pub save_sound_command: String,
+
+    /// Whether a frame that fails to encode is skipped (`skip`) or stops the recorder (`abort`)
+    pub on_encode_error: OnEncodeError,
+
+    /// Pause capture while no fullscreen window is focused. Only supported on Hyprland and Sway.
+    pub fullscreen_only: bool,
+
+    /// Start with capture paused for this many seconds, e.g. to skip the desktop when started at
+    /// login. `Arm` over D-Bus starts it earlier. 0 captures right away.
+    pub arm_delay_seconds: u64,
+
+    /// Serve a live MJPEG preview of the capture over HTTP on this address, e.g. `0.0.0.0:8080`.
+    /// There is no authentication so only expose it on trusted networks.
+    pub preview_stream_address: Option<String>,
+
+    /// JPEG quality of the preview stream from 1 to 100
+    pub preview_stream_quality: u8,
+
+    /// Compute the spectrum of the captured audio for `GetAudioSpectrum` and the preview's
+    /// `/spectrum` WebSocket. Every captured audio frame is copied for it, so it's off by default.
+    pub audio_spectrum: bool,
+
+    /// Also write the encoded video as a live HLS stream, to watch the capture from another
+    /// machine with any HLS player. The replay buffer is unaffected.
+    pub hls_output: Option<HlsConfig>,
+
+    /// Also mux the encoded video continuously into `-` for stdout or the path of a named pipe,
+    /// e.g. to pipe it into `ffplay -`. Opening a named pipe waits for a reader. Writing stops
+    /// when the reader closes its end.
+    pub stream_output: Option<String>,
+
+    /// Extra options passed to the video encoder when it's opened, e.g. `spatial-aq = "1"` for
+    /// NVENC. They override the quality preset. Options the encoder doesn't recognise are logged
+    /// and ignored.
+    pub encoder_options: BTreeMap<String, String>,
+
+    /// H.264 profile to encode in, `baseline`, `main` or `high`, for players that can't decode
+    /// anything above it. Unset leaves it to the encoder.
+    pub h264_profile: Option<String>,
+
+    /// Highest H.264 level the stream may need, e.g. `4.1`. Unset leaves it to the encoder.
+    pub h264_level: Option<String>,
+
+    /// Extra options passed to the muxer when writing a clip, e.g. `movflags = "+faststart"`.
+    /// Options the muxer doesn't recognise are logged and ignored.
+    pub muxer_options: BTreeMap<String, String>,
+
+    /// Named encoder settings that `SaveWithPreset` re-encodes a clip with, e.g. `[presets.share]`
+    pub presets: BTreeMap<String, EncodePreset>,
 }
 
 impl Default for AppConfig {
@@ -28,10 +496,353 @@ impl Default for AppConfig {
         Self {
             encoder: "h264_nvenc".to_string(),
             max_seconds: 300,
+            buffer_limit: None,
             use_mic: false,
-            quality: QualityPreset::MEDIUM
+            audio_sources: Vec::new(),
+            quality: QualityPreset::MEDIUM,
+            chroma_format: None,
+            mic_noise_gate_db: None,
+            mic_noise_gate_attack_ms: 5,
+            mic_noise_gate_release_ms: 150,
+            mic_agc: false,
+            mic_target_db: DEFAULT_MIC_TARGET_DB,
+            keyframe_on_save: false,
+            closed_gop: true,
+            scene_detection: false,
+            scene_cut_threshold: DEFAULT_SCENE_CUT_THRESHOLD,
+            vfr_output: false,
+            first_frame_offset: FirstFrameOffset::Zero,
+            short_audio: ShortAudio::Accept,
+            time_base_den: DEFAULT_TIME_BASE_DEN,
+            output_time_base_den: DEFAULT_TIME_BASE_DEN,
+            save_debounce_ms: 1000,
+            save_sync_timeout_secs: DEFAULT_SAVE_SYNC_TIMEOUT_SECS,
+            subtitle_mode: SubtitleMode::None,
+            memory_check: true,
+            audio_prebuffer: true,
+            audio_node_latency: DEFAULT_AUDIO_NODE_LATENCY.to_string(),
+            audio_frame_ms: DEFAULT_AUDIO_FRAME_MS,
+            pw_sample_format: SampleFormat::F32,
+            audio_sample_format: SampleFormat::F32,
+            video_node_latency: None,
+            pw_video_buffers: DEFAULT_PW_VIDEO_BUFFERS,
+            persistent_buffer: false,
+            persist_interval_secs: DEFAULT_PERSIST_INTERVAL_SECS,
+            persist_buffer_path: default_persist_buffer_path(),
+            segment_dir: std::env::temp_dir().join("screen-recorder-segments"),
+            encrypt_clips: false,
+            encryption_key_file: None,
+            capture_region: None,
+            cursor_metadata: false,
+            cursor_on_save: false,
+            portal_cancel_retry_secs: 0,
+            portal_persist: PortalPersist::None,
+            portal_restore_token_path: default_portal_restore_token_path(),
+            video_timeout_secs: 10,
+            video_ready_timeout_secs: 15,
+            on_video_ready_timeout: OnVideoReadyTimeout::AudioOnly,
+            exit_on_capture_failure: true,
+            buffer_heartbeat_secs: 0,
+            dump_raw_packets: false,
+            clip_checksums: false,
+            sound_on_save: false,
+            save_sound_command: DEFAULT_SAVE_SOUND_COMMAND.to_string(),
+            on_encode_error: OnEncodeError::Skip,
+            fullscreen_only: false,
+            arm_delay_seconds: 0,
+            preview_stream_address: None,
+            preview_stream_quality: DEFAULT_PREVIEW_STREAM_QUALITY,
+            audio_spectrum: false,
+            hls_output: None,
+            stream_output: None,
+            encoder_options: BTreeMap::new(),
+            h264_profile: None,
+            h264_level: None,
+            muxer_options: BTreeMap::new(),
+            presets: BTreeMap::new(),
+        }
+    }
+}
+
+const DEFAULT_AUDIO_NODE_LATENCY: &str = "1024/48000";
+const DEFAULT_AUDIO_FRAME_MS: f32 = 20.0;
+const DEFAULT_MIC_TARGET_DB: f32 = -12.0;
+/// Frame lengths in ms that Opus can encode
+const OPUS_FRAME_MS: [f32; 6] = [2.5, 5.0, 10.0, 20.0, 40.0, 60.0];
+const DEFAULT_PREVIEW_STREAM_QUALITY: u8 = 70;
+const DEFAULT_HLS_SEGMENT_DURATION_SECS: u32 = 2;
+const DEFAULT_SAVE_SYNC_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_SCENE_CUT_THRESHOLD: f32 = 0.6;
+const DEFAULT_TIME_BASE_DEN: u32 = 1_000_000;
+const DEFAULT_PW_VIDEO_BUFFERS: u32 = 4;
+const DEFAULT_PERSIST_INTERVAL_SECS: u64 = 60;
+const DEFAULT_SAVE_SOUND_COMMAND: &str =
+    "pw-play /usr/share/sounds/freedesktop/stereo/camera-shutter.oga";
+pub const MIN_PW_VIDEO_BUFFERS: u32 = 2;
+pub const MAX_PW_VIDEO_BUFFERS: u32 = 8;
+pub const H264_PROFILES: [&str; 3] = ["baseline", "main", "high"];
+/// Levels as `level_idc`, i.e. ten times the level number
+const H264_LEVELS: [i32; 19] = [
+    10, 11, 12, 13, 20, 21, 22, 30, 31, 32, 40, 41, 42, 50, 51, 52, 60, 61, 62,
+];
+/// WebM isn't one, it only takes VP8/VP9/AV1 video while clips are H.264 or HEVC
+const PRESET_OUTPUT_FORMATS: [&str; 3] = ["mp4", "mkv", "ts"];
+
+fn default_persist_buffer_path() -> PathBuf {
+    BaseDirs::new()
+        .map(|dirs| dirs.cache_dir().to_path_buf())
+        .unwrap_or_else(std::env::temp_dir)
+        .join("screen-recorder")
+        .join("buffer.mmap")
+}
+
+fn default_portal_restore_token_path() -> PathBuf {
+    BaseDirs::new()
+        .map(|dirs| dirs.cache_dir().to_path_buf())
+        .unwrap_or_else(std::env::temp_dir)
+        .join("screen-recorder")
+        .join("restore_token")
+}
+
+impl AppConfig {
+    /// Replace any invalid values with their defaults, warning about each one
+    fn validated(mut self) -> Self {
+        match self.buffer_limit {
+            Some(limit) if limit.is_zero() => {
+                warn!(
+                    "Invalid buffer_limit {:?}, it has to be above 0. Using max_seconds",
+                    limit
+                );
+                self.buffer_limit = None;
+            }
+            Some(limit) if !limit.holds_save(&self.quality) => {
+                warn!(
+                    "Invalid buffer_limit {:?}, it can't hold the {} GOPs a save needs. Using \
+                    max_seconds",
+                    limit, MIN_SAVE_GOPS
+                );
+                self.buffer_limit = None;
+            }
+            // Audio, subtitles and the memory check go by `max_seconds`
+            Some(BufferLimit::ByDuration { seconds }) => self.max_seconds = seconds,
+            _ => {}
+        }
+
+        if parse_node_latency(&self.audio_node_latency).is_none() {
+            warn!(
+                "Invalid audio_node_latency {:?}, expected quantum/rate. Using {}",
+                self.audio_node_latency, DEFAULT_AUDIO_NODE_LATENCY
+            );
+            self.audio_node_latency = DEFAULT_AUDIO_NODE_LATENCY.to_string();
         }
+
+        if !OPUS_FRAME_MS.contains(&self.audio_frame_ms) {
+            warn!(
+                "Invalid audio_frame_ms {}, expected one of {:?}. Using {}",
+                self.audio_frame_ms, OPUS_FRAME_MS, DEFAULT_AUDIO_FRAME_MS
+            );
+            self.audio_frame_ms = DEFAULT_AUDIO_FRAME_MS;
+        }
+
+        if !self.mic_target_db.is_finite() || self.mic_target_db >= 0.0 {
+            warn!(
+                "Invalid mic_target_db {}, expected a level below 0 dBFS. Using {}",
+                self.mic_target_db, DEFAULT_MIC_TARGET_DB
+            );
+            self.mic_target_db = DEFAULT_MIC_TARGET_DB;
+        }
+
+        if let Some(profile) = &self.h264_profile {
+            if !H264_PROFILES.contains(&profile.as_str()) {
+                warn!(
+                    "Unknown h264_profile {:?}, expected one of {:?}. Ignoring it",
+                    profile, H264_PROFILES
+                );
+                self.h264_profile = None;
+            }
+        }
+
+        if let Some(level) = &self.h264_level {
+            if h264_level_idc(level).is_none() {
+                warn!(
+                    "Unknown h264_level {:?}, expected a level like 4.1. Ignoring it",
+                    level
+                );
+                self.h264_level = None;
+            }
+        }
+
+        if let Some(latency) = &self.video_node_latency {
+            if parse_node_latency(latency).is_none() {
+                warn!(
+                    "Invalid video_node_latency {:?}, expected quantum/rate. Ignoring it",
+                    latency
+                );
+                self.video_node_latency = None;
+            }
+        }
+
+        if self.save_sync_timeout_secs == 0 {
+            warn!(
+                "save_sync_timeout_secs must be above 0. Using {}",
+                DEFAULT_SAVE_SYNC_TIMEOUT_SECS
+            );
+            self.save_sync_timeout_secs = DEFAULT_SAVE_SYNC_TIMEOUT_SECS;
+        }
+
+        if !(self.scene_cut_threshold > 0.0 && self.scene_cut_threshold <= 1.0) {
+            warn!(
+                "Invalid scene_cut_threshold {}, expected above 0 up to 1. Using {}",
+                self.scene_cut_threshold, DEFAULT_SCENE_CUT_THRESHOLD
+            );
+            self.scene_cut_threshold = DEFAULT_SCENE_CUT_THRESHOLD;
+        }
+
+        if !(MIN_PW_VIDEO_BUFFERS..=MAX_PW_VIDEO_BUFFERS).contains(&self.pw_video_buffers) {
+            warn!(
+                "Invalid pw_video_buffers {}, expected {} to {}. Using {}",
+                self.pw_video_buffers,
+                MIN_PW_VIDEO_BUFFERS,
+                MAX_PW_VIDEO_BUFFERS,
+                DEFAULT_PW_VIDEO_BUFFERS
+            );
+            self.pw_video_buffers = DEFAULT_PW_VIDEO_BUFFERS;
+        }
+
+        if let Some(hls) = &mut self.hls_output {
+            if hls.segment_duration_secs == 0 {
+                warn!(
+                    "Invalid hls_output.segment_duration_secs 0. Using {}",
+                    DEFAULT_HLS_SEGMENT_DURATION_SECS
+                );
+                hls.segment_duration_secs = DEFAULT_HLS_SEGMENT_DURATION_SECS;
+            }
+        }
+
+        if !(1..=100).contains(&self.preview_stream_quality) {
+            warn!(
+                "Invalid preview_stream_quality {}, expected 1 to 100. Using {}",
+                self.preview_stream_quality, DEFAULT_PREVIEW_STREAM_QUALITY
+            );
+            self.preview_stream_quality = DEFAULT_PREVIEW_STREAM_QUALITY;
+        }
+
+        for (name, preset) in self.presets.iter_mut() {
+            if let Some(format) = &preset.output_format {
+                if !PRESET_OUTPUT_FORMATS.contains(&format.as_str()) {
+                    warn!(
+                        "Invalid output_format {:?} in preset {}, expected one of {:?}. Using mp4",
+                        format, name, PRESET_OUTPUT_FORMATS
+                    );
+                    preset.output_format = None;
+                }
+            }
+
+            if let Some(scale_factor) = preset.scale_factor {
+                if !(scale_factor > 0.0 && scale_factor <= 1.0) {
+                    warn!(
+                        "Invalid scale_factor {} in preset {}, expected 0 to 1. Not scaling",
+                        scale_factor, name
+                    );
+                    preset.scale_factor = None;
+                }
+            }
+        }
+
+        if self.time_base_den == 0 || self.time_base_den > i32::MAX as u32 {
+            warn!(
+                "Invalid time_base_den {}, expected 1 to {}. Using {}",
+                self.time_base_den,
+                i32::MAX,
+                DEFAULT_TIME_BASE_DEN
+            );
+            self.time_base_den = DEFAULT_TIME_BASE_DEN;
+        }
+
+        if self.output_time_base_den == 0 || self.output_time_base_den > i32::MAX as u32 {
+            warn!(
+                "Invalid output_time_base_den {}, expected 1 to {}. Using {}",
+                self.output_time_base_den,
+                i32::MAX,
+                DEFAULT_TIME_BASE_DEN
+            );
+            self.output_time_base_den = DEFAULT_TIME_BASE_DEN;
+        }
+
+        if let Some(region) = &self.capture_region {
+            if Region::parse(region).is_none() {
+                warn!(
+                    "Invalid capture_region {:?}, expected x,y WxH. Capturing the whole monitor",
+                    region
+                );
+                self.capture_region = None;
+            }
+        }
+
+        self
+    }
+}
+
+/// A rectangle of the captured monitor, in pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Parse a geometry string of the form `x,y WxH`, e.g. `100,200 1280x720`
+    pub fn parse(geometry: &str) -> Option<Self> {
+        let (position, size) = geometry.trim().split_once(' ')?;
+        let (x, y) = position.split_once(',')?;
+        let (width, height) = size.trim().split_once('x')?;
+
+        let region = Self {
+            x: x.trim().parse().ok()?,
+            y: y.trim().parse().ok()?,
+            width: width.trim().parse().ok()?,
+            height: height.trim().parse().ok()?,
+        };
+
+        if region.width == 0 || region.height == 0 {
+            return None;
+        }
+
+        Some(region)
+    }
+
+    /// Whether the region lies entirely within a monitor of the given size
+    pub fn fits_within(&self, width: u32, height: u32) -> bool {
+        self.x
+            .checked_add(self.width)
+            .is_some_and(|right| right <= width)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|bottom| bottom <= height)
+    }
+}
+
+/// Parse a PipeWire node latency string of the form `quantum/rate` into its parts
+pub fn parse_node_latency(latency: &str) -> Option<(u32, u32)> {
+    let (quantum, rate) = latency.split_once('/')?;
+    let quantum = quantum.trim().parse::<u32>().ok()?;
+    let rate = rate.trim().parse::<u32>().ok()?;
+
+    if quantum == 0 || rate == 0 {
+        return None;
     }
+
+    Some((quantum, rate))
+}
+
+/// Turn a level like `4.1` or `4` into its `level_idc`, e.g. 41, or `None` if H.264 has no such
+/// level
+pub fn h264_level_idc(level: &str) -> Option<i32> {
+    let level_idc = (level.trim().parse::<f32>().ok()? * 10.0).round() as i32;
+    H264_LEVELS.contains(&level_idc).then_some(level_idc)
 }
 
 pub fn load_or_create_config() -> AppConfig {
@@ -53,7 +864,10 @@ pub fn load_or_create_config() -> AppConfig {
     let config = settings.build();
 
     match config {
-        Ok(c) => c.try_deserialize().unwrap_or_default(),
+        Ok(c) => c
+            .try_deserialize::<AppConfig>()
+            .unwrap_or_default()
+            .validated(),
         Err(_) => AppConfig::default(),
     }
 }
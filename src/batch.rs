@@ -0,0 +1,83 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use ffmpeg_next as ffmpeg;
+use log::{info, warn};
+
+use crate::{application_config::AppConfig, transcode};
+
+/// Re-encodes every clip in `input_dir` with the named preset from the config into `output_dir`,
+/// one after another, keeping their file names. Files that can't be opened as media, or fail to
+/// re-encode, are skipped with a warning.
+pub fn run(
+    config: &AppConfig,
+    input_dir: &Path,
+    output_dir: &Path,
+    preset_name: &str,
+) -> Result<()> {
+    ffmpeg::log::set_level(ffmpeg::log::Level::Error);
+    ffmpeg::init()?;
+
+    let preset = config
+        .presets
+        .get(preset_name)
+        .with_context(|| format!("Unknown preset \"{}\"", preset_name))?;
+    let extension = preset.output_format.as_deref().unwrap_or("mp4");
+
+    let mut clips: Vec<PathBuf> = std::fs::read_dir(input_dir)
+        .with_context(|| format!("Could not read {}", input_dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    clips.sort();
+    std::fs::create_dir_all(output_dir)
+        .with_context(|| format!("Could not create {}", output_dir.display()))?;
+
+    let mut skipped = 0;
+    for (index, input) in clips.iter().enumerate() {
+        let Some(stem) = input.file_stem() else {
+            continue;
+        };
+        let output = output_dir.join(stem).with_extension(extension);
+        println!(
+            "[{}/{}] {} -> {}",
+            index + 1,
+            clips.len(),
+            input.display(),
+            output.display()
+        );
+
+        // Checksums, packet dumps and anything else that isn't a clip
+        if let Err(e) = ffmpeg::format::input(input) {
+            warn!("Skipping {:?}, it could not be demuxed: {}", input, e);
+            println!("  skipped, not a clip: {}", e);
+            skipped += 1;
+            continue;
+        }
+
+        if output.exists() {
+            warn!("Skipping {:?}, {:?} already exists", input, output);
+            println!("  skipped, {} already exists", output.display());
+            skipped += 1;
+            continue;
+        }
+
+        match transcode::transcode(input, &output, preset, &config.encoder, None, None) {
+            Ok(()) => info!("Re-encoded {:?} to {:?}", input, output),
+            Err(e) => {
+                warn!("Could not re-encode {:?}: {:?}", input, e);
+                println!("  failed: {}", e);
+                let _ = std::fs::remove_file(&output);
+                skipped += 1;
+            }
+        }
+    }
+
+    println!(
+        "Re-encoded {} clips into {}, skipped {}",
+        clips.len() - skipped,
+        output_dir.display(),
+        skipped
+    );
+    Ok(())
+}
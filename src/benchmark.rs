@@ -0,0 +1,169 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use ffmpeg_next::{self as ffmpeg, format::Pixel};
+
+use crate::{
+    application_config::AppConfig,
+    encoder_report::VIDEO_ENCODERS,
+    encoders::video_encoder::{VideoEncoder, ONE_MICROS},
+    RawVideoFrame,
+};
+
+/// Number of synthetic frames fed to every encoder
+const BENCH_FRAMES: usize = 300;
+
+const BENCH_WIDTH: u32 = 1920;
+const BENCH_HEIGHT: u32 = 1080;
+
+/// Frame rate the frames are timestamped at, what the encoders are opened for
+const BENCH_FPS: u32 = 60;
+
+/// Bits per pixel per frame H.264 roughly needs for good quality game footage, used to turn the
+/// frame rate an encoder sustains into a bitrate
+const BITS_PER_PIXEL: f64 = 0.1;
+
+/// What one encoder achieved on the synthetic frames
+struct BenchResult {
+    fps: f64,
+    average_latency: Duration,
+    memory_kib: u64,
+    recommended_kbps: u32,
+}
+
+/// Encodes synthetic 1080p frames with the configured encoder and every other encoder the
+/// recorder supports, then prints a table comparing their throughput.
+pub fn run(config: &Arc<AppConfig>) -> Result<()> {
+    ffmpeg::log::set_level(ffmpeg::log::Level::Error);
+    ffmpeg::init()?;
+
+    let mut encoders = vec![config.encoder.as_str()];
+    encoders.extend(
+        VIDEO_ENCODERS
+            .iter()
+            .filter(|&&name| name != config.encoder)
+            .filter(|&&name| ffmpeg::codec::encoder::find_by_name(name).is_some()),
+    );
+
+    println!(
+        "Encoding {} {}x{} BGRA frames with each encoder",
+        BENCH_FRAMES, BENCH_WIDTH, BENCH_HEIGHT
+    );
+    println!(
+        "{:<12} {:<9} {:>8} {:>12} {:>10} {:>17}",
+        "ENCODER", "TYPE", "FPS", "LATENCY", "MEMORY", "RECOMMENDED KBPS"
+    );
+    for name in encoders {
+        let kind = if name.starts_with("lib") {
+            "software"
+        } else {
+            "hardware"
+        };
+        match bench_encoder(name, config) {
+            Ok(result) => println!(
+                "{:<12} {:<9} {:>8.1} {:>10.2}ms {:>7}MiB {:>17}",
+                name,
+                kind,
+                result.fps,
+                result.average_latency.as_secs_f64() * 1000.0,
+                result.memory_kib / 1024,
+                result.recommended_kbps
+            ),
+            Err(e) => println!("{:<12} {:<9} failed: {}", name, kind, e),
+        }
+    }
+    println!(
+        "RECOMMENDED KBPS is a video_bitrate_kbps the encoder keeps up with at up to {} fps",
+        BENCH_FPS
+    );
+
+    Ok(())
+}
+
+fn bench_encoder(name: &str, config: &Arc<AppConfig>) -> Result<BenchResult> {
+    let memory_before = resident_memory_kib();
+    let mut encoder = VideoEncoder::new(
+        BENCH_WIDTH,
+        BENCH_HEIGHT,
+        config.max_seconds,
+        name,
+        Arc::clone(config),
+    )?;
+    if encoder.encoder_name() != name {
+        bail!("out of NVENC sessions");
+    }
+
+    // Only the time spent in the encoder counts, not drawing the frames
+    let mut encoding = Duration::ZERO;
+    for index in 0..BENCH_FRAMES {
+        let frame = RawVideoFrame {
+            bytes: Vec::new(),
+            frame: Some(gradient_frame(index)),
+            timestamp: (index * ONE_MICROS / BENCH_FPS as usize) as i64,
+            format: Pixel::BGRA,
+            encoded_codec: None,
+            cursor: None,
+        };
+
+        let start = Instant::now();
+        encoder.process(frame)?;
+        encoding += start.elapsed();
+    }
+    let start = Instant::now();
+    encoder.drain()?;
+    encoding += start.elapsed();
+
+    let memory_kib = resident_memory_kib().saturating_sub(memory_before);
+
+    // An encoder that can't keep up in real time drops frames, so only budget bits for the
+    // frames it manages
+    let fps = BENCH_FRAMES as f64 / encoding.as_secs_f64();
+    let sustained_fps = fps.min(BENCH_FPS as f64);
+    let recommended_bps = BITS_PER_PIXEL * BENCH_WIDTH as f64 * BENCH_HEIGHT as f64 * sustained_fps;
+    // Rounded to 500kbps steps
+    let recommended_kbps = ((recommended_bps / 500_000.0).round() * 500.0) as u32;
+
+    Ok(BenchResult {
+        fps,
+        average_latency: encoding / BENCH_FRAMES as u32,
+        memory_kib,
+        recommended_kbps,
+    })
+}
+
+/// A diagonal gradient that scrolls with `index`, so consecutive frames differ like motion would
+fn gradient_frame(index: usize) -> ffmpeg::util::frame::video::Video {
+    let mut frame = ffmpeg::util::frame::video::Video::new(Pixel::BGRA, BENCH_WIDTH, BENCH_HEIGHT);
+    let stride = frame.stride(0);
+    let data = frame.data_mut(0);
+    for y in 0..BENCH_HEIGHT as usize {
+        let row = &mut data[y * stride..y * stride + BENCH_WIDTH as usize * 4];
+        for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+            let shifted = x + index * 8;
+            pixel.copy_from_slice(&[
+                (shifted + y) as u8,
+                (shifted / 4) as u8,
+                (y / 4 + index) as u8,
+                255,
+            ]);
+        }
+    }
+
+    frame
+}
+
+/// Resident memory of the process in KiB, 0 if it can't be read
+fn resident_memory_kib() -> u64 {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("VmRSS:"))
+                .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,112 @@
+//! Command line client for the recorder's `com.rust.GameClip` D-Bus service, e.g.
+//! `sr-ctl save-last 30` from a hotkey daemon or script.
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use zbus::{proxy, Connection};
+
+#[derive(Parser)]
+#[command(version, about = "Control a running screen recorder over D-Bus")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Save the whole replay buffer to a clip
+    Save,
+
+    /// Save only the last SECONDS of the buffer, starting at the keyframe before
+    SaveLast { seconds: u32 },
+
+    /// Stop adding captured frames to the buffer, keeping what is buffered
+    Pause,
+
+    /// Add captured frames to the buffer again after `pause`
+    Resume,
+
+    /// Show whether the recorder is ready to save and how much is buffered
+    Status,
+
+    /// Show the GOP structure of the buffered video
+    BufferStats,
+}
+
+#[proxy(
+    interface = "com.rust.GameClip",
+    default_service = "com.rust.GameClip",
+    default_path = "/com/rust/GameClip"
+)]
+trait GameClip {
+    fn save_clip(&self) -> zbus::Result<String>;
+
+    fn save_clip_with_cursor(&self, seconds: u32, with_cursor: bool) -> zbus::Result<String>;
+
+    fn get_buffer_stats(&self) -> zbus::Result<(u64, u64, u64, u64)>;
+
+    fn get_capture_fps(&self) -> zbus::Result<f64>;
+
+    fn get_version(&self) -> zbus::Result<String>;
+
+    fn pause(&self) -> zbus::Result<()>;
+
+    fn resume(&self) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn is_ready(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn is_capturing(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn buffer_ready(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn buffered_duration(&self) -> zbus::Result<u64>;
+
+    #[zbus(property)]
+    fn active_video_encoder(&self) -> zbus::Result<String>;
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let connection = Connection::session()
+        .await
+        .context("Could not connect to the session bus")?;
+    let recorder = GameClipProxy::new(&connection)
+        .await
+        .context("Could not reach the recorder, is it running?")?;
+
+    match cli.command {
+        Command::Save => println!("{}", recorder.save_clip().await?),
+        Command::SaveLast { seconds } => {
+            println!("{}", recorder.save_clip_with_cursor(seconds, true).await?)
+        }
+        Command::Pause => recorder.pause().await?,
+        Command::Resume => recorder.resume().await?,
+        Command::Status => {
+            println!("Version: {}", recorder.get_version().await?);
+            println!("Encoders ready: {}", recorder.is_ready().await?);
+            println!("Capturing: {}", recorder.is_capturing().await?);
+            println!("Ready to save: {}", recorder.buffer_ready().await?);
+            println!(
+                "Buffered: {:.1}s",
+                recorder.buffered_duration().await? as f64 / 1000.0
+            );
+            println!("Capture rate: {:.1} fps", recorder.get_capture_fps().await?);
+            println!("Video encoder: {}", recorder.active_video_encoder().await?);
+        }
+        Command::BufferStats => {
+            let (i_frames, p_frames, b_frames, buffered_ms) = recorder.get_buffer_stats().await?;
+            println!("I-frames: {}", i_frames);
+            println!("P-frames: {}", p_frames);
+            println!("B-frames: {}", b_frames);
+            println!("Buffered: {:.1}s", buffered_ms as f64 / 1000.0);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+/// Extension of the sidecar next to the clip, e.g. `clip_1700000000.mp4.sha256`
+pub const EXTENSION: &str = "sha256";
+
+/// Hash `clip` as it is on disk with SHA-256 and write the digest next to it in the format
+/// `sha256sum` writes, so `sha256sum -c clip_1700000000.mp4.sha256` verifies it later
+pub fn write_sidecar(clip: &Path) -> Result<PathBuf> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut BufReader::new(File::open(clip)?), &mut hasher)?;
+    let digest = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    let file_name = clip
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let sidecar = PathBuf::from(format!("{}.{}", clip.display(), EXTENSION));
+    let mut file = File::create(&sidecar)?;
+    writeln!(file, "{}  {}", digest, file_name)?;
+
+    Ok(sidecar)
+}
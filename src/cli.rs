@@ -0,0 +1,44 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Keep a replay buffer of the screen and save clips of it over D-Bus"
+)]
+pub struct Cli {
+    /// Only record this part of the monitor, as `x,y WxH` like slurp prints it. Overrides
+    /// `capture_region` from the config.
+    #[arg(long, value_name = "GEOMETRY")]
+    pub region: Option<String>,
+
+    /// Run a tool instead of recording
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check every stage of a recording session once and print a pass/fail report
+    SelfTest,
+
+    /// List the encoders that can be set as `encoder` in the config
+    ListEncoders,
+
+    /// Compare how fast every available video encoder is on this machine
+    Benchmark,
+
+    /// Re-encode every clip in INPUT_DIR with a preset from the config into OUTPUT_DIR
+    Reencode {
+        input_dir: PathBuf,
+        output_dir: PathBuf,
+        preset: String,
+    },
+
+    /// Decrypt a clip saved with `encrypt_clips`, next to it unless OUTPUT is given
+    Decrypt {
+        input: PathBuf,
+        output: Option<PathBuf>,
+    },
+}
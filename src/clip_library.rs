@@ -0,0 +1,221 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{bail, Result};
+use ffmpeg_next as ffmpeg;
+use log::{debug, warn};
+
+use crate::i18n::tr;
+
+const CLIP_PREFIX: &str = "clip_";
+
+/// Joined clips get a prefix of their own so they aren't listed (and joined again) as clips
+const SESSION_PREFIX: &str = "session_";
+const CLIP_EXTENSIONS: [&str; 3] = ["mp4", "mkv", "ts"];
+
+/// A saved clip found in the output directory
+#[derive(Clone, Debug)]
+pub struct ClipInfo {
+    pub path: PathBuf,
+    pub size_bytes: u64,
+    pub duration_ms: u64,
+}
+
+/// Outcome of a save attempt
+#[derive(Clone, Debug)]
+pub struct SaveOutcome {
+    /// Empty if the save failed before a file name was picked
+    pub filename: String,
+    pub success: bool,
+
+    /// Unix timestamp in seconds of when the save finished
+    pub timestamp: i64,
+    pub error: Option<String>,
+
+    /// How much later audio started than video in the saved clip, in micro seconds
+    pub av_sync_offset_us: i64,
+}
+
+/// Keeps track of the clips saved in the output directory.
+///
+/// Scanning probes every clip with ffmpeg, so the result is cached until the next save
+/// invalidates it.
+pub struct ClipLibrary {
+    output_dir: PathBuf,
+    cache: Mutex<Option<Vec<ClipInfo>>>,
+    last_save: Mutex<Option<SaveOutcome>>,
+}
+
+impl ClipLibrary {
+    pub fn new(output_dir: PathBuf) -> Self {
+        Self {
+            output_dir,
+            cache: Mutex::new(None),
+            last_save: Mutex::new(None),
+        }
+    }
+
+    /// Record the outcome of a save attempt, `result` holding the clip's A/V sync offset in
+    /// microseconds or why the save failed
+    pub fn record_save(&self, filename: &str, result: Result<i64, String>) {
+        if result.is_ok() {
+            self.invalidate();
+        }
+
+        *self.last_save.lock().unwrap() = Some(SaveOutcome {
+            filename: filename.to_string(),
+            success: result.is_ok(),
+            timestamp: chrono::Local::now().timestamp(),
+            av_sync_offset_us: *result.as_ref().unwrap_or(&0),
+            error: result.err(),
+        });
+    }
+
+    /// The outcome of the most recent save attempt, if there was one
+    pub fn last_save(&self) -> Option<SaveOutcome> {
+        self.last_save.lock().unwrap().clone()
+    }
+
+    /// Returns all saved clips, scanning the output directory if the cache is stale
+    pub fn clips(&self) -> Result<Vec<ClipInfo>> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(clips) = cache.as_ref() {
+            return Ok(clips.clone());
+        }
+
+        let clips = self.scan()?;
+        *cache = Some(clips.clone());
+        Ok(clips)
+    }
+
+    /// Drop the cached clip list so the next call to [`ClipLibrary::clips`] rescans
+    pub fn invalidate(&self) {
+        self.cache.lock().unwrap().take();
+    }
+
+    /// Deletes a clip, refusing anything that isn't a clip directly inside the output directory.
+    ///
+    /// Returns `Ok(false)` if the path was rejected or doesn't exist.
+    pub fn delete(&self, path: &Path) -> Result<bool> {
+        let (Ok(path), Ok(output_dir)) = (path.canonicalize(), self.output_dir.canonicalize())
+        else {
+            return Ok(false);
+        };
+
+        if path.parent() != Some(output_dir.as_path()) || !is_clip(&path) {
+            warn!(
+                "Refusing to delete {:?}, not a clip in {:?}",
+                path, output_dir
+            );
+            return Ok(false);
+        }
+
+        fs::remove_file(&path)?;
+        self.invalidate();
+
+        debug!("Deleted clip {:?}", path);
+        Ok(true)
+    }
+
+    /// Join the `count` most recently saved clips, oldest first, into a single file in the output
+    /// directory without re-encoding. Returns the path of the joined file.
+    pub fn concat_recent(&self, count: usize) -> Result<PathBuf> {
+        let clips = self.clips()?;
+        if clips.len() < count {
+            bail!(tr!(
+                "Only {} clips are saved, can't join {}",
+                clips.len(),
+                count
+            ));
+        }
+
+        // Clip names start with the unix timestamp they were saved at, so sorting by path sorts
+        // them by age
+        let inputs: Vec<PathBuf> = clips[clips.len() - count..]
+            .iter()
+            .map(|clip| clip.path.clone())
+            .collect();
+        let extension = inputs[0]
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("mp4");
+
+        let filename = self.output_dir.join(format!(
+            "{}{}.{}",
+            SESSION_PREFIX,
+            chrono::Local::now().timestamp(),
+            extension
+        ));
+        let filename = PathBuf::from(crate::claim_filename(&filename.to_string_lossy())?);
+
+        if let Err(e) = crate::concat::concat(&inputs, &filename) {
+            let _ = fs::remove_file(&filename);
+            return Err(e);
+        }
+
+        debug!("Joined {} clips into {:?}", count, filename);
+        Ok(filename)
+    }
+
+    fn scan(&self) -> Result<Vec<ClipInfo>> {
+        let mut clips = Vec::new();
+        for entry in fs::read_dir(&self.output_dir)? {
+            let path = entry?.path();
+            if !path.is_file() || !is_clip(&path) {
+                continue;
+            }
+
+            let size_bytes = fs::metadata(&path)?.len();
+            // Encrypted clips can't be probed without the key
+            let duration_ms = if is_encrypted(&path) {
+                0
+            } else {
+                match ffmpeg::format::input(&path) {
+                    Ok(input) => {
+                        (input.duration().max(0) as u64 * 1000) / ffmpeg::ffi::AV_TIME_BASE as u64
+                    }
+                    Err(e) => {
+                        warn!("Could not probe {:?}: {:?}", path, e);
+                        0
+                    }
+                }
+            };
+
+            clips.push(ClipInfo {
+                path,
+                size_bytes,
+                duration_ms,
+            });
+        }
+
+        clips.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(clips)
+    }
+}
+
+/// Matches the `clip_*.{mp4,mkv,ts}` files written by a save, or `clip_*.mp4.enc` etc. when
+/// they are encrypted
+fn is_clip(path: &Path) -> bool {
+    let name_matches = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(CLIP_PREFIX));
+    let container = if is_encrypted(path) {
+        path.file_stem().map(Path::new).and_then(Path::extension)
+    } else {
+        path.extension()
+    };
+    let extension_matches = container
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| CLIP_EXTENSIONS.contains(&extension));
+
+    name_matches && extension_matches
+}
+
+fn is_encrypted(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == crate::encryption::EXTENSION)
+}
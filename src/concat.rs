@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use ffmpeg_next::{self as ffmpeg, media::Type, Rational, Rescale};
+use log::debug;
+
+/// Join `inputs` back to back into `output` without re-encoding.
+///
+/// Every clip has to have the same streams with the same codecs, as clips saved by the same
+/// encoder settings do. Clips of a different resolution or audio format are rejected with an
+/// error naming the clip, since the players would stumble over the format changing mid file.
+pub fn concat(inputs: &[PathBuf], output: &Path) -> Result<()> {
+    let first = inputs.first().context("No clips to concatenate")?;
+    let first_ctx = ffmpeg::format::input(first)?;
+    let mut output_ctx = ffmpeg::format::output(&output)?;
+    let layout: Vec<ffmpeg::codec::Parameters> = first_ctx
+        .streams()
+        .map(|stream| stream.parameters())
+        .collect();
+    for parameters in &layout {
+        let mut stream = output_ctx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        stream.set_parameters(parameters.clone());
+        // Let the muxer pick the tag for its container
+        unsafe {
+            (*stream.parameters().as_mut_ptr()).codec_tag = 0;
+        }
+    }
+
+    output_ctx.write_header()?;
+
+    let micros = Rational::new(1, 1_000_000);
+    // Where the next clip starts, in micro seconds from the start of the output
+    let mut clip_offset_us = 0;
+    debug!("CONCAT START: {} clips -> {:?}", inputs.len(), output);
+    for input in inputs {
+        let mut input_ctx = ffmpeg::format::input(input)?;
+        check_layout(input, &input_ctx, &layout)?;
+
+        // Clips can start above 0, keep the gap between streams, e.g. audio shifted to line up
+        // with video, and only move the clip as a whole. AV_TIME_BASE is micro seconds.
+        let shift_us = clip_offset_us - input_ctx.start_time().max(0);
+
+        let mut clip_end_us = clip_offset_us;
+        for (stream, mut packet) in input_ctx.packets() {
+            let index = stream.index();
+            let input_time_base = stream.time_base();
+            let output_time_base = output_ctx
+                .stream(index)
+                .context("Could not get output stream")?
+                .time_base();
+
+            let offset = shift_us.rescale(micros, input_time_base);
+            packet.set_pts(packet.pts().map(|pts| pts + offset));
+            packet.set_dts(packet.dts().map(|dts| dts + offset));
+
+            if let Some(pts) = packet.pts() {
+                let end = (pts + packet.duration()).rescale(input_time_base, micros);
+                clip_end_us = clip_end_us.max(end);
+            }
+
+            packet.rescale_ts(input_time_base, output_time_base);
+            packet.set_position(-1);
+            packet.write_interleaved(&mut output_ctx)?;
+        }
+
+        debug!("Appended {:?} at {}us", input, clip_offset_us);
+        clip_offset_us = clip_end_us;
+    }
+    debug!("CONCAT END");
+
+    output_ctx.write_trailer()?;
+
+    Ok(())
+}
+
+/// Make sure `input` has the streams of the first clip in the same order and format
+fn check_layout(
+    input: &Path,
+    input_ctx: &ffmpeg::format::context::Input,
+    layout: &[ffmpeg::codec::Parameters],
+) -> Result<()> {
+    if input_ctx.streams().count() != layout.len() {
+        bail!(
+            "{:?} has {} streams, expected {}",
+            input,
+            input_ctx.streams().count(),
+            layout.len()
+        );
+    }
+
+    for (stream, expected) in input_ctx.streams().zip(layout) {
+        let parameters = stream.parameters();
+        if parameters.id() != expected.id() {
+            bail!(
+                "Stream {} of {:?} is {:?}, expected {:?}",
+                stream.index(),
+                input,
+                parameters.id(),
+                expected.id()
+            );
+        }
+
+        let (actual, expected) = unsafe { (&*parameters.as_ptr(), &*expected.as_ptr()) };
+        match parameters.medium() {
+            Type::Video if (actual.width, actual.height) != (expected.width, expected.height) => {
+                bail!(
+                    "{:?} is {}x{}, the other clips are {}x{}, clips of different resolutions \
+                     can't be joined without re-encoding",
+                    input,
+                    actual.width,
+                    actual.height,
+                    expected.width,
+                    expected.height
+                );
+            }
+            Type::Audio
+                if actual.sample_rate != expected.sample_rate
+                    || actual.ch_layout.nb_channels != expected.ch_layout.nb_channels =>
+            {
+                bail!(
+                    "{:?} has {} channel audio at {}Hz, the other clips have {} channels at {}Hz",
+                    input,
+                    actual.ch_layout.nb_channels,
+                    actual.sample_rate,
+                    expected.ch_layout.nb_channels,
+                    expected.sample_rate
+                );
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
@@ -1,25 +1,883 @@
-use log::debug;
-use tokio::sync::mpsc;
-use zbus::interface;
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicI32, AtomicU64},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use ffmpeg_next as ffmpeg;
+use log::{debug, info};
+use pipewire as pw;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use zbus::{fdo, interface, object_server::SignalEmitter};
+
+use crate::{
+    clip_library::ClipLibrary,
+    encoders::{audio_analyzer::AudioSpectrum, buffer, subtitle_encoder::SubtitleEntry},
+    i18n::tr,
+    BufferNotReady, CaptureStats, Readiness,
+};
+
+/// Requests sent from the D-Bus service to the main loop
+pub enum SaveRequest {
+    /// Save the buffered audio and video to a clip
+    Clip(ClipOptions),
+
+    /// Save only the buffered audio to a WAV file, replying with the file name
+    AudioOnly(oneshot::Sender<Result<String, String>>),
+
+    /// Encode the most recent video frame to a JPEG of the given quality (1-100)
+    CurrentFrame(u8, oneshot::Sender<Result<Vec<u8>, String>>),
+
+    /// Encode the buffered frame shown the given milliseconds before the newest one to a JPEG of
+    /// the given quality (1-100)
+    BufferedFrame(u32, u8, oneshot::Sender<Result<Vec<u8>, String>>),
+
+    /// Estimate the size in bytes of a clip of the last given seconds without saving it
+    EstimateSize(u32, oneshot::Sender<u64>),
+
+    /// Save a clip re-encoded with the named preset from the config, replying with the file name
+    Preset(String, oneshot::Sender<Result<String, String>>),
+
+    /// Stream a clip into the stdin of a shell command, replying with its exit status
+    SaveTo(String, oneshot::Sender<Result<String, String>>),
+
+    /// Report the buffered video as `(i_frames, p_frames, b_frames, buffered_ms)`
+    BufferStats(oneshot::Sender<(u64, u64, u64, u64)>),
+
+    /// Estimate the perceived quality of the buffered video in `[0, 1]`
+    Quality(oneshot::Sender<Result<f32, String>>),
+
+    /// Show a line of text in saved clips for the given time on the capture clock
+    Subtitle(SubtitleEntry),
+
+    /// Start capturing now instead of waiting for `arm_delay_seconds` to pass
+    Arm,
+
+    /// Stop adding captured frames to the buffer until [`SaveRequest::Resume`]
+    Pause,
+
+    /// Add captured frames to the buffer again after [`SaveRequest::Pause`]
+    Resume,
+
+    /// Change the video bitrate in kbps without restarting capture
+    SetBitrate(u32, oneshot::Sender<Result<(), String>>),
+
+    /// Write the buffered video as MPEG-TS segments listed in an ffconcat manifest at the path
+    ConcatList(PathBuf, oneshot::Sender<Result<(), String>>),
+
+    /// Start a new screen cast session and restart video capture on it
+    ReconnectPortal(oneshot::Sender<Result<(), String>>),
+}
+
+/// What goes into a clip saved through [`SaveRequest::Clip`]
+#[derive(Debug)]
+pub struct ClipOptions {
+    /// Tags the clip and its file name if one was set
+    pub title: Option<String>,
+
+    /// Free text written to the clip's metadata
+    pub description: Option<String>,
+
+    /// Only save the last this many seconds, starting at a keyframe and ending as many seconds
+    /// after it. 0 saves everything.
+    pub seconds: u32,
+
+    /// Draw the cursor kept in the buffer with `cursor_on_save` onto the clip
+    pub with_cursor: bool,
+
+    /// Receives the file name once the clip is written, or why saving failed
+    pub done: Option<oneshot::Sender<Result<String, String>>>,
+}
+
+impl ClipOptions {
+    /// The whole buffer with the cursor, as `SaveClip` saves it
+    pub fn full(title: Option<String>) -> Self {
+        Self {
+            title,
+            description: None,
+            seconds: 0,
+            with_cursor: true,
+            done: None,
+        }
+    }
+}
+
+/// A capture status change to be broadcast through the `StatusChanged` signal
+#[derive(Debug)]
+pub struct StatusUpdate {
+    pub state: String,
+    pub detail: String,
+}
+
+impl StatusUpdate {
+    pub fn new(state: &str, detail: impl Into<String>) -> Self {
+        Self {
+            state: state.to_string(),
+            detail: detail.into(),
+        }
+    }
+}
 
 pub trait GameClip {
-    async fn save_clip(&self);
+    async fn save_clip(&self) -> fdo::Result<String>;
+
+    async fn save_clip_with_cursor(&self, seconds: u32, with_cursor: bool) -> fdo::Result<String>;
+
+    async fn save_clip_with_metadata(
+        &self,
+        title: String,
+        description: String,
+    ) -> fdo::Result<String>;
+
+    async fn save_clip_sync(&self, seconds: u32) -> fdo::Result<String>;
+
+    async fn save_scheduled(&self, delay_seconds: u32) -> String;
+
+    async fn cancel_scheduled_save(&self, save_id: String) -> bool;
+
+    async fn save_audio_only(&self) -> fdo::Result<String>;
+
+    async fn save_with_preset(&self, preset: String) -> fdo::Result<String>;
+
+    async fn save_to(&self, command: String) -> fdo::Result<String>;
+
+    async fn get_saved_clips(&self) -> fdo::Result<Vec<(String, u64, u64)>>;
+
+    async fn delete_clip(&self, path: String) -> fdo::Result<bool>;
+
+    async fn concat_clips(&self, count: u32) -> fdo::Result<String>;
+
+    async fn get_current_frame(&self, quality: u8) -> fdo::Result<Vec<u8>>;
+
+    async fn get_buffered_frame(&self, ms_ago: u32, quality: u8) -> fdo::Result<Vec<u8>>;
+
+    async fn estimate_clip_size(&self, seconds: u32) -> fdo::Result<u64>;
+
+    async fn get_buffer_stats(&self) -> fdo::Result<(u64, u64, u64, u64)>;
+
+    async fn get_capture_stats(&self) -> (u64, u64);
+
+    async fn get_capture_fps(&self) -> f64;
+
+    async fn get_audio_spectrum(&self) -> Vec<f64>;
+
+    async fn estimate_clip_quality(&self) -> fdo::Result<f64>;
+
+    async fn get_last_save(&self) -> (String, bool, i64, String);
+
+    async fn set_title(&self, session_id: String, title: String);
+
+    async fn add_subtitle(&self, start_ms: u64, end_ms: u64, text: String) -> fdo::Result<()>;
+
+    async fn clear_title(&self);
+
+    async fn arm(&self) -> fdo::Result<()>;
+
+    async fn pause(&self) -> fdo::Result<()>;
+
+    async fn resume(&self) -> fdo::Result<()>;
+
+    async fn set_bitrate(&self, kbps: u32) -> fdo::Result<()>;
+
+    async fn export_concat_list(&self, manifest: String) -> fdo::Result<()>;
+
+    async fn remove_concat_list(&self, manifest: String) -> fdo::Result<()>;
+
+    async fn reconnect_portal(&self) -> fdo::Result<()>;
+
+    async fn get_version(&self) -> String;
+
+    async fn send_heartbeat(&self) -> fdo::Result<()>;
+
+    async fn is_ready(&self) -> bool;
+
+    async fn is_capturing(&self) -> bool;
+
+    async fn buffered_duration(&self) -> u64;
+
+    async fn buffer_ready(&self) -> bool;
+
+    async fn mic_gain_db(&self) -> f64;
+
+    async fn active_video_encoder(&self) -> String;
+
+    async fn status_changed(
+        emitter: &SignalEmitter<'_>,
+        state: &str,
+        detail: &str,
+    ) -> zbus::Result<()>;
+
+    async fn capture_restarted(emitter: &SignalEmitter<'_>, attempts: u32) -> zbus::Result<()>;
+
+    async fn node_state_changed(
+        emitter: &SignalEmitter<'_>,
+        node: &str,
+        state: &str,
+    ) -> zbus::Result<()>;
 }
 
 pub struct ClipService {
-    save_tx: mpsc::Sender<()>,
+    save_tx: mpsc::Sender<SaveRequest>,
+    clip_library: Arc<ClipLibrary>,
+    readiness: Arc<Readiness>,
+    capture_stats: Arc<CaptureStats>,
+    audio_spectrum: Arc<AudioSpectrum>,
+
+    /// Gain the microphone gain control applies, in hundredths of a dB
+    mic_gain: Arc<AtomicI32>,
+
+    /// Title applied to the next saved clip
+    pending_title: Mutex<Option<String>>,
+
+    /// Video encoder capture is encoded with, which differs from `encoder` after a fallback
+    active_video_encoder: Mutex<String>,
+
+    /// Save requests arriving this soon after the previous one are ignored
+    save_debounce: Duration,
+    last_save_request: Mutex<Option<Instant>>,
+
+    /// How long `SaveClipSync` waits for the clip to be written
+    save_sync_timeout: Duration,
+
+    /// Saves waiting for their delay to pass, by the id `SaveScheduled` returned
+    scheduled_saves: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
+    next_scheduled_save: AtomicU64,
+
+    /// Only manifests listing files in here are removed by `RemoveConcatList`
+    segment_dir: PathBuf,
 }
 
 impl ClipService {
-    pub fn new(save_tx: mpsc::Sender<()>) -> Self {
-        Self { save_tx }
+    pub fn new(
+        save_tx: mpsc::Sender<SaveRequest>,
+        clip_library: Arc<ClipLibrary>,
+        readiness: Arc<Readiness>,
+        capture_stats: Arc<CaptureStats>,
+        audio_spectrum: Arc<AudioSpectrum>,
+        mic_gain: Arc<AtomicI32>,
+        save_debounce: Duration,
+        save_sync_timeout: Duration,
+        segment_dir: PathBuf,
+    ) -> Self {
+        Self {
+            save_tx,
+            clip_library,
+            readiness,
+            capture_stats,
+            audio_spectrum,
+            mic_gain,
+            pending_title: Mutex::new(None),
+            active_video_encoder: Mutex::new(String::new()),
+            save_debounce,
+            last_save_request: Mutex::new(None),
+            save_sync_timeout,
+            scheduled_saves: Arc::default(),
+            next_scheduled_save: AtomicU64::new(1),
+            segment_dir,
+        }
+    }
+
+    /// Set what `ActiveVideoEncoder` reports once the video encoder is open
+    pub fn set_active_video_encoder(&self, encoder_name: &str) {
+        *self.active_video_encoder.lock().unwrap() = encoder_name.to_string();
+    }
+
+    /// Send a save request, tagged with the title set through `SetTitle` unless `options` has
+    /// one. Returns `"saving"`, or `"debounced"` if the request came too soon after the previous
+    /// one, in which case `options.done` is dropped.
+    async fn request_clip(&self, mut options: ClipOptions) -> fdo::Result<String> {
+        if !self.readiness.buffer_ready() {
+            return Err(fdo::Error::Failed(BufferNotReady.to_string()));
+        }
+
+        {
+            let now = Instant::now();
+            let mut last_save_request = self.last_save_request.lock().unwrap();
+            if let Some(last) = *last_save_request {
+                if now.duration_since(last) < self.save_debounce {
+                    info!(
+                        "Ignoring save request {:?} after the previous one",
+                        now.duration_since(last)
+                    );
+                    return Ok("debounced".to_string());
+                }
+            }
+            *last_save_request = Some(now);
+        }
+
+        if options.title.is_none() {
+            options.title = self.pending_title.lock().unwrap().take();
+        }
+        let _ = self.save_tx.send(SaveRequest::Clip(options)).await;
+        debug!("Save clip received!");
+        Ok("saving".to_string())
     }
 }
 
 #[interface(name = "com.rust.GameClip")]
 impl GameClip for ClipService {
-    async fn save_clip(&self) {
-        let _ = self.save_tx.send(()).await;
-        debug!("Save clip received!");
+    /// Returns `"saving"`, or `"debounced"` if the request came too soon after the previous one.
+    /// Fails while fewer than [`crate::encoders::buffer::MIN_SAVE_GOPS`] GOPs are buffered.
+    async fn save_clip(&self) -> fdo::Result<String> {
+        self.request_clip(ClipOptions::full(None)).await
+    }
+
+    /// Like `SaveClip`, but only saves the last `seconds` (0 for the whole buffer) and
+    /// decides whether the cursor is drawn.
+    ///
+    /// Choosing needs `cursor_metadata` and `cursor_on_save`, so the cursor is kept beside the
+    /// frames rather than drawn into them. Clips with the cursor are then re-encoded, clips
+    /// without it are saved as buffered. Otherwise `with_cursor` is ignored.
+    async fn save_clip_with_cursor(&self, seconds: u32, with_cursor: bool) -> fdo::Result<String> {
+        self.request_clip(ClipOptions {
+            seconds,
+            with_cursor,
+            ..ClipOptions::full(None)
+        })
+        .await
+    }
+
+    /// Like `SaveClip`, but writes `title` and `description` to the clip's metadata. `title`
+    /// also goes in the file name and replaces one set with `SetTitle`. Empty values are left
+    /// out.
+    async fn save_clip_with_metadata(
+        &self,
+        title: String,
+        description: String,
+    ) -> fdo::Result<String> {
+        self.request_clip(ClipOptions {
+            description: (!description.is_empty()).then_some(description),
+            ..ClipOptions::full((!title.is_empty()).then_some(title))
+        })
+        .await
+    }
+
+    /// Saves the last `seconds` (0 for the whole buffer) like `SaveClipWithCursor` with the
+    /// cursor, but only replies once the clip is written, with its file name. Fails if saving
+    /// fails, the request is debounced or writing takes longer than `save_sync_timeout_secs`.
+    async fn save_clip_sync(&self, seconds: u32) -> fdo::Result<String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let options = ClipOptions {
+            seconds,
+            done: Some(reply_tx),
+            ..ClipOptions::full(None)
+        };
+        if self.request_clip(options).await? == "debounced" {
+            return Err(fdo::Error::Failed(tr!(
+                "Save ignored, it came too soon after the previous one"
+            )));
+        }
+
+        match tokio::time::timeout(self.save_sync_timeout, reply_rx).await {
+            Ok(reply) => reply
+                .map_err(|e| fdo::Error::Failed(e.to_string()))?
+                .map_err(fdo::Error::Failed),
+            Err(_) => Err(fdo::Error::Failed(tr!(
+                "Timed out waiting for the clip to be written"
+            ))),
+        }
     }
+
+    /// Saves a clip once `delay_seconds` have passed, so whatever happens in the meantime makes
+    /// it into the clip. Returns an id to cancel the save with. The title set at the time of the
+    /// call is used, and the save debounce doesn't apply.
+    async fn save_scheduled(&self, delay_seconds: u32) -> String {
+        let save_id = format!(
+            "scheduled_{}",
+            self.next_scheduled_save
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        );
+        let title = self.pending_title.lock().unwrap().take();
+
+        let save_tx = self.save_tx.clone();
+        let pending = Arc::clone(&self.scheduled_saves);
+        let id = save_id.clone();
+        {
+            // Hold the lock while spawning so the task can't finish and remove itself before it
+            // is inserted
+            let mut scheduled_saves = self.scheduled_saves.lock().unwrap();
+            let handle = tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(delay_seconds as u64)).await;
+                let still_pending = pending.lock().unwrap().remove(&id).is_some();
+                if !still_pending {
+                    return;
+                }
+
+                info!("Running scheduled save {}", id);
+                let _ = save_tx
+                    .send(SaveRequest::Clip(ClipOptions::full(title)))
+                    .await;
+            });
+            scheduled_saves.insert(save_id.clone(), handle);
+        }
+
+        debug!("Save scheduled in {}s as {}", delay_seconds, save_id);
+        save_id
+    }
+
+    /// Returns whether the save was still pending, false if it already ran or the id is unknown
+    async fn cancel_scheduled_save(&self, save_id: String) -> bool {
+        match self.scheduled_saves.lock().unwrap().remove(&save_id) {
+            Some(handle) => {
+                handle.abort();
+                info!("Cancelled scheduled save {}", save_id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn save_audio_only(&self) -> fdo::Result<String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::AudioOnly(reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        debug!("Save audio only received!");
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Saves a clip re-encoded with the settings of `preset` from the config's `[presets]`,
+    /// replying with the file name it is written to once encoding starts
+    async fn save_with_preset(&self, preset: String) -> fdo::Result<String> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::Preset(preset, reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        debug!("Save with preset received!");
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Streams a clip as Matroska into the stdin of `command`, run with `sh -c`, e.g.
+    /// `rclone rcat remote:clip.mkv`. Replies with the command's exit status once it exits.
+    async fn save_to(&self, command: String) -> fdo::Result<String> {
+        if !self.readiness.buffer_ready() {
+            return Err(fdo::Error::Failed(BufferNotReady.to_string()));
+        }
+
+        info!("Save to command received: {}", command);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::SaveTo(command, reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Lists saved clips as `(file_path, size_bytes, duration_ms)`
+    async fn get_saved_clips(&self) -> fdo::Result<Vec<(String, u64, u64)>> {
+        let clip_library = Arc::clone(&self.clip_library);
+        let clips = tokio::task::spawn_blocking(move || clip_library.clips())
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        Ok(clips
+            .into_iter()
+            .map(|clip| {
+                (
+                    clip.path.to_string_lossy().into_owned(),
+                    clip.size_bytes,
+                    clip.duration_ms,
+                )
+            })
+            .collect())
+    }
+
+    /// Deletes a saved clip, returns false if `path` isn't a clip in the output directory
+    async fn delete_clip(&self, path: String) -> fdo::Result<bool> {
+        debug!("Delete clip received for {}", path);
+        self.clip_library
+            .delete(Path::new(&path))
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Joins the `count` most recent clips into one file without re-encoding and returns its path.
+    /// Fails if fewer clips are saved or their resolutions or formats differ.
+    async fn concat_clips(&self, count: u32) -> fdo::Result<String> {
+        if count < 2 {
+            return Err(fdo::Error::InvalidArgs(tr!(
+                "Need at least 2 clips to join, got {}",
+                count
+            )));
+        }
+
+        info!("Concat clips received for the last {} clips", count);
+        let clip_library = Arc::clone(&self.clip_library);
+        let path = tokio::task::spawn_blocking(move || clip_library.concat_recent(count as usize))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Returns a JPEG snapshot of the most recent video frame, `quality` ranges from 1 to 100
+    async fn get_current_frame(&self, quality: u8) -> fdo::Result<Vec<u8>> {
+        if !(1..=100).contains(&quality) {
+            return Err(fdo::Error::InvalidArgs(tr!(
+                "Quality must be between 1 and 100, got {}",
+                quality
+            )));
+        }
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::CurrentFrame(quality, reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Returns a JPEG snapshot of the buffered frame shown `ms_ago` milliseconds before the newest
+    /// one, e.g. for a thumbnail of a moment that already passed. `quality` ranges from 1 to 100.
+    async fn get_buffered_frame(&self, ms_ago: u32, quality: u8) -> fdo::Result<Vec<u8>> {
+        if !(1..=100).contains(&quality) {
+            return Err(fdo::Error::InvalidArgs(tr!(
+                "Quality must be between 1 and 100, got {}",
+                quality
+            )));
+        }
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::BufferedFrame(ms_ago, quality, reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Estimates the size in bytes a clip of the last `seconds` would have, without saving it
+    async fn estimate_clip_size(&self, seconds: u32) -> fdo::Result<u64> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::EstimateSize(seconds, reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Describes the buffered video as `(i_frames, p_frames, b_frames, buffered_ms)`, useful to
+    /// check the GOP structure the encoder produces
+    async fn get_buffer_stats(&self) -> fdo::Result<(u64, u64, u64, u64)> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::BufferStats(reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Video frames dropped since startup as `(compositor, encoder)`. Compositor drops are frames
+    /// it never delivered, encoder drops are frames delivered while the encoder was behind.
+    async fn get_capture_stats(&self) -> (u64, u64) {
+        (
+            self.capture_stats
+                .frames_dropped_compositor
+                .load(std::sync::atomic::Ordering::Relaxed),
+            self.capture_stats
+                .frames_dropped_encoder
+                .load(std::sync::atomic::Ordering::Relaxed),
+        )
+    }
+
+    /// Frames the compositor delivered over the last second, as opposed to the negotiated rate
+    async fn get_capture_fps(&self) -> f64 {
+        self.capture_stats.capture_fps()
+    }
+
+    /// Magnitude spectrum of the last 1024 captured audio samples from 0 to 1, one bin per
+    /// 46.875Hz starting at 0Hz. Updated every 100ms, empty until enough audio was captured or
+    /// when `audio_spectrum` is off.
+    async fn get_audio_spectrum(&self) -> Vec<f64> {
+        self.audio_spectrum
+            .get()
+            .into_iter()
+            .map(|bin| bin as f64)
+            .collect()
+    }
+
+    /// Perceived quality of the buffered video from 0 to 1, see `VideoBuffer::compute_crf_estimate`.
+    /// Decodes part of the buffer so it takes a while, and fails unless built with the
+    /// `compute_ssim` feature.
+    async fn estimate_clip_quality(&self) -> fdo::Result<f64> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::Quality(reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map(f64::from)
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Outcome of the most recent save as `(filename, success, timestamp, error)`. The timestamp
+    /// is in unix seconds and 0 if nothing was saved yet.
+    async fn get_last_save(&self) -> (String, bool, i64, String) {
+        match self.clip_library.last_save() {
+            Some(outcome) => (
+                outcome.filename,
+                outcome.success,
+                outcome.timestamp,
+                outcome.error.unwrap_or_default(),
+            ),
+            None => (String::new(), false, 0, String::new()),
+        }
+    }
+
+    /// Tags the next saved clip with `title`, written to the clip's metadata and file name. Only
+    /// one capture session exists so `session_id` is just logged.
+    async fn set_title(&self, session_id: String, title: String) {
+        debug!("Set title received for session {}: {}", session_id, title);
+        *self.pending_title.lock().unwrap() = Some(title);
+    }
+
+    /// Shows `text` in saved clips from `start_ms` to `end_ms`, counted from when capture started.
+    /// How it is saved depends on `subtitle_mode` in the config.
+    async fn add_subtitle(&self, start_ms: u64, end_ms: u64, text: String) -> fdo::Result<()> {
+        if end_ms <= start_ms {
+            return Err(fdo::Error::InvalidArgs(tr!(
+                "Subtitle must end after it starts"
+            )));
+        }
+
+        let entry = SubtitleEntry {
+            start_us: start_ms as i64 * 1000,
+            end_us: end_ms as i64 * 1000,
+            text,
+        };
+        self.save_tx
+            .send(SaveRequest::Subtitle(entry))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Clears a title set with `SetTitle` before it was used by a save
+    async fn clear_title(&self) {
+        debug!("Clear title received");
+        self.pending_title.lock().unwrap().take();
+    }
+
+    /// Starts capturing while capture is still waiting out `arm_delay_seconds`. Does nothing once
+    /// armed.
+    async fn arm(&self) -> fdo::Result<()> {
+        debug!("Arm received");
+        self.save_tx
+            .send(SaveRequest::Arm)
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Stops adding captured frames to the buffer, what is buffered stays and can still be saved.
+    /// Does nothing while already paused.
+    async fn pause(&self) -> fdo::Result<()> {
+        debug!("Pause received");
+        self.save_tx
+            .send(SaveRequest::Pause)
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Resumes capture paused with `Pause`. It stays paused while `fullscreen_only` or
+    /// `arm_delay_seconds` hold it.
+    async fn resume(&self) -> fdo::Result<()> {
+        debug!("Resume received");
+        self.save_tx
+            .send(SaveRequest::Resume)
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Changes the video bitrate to `kbps` until the recorder restarts. Already buffered frames
+    /// keep the bitrate they were encoded with.
+    async fn set_bitrate(&self, kbps: u32) -> fdo::Result<()> {
+        if kbps == 0 {
+            return Err(fdo::Error::InvalidArgs(tr!("Bitrate must be above 0 kbps")));
+        }
+
+        info!("Set bitrate received: {}k", kbps);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::SetBitrate(kbps, reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Writes every complete GOP in the buffer to its own MPEG-TS segment in `segment_dir` and an
+    /// ffconcat manifest listing them to `manifest`, for `ffmpeg -f concat -safe 0 -i <manifest>`
+    async fn export_concat_list(&self, manifest: String) -> fdo::Result<()> {
+        info!("Export concat list received: {}", manifest);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::ConcatList(PathBuf::from(manifest), reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Deletes a manifest written by `ExportConcatList` and its segments once it's been used.
+    /// Manifests listing files outside `segment_dir` are refused.
+    async fn remove_concat_list(&self, manifest: String) -> fdo::Result<()> {
+        debug!("Remove concat list received: {}", manifest);
+        let segment_dir = self.segment_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            buffer::remove_concat_list(Path::new(&manifest), &segment_dir)
+        })
+        .await
+        .map_err(|e| fdo::Error::Failed(e.to_string()))?
+        .map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+
+    /// Goes through the screen cast portal again and restarts video capture on the new stream, for
+    /// when the session died with the compositor. The previous monitor is restored without asking
+    /// if the portal allows it. Already buffered frames are kept, and capture carries on with the
+    /// old session until the new one is there.
+    async fn reconnect_portal(&self) -> fdo::Result<()> {
+        info!("Reconnect portal received");
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.save_tx
+            .send(SaveRequest::ReconnectPortal(reply_tx))
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+
+        reply_rx
+            .await
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// Does nothing, for watchdogs and clients to check the recorder still answers on the bus
+    async fn send_heartbeat(&self) -> fdo::Result<()> {
+        debug!("Heartbeat received");
+        Ok(())
+    }
+
+    /// Versions of the daemon and the libraries it runs against, e.g.
+    /// `0.1.0 (3f2a9c1) ffmpeg 59.39.100 pipewire 1.2.7`, for client compatibility checks and bug
+    /// reports. The FFmpeg version is libavutil's.
+    async fn get_version(&self) -> String {
+        let ffmpeg = ffmpeg::util::version();
+        // PipeWire returns a static string
+        let pipewire = unsafe { CStr::from_ptr(pw::sys::pw_get_library_version()) };
+        format!(
+            "{} ({}) ffmpeg {}.{}.{} pipewire {}",
+            env!("CARGO_PKG_VERSION"),
+            env!("GIT_HASH"),
+            ffmpeg >> 16,
+            (ffmpeg >> 8) & 0xff,
+            ffmpeg & 0xff,
+            pipewire.to_string_lossy()
+        )
+    }
+
+    /// Whether both encoders are open and have processed a frame, saves before then are empty
+    #[zbus(property)]
+    async fn is_ready(&self) -> bool {
+        self.readiness.is_ready()
+    }
+
+    /// Whether captured frames go into the buffer, false until armed and while paused by `Pause`
+    /// or `fullscreen_only`. Not signalled, `StatusChanged` reports the same transitions.
+    #[zbus(property)]
+    async fn is_capturing(&self) -> bool {
+        self.readiness.is_capturing()
+    }
+
+    /// Milliseconds of video in the buffer, updated with every encoded frame. Checked twice a
+    /// second, `PropertiesChanged` is emitted once it moved by more than 500ms since the last one.
+    #[zbus(property)]
+    async fn buffered_duration(&self) -> u64 {
+        self.readiness.buffered_ms()
+    }
+
+    /// Whether enough is buffered to save a clip, i.e. at least
+    /// [`crate::encoders::buffer::MIN_SAVE_GOPS`] GOPs. Saves before then fail. Refreshed twice a second.
+    #[zbus(property)]
+    async fn buffer_ready(&self) -> bool {
+        self.readiness.buffer_ready()
+    }
+
+    /// Gain in dB the microphone gain control currently applies, 0 while `mic_agc` is off. Not
+    /// signalled, poll it for a level meter.
+    #[zbus(property)]
+    async fn mic_gain_db(&self) -> f64 {
+        self.mic_gain.load(std::sync::atomic::Ordering::Relaxed) as f64 / 100.0
+    }
+
+    /// Name of the video encoder in use, e.g. `libx264` when `h264_nvenc` was configured but the
+    /// driver was out of NVENC sessions. Empty until it's open.
+    #[zbus(property)]
+    async fn active_video_encoder(&self) -> String {
+        self.active_video_encoder.lock().unwrap().clone()
+    }
+
+    /// Emitted when capture starts, pauses or fails, e.g. `("error", "video stream disconnected")`
+    #[zbus(signal)]
+    async fn status_changed(
+        emitter: &SignalEmitter<'_>,
+        state: &str,
+        detail: &str,
+    ) -> zbus::Result<()>;
+
+    /// Emitted once frames arrive again after the video capture had to be restarted, with the
+    /// number of restarts it took
+    #[zbus(signal)]
+    async fn capture_restarted(emitter: &SignalEmitter<'_>, attempts: u32) -> zbus::Result<()>;
+
+    /// Emitted when a PipeWire node the capture depends on changes state, e.g.
+    /// `("video source", "suspended")` when the compositor stops sending frames. The node is
+    /// `video source` for the compositor's screencast or the name of one of the recorder's streams.
+    #[zbus(signal)]
+    async fn node_state_changed(
+        emitter: &SignalEmitter<'_>,
+        node: &str,
+        state: &str,
+    ) -> zbus::Result<()>;
 }
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use ffmpeg_next::{self as ffmpeg, format::Pixel, Rational};
+
+/// Video encoders worth configuring, in the order they are listed
+pub const VIDEO_ENCODERS: [&str; 6] = [
+    "h264_nvenc",
+    "hevc_nvenc",
+    "av1_nvenc",
+    "h264_vaapi",
+    "h264_qsv",
+    "libx264",
+];
+
+const AUDIO_ENCODERS: [&str; 3] = ["libopus", "opus", "aac"];
+
+/// Size of the frames the video encoders are test opened with
+const PROBE_WIDTH: u32 = 256;
+const PROBE_HEIGHT: u32 = 144;
+
+/// Prints which of the encoders the recorder can use are in the linked FFmpeg and, for video,
+/// whether they open on this machine.
+pub fn run() -> Result<()> {
+    ffmpeg::log::set_level(ffmpeg::log::Level::Error);
+    ffmpeg::init()?;
+
+    println!("{:<12} {:<6} STATUS", "ENCODER", "TYPE");
+    for name in VIDEO_ENCODERS {
+        println!("{:<12} {:<6} {}", name, "video", probe_video(name));
+    }
+    for name in AUDIO_ENCODERS {
+        let status = match ffmpeg::codec::encoder::find_by_name(name) {
+            Some(_) => "available",
+            None => "not in linked ffmpeg",
+        };
+        println!("{:<12} {:<6} {}", name, "audio", status);
+    }
+
+    Ok(())
+}
+
+fn probe_video(name: &str) -> String {
+    let Some(codec) = ffmpeg::codec::encoder::find_by_name(name) else {
+        return "not in linked ffmpeg".to_string();
+    };
+
+    let Some(format) = codec
+        .video()
+        .ok()
+        .and_then(|video| video.formats())
+        .and_then(|mut formats| formats.next())
+    else {
+        return "linked, no pixel formats reported".to_string();
+    };
+
+    // These only take frames uploaded to a device, which the recorder doesn't set up
+    if matches!(format, Pixel::VAAPI | Pixel::QSV | Pixel::CUDA) {
+        return "linked, needs a hardware device (not probed)".to_string();
+    }
+
+    let opened = ffmpeg::codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()
+        .and_then(|mut encoder| {
+            encoder.set_width(PROBE_WIDTH);
+            encoder.set_height(PROBE_HEIGHT);
+            encoder.set_format(format);
+            encoder.set_time_base(Rational::new(1, 60));
+            encoder.open()
+        });
+
+    match opened {
+        Ok(_) => "available".to_string(),
+        Err(e) => format!("linked, fails to open: {}", e),
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::warn;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Number of samples each spectrum is computed over, giving `FFT_SIZE / 2` bins of 46.875Hz at
+/// 48kHz
+pub const FFT_SIZE: usize = 1024;
+
+/// How often the spectrum is recomputed
+pub const ANALYZE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Captured audio is interleaved stereo
+const CHANNELS: usize = 2;
+
+/// The latest magnitude spectrum of the captured audio, shared between the analyzer and whoever
+/// displays it
+#[derive(Default)]
+pub struct AudioSpectrum {
+    bins: Mutex<Vec<f32>>,
+}
+
+impl AudioSpectrum {
+    /// Magnitude of each frequency bin from 0 (silence) to 1 (a full scale sine), lowest
+    /// frequency first. Empty until enough audio has been captured.
+    pub fn get(&self) -> Vec<f32> {
+        self.bins.lock().unwrap().clone()
+    }
+
+    fn set(&self, bins: Vec<f32>) {
+        *self.bins.lock().unwrap() = bins;
+    }
+}
+
+/// Computes the spectrum of the captured audio for visualisation, off to the side of the encoding
+/// pipeline. Samples are downmixed to mono and the last [`FFT_SIZE`] of them are analyzed every
+/// [`ANALYZE_INTERVAL`].
+pub struct AudioAnalyzer {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    samples: VecDeque<f32>,
+    spectrum: Arc<AudioSpectrum>,
+}
+
+impl AudioAnalyzer {
+    pub fn new(spectrum: Arc<AudioSpectrum>) -> Self {
+        // Hann window so the edges of the analyzed slice don't smear energy over every bin
+        let window = (0..FFT_SIZE)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / FFT_SIZE as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(FFT_SIZE),
+            window,
+            samples: VecDeque::with_capacity(FFT_SIZE),
+            spectrum,
+        }
+    }
+
+    /// Analyze the samples broadcast on `samples_rx` until the sender is dropped
+    pub async fn run(mut self, mut samples_rx: broadcast::Receiver<Arc<[f32]>>) {
+        let mut interval = tokio::time::interval(ANALYZE_INTERVAL);
+        loop {
+            tokio::select! {
+                samples = samples_rx.recv() => match samples {
+                    Ok(samples) => self.push(&samples),
+                    // Only the most recent audio matters, carry on from where the channel is now
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Audio analyzer fell behind, skipped {} frames", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = interval.tick() => self.analyze(),
+            }
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(CHANNELS) {
+            if self.samples.len() == FFT_SIZE {
+                self.samples.pop_front();
+            }
+            self.samples
+                .push_back(frame.iter().sum::<f32>() / CHANNELS as f32);
+        }
+    }
+
+    fn analyze(&self) {
+        if self.samples.len() < FFT_SIZE {
+            return;
+        }
+
+        let mut buffer: Vec<Complex<f32>> = self
+            .samples
+            .iter()
+            .zip(&self.window)
+            .map(|(sample, weight)| Complex::new(sample * weight, 0.0))
+            .collect();
+        self.fft.process(&mut buffer);
+
+        // Scale so a full scale sine peaks at 1: dividing by the window's sum undoes its
+        // attenuation and doubling makes up for the mirrored half of the spectrum that is dropped
+        let scale = 2.0 / self.window.iter().sum::<f32>();
+        let bins = buffer[..FFT_SIZE / 2]
+            .iter()
+            .map(|bin| bin.norm() * scale)
+            .collect();
+        self.spectrum.set(bins);
+    }
+}
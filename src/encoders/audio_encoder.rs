@@ -1,9 +1,13 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, sync::Arc};
 
 use anyhow::Result;
-use ffmpeg_next::{self as ffmpeg, Rational};
+use ffmpeg_next::{self as ffmpeg, format::Sample, Rational, Rescale};
+use log::warn;
 
-use crate::RawAudioFrame;
+use crate::{
+    application_config::{AppConfig, SampleFormat},
+    RawAudioFrame,
+};
 
 use super::{buffer::AudioBuffer, video_encoder::ONE_MICROS};
 
@@ -12,20 +16,24 @@ const MIN_RMS: f32 = 0.01;
 pub struct AudioEncoder {
     encoder: Option<ffmpeg::codec::encoder::Audio>,
     audio_buffer: AudioBuffer,
+    /// Samples sent to the encoder so far
     next_pts: i64,
     leftover_data: VecDeque<f32>,
+    /// Settings the encoder is opened and reopened with
+    config: Arc<AppConfig>,
 }
 
 impl AudioEncoder {
-    pub fn new(max_seconds: u32) -> Result<Self, ffmpeg::Error> {
-        let encoder = Some(Self::create_opus_encoder()?);
-        let max_time = max_seconds as usize * ONE_MICROS;
+    pub fn new(config: Arc<AppConfig>) -> Result<Self, ffmpeg::Error> {
+        let encoder = Some(Self::create_opus_encoder(&config)?);
+        let max_time = config.max_seconds as usize * ONE_MICROS;
 
         Ok(Self {
             encoder,
             audio_buffer: AudioBuffer::new(max_time),
             next_pts: 0,
             leftover_data: VecDeque::new(),
+            config,
         })
     }
 
@@ -34,19 +42,25 @@ impl AudioEncoder {
             let n_channels = encoder.channels() as usize;
             let total_samples = raw_frame.samples.len();
 
-            if total_samples % n_channels != 0 {
+            if n_channels == 0 || total_samples % n_channels != 0 {
                 return Err(ffmpeg::Error::InvalidData);
             }
 
-            let frame_size = encoder.frame_size() as usize;
-
             // Boost the audio so that even if system audio level is low
             // it's still audible in playback
             Self::boost_with_rms(raw_frame.get_samples_mut())?;
             self.leftover_data.extend(raw_frame.get_samples());
 
-            // Send chunked frames to encoder
-            while self.leftover_data.len() >= frame_size {
+            // Encoders that take any number of samples per frame, e.g. PCM, report a frame size
+            // of 0, hand those everything buffered at once
+            let frame_size = match encoder.frame_size() as usize {
+                0 => self.leftover_data.len(),
+                frame_size => frame_size,
+            };
+
+            // Send chunked frames to encoder. Nothing is sent while nothing is buffered, which
+            // would loop forever with a frame size of 0
+            while frame_size > 0 && self.leftover_data.len() >= frame_size {
                 let frame_samples: Vec<f32> = self.leftover_data.drain(..frame_size).collect();
                 let mut frame = ffmpeg::frame::Audio::new(
                     encoder.format(),
@@ -55,8 +69,11 @@ impl AudioEncoder {
                 );
 
                 // Capture time in vec
-                frame.plane_mut(0).copy_from_slice(&frame_samples);
-                frame.set_pts(Some(self.next_pts));
+                write_samples(&mut frame, &frame_samples);
+                frame.set_pts(Some(self.next_pts.rescale(
+                    Rational::new(1, encoder.rate() as i32),
+                    encoder.time_base(),
+                )));
                 frame.set_rate(encoder.rate());
 
                 self.audio_buffer.insert_capture_time(raw_frame.timestamp);
@@ -86,49 +103,163 @@ impl AudioEncoder {
         &self.audio_buffer
     }
 
+    pub fn get_buffer_mut(&mut self) -> &mut AudioBuffer {
+        &mut self.audio_buffer
+    }
+
     // Drain remaining frames being processed in the encoder
     pub fn drain(&mut self) -> Result<(), ffmpeg::Error> {
         if let Some(ref mut encoder) = self.encoder {
-            encoder.send_eof()?;
-            let mut packet = ffmpeg::codec::packet::Packet::empty();
-            while encoder.receive_packet(&mut packet).is_ok() {
+            let packets = Self::flush_encoder(encoder)?;
+            self.buffer_drained(packets);
+        }
+        Ok(())
+    }
+
+    // Same as `drain` but run on the blocking thread pool so the async executor isn't held up
+    pub async fn drain_async(&mut self) -> Result<(), ffmpeg::Error> {
+        let Some(mut encoder) = self.encoder.take() else {
+            return Ok(());
+        };
+
+        let (encoder, packets) = tokio::task::spawn_blocking(move || {
+            let packets = Self::flush_encoder(&mut encoder);
+            (encoder, packets)
+        })
+        .await
+        .map_err(|_| ffmpeg::Error::Bug)?;
+        self.encoder = Some(encoder);
+
+        self.buffer_drained(packets?);
+        Ok(())
+    }
+
+    /// Encode `frames` frames of silence with an encoder set up like the capture one, for padding
+    /// clips whose audio ends before the video
+    pub fn encode_silence(
+        frames: usize,
+        config: &AppConfig,
+    ) -> Result<Vec<Vec<u8>>, ffmpeg::Error> {
+        let mut encoder = Self::create_opus_encoder(config)?;
+        let frame_size = encoder.frame_size() as usize;
+        if frame_size == 0 {
+            return Err(ffmpeg::Error::InvalidData);
+        }
+        let silence = vec![0.0; frame_size];
+
+        let mut packets = Vec::with_capacity(frames);
+        let mut next_pts = 0;
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        // The encoder holds back its lookahead, keep feeding it until enough packets come out
+        while packets.len() < frames {
+            let mut frame =
+                ffmpeg::frame::Audio::new(encoder.format(), frame_size, encoder.channel_layout());
+            write_samples(&mut frame, &silence);
+            frame.set_pts(Some(next_pts.rescale(
+                Rational::new(1, encoder.rate() as i32),
+                encoder.time_base(),
+            )));
+            frame.set_rate(encoder.rate());
+            encoder.send_frame(&frame)?;
+            next_pts += frame_size as i64;
+
+            while packets.len() < frames && encoder.receive_packet(&mut packet).is_ok() {
                 if let Some(data) = packet.data() {
-                    let pts = packet.pts().unwrap_or(0);
-                    self.audio_buffer.insert_frame(pts, data.to_vec());
+                    packets.push(data.to_vec());
                 }
             }
         }
-        Ok(())
+        Ok(packets)
+    }
+
+    fn flush_encoder(
+        encoder: &mut ffmpeg::codec::encoder::Audio,
+    ) -> Result<Vec<ffmpeg::codec::packet::Packet>, ffmpeg::Error> {
+        encoder.send_eof()?;
+        let mut packets = Vec::new();
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            packets.push(packet);
+            packet = ffmpeg::codec::packet::Packet::empty();
+        }
+        Ok(packets)
+    }
+
+    fn buffer_drained(&mut self, packets: Vec<ffmpeg::codec::packet::Packet>) {
+        for packet in packets {
+            if let Some(data) = packet.data() {
+                let pts = packet.pts().unwrap_or(0);
+                self.audio_buffer.insert_frame(pts, data.to_vec());
+            }
+        }
     }
 
     pub fn reset_encoder(&mut self) -> Result<(), ffmpeg::Error> {
         self.encoder.take();
         self.audio_buffer.reset();
 
-        self.encoder = Some(Self::create_opus_encoder()?);
+        self.encoder = Some(Self::create_opus_encoder(&self.config)?);
         Ok(())
     }
 }
 
 impl AudioEncoder {
-    fn create_opus_encoder() -> Result<ffmpeg::codec::encoder::Audio, ffmpeg::Error> {
+    fn create_opus_encoder(
+        config: &AppConfig,
+    ) -> Result<ffmpeg::codec::encoder::Audio, ffmpeg::Error> {
         let encoder_codec = ffmpeg::codec::encoder::find(ffmpeg_next::codec::Id::OPUS)
             .ok_or(ffmpeg::Error::EncoderNotFound)?;
 
+        let format = config.audio_sample_format;
+        if format == SampleFormat::F32 {
+            return Self::open_opus_encoder(encoder_codec, format, config);
+        }
+
+        let supported = encoder_codec
+            .audio()?
+            .formats()
+            .map_or(true, |mut formats| formats.any(|f| f == format.sample()));
+        let opened = if supported {
+            Self::open_opus_encoder(encoder_codec, format, config)
+        } else {
+            Err(ffmpeg::Error::InvalidData)
+        };
+        opened.or_else(|e| {
+            warn!(
+                "Opus encoder {} doesn't take {:?} samples ({:?}), falling back to f32",
+                encoder_codec.name(),
+                format,
+                e
+            );
+            Self::open_opus_encoder(encoder_codec, SampleFormat::F32, config)
+        })
+    }
+
+    fn open_opus_encoder(
+        encoder_codec: ffmpeg::Codec,
+        format: SampleFormat,
+        config: &AppConfig,
+    ) -> Result<ffmpeg::codec::encoder::Audio, ffmpeg::Error> {
         let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(encoder_codec)
             .encoder()
             .audio()?;
 
         encoder_ctx.set_rate(48000);
         encoder_ctx.set_bit_rate(70_000);
-        encoder_ctx.set_format(ffmpeg::format::Sample::F32(
-            ffmpeg_next::format::sample::Type::Packed,
-        ));
-        encoder_ctx.set_time_base(Rational::new(1, 48000));
+        encoder_ctx.set_format(format.sample());
+        if format == SampleFormat::S24 {
+            unsafe {
+                (*encoder_ctx.as_mut_ptr()).bits_per_raw_sample = 24;
+            }
+        }
+        encoder_ctx.set_time_base(Rational::new(1, config.time_base_den as i32));
         encoder_ctx.set_frame_rate(Some(Rational::new(1, 48000)));
         encoder_ctx.set_channel_layout(ffmpeg::channel_layout::ChannelLayout::STEREO);
 
-        let mut encoder = encoder_ctx.open()?;
+        // Samples are gathered into frames of this length whatever size PipeWire delivers them in
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("frame_duration", &config.audio_frame_ms.to_string());
+        let mut encoder = encoder_ctx.open_with(opts)?;
 
         // Opus frame size is based on n channels so need to update it
         unsafe {
@@ -156,3 +287,119 @@ impl AudioEncoder {
         Ok(())
     }
 }
+
+/// Copy captured `f32` samples into `frame`, converting them to the encoder's sample format
+fn write_samples(frame: &mut ffmpeg::frame::Audio, samples: &[f32]) {
+    match frame.format() {
+        Sample::I16(_) => {
+            for (dst, src) in frame.plane_mut::<i16>(0).iter_mut().zip(samples) {
+                *dst = (src.clamp(-1.0, 1.0) * 32_767.0) as i16;
+            }
+        }
+        Sample::I32(_) => {
+            // 24 bit samples in the top of each i32, the low byte stays empty
+            for (dst, src) in frame.plane_mut::<i32>(0).iter_mut().zip(samples) {
+                *dst = ((src.clamp(-1.0, 1.0) * 8_388_607.0) as i32) << 8;
+            }
+        }
+        _ => frame.plane_mut(0).copy_from_slice(samples),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+
+    /// PCM takes any number of samples per frame, so it reports a frame size of 0
+    fn pcm_encoder() -> AudioEncoder {
+        ffmpeg::init().unwrap();
+        let codec = ffmpeg::codec::encoder::find(ffmpeg::codec::Id::PCM_F32LE).unwrap();
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .audio()
+            .unwrap();
+        encoder_ctx.set_rate(48000);
+        encoder_ctx.set_format(Sample::F32(ffmpeg::format::sample::Type::Packed));
+        encoder_ctx.set_time_base(Rational::new(1, 48000));
+        encoder_ctx.set_channel_layout(ffmpeg::channel_layout::ChannelLayout::STEREO);
+        let encoder = encoder_ctx.open().unwrap();
+        assert_eq!(encoder.frame_size(), 0);
+
+        AudioEncoder {
+            encoder: Some(encoder),
+            audio_buffer: AudioBuffer::new(10 * ONE_MICROS),
+            next_pts: 0,
+            leftover_data: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn process_encodes_with_a_frame_size_of_zero() {
+        // Run it on its own thread, it used to loop forever
+        let (done_tx, done_rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut encoder = pcm_encoder();
+            for samples in [vec![0.25; 960 * 2], Vec::new(), vec![0.25; 480 * 2]] {
+                let mut frame = RawAudioFrame {
+                    samples,
+                    timestamp: 0,
+                };
+                encoder.process(&mut frame).unwrap();
+            }
+            done_tx
+                .send(encoder.get_buffer().get_frames().len())
+                .unwrap();
+        });
+
+        let packets = done_rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("process() hung or panicked with a frame size of 0");
+        assert_eq!(packets, 2);
+    }
+
+    /// Write `samples` into a mono frame in `format`, one sample per frame sample
+    fn written(format: SampleFormat, samples: &[f32]) -> ffmpeg::frame::Audio {
+        ffmpeg::init().unwrap();
+        let mut frame = ffmpeg::frame::Audio::new(
+            format.sample(),
+            samples.len(),
+            ffmpeg::channel_layout::ChannelLayout::MONO,
+        );
+        write_samples(&mut frame, samples);
+        frame
+    }
+
+    const SAMPLES: [f32; 5] = [0.0, 0.5, -1.0, 2.0, -2.0];
+
+    #[test]
+    fn write_samples_copies_f32() {
+        let frame = written(SampleFormat::F32, &SAMPLES);
+        assert_eq!(frame.plane::<f32>(0), &SAMPLES);
+    }
+
+    #[test]
+    fn write_samples_scales_and_clamps_s16() {
+        let frame = written(SampleFormat::S16, &SAMPLES);
+        assert_eq!(
+            frame.plane::<i16>(0),
+            &[0, 16_383, -32_767, 32_767, -32_767]
+        );
+    }
+
+    #[test]
+    fn write_samples_puts_s24_in_the_top_of_i32() {
+        let frame = written(SampleFormat::S24, &SAMPLES);
+        assert_eq!(
+            frame.plane::<i32>(0),
+            &[
+                0,
+                4_194_303 << 8,
+                -8_388_607 << 8,
+                8_388_607 << 8,
+                -8_388_607 << 8
+            ]
+        );
+    }
+}
@@ -1,34 +1,69 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    ops::RangeBounds,
+    path::{Path, PathBuf},
+};
 
-use log::warn;
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use ffmpeg_next as ffmpeg;
+use log::{debug, warn};
+
+use crate::{application_config::BufferLimit, pw_capture::cursor::CursorOverlay};
+
+use super::video_muxer;
+
+/// Micro seconds in a second, the unit buffered timestamps are in
+pub const ONE_MICROS: usize = 1_000_000;
+
+/// Frames between the keyframes the video encoder inserts on its own
+pub const GOP_SIZE: u32 = 30;
+
+/// Clips are only saved once this many GOPs are buffered, so a save during the first seconds of
+/// capture still starts and ends on a keyframe
+pub const MIN_SAVE_GOPS: usize = 2;
+
+/// First line of every manifest written by [`VideoBuffer::export_as_concat_list`]
+const FFCONCAT_HEADER: &str = "ffconcat version 1.0";
 
 /// Represents a single encoded video frame
 #[derive(Clone, Debug)]
 pub struct VideoFrameData {
-    frame_bytes: Vec<u8>,
+    /// Shared, so copying a buffer doesn't copy the encoded data
+    frame_bytes: Bytes,
     pts: i64,
     is_key: bool,
+
+    /// Set by the buffer on insert when the frame is shown before a frame decoded earlier
+    is_b_frame: bool,
 }
 
 impl VideoFrameData {
     pub fn new(frame_bytes: Vec<u8>, is_key: bool, dts: i64) -> Self {
         Self {
-            frame_bytes,
+            frame_bytes: Bytes::from(frame_bytes),
             is_key,
             pts: dts,
+            is_b_frame: false,
         }
     }
 
-    pub fn get_raw_bytes(&self) -> &Vec<u8> {
+    pub fn get_raw_bytes(&self) -> &[u8] {
         &self.frame_bytes
     }
 
     pub fn get_pts(&self) -> &i64 {
         &self.pts
     }
+
+    pub fn is_key(&self) -> bool {
+        self.is_key
+    }
 }
 
-/// Rolling buffer which holds up to the last `max_time` seconds of video frames.
+/// Rolling buffer which holds the last video frames up to its [`BufferLimit`].
 ///
 /// The buffer is ordered by decoding timestamp (DTS) and maintains complete GOPs (groups of pictures),
 /// ensuring that no partial GOPs are kept when trimming for ease of muxing and playback.
@@ -36,51 +71,115 @@ impl VideoFrameData {
 pub struct VideoBuffer {
     frames: BTreeMap<i64, VideoFrameData>,
 
-    /// Maximum duration (in seconds) that the buffer should retain.
-    /// Once the difference between the newest and oldest frame exceeds this, older GOPs are trimmed.
-    max_time: usize,
+    /// How much the buffer should retain. Once it's exceeded, older GOPs are trimmed.
+    limit: BufferLimit,
+
+    /// Seconds a size or frame count limit may hold at most, see [`Self::set_max_seconds`]
+    max_seconds: Option<u32>,
+
+    /// Size in bytes of the buffered frames, kept up to date as frames are inserted and trimmed
+    total_bytes: u64,
 
     /// List of DTS values corresponding to key frames, ordered by insertion.
     /// Used to identify GOP boundaries for trimming purposes.
     key_frame_keys: Vec<i64>,
+
+    /// Number of buffered (I, P, B) frames, kept up to date as frames are inserted and trimmed
+    frame_counts: (usize, usize, usize),
+
+    /// PTS of the newest buffered frame, kept up to date as frames are inserted. Trimming only
+    /// removes frames shown before the key frame the buffer then starts with, so it never changes.
+    newest_pts: Option<i64>,
+
+    /// Where the cursor was on each frame when it's left out of the encoded video, see
+    /// [`Self::insert_cursor`]
+    cursors: BTreeMap<i64, Option<CursorOverlay>>,
 }
 
 impl VideoBuffer {
-    /// Creates a new `FrameBuffer` with a specified maximum duration.
+    /// Creates a new `FrameBuffer` with a specified limit.
     ///
     /// # Arguments
     ///
-    /// * `max_time` - Maximum duration (in seconds) of video frames to retain in the buffer.
-    pub fn new(max_time: usize) -> Self {
+    /// * `limit` - How much video to retain in the buffer.
+    pub fn new(limit: BufferLimit) -> Self {
         Self {
             frames: BTreeMap::new(),
-            max_time,
+            limit,
+            max_seconds: None,
+            total_bytes: 0,
             key_frame_keys: Vec::new(),
+            frame_counts: (0, 0, 0),
+            newest_pts: None,
+            cursors: BTreeMap::new(),
         }
     }
 
-    /// Inserts a new video frame into the buffer, keeping the buffer size within `max_time`.
+    /// Inserts a new video frame into the buffer, keeping the buffer within its limit.
     ///
-    /// If the inserted frame is a key frame, its timestamp is recorded to track GOP boundaries.
-    /// After insertion, older frames are trimmed if the total duration exceeds `max_time`.
+    /// If the inserted frame is a key frame, its timestamp is recorded to track GOP boundaries and,
+    /// if the buffer exceeds its limit, the oldest GOP is trimmed. Only one GOP goes per
+    /// key frame so the work is spread evenly over time, a buffer that grew by more than one GOP
+    /// shrinks back over the next few.
     ///
     /// # Arguments
     ///
     /// * `timestamp` - The decoding timestamp (DTS) of the frame.
     /// * `frame` - A [`VideoFrameData`] representing an encoded frame.
-    pub fn insert(&mut self, timestamp: i64, frame: VideoFrameData) {
-        if frame.is_key {
+    pub fn insert(&mut self, timestamp: i64, mut frame: VideoFrameData) {
+        let is_key = frame.is_key;
+        if is_key {
             self.key_frame_keys.push(timestamp);
         }
 
-        self.frames.insert(timestamp, frame);
+        // FFmpeg has no packet flag for B-frames, but they are the only frames displayed before
+        // one that was decoded earlier
+        frame.is_b_frame = !frame.is_key
+            && self
+                .newest_pts
+                .is_some_and(|newest_pts| frame.pts < newest_pts);
+        self.newest_pts = Some(self.newest_pts.map_or(frame.pts, |pts| pts.max(frame.pts)));
+
+        self.count_frame(&frame, true);
+        if let Some(replaced) = self.frames.insert(timestamp, frame) {
+            self.count_frame(&replaced, false);
+            if self.newest_pts == Some(replaced.pts) {
+                self.newest_pts = self.frames.values().map(|frame| frame.pts).max();
+            }
+        }
 
-        // Trim old GOPs if buffer exceeds max_time
-        while let (Some(oldest), Some(newest)) = (self.oldest_pts(), self.newest_pts()) {
-            if newest - oldest >= self.max_time as i64 {
-                self.trim_oldest_gop();
-            } else {
-                break;
+        // A new key frame completes the GOP before it, so there is always one to trim here
+        if is_key && self.key_frame_keys.len() > 1 && self.exceeds_limit() {
+            self.trim_oldest_gop();
+        }
+    }
+
+    /// Changes how much the buffer retains. A buffer already over the new limit shrinks a GOP at
+    /// a time as new key frames come in.
+    pub fn set_limit(&mut self, limit: BufferLimit) {
+        self.limit = limit;
+    }
+
+    pub fn get_limit(&self) -> BufferLimit {
+        self.limit
+    }
+
+    /// Also trim a buffer under a size or frame count limit once it holds `seconds`, so it never
+    /// reaches further back than the audio buffered alongside it
+    pub fn set_max_seconds(&mut self, seconds: u32) {
+        self.max_seconds = Some(seconds);
+    }
+
+    fn exceeds_limit(&self) -> bool {
+        let exceeds_seconds =
+            |seconds: u32| self.duration_us() >= seconds as i64 * ONE_MICROS as i64;
+        match self.limit {
+            BufferLimit::ByDuration { seconds } => exceeds_seconds(seconds),
+            BufferLimit::BySize { max_bytes } => {
+                self.total_bytes > max_bytes || self.max_seconds.is_some_and(exceeds_seconds)
+            }
+            BufferLimit::ByFrameCount { max_frames } => {
+                self.frames.len() > max_frames || self.max_seconds.is_some_and(exceeds_seconds)
             }
         }
     }
@@ -89,7 +188,7 @@ impl VideoBuffer {
     ///
     /// Returns `None` if the buffer is empty.
     pub fn newest_pts(&self) -> Option<i64> {
-        self.frames.values().map(|frame| frame.pts).max()
+        self.newest_pts
     }
 
     /// Returns the presentation timestamp (PTS) of the oldest frame in the buffer.
@@ -99,6 +198,14 @@ impl VideoBuffer {
         self.frames.values().map(|frame| frame.pts).min()
     }
 
+    /// Returns the time in micro seconds between the oldest and newest buffered frame
+    pub fn duration_us(&self) -> i64 {
+        match (self.oldest_pts(), self.newest_pts()) {
+            (Some(oldest), Some(newest)) => newest - oldest,
+            _ => 0,
+        }
+    }
+
     /// Returns the decoding timestamp (DTS) of the most recent key frame (start of the last GOP).
     ///
     /// Returns `None` if no key frames have been inserted.
@@ -106,6 +213,51 @@ impl VideoBuffer {
         self.key_frame_keys.last()
     }
 
+    /// Returns the number of buffered key frames, each starting a GOP. Every GOP but the last is
+    /// complete.
+    pub fn get_gop_count(&self) -> usize {
+        self.key_frame_keys.len()
+    }
+
+    /// Returns the decoding timestamp (DTS) of the last key frame presented at or before `pts`.
+    ///
+    /// Returns `None` if `pts` is older than every buffered key frame.
+    pub fn keyframe_at_or_before(&self, pts: i64) -> Option<i64> {
+        // Key frames are never reordered, so their PTS increase along with the DTS they are
+        // stored by
+        let index = self
+            .key_frame_keys
+            .partition_point(|dts| self.frames.get(dts).is_some_and(|frame| frame.pts <= pts));
+
+        index.checked_sub(1).map(|index| self.key_frame_keys[index])
+    }
+
+    /// Returns the decoding timestamp (DTS) to end a clip at so it shows every frame presented up
+    /// to `pts`. Frames decoded after it aren't needed for those, so they can be left out without
+    /// re-encoding.
+    ///
+    /// Returns `None` if no frame is presented at or before `pts`.
+    pub fn last_dts_shown_by(&self, pts: i64) -> Option<i64> {
+        self.frames
+            .iter()
+            .filter(|(_, frame)| frame.pts <= pts)
+            .map(|(&dts, _)| dts)
+            .max()
+    }
+
+    /// Removes the GOPs shown entirely before `pts`, so the buffer starts with the last key frame
+    /// presented at or before it.
+    pub fn trim_before(&mut self, pts: i64) {
+        while self.key_frame_keys.len() > 1
+            && self
+                .frames
+                .get(&self.key_frame_keys[1])
+                .is_some_and(|frame| frame.pts <= pts)
+        {
+            self.trim_oldest_gop();
+        }
+    }
+
     /// Removes the oldest group of pictures (GOP) from the buffer.
     ///
     /// A GOP is considered complete when there is at least one subsequent key frame.
@@ -127,21 +279,318 @@ impl VideoBuffer {
         }
 
         for dts in dts_to_remove {
-            self.frames.remove(&dts);
+            if let Some(frame) = self.frames.remove(&dts) {
+                self.count_frame(&frame, false);
+            }
         }
 
         // Remove deleted key frame
         self.key_frame_keys.remove(0);
+
+        if let Some(oldest) = self.oldest_pts() {
+            self.cursors = self.cursors.split_off(&oldest);
+        }
+    }
+
+    /// Record the cursor of the frame captured at `pts`, `None` if it wasn't on screen, so it can
+    /// be drawn onto clips at save time instead of into every frame
+    pub fn insert_cursor(&mut self, pts: i64, cursor: Option<CursorOverlay>) {
+        self.cursors.insert(pts, cursor);
+    }
+
+    /// Returns the cursor to draw over the frame presented at `pts`, if one was recorded
+    pub fn cursor_at(&self, pts: i64) -> Option<&CursorOverlay> {
+        self.cursors.range(..=pts).next_back()?.1.as_ref()
+    }
+
+    /// Whether cursors were recorded with [`Self::insert_cursor`] for the buffered frames
+    pub fn has_cursors(&self) -> bool {
+        !self.cursors.is_empty()
+    }
+
+    /// Returns the PTS a clip of the last `seconds` starts at. Clips can only start on a key
+    /// frame, so this is the key frame at or before that point, or the point itself when it's
+    /// older than the buffer.
+    ///
+    /// Returns `None` if the buffer is empty.
+    pub fn clip_start_pts(&self, seconds: u32) -> Option<i64> {
+        let cutoff = self.newest_pts()? - seconds as i64 * ONE_MICROS as i64;
+        let start = self
+            .keyframe_at_or_before(cutoff)
+            .and_then(|dts| self.frames.get(&dts))
+            .map_or(cutoff, |keyframe| keyframe.pts);
+        Some(start)
+    }
+
+    /// Returns the total size in bytes of the frames with a PTS of at least `pts`
+    pub fn bytes_since(&self, pts: i64) -> usize {
+        self.frames
+            .values()
+            .filter(|frame| frame.pts >= pts)
+            .map(|frame| frame.frame_bytes.len())
+            .sum()
+    }
+
+    /// Returns the number of buffered (I, P, B) frames
+    pub fn frame_count_by_type(&self) -> (usize, usize, usize) {
+        self.frame_counts
+    }
+
+    /// Returns the number of buffered frames
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    fn count_frame(&mut self, frame: &VideoFrameData, inserted: bool) {
+        let bytes = frame.frame_bytes.len() as u64;
+        if inserted {
+            self.total_bytes += bytes;
+        } else {
+            self.total_bytes = self.total_bytes.saturating_sub(bytes);
+        }
+
+        let count = if frame.is_key {
+            &mut self.frame_counts.0
+        } else if frame.is_b_frame {
+            &mut self.frame_counts.2
+        } else {
+            &mut self.frame_counts.1
+        };
+
+        if inserted {
+            *count += 1;
+        } else {
+            *count = count.saturating_sub(1);
+        }
     }
 
     pub fn get_frames(&self) -> &BTreeMap<i64, VideoFrameData> {
         &self.frames
     }
 
+    /// Writes every complete GOP to its own MPEG-TS segment and an `ffconcat` manifest listing
+    /// them to `output`, so the buffer can be joined with `ffmpeg -f concat` without re-encoding.
+    ///
+    /// The segments go to a directory named after the manifest inside `segment_dir` and stay
+    /// until [`remove_concat_list`] is called with the manifest. The GOP still being encoded is
+    /// left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Path of the manifest.
+    /// * `segment_dir` - Where the directory of segments is created.
+    /// * `parameters` - Codec parameters of the buffered video, as the encoder reports them.
+    pub fn export_as_concat_list(
+        &self,
+        output: &Path,
+        segment_dir: &Path,
+        parameters: &ffmpeg::codec::Parameters,
+    ) -> Result<()> {
+        if self.key_frame_keys.len() < 2 {
+            bail!("No complete GOP buffered yet");
+        }
+
+        let name = output
+            .file_stem()
+            .context("The manifest path has no file name")?;
+        let segments = segment_dir.join(name);
+        if segments.exists() {
+            bail!(
+                "{:?} already exists, remove the previous export first",
+                segments
+            );
+        }
+        fs::create_dir_all(&segments)?;
+
+        let result = self.write_segments(output, &segments, parameters);
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&segments);
+        }
+        result
+    }
+
+    fn write_segments(
+        &self,
+        output: &Path,
+        segments: &Path,
+        parameters: &ffmpeg::codec::Parameters,
+    ) -> Result<()> {
+        let first_keyframe = self.key_frame_keys[0];
+        // Every segment counts from the first, so they play as one timeline when concatenated
+        let timeline_start =
+            video_muxer::timeline_start(first_keyframe, &self.frames[&first_keyframe]);
+
+        let mut manifest = BufWriter::new(File::create(output)?);
+        writeln!(manifest, "{}", FFCONCAT_HEADER)?;
+        for (index, gop) in self.key_frame_keys.windows(2).enumerate() {
+            let (start, end) = (gop[0], gop[1]);
+            let path = segments.join(format!("segment_{:04}.ts", index));
+
+            video_muxer::write_ts_file(
+                &path,
+                parameters.clone(),
+                timeline_start,
+                self.frames
+                    .range(start..end)
+                    .map(|(&dts, frame)| (dts, frame)),
+            )?;
+
+            // The demuxer lines segments up by their durations, probing them would be off by
+            // the last frame
+            let duration_us = self.frames[&end].pts - self.frames[&start].pts;
+            writeln!(manifest, "file '{}'", escape_concat_path(&path))?;
+            writeln!(manifest, "duration {:.6}", duration_us as f64 / 1_000_000.0)?;
+        }
+        manifest.flush()?;
+
+        debug!(
+            "Exported {} GOPs to {:?}, listed in {:?}",
+            self.key_frame_keys.len() - 1,
+            segments,
+            output
+        );
+        Ok(())
+    }
+
     pub fn reset(&mut self) {
         self.frames.clear();
         self.key_frame_keys.clear();
+        self.frame_counts = (0, 0, 0);
+        self.newest_pts = None;
+        self.total_bytes = 0;
+        self.cursors.clear();
+    }
+
+    /// Copies the frames with a DTS within `range` into a new buffer with the same limit, so they
+    /// can be worked on without holding on to this one. Cursors are left out.
+    pub fn copy_range(&self, range: impl RangeBounds<i64>) -> Self {
+        let mut copy = Self::new(self.limit);
+        copy.max_seconds = self.max_seconds;
+        copy.append_range(self, range);
+        copy
+    }
+
+    /// Adds the frames of `other` with a DTS within `range` as they are, without trimming. They
+    /// have to be decoded after every frame already buffered. Frames share their data, so only
+    /// the index is copied.
+    pub fn append_range(&mut self, other: &Self, range: impl RangeBounds<i64>) {
+        for (&dts, frame) in other.frames.range(range) {
+            if frame.is_key {
+                self.key_frame_keys.push(dts);
+            }
+            self.newest_pts = Some(self.newest_pts.map_or(frame.pts, |pts| pts.max(frame.pts)));
+            self.count_frame(frame, true);
+            self.frames.insert(dts, frame.clone());
+        }
+    }
+
+    /// Moves the buffered frames out, leaving an empty buffer with the same limit behind
+    pub fn take(&mut self) -> Self {
+        let mut empty = Self::new(self.limit);
+        empty.max_seconds = self.max_seconds;
+        std::mem::replace(self, empty)
+    }
+}
+
+/// Rough size of the container header and trailer added to the packet data of a clip
+const MUX_OVERHEAD_BYTES: u64 = 4 * 1024;
+
+/// Estimate the size of a clip of the last `seconds` from the buffered packet sizes plus the
+/// container overhead. Returns 0 while no video is buffered.
+pub fn estimate_clip_size(
+    video_buffer: &VideoBuffer,
+    audio_buffer: &AudioBuffer,
+    seconds: u32,
+) -> u64 {
+    let Some(start) = video_buffer.clip_start_pts(seconds) else {
+        return 0;
+    };
+
+    (video_buffer.bytes_since(start) + audio_buffer.bytes_since(start)) as u64 + MUX_OVERHEAD_BYTES
+}
+
+/// Delete a manifest written by [`VideoBuffer::export_as_concat_list`] along with the segments it
+/// lists, once it has been consumed. Refuses manifests listing anything but `segment_*.ts` files
+/// in a directory of `segment_dir`, so it can't be used to delete other files.
+pub fn remove_concat_list(manifest: &Path, segment_dir: &Path) -> Result<()> {
+    let contents = fs::read_to_string(manifest)
+        .with_context(|| format!("Could not read the manifest {:?}", manifest))?;
+    if contents.lines().next() != Some(FFCONCAT_HEADER) {
+        bail!("{:?} is not an ffconcat manifest", manifest);
+    }
+    let segment_dir = segment_dir
+        .canonicalize()
+        .with_context(|| format!("Could not resolve the segment directory {:?}", segment_dir))?;
+
+    // Check every segment before deleting any
+    let mut segments = Vec::new();
+    for line in contents.lines() {
+        let Some(path) = line
+            .strip_prefix("file '")
+            .and_then(|path| path.strip_suffix('\''))
+        else {
+            continue;
+        };
+
+        let path = PathBuf::from(path.replace("'\\''", "'"));
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("Segment {:?} is already gone", path);
+                continue;
+            }
+            Err(e) => bail!("Could not resolve segment {:?}: {}", path, e),
+        };
+        if !is_exported_segment(&path, &segment_dir) {
+            bail!(
+                "{:?} lists {:?}, which is not a segment in {:?}",
+                manifest,
+                path,
+                segment_dir
+            );
+        }
+        segments.push(path);
+    }
+
+    let mut export_dirs = Vec::new();
+    for path in segments {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Could not remove segment {:?}: {}", path, e);
+        }
+        if let Some(parent) = path.parent() {
+            if !export_dirs.iter().any(|dir| dir == parent) {
+                export_dirs.push(parent.to_path_buf());
+            }
+        }
     }
+
+    // Only removes the directories if nothing else was put in them
+    for dir in export_dirs {
+        let _ = fs::remove_dir(dir);
+    }
+    fs::remove_file(manifest)?;
+
+    debug!("Removed concat list {:?}", manifest);
+    Ok(())
+}
+
+/// Whether the resolved `path` is named like the segments `export_as_concat_list` writes and sits
+/// in an export's directory directly under `segment_dir`
+fn is_exported_segment(path: &Path, segment_dir: &Path) -> bool {
+    let named_like_segment = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("segment_"))
+        .and_then(|name| name.strip_suffix(".ts"))
+        .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()));
+
+    named_like_segment && path.parent().and_then(Path::parent) == Some(segment_dir)
+}
+
+/// Quote a path for a `file` line in an ffconcat manifest, which ends the quoted string at every
+/// `'`
+fn escape_concat_path(path: &Path) -> String {
+    path.to_string_lossy().replace('\'', "'\\''")
 }
 
 #[derive(Clone)]
@@ -197,6 +646,49 @@ impl AudioBuffer {
         self.frames.keys().next().copied()
     }
 
+    /// Removes all frames captured strictly before `pts_us`, returning how many were removed.
+    ///
+    /// Audio frames are keyed by the encoder's PTS, so the comparison uses their capture time
+    /// which shares its microsecond timeline with video PTS. This keeps both buffers aligned when
+    /// the video buffer trims a GOP.
+    ///
+    /// # Arguments
+    ///
+    /// * `pts_us` - Timestamp in micro seconds, usually the oldest PTS left in the video buffer.
+    pub fn trim_before(&mut self, pts_us: i64) -> usize {
+        let count = self
+            .capture_times
+            .iter()
+            .take_while(|&&time| time < pts_us)
+            .count()
+            .min(self.frames.len());
+
+        for _ in 0..count {
+            self.frames.pop_first();
+        }
+        self.capture_times.drain(..count);
+
+        count
+    }
+
+    /// Returns the total size in bytes of the frames captured at or after `capture_time`
+    pub fn bytes_since(&self, capture_time: i64) -> usize {
+        self.frames
+            .values()
+            .zip(&self.capture_times)
+            .filter(|(_, &time)| time >= capture_time)
+            .map(|(frame, _)| frame.len())
+            .sum()
+    }
+
+    /// Returns the time in micro seconds between the oldest and newest buffered capture
+    pub fn duration_us(&self) -> i64 {
+        match (self.capture_times.first(), self.capture_times.last()) {
+            (Some(oldest), Some(newest)) => newest - oldest,
+            _ => 0,
+        }
+    }
+
     pub fn get_capture_times(&self) -> &Vec<i64> {
         &self.capture_times
     }
@@ -213,4 +705,201 @@ impl AudioBuffer {
         self.frames.clear();
         self.capture_times.clear();
     }
+
+    /// Moves the buffered frames out, leaving an empty buffer with the same `max_time` behind
+    pub fn take(&mut self) -> Self {
+        std::mem::replace(self, Self::new(self.max_time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: i64 = ONE_MICROS as i64;
+
+    /// A frame of `size` bytes every 100ms for `seconds`, with a key frame every second
+    fn video_buffer(seconds: i64, size: usize) -> VideoBuffer {
+        let mut buffer = VideoBuffer::new(BufferLimit::ByDuration { seconds: 60 });
+        for index in 0..seconds * 10 {
+            let pts = index * SECOND / 10;
+            buffer.insert(
+                pts,
+                VideoFrameData::new(vec![0; size], index % 10 == 0, pts),
+            );
+        }
+        buffer
+    }
+
+    /// A packet of `size` bytes captured every 20ms for `seconds`
+    fn audio_buffer(seconds: i64, size: usize) -> AudioBuffer {
+        let mut buffer = AudioBuffer::new(60 * ONE_MICROS);
+        for index in 0..seconds * 50 {
+            buffer.insert_capture_time(index * SECOND / 50);
+            buffer.insert_frame(index, vec![0; size]);
+        }
+        buffer
+    }
+
+    #[test]
+    fn frame_count_limit_is_held_to_max_seconds() {
+        let mut buffer = VideoBuffer::new(BufferLimit::ByFrameCount { max_frames: 1000 });
+        buffer.set_max_seconds(3);
+        for index in 0..100 {
+            let pts = index * SECOND / 10;
+            buffer.insert(pts, VideoFrameData::new(vec![0; 10], index % 10 == 0, pts));
+        }
+
+        assert!(buffer.duration_us() < 3 * SECOND);
+        assert_eq!(buffer.get_gop_count(), 3);
+    }
+
+    #[test]
+    fn newest_pts_follows_reordered_frames_and_trimming() {
+        let mut buffer = VideoBuffer::new(BufferLimit::ByDuration { seconds: 1 });
+        assert_eq!(buffer.newest_pts(), None);
+
+        // Decoded I P B, shown I B P
+        buffer.insert(0, VideoFrameData::new(vec![0; 10], true, 0));
+        buffer.insert(1, VideoFrameData::new(vec![0; 10], false, 2 * SECOND / 10));
+        buffer.insert(2, VideoFrameData::new(vec![0; 10], false, SECOND / 10));
+        assert_eq!(buffer.newest_pts(), Some(2 * SECOND / 10));
+        assert_eq!(buffer.frame_count_by_type(), (1, 1, 1));
+
+        // The next key frame pushes the buffer past its limit and trims the first GOP
+        buffer.insert(3, VideoFrameData::new(vec![0; 10], true, 2 * SECOND));
+        assert_eq!(buffer.get_gop_count(), 1);
+        assert_eq!(buffer.newest_pts(), Some(2 * SECOND));
+
+        buffer.reset();
+        assert_eq!(buffer.newest_pts(), None);
+    }
+
+    #[test]
+    fn copy_range_keeps_only_the_frames_within() {
+        let buffer = video_buffer(3, 100);
+
+        let copy = buffer.copy_range(..=2 * SECOND);
+        assert_eq!(copy.get_gop_count(), 3);
+        assert_eq!(copy.frame_count(), 21);
+        assert_eq!(copy.newest_pts(), Some(2 * SECOND));
+        assert_eq!(copy.bytes_since(0), 2100);
+
+        let mut gops = buffer.copy_range(0..SECOND);
+        gops.append_range(&buffer, 2 * SECOND..);
+        assert_eq!(gops.get_gop_count(), 2);
+        assert_eq!(gops.frame_count(), 20);
+        assert_eq!(gops.frame_count_by_type(), (2, 18, 0));
+    }
+
+    #[test]
+    fn video_bytes_since_counts_frames_shown_from_pts() {
+        let buffer = video_buffer(3, 100);
+
+        assert_eq!(buffer.bytes_since(0), 3000);
+        assert_eq!(buffer.bytes_since(SECOND), 2000);
+        assert_eq!(buffer.bytes_since(SECOND + 1), 1900);
+        assert_eq!(buffer.bytes_since(3 * SECOND), 0);
+    }
+
+    #[test]
+    fn audio_bytes_since_goes_by_capture_time() {
+        let buffer = audio_buffer(1, 10);
+
+        assert_eq!(buffer.bytes_since(0), 500);
+        assert_eq!(buffer.bytes_since(SECOND / 2), 250);
+        assert_eq!(buffer.bytes_since(SECOND), 0);
+    }
+
+    #[test]
+    fn clip_start_pts_goes_back_to_a_key_frame() {
+        let buffer = video_buffer(3, 100);
+
+        // The newest frame is at 2.9s, so a 1s clip would start at 1.9s
+        assert_eq!(buffer.clip_start_pts(1), Some(SECOND));
+        assert_eq!(buffer.clip_start_pts(0), Some(2 * SECOND));
+        // Older than the buffer, nothing to round back to
+        assert_eq!(
+            buffer.clip_start_pts(10),
+            Some(29 * SECOND / 10 - 10 * SECOND)
+        );
+
+        let empty = VideoBuffer::new(BufferLimit::ByDuration { seconds: 60 });
+        assert_eq!(empty.clip_start_pts(1), None);
+    }
+
+    #[test]
+    fn estimate_clip_size_counts_video_and_audio_from_the_clip_start() {
+        let video = video_buffer(3, 100);
+        let audio = audio_buffer(3, 10);
+
+        // Starts at the key frame at 1s: 20 video frames and 100 audio packets
+        assert_eq!(
+            estimate_clip_size(&video, &audio, 1),
+            2000 + 1000 + MUX_OVERHEAD_BYTES
+        );
+        assert_eq!(
+            estimate_clip_size(&video, &audio, 10),
+            3000 + 1500 + MUX_OVERHEAD_BYTES
+        );
+
+        let empty = VideoBuffer::new(BufferLimit::ByDuration { seconds: 60 });
+        assert_eq!(estimate_clip_size(&empty, &audio, 1), 0);
+    }
+
+    /// A manifest in `dir` listing `paths`, as `export_as_concat_list` writes them
+    fn write_manifest(dir: &Path, paths: &[PathBuf]) -> PathBuf {
+        let manifest = dir.join("export.ffconcat");
+        let mut contents = format!("{}\n", FFCONCAT_HEADER);
+        for path in paths {
+            contents += &format!("file '{}'\nduration 1.000000\n", escape_concat_path(path));
+        }
+        fs::write(&manifest, contents).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn remove_concat_list_deletes_the_exported_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let segment_dir = dir.path().join("segments");
+        let export = segment_dir.join("export");
+        fs::create_dir_all(&export).unwrap();
+        let segments = [
+            export.join("segment_0000.ts"),
+            export.join("segment_0001.ts"),
+        ];
+        for segment in &segments {
+            fs::write(segment, b"ts").unwrap();
+        }
+        let manifest = write_manifest(dir.path(), &segments);
+
+        remove_concat_list(&manifest, &segment_dir).unwrap();
+        assert!(!manifest.exists());
+        assert!(!export.exists());
+        assert!(segment_dir.exists());
+    }
+
+    #[test]
+    fn remove_concat_list_refuses_files_that_are_not_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let segment_dir = dir.path().join("segments");
+        let export = segment_dir.join("export");
+        fs::create_dir_all(&export).unwrap();
+        let segment = export.join("segment_0000.ts");
+        let outside = dir.path().join("segment_0001.ts");
+        let misnamed = export.join("notes.txt");
+        let escaping = export.join("..").join("..").join("segment_0001.ts");
+        for file in [&segment, &outside, &misnamed] {
+            fs::write(file, b"keep").unwrap();
+        }
+
+        for listed in [&outside, &misnamed, &escaping] {
+            let manifest = write_manifest(dir.path(), &[segment.clone(), listed.clone()]);
+            assert!(remove_concat_list(&manifest, &segment_dir).is_err());
+            assert!(manifest.exists());
+        }
+        for file in [&segment, &outside, &misnamed] {
+            assert!(file.exists());
+        }
+    }
 }
@@ -0,0 +1,118 @@
+use ffmpeg_next::{self as ffmpeg, format::Pixel, software::scaling, Rational};
+use log::debug;
+
+/// Tried in order before falling back to the software `mjpeg` encoder. They are only used when
+/// they accept frames from system memory, otherwise opening them would need a device context.
+const HARDWARE_ENCODERS: [&str; 2] = ["mjpeg_nvenc", "mjpeg_vaapi"];
+const SOFTWARE_ENCODER: &str = "mjpeg";
+
+/// Encodes single raw video frames to JPEG for previews
+pub struct MjpegEncoder;
+
+impl MjpegEncoder {
+    /// Encode `frame` to a JPEG image.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - A raw frame in any pixel format swscale can convert from.
+    /// * `quality` - JPEG quality from 1 (smallest) to 100 (best).
+    pub fn encode(frame: &ffmpeg::frame::Video, quality: u8) -> Result<Vec<u8>, ffmpeg::Error> {
+        let (mut encoder, pixel_format) =
+            Self::open_encoder(frame.width(), frame.height(), quality)?;
+
+        let mut src_frame = if frame.format() == pixel_format {
+            frame.clone()
+        } else {
+            let mut scaler = scaling::Context::get(
+                frame.format(),
+                frame.width(),
+                frame.height(),
+                pixel_format,
+                frame.width(),
+                frame.height(),
+                scaling::Flags::BILINEAR,
+            )?;
+            let mut scaled_frame = ffmpeg::frame::Video::empty();
+            scaler.run(frame, &mut scaled_frame)?;
+            scaled_frame
+        };
+
+        src_frame.set_pts(Some(0));
+        unsafe {
+            // With a fixed qscale the encoder takes the quality from each frame
+            (*src_frame.as_mut_ptr()).quality = Self::lambda(quality);
+        }
+
+        encoder.send_frame(&src_frame)?;
+        encoder.send_eof()?;
+
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        encoder.receive_packet(&mut packet)?;
+
+        packet
+            .data()
+            .map(|data| data.to_vec())
+            .ok_or(ffmpeg::Error::InvalidData)
+    }
+}
+
+impl MjpegEncoder {
+    pub(super) fn open_encoder(
+        width: u32,
+        height: u32,
+        quality: u8,
+    ) -> Result<(ffmpeg::codec::encoder::Video, Pixel), ffmpeg::Error> {
+        for name in HARDWARE_ENCODERS {
+            match Self::try_open(name, width, height, quality) {
+                Ok(opened) => return Ok(opened),
+                Err(e) => debug!("Could not open {}: {:?}", name, e),
+            }
+        }
+
+        Self::try_open(SOFTWARE_ENCODER, width, height, quality)
+    }
+
+    fn try_open(
+        name: &str,
+        width: u32,
+        height: u32,
+        quality: u8,
+    ) -> Result<(ffmpeg::codec::encoder::Video, Pixel), ffmpeg::Error> {
+        let codec =
+            ffmpeg::codec::encoder::find_by_name(name).ok_or(ffmpeg::Error::EncoderNotFound)?;
+
+        // Hardware only formats can't be fed from system memory, skip those
+        let pixel_format = codec
+            .video()?
+            .formats()
+            .and_then(|mut formats| {
+                formats.find(|format| !matches!(format, Pixel::VAAPI | Pixel::CUDA | Pixel::QSV))
+            })
+            .ok_or(ffmpeg::Error::InvalidData)?;
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+
+        encoder_ctx.set_width(width);
+        encoder_ctx.set_height(height);
+        encoder_ctx.set_format(pixel_format);
+        encoder_ctx.set_time_base(Rational::new(1, 25));
+
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("flags", "+qscale");
+        opts.set("global_quality", &Self::lambda(quality).to_string());
+
+        let encoder = encoder_ctx.open_with(opts)?;
+        debug!("Encoding preview with {} as {:?}", name, pixel_format);
+
+        Ok((encoder, pixel_format))
+    }
+
+    /// Map a 1-100 quality onto MJPEG's qscale range of 31 (worst) to 2 (best), as a lambda
+    pub(super) fn lambda(quality: u8) -> i32 {
+        let quality = quality.clamp(1, 100) as i32;
+        let qscale = 31 - (quality - 1) * 29 / 99;
+        qscale * ffmpeg::ffi::FF_QP2LAMBDA as i32
+    }
+}
@@ -0,0 +1,97 @@
+use bytes::Bytes;
+use ffmpeg_next::{self as ffmpeg, format::Pixel, software::scaling};
+use tokio::sync::broadcast;
+
+use super::mjpeg_encoder::MjpegEncoder;
+
+/// JPEG encodes every frame it's given and broadcasts it to the preview stream's clients.
+///
+/// Unlike [`super::video_encoder::VideoEncoder`] nothing is buffered, each frame is sent out as
+/// soon as it's encoded and dropped for clients that fall behind.
+pub struct MjpegStreamEncoder {
+    encoder: Option<ffmpeg::codec::encoder::Video>,
+    pixel_format: Pixel,
+    scaler: Option<scaling::Context>,
+    quality: u8,
+    next_pts: i64,
+    frames_tx: broadcast::Sender<Bytes>,
+}
+
+impl MjpegStreamEncoder {
+    /// # Arguments
+    ///
+    /// * `quality` - JPEG quality from 1 (smallest) to 100 (best).
+    /// * `frames_tx` - Channel the encoded JPEG images are broadcast on.
+    pub fn new(quality: u8, frames_tx: broadcast::Sender<Bytes>) -> Self {
+        Self {
+            encoder: None,
+            pixel_format: Pixel::None,
+            scaler: None,
+            quality,
+            next_pts: 0,
+            frames_tx,
+        }
+    }
+
+    /// Whether anyone is watching the stream, encoding can be skipped otherwise
+    pub fn has_clients(&self) -> bool {
+        self.frames_tx.receiver_count() > 0
+    }
+
+    pub fn process(&mut self, frame: &ffmpeg::frame::Video) -> Result<(), ffmpeg::Error> {
+        // (Re)open when the first frame arrives or the capture size changes
+        let size_changed = self.encoder.as_ref().is_some_and(|encoder| {
+            encoder.width() != frame.width() || encoder.height() != frame.height()
+        });
+        if self.encoder.is_none() || size_changed {
+            let (encoder, pixel_format) =
+                MjpegEncoder::open_encoder(frame.width(), frame.height(), self.quality)?;
+            self.encoder = Some(encoder);
+            self.pixel_format = pixel_format;
+            self.scaler = None;
+        }
+
+        let Some(ref mut encoder) = self.encoder else {
+            return Ok(());
+        };
+
+        let mut src_frame = if frame.format() == self.pixel_format {
+            frame.clone()
+        } else {
+            let scaler = match self.scaler {
+                Some(ref mut scaler) => scaler,
+                None => self.scaler.insert(scaling::Context::get(
+                    frame.format(),
+                    frame.width(),
+                    frame.height(),
+                    self.pixel_format,
+                    frame.width(),
+                    frame.height(),
+                    scaling::Flags::BILINEAR,
+                )?),
+            };
+
+            let mut scaled_frame = ffmpeg::frame::Video::empty();
+            scaler.run(frame, &mut scaled_frame)?;
+            scaled_frame
+        };
+
+        src_frame.set_pts(Some(self.next_pts));
+        self.next_pts += 1;
+        unsafe {
+            (*src_frame.as_mut_ptr()).quality = MjpegEncoder::lambda(self.quality);
+        }
+
+        encoder.send_frame(&src_frame)?;
+
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            if let Some(data) = packet.data() {
+                // No receivers just means nobody is watching right now
+                let _ = self.frames_tx.send(Bytes::copy_from_slice(data));
+            }
+        }
+
+        Ok(())
+    }
+}
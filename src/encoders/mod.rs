@@ -1,3 +1,47 @@
 pub mod video_encoder;
+pub mod audio_analyzer;
 pub mod audio_encoder;
 pub mod buffer;
+pub mod mjpeg_encoder;
+pub mod mjpeg_stream_encoder;
+pub mod packet_dump;
+pub mod persistence;
+#[cfg(feature = "compute_ssim")]
+pub mod quality;
+pub mod subtitle_encoder;
+pub mod video_muxer;
+
+use ffmpeg_next as ffmpeg;
+
+use buffer::VideoFrameData;
+
+/// Decode `frames`, handing every decoded frame to `take` until it returns `true`. Frames held back
+/// for reordering only come out once the decoder is flushed, so it is flushed if `frames` run out
+/// first.
+///
+/// Returns whether `take` was satisfied.
+pub fn decode_until<'a>(
+    decoder: &mut ffmpeg::codec::decoder::Video,
+    frames: impl Iterator<Item = &'a VideoFrameData>,
+    mut take: impl FnMut(&mut ffmpeg::frame::Video) -> bool,
+) -> Result<bool, ffmpeg::Error> {
+    let mut frame = ffmpeg::frame::Video::empty();
+    for data in frames {
+        let mut packet = ffmpeg::Packet::copy(data.get_raw_bytes());
+        packet.set_pts(Some(*data.get_pts()));
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut frame).is_ok() {
+            if take(&mut frame) {
+                return Ok(true);
+            }
+        }
+    }
+
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut frame).is_ok() {
+        if take(&mut frame) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,115 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Result;
+use ffmpeg_next::Rational;
+use log::debug;
+
+use super::buffer::{AudioBuffer, VideoBuffer};
+
+const MAGIC: &[u8; 8] = b"SRPACKET";
+const VERSION: u32 = 1;
+
+const VIDEO_STREAM: u8 = 0;
+const AUDIO_STREAM: u8 = 1;
+
+/// Set in a record's flags when the packet is a keyframe
+const FLAG_KEY: u8 = 1;
+
+/// Extension of the sidecar next to the clip, e.g. `clip_1700000000.mp4.packets`
+pub const EXTENSION: &str = "packets";
+
+/// Write every buffered video and audio packet as it is before muxing to `path`, so a clip that
+/// came out broken can be analysed or remuxed offline. All values are little endian.
+///
+/// The file starts with a header
+///
+/// | offset | field                          |
+/// |--------|--------------------------------|
+/// | 0      | magic `SRPACKET`               |
+/// | 8      | version (u32)                  |
+/// | 12     | video time base num, den (i32) |
+/// | 20     | audio time base num, den (i32) |
+///
+/// followed by all video records, then all audio records, each
+///
+/// | offset | field          |
+/// |--------|----------------|
+/// | 0      | stream (u8)    |
+/// | 1      | flags (u8)     |
+/// | 2      | pts (i64)      |
+/// | 10     | dts (i64)      |
+/// | 18     | length (u32)   |
+/// | 22     | packet data    |
+///
+/// Stream is 0 for video and 1 for audio, flag 1 marks keyframes. Video timestamps are in the
+/// video time base. Audio packets have their pts in the audio time base and, as audio is never
+/// reordered, their capture time in micro seconds in place of the dts.
+pub fn write(
+    path: &Path,
+    video_buffer: &VideoBuffer,
+    video_time_base: Rational,
+    audio_buffer: &AudioBuffer,
+    audio_time_base: Rational,
+) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    for time_base in [video_time_base, audio_time_base] {
+        writer.write_all(&time_base.numerator().to_le_bytes())?;
+        writer.write_all(&time_base.denominator().to_le_bytes())?;
+    }
+
+    for (&dts, frame) in video_buffer.get_frames() {
+        let flags = if frame.is_key() { FLAG_KEY } else { 0 };
+        write_record(
+            &mut writer,
+            VIDEO_STREAM,
+            flags,
+            *frame.get_pts(),
+            dts,
+            frame.get_raw_bytes(),
+        )?;
+    }
+
+    let capture_times = audio_buffer.get_capture_times();
+    for (index, (&pts, frame)) in audio_buffer.get_frames().iter().enumerate() {
+        let capture_time = capture_times.get(index).copied().unwrap_or(pts);
+        write_record(
+            &mut writer,
+            AUDIO_STREAM,
+            FLAG_KEY,
+            pts,
+            capture_time,
+            frame,
+        )?;
+    }
+    writer.flush()?;
+
+    debug!(
+        "Dumped {} video and {} audio packets to {:?}",
+        video_buffer.get_frames().len(),
+        audio_buffer.get_frames().len(),
+        path
+    );
+    Ok(())
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    stream: u8,
+    flags: u8,
+    pts: i64,
+    dts: i64,
+    data: &[u8],
+) -> Result<()> {
+    writer.write_all(&[stream, flags])?;
+    writer.write_all(&pts.to_le_bytes())?;
+    writer.write_all(&dts.to_le_bytes())?;
+    writer.write_all(&(data.len() as u32).to_le_bytes())?;
+    writer.write_all(data)?;
+    Ok(())
+}
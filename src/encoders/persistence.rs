@@ -0,0 +1,477 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use ffmpeg_next::{Rational, Rescale};
+use log::{debug, warn};
+use memmap2::{Mmap, MmapMut};
+
+use super::buffer::{AudioBuffer, VideoBuffer, VideoFrameData};
+
+const MAGIC: &[u8; 8] = b"SRBUFFER";
+const VERSION: u32 = 2;
+
+/// Size of the file header, see [`Header`] for the layout.
+const HEADER_SIZE: usize = 72;
+
+/// dts (i64) + pts (i64) + is_key (u8) + length (u32)
+const VIDEO_RECORD_HEADER: usize = 8 + 8 + 1 + 4;
+
+/// pts (i64) + length (u32)
+const AUDIO_RECORD_HEADER: usize = 8 + 4;
+
+/// Header at the start of the persisted buffer file. All values are little endian.
+///
+/// | offset | field           |
+/// |--------|-----------------|
+/// | 0      | magic `SRBUFFER`|
+/// | 8      | version (u32)   |
+/// | 12     | width (u32)     |
+/// | 16     | height (u32)    |
+/// | 20     | audio time base |
+/// | 24     | read cursor     |
+/// | 32     | write cursor    |
+/// | 40     | video frames    |
+/// | 48     | audio frames    |
+/// | 56     | capture times   |
+/// | 64     | checksum        |
+///
+/// The cursors are byte offsets into the file delimiting the record data, which is made up of
+/// all video records, then all audio records, then the audio capture times. The checksum is a
+/// FNV-1a hash of that data. Video timestamps are in micro seconds, audio PTS in 1 over the
+/// audio time base (u32), the `time_base_den` the audio encoder had when the file was written.
+///
+/// The file is a snapshot rewritten whole, periodically and on shutdown, not a ring written to
+/// while capturing, so the read cursor always points right after the header and the write cursor
+/// at the end of the data.
+struct Header {
+    width: u32,
+    height: u32,
+    audio_time_base_den: u32,
+    read_cursor: u64,
+    write_cursor: u64,
+    video_frames: u64,
+    audio_frames: u64,
+    capture_times: u64,
+    checksum: u64,
+}
+
+impl Header {
+    fn write_to(&self, bytes: &mut [u8]) {
+        let mut writer = RecordWriter::new(bytes);
+        writer.put_bytes(MAGIC);
+        writer.put_u32(VERSION);
+        writer.put_u32(self.width);
+        writer.put_u32(self.height);
+        writer.put_u32(self.audio_time_base_den);
+        writer.put_u64(self.read_cursor);
+        writer.put_u64(self.write_cursor);
+        writer.put_u64(self.video_frames);
+        writer.put_u64(self.audio_frames);
+        writer.put_u64(self.capture_times);
+        writer.put_u64(self.checksum);
+    }
+
+    fn read_from(bytes: &[u8]) -> Result<Self> {
+        let mut reader = RecordReader::new(bytes);
+        if reader.get_bytes(MAGIC.len())? != MAGIC {
+            bail!("Not a persisted buffer file");
+        }
+
+        let version = reader.get_u32()?;
+        if version != VERSION {
+            bail!("Unsupported persisted buffer version {}", version);
+        }
+
+        let width = reader.get_u32()?;
+        let height = reader.get_u32()?;
+        let audio_time_base_den = reader.get_u32()?;
+        if audio_time_base_den == 0 || audio_time_base_den > i32::MAX as u32 {
+            bail!(
+                "Invalid persisted audio time base 1/{}",
+                audio_time_base_den
+            );
+        }
+
+        Ok(Self {
+            width,
+            height,
+            audio_time_base_den,
+            read_cursor: reader.get_u64()?,
+            write_cursor: reader.get_u64()?,
+            video_frames: reader.get_u64()?,
+            audio_frames: reader.get_u64()?,
+            capture_times: reader.get_u64()?,
+            checksum: reader.get_u64()?,
+        })
+    }
+}
+
+/// Write the contents of both buffers to a memory mapped file at `path`, replacing what it held.
+///
+/// The data goes to a temporary file next to `path` first, which is flushed, synced and renamed
+/// over `path`, so a crash while writing leaves the previous snapshot in place.
+///
+/// `audio_time_base_den` is the denominator of the time base the audio PTS are in.
+pub fn persist_buffers(
+    path: &Path,
+    width: u32,
+    height: u32,
+    audio_time_base_den: u32,
+    video_buffer: &VideoBuffer,
+    audio_buffer: &AudioBuffer,
+) -> Result<()> {
+    let data_len: usize = video_buffer
+        .get_frames()
+        .values()
+        .map(|frame| VIDEO_RECORD_HEADER + frame.get_raw_bytes().len())
+        .sum::<usize>()
+        + audio_buffer
+            .get_frames()
+            .values()
+            .map(|frame| AUDIO_RECORD_HEADER + frame.len())
+            .sum::<usize>()
+        + audio_buffer.get_capture_times().len() * 8;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = PathBuf::from(temp_name);
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)?;
+    file.set_len((HEADER_SIZE + data_len) as u64)?;
+
+    let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+    let (header_bytes, data) = mmap.split_at_mut(HEADER_SIZE);
+
+    let mut writer = RecordWriter::new(data);
+    for (dts, frame) in video_buffer.get_frames() {
+        writer.put_i64(*dts);
+        writer.put_i64(*frame.get_pts());
+        writer.put_bytes(&[frame.is_key() as u8]);
+        writer.put_u32(frame.get_raw_bytes().len() as u32);
+        writer.put_bytes(frame.get_raw_bytes());
+    }
+
+    for (pts, frame) in audio_buffer.get_frames() {
+        writer.put_i64(*pts);
+        writer.put_u32(frame.len() as u32);
+        writer.put_bytes(frame);
+    }
+
+    for capture_time in audio_buffer.get_capture_times() {
+        writer.put_i64(*capture_time);
+    }
+
+    let header = Header {
+        width,
+        height,
+        audio_time_base_den,
+        read_cursor: HEADER_SIZE as u64,
+        write_cursor: (HEADER_SIZE + writer.position()) as u64,
+        video_frames: video_buffer.get_frames().len() as u64,
+        audio_frames: audio_buffer.get_frames().len() as u64,
+        capture_times: audio_buffer.get_capture_times().len() as u64,
+        checksum: checksum(data),
+    };
+    header.write_to(header_bytes);
+
+    mmap.flush()?;
+    file.sync_all()?;
+    drop(mmap);
+    fs::rename(&temp_path, path)?;
+
+    debug!(
+        "Persisted {} video and {} audio frames to {:?}",
+        header.video_frames, header.audio_frames, path
+    );
+    Ok(())
+}
+
+/// Restore previously persisted buffers from `path` into the given (empty) buffers.
+///
+/// Restored timestamps are shifted to end just before zero so new capture continues right after
+/// them. Audio PTS written with another `time_base_den` are rescaled into
+/// `audio_time_base_den`. The file is removed once restored so the same content isn't replayed
+/// twice.
+///
+/// Returns `Ok(false)` if there is nothing to restore.
+pub fn restore_buffers(
+    path: &Path,
+    width: u32,
+    height: u32,
+    audio_time_base_den: u32,
+    video_buffer: &mut VideoBuffer,
+    audio_buffer: &mut AudioBuffer,
+) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    let file = File::open(path)?;
+    let mmap = unsafe { Mmap::map(&file)? };
+    if mmap.len() < HEADER_SIZE {
+        bail!("Persisted buffer file is truncated");
+    }
+
+    let header = Header::read_from(&mmap[..HEADER_SIZE])?;
+    if header.width != width || header.height != height {
+        bail!(
+            "Persisted buffer was captured at {}x{}, current capture is {}x{}",
+            header.width,
+            header.height,
+            width,
+            height
+        );
+    }
+
+    let data = mmap
+        .get(header.read_cursor as usize..header.write_cursor as usize)
+        .context("Persisted buffer cursors are out of bounds")?;
+    if checksum(data) != header.checksum {
+        bail!("Persisted buffer checksum mismatch");
+    }
+
+    let mut reader = RecordReader::new(data);
+
+    let mut video_frames = Vec::with_capacity(header.video_frames as usize);
+    for _ in 0..header.video_frames {
+        let dts = reader.get_i64()?;
+        let pts = reader.get_i64()?;
+        let is_key = reader.get_bytes(1)?[0] != 0;
+        let len = reader.get_u32()? as usize;
+        video_frames.push((dts, pts, is_key, reader.get_bytes(len)?.to_vec()));
+    }
+
+    if header.audio_time_base_den != audio_time_base_den {
+        warn!(
+            "Persisted audio was encoded with time base 1/{}, rescaling it to 1/{}",
+            header.audio_time_base_den, audio_time_base_den
+        );
+    }
+    let stored_time_base = Rational::new(1, header.audio_time_base_den as i32);
+    let time_base = Rational::new(1, audio_time_base_den as i32);
+
+    let mut audio_frames = Vec::with_capacity(header.audio_frames as usize);
+    for _ in 0..header.audio_frames {
+        let pts = reader.get_i64()?.rescale(stored_time_base, time_base);
+        let len = reader.get_u32()? as usize;
+        audio_frames.push((pts, reader.get_bytes(len)?.to_vec()));
+    }
+
+    let mut capture_times = Vec::with_capacity(header.capture_times as usize);
+    for _ in 0..header.capture_times {
+        capture_times.push(reader.get_i64()?);
+    }
+
+    // Video PTS and audio capture times share the same microsecond timeline, audio PTS is in
+    // the audio encoder's time base so it gets its own offset
+    let newest_us = video_frames
+        .iter()
+        .map(|(_, pts, _, _)| *pts)
+        .chain(capture_times.iter().copied())
+        .max()
+        .unwrap_or(0);
+    let time_offset = -(newest_us + frame_step(video_frames.iter().map(|(_, pts, _, _)| *pts)));
+
+    let newest_audio_pts = audio_frames.last().map(|(pts, _)| *pts).unwrap_or(0);
+    let audio_offset = -(newest_audio_pts + frame_step(audio_frames.iter().map(|(pts, _)| *pts)));
+
+    for (dts, pts, is_key, bytes) in video_frames {
+        video_buffer.insert(
+            dts + time_offset,
+            VideoFrameData::new(bytes, is_key, pts + time_offset),
+        );
+    }
+
+    let mut capture_times = capture_times.into_iter();
+    for (pts, bytes) in audio_frames {
+        if let Some(capture_time) = capture_times.next() {
+            audio_buffer.insert_capture_time(capture_time + time_offset);
+        }
+        audio_buffer.insert_frame(pts + audio_offset, bytes);
+    }
+    for capture_time in capture_times {
+        audio_buffer.insert_capture_time(capture_time + time_offset);
+    }
+
+    drop(mmap);
+    fs::remove_file(path)?;
+
+    debug!(
+        "Restored {} video and {} audio frames from {:?}",
+        header.video_frames, header.audio_frames, path
+    );
+    Ok(true)
+}
+
+/// Distance between the last two timestamps, used to leave a one frame gap after restored data
+fn frame_step(timestamps: impl DoubleEndedIterator<Item = i64>) -> i64 {
+    let mut newest = timestamps.rev();
+    match (newest.next(), newest.next()) {
+        (Some(last), Some(previous)) => (last - previous).max(1),
+        _ => 1,
+    }
+}
+
+/// 64 bit FNV-1a
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+struct RecordWriter<'a> {
+    bytes: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> RecordWriter<'a> {
+    fn new(bytes: &'a mut [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn put_bytes(&mut self, value: &[u8]) {
+        self.bytes[self.position..self.position + value.len()].copy_from_slice(value);
+        self.position += value.len();
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.put_bytes(&value.to_le_bytes());
+    }
+
+    fn put_u64(&mut self, value: u64) {
+        self.put_bytes(&value.to_le_bytes());
+    }
+
+    fn put_i64(&mut self, value: i64) {
+        self.put_bytes(&value.to_le_bytes());
+    }
+}
+
+struct RecordReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn get_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let value = self
+            .bytes
+            .get(self.position..self.position + len)
+            .context("Unexpected end of persisted buffer")?;
+        self.position += len;
+        Ok(value)
+    }
+
+    fn get_u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.get_bytes(4)?.try_into()?))
+    }
+
+    fn get_u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.get_bytes(8)?.try_into()?))
+    }
+
+    fn get_i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.get_bytes(8)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application_config::BufferLimit;
+
+    fn buffers() -> (VideoBuffer, AudioBuffer) {
+        let mut video = VideoBuffer::new(BufferLimit::ByDuration { seconds: 60 });
+        video.insert(0, VideoFrameData::new(vec![1; 4], true, 0));
+        video.insert(20_000, VideoFrameData::new(vec![2; 4], false, 20_000));
+
+        let mut audio = AudioBuffer::new(60_000_000);
+        for i in 0..2 {
+            audio.insert_capture_time(i * 20_000);
+            audio.insert_frame(i * 960, vec![3; 4]);
+        }
+        (video, audio)
+    }
+
+    fn restored(path: &Path, audio_time_base_den: u32) -> (VideoBuffer, AudioBuffer) {
+        let mut video = VideoBuffer::new(BufferLimit::ByDuration { seconds: 60 });
+        let mut audio = AudioBuffer::new(60_000_000);
+        assert!(restore_buffers(
+            path,
+            1920,
+            1080,
+            audio_time_base_den,
+            &mut video,
+            &mut audio
+        )
+        .unwrap());
+        (video, audio)
+    }
+
+    #[test]
+    fn restores_what_was_persisted_ending_before_zero() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("buffer");
+        let (video, audio) = buffers();
+        persist_buffers(&path, 1920, 1080, 48_000, &video, &audio).unwrap();
+
+        let (video, audio) = restored(&path, 48_000);
+        assert!(!path.exists());
+        assert_eq!(
+            video.get_frames().keys().copied().collect::<Vec<_>>(),
+            vec![-40_000, -20_000]
+        );
+        assert_eq!(
+            audio.get_frames().keys().copied().collect::<Vec<_>>(),
+            vec![-1_920, -960]
+        );
+        assert_eq!(audio.get_capture_times(), &vec![-40_000, -20_000]);
+    }
+
+    #[test]
+    fn audio_is_rescaled_into_a_different_time_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("buffer");
+        let (video, audio) = buffers();
+        persist_buffers(&path, 1920, 1080, 48_000, &video, &audio).unwrap();
+
+        // 960 samples at 48kHz are 20ms
+        let (_, audio) = restored(&path, 1_000_000);
+        assert_eq!(
+            audio.get_frames().keys().copied().collect::<Vec<_>>(),
+            vec![-40_000, -20_000]
+        );
+    }
+
+    #[test]
+    fn rejects_a_different_capture_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("buffer");
+        let (video, audio) = buffers();
+        persist_buffers(&path, 1920, 1080, 48_000, &video, &audio).unwrap();
+
+        let mut video = VideoBuffer::new(BufferLimit::ByDuration { seconds: 60 });
+        let mut audio = AudioBuffer::new(60_000_000);
+        assert!(restore_buffers(&path, 1280, 720, 48_000, &mut video, &mut audio).is_err());
+    }
+}
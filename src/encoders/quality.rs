@@ -0,0 +1,138 @@
+use anyhow::{bail, Result};
+use ffmpeg_next::{self as ffmpeg, format::Pixel, software::scaling};
+use ssimulacra2::{compute_frame_ssimulacra2, ColorPrimaries, Rgb, TransferCharacteristic};
+
+use super::{
+    buffer::{VideoBuffer, VideoFrameData},
+    decode_until,
+};
+
+/// Number of key frames across the buffer the quality is sampled at
+const SAMPLES: usize = 10;
+
+impl VideoBuffer {
+    /// Estimates the perceived quality of the buffered video in `[0, 1]`.
+    ///
+    /// Decodes 10 evenly spaced key frames together with the frame shown after each, scores every
+    /// pair with SSIMULACRA2 and averages the scores scaled from 0-100. Adjacent frames also
+    /// differ by motion, so a busy scene scores lower than a static one at the same bitrate.
+    ///
+    /// # Arguments
+    ///
+    /// * `parameters` - Codec parameters of the encoder that produced the buffered frames.
+    pub fn compute_crf_estimate(&self, parameters: &ffmpeg::codec::Parameters) -> Result<f32> {
+        let frames = self.get_frames();
+        let samples = self.sampled_key_frames();
+        if samples.is_empty() {
+            bail!("No key frames buffered");
+        }
+
+        let mut scores = Vec::with_capacity(SAMPLES);
+        for start in samples {
+            let Some((key_frame, next_frame)) =
+                decode_pair(frames.range(start..).map(|(_, frame)| frame), parameters)?
+            else {
+                continue;
+            };
+
+            let score = compute_frame_ssimulacra2(to_rgb(&key_frame)?, to_rgb(&next_frame)?)?;
+            scores.push((score / 100.0).clamp(0.0, 1.0) as f32);
+        }
+
+        if scores.is_empty() {
+            bail!("No GOP in the buffer holds more than one frame");
+        }
+
+        Ok(scores.iter().sum::<f32>() / scores.len() as f32)
+    }
+
+    /// Copies the GOPs [`Self::compute_crf_estimate`] samples into a new buffer, which estimates
+    /// the same quality without holding on to this one
+    pub fn quality_samples(&self) -> VideoBuffer {
+        let frames = self.get_frames();
+        let mut copy = VideoBuffer::new(self.get_limit());
+        for start in self.sampled_key_frames() {
+            let end = frames
+                .range(start + 1..)
+                .find(|(_, frame)| frame.is_key())
+                .map(|(&dts, _)| dts);
+            match end {
+                Some(end) => copy.append_range(self, start..end),
+                None => copy.append_range(self, start..),
+            }
+        }
+        copy
+    }
+
+    /// DTS of up to [`SAMPLES`] key frames spread evenly across the buffer
+    fn sampled_key_frames(&self) -> Vec<i64> {
+        let key_frames: Vec<i64> = self
+            .get_frames()
+            .iter()
+            .filter(|(_, frame)| frame.is_key())
+            .map(|(&dts, _)| dts)
+            .collect();
+        if key_frames.is_empty() {
+            return key_frames;
+        }
+
+        let step = key_frames.len() as f32 / SAMPLES.min(key_frames.len()) as f32;
+        (0..SAMPLES.min(key_frames.len()))
+            .map(|sample| key_frames[(sample as f32 * step) as usize])
+            .collect()
+    }
+}
+
+/// Decode `frames`, starting at a key frame, until the key frame and the frame shown after it
+/// come out. Returns `None` if the GOP ends first.
+fn decode_pair<'a>(
+    frames: impl Iterator<Item = &'a VideoFrameData>,
+    parameters: &ffmpeg::codec::Parameters,
+) -> Result<Option<(ffmpeg::frame::Video, ffmpeg::frame::Video)>> {
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(parameters.clone())?
+        .decoder()
+        .video()?;
+
+    let gop = frames
+        .enumerate()
+        .take_while(|(index, data)| *index == 0 || !data.is_key())
+        .map(|(_, data)| data);
+    let mut decoded = Vec::with_capacity(2);
+    decode_until(&mut decoder, gop, |frame| {
+        decoded.push(std::mem::replace(frame, ffmpeg::frame::Video::empty()));
+        decoded.len() == 2
+    })?;
+
+    let mut decoded = decoded.into_iter();
+    Ok(decoded.next().zip(decoded.next()))
+}
+
+fn to_rgb(frame: &ffmpeg::frame::Video) -> Result<Rgb> {
+    let (width, height) = (frame.width(), frame.height());
+    let mut scaler = scaling::Context::get(
+        frame.format(),
+        width,
+        height,
+        Pixel::RGB24,
+        width,
+        height,
+        scaling::Flags::BILINEAR,
+    )?;
+    let mut rgb_frame = ffmpeg::frame::Video::empty();
+    scaler.run(frame, &mut rgb_frame)?;
+
+    let stride = rgb_frame.stride(0);
+    let data = rgb_frame.data(0);
+    let pixels = (0..height as usize)
+        .flat_map(|row| data[row * stride..][..width as usize * 3].chunks_exact(3))
+        .map(|pixel| [0, 1, 2].map(|channel| pixel[channel] as f32 / 255.0))
+        .collect();
+
+    Ok(Rgb::new(
+        pixels,
+        width as usize,
+        height as usize,
+        TransferCharacteristic::SRGB,
+        ColorPrimaries::BT709,
+    )?)
+}
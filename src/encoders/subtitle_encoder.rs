@@ -0,0 +1,193 @@
+use std::fmt::Write;
+
+use anyhow::{bail, Result};
+use ffmpeg_next::{self as ffmpeg, Rational, Rescale};
+
+use super::video_encoder::ONE_MICROS;
+
+/// `tx3g` sample description for `mov_text`: left aligned white text on a transparent
+/// background in the player's default font, the same one FFmpeg's encoder writes.
+const TEXT_SAMPLE_ENTRY: [u8; 48] = [
+    0x00, 0x00, 0x00, 0x00, // display flags
+    0x01, 0xFF, // horizontal and vertical justification
+    0x00, 0x00, 0x00, 0x00, // background colour
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // text box
+    0x00, 0x00, 0x00, 0x00, // style start and end character
+    0x00, 0x01, // font id
+    0x00, // face style
+    0x12, // font size
+    0xFF, 0xFF, 0xFF, 0xFF, // text colour
+    0x00, 0x00, 0x00, 0x12, b'f', b't', b'a', b'b', // font table box
+    0x00, 0x01, // font count
+    0x00, 0x01, 0x05, b'S', b'e', b'r', b'i', b'f', // font 1
+];
+
+/// Header of the ASS script used to burn subtitles in. Without `PlayResX`/`PlayResY` libass
+/// scales everything from 384x288 so the text size follows the clip's resolution.
+const ASS_HEADER: &str = "[Script Info]
+ScriptType: v4.00+
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Default,Sans,16,&H00FFFFFF,&H000000FF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,1,0,2,10,10,10,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+";
+
+/// A line of text shown from `start_us` until `end_us`, both on the capture clock shared with
+/// the video PTS
+#[derive(Clone, Debug)]
+pub struct SubtitleEntry {
+    pub start_us: i64,
+    pub end_us: i64,
+    pub text: String,
+}
+
+/// Subtitles added during capture, ordered by start time.
+///
+/// Like the video buffer it only holds what can still end up in a clip, entries that ended more
+/// than `max_time` before the latest one started are dropped.
+pub struct SubtitleTrack {
+    entries: Vec<SubtitleEntry>,
+    max_time: i64,
+}
+
+impl SubtitleTrack {
+    pub fn new(max_seconds: u32) -> Self {
+        Self {
+            entries: Vec::new(),
+            max_time: max_seconds as i64 * ONE_MICROS as i64,
+        }
+    }
+
+    pub fn add(&mut self, entry: SubtitleEntry) {
+        let cutoff = entry.start_us - self.max_time;
+        self.entries.retain(|existing| existing.end_us >= cutoff);
+
+        let index = self
+            .entries
+            .partition_point(|existing| existing.start_us <= entry.start_us);
+        self.entries.insert(index, entry);
+    }
+
+    pub fn entries(&self) -> &[SubtitleEntry] {
+        &self.entries
+    }
+}
+
+/// Turns subtitle entries into something a clip can carry, either a `mov_text` stream muxed
+/// next to the video or an ASS script to burn into it
+pub struct SubtitleEncoder;
+
+impl SubtitleEncoder {
+    /// Add a `mov_text` stream to `output`, returning its index
+    pub fn add_stream(output: &mut ffmpeg::format::context::Output) -> Result<usize> {
+        let mut stream = output.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        stream.set_time_base(Rational::new(1, 1000));
+
+        unsafe {
+            let parameters = stream.parameters().as_mut_ptr();
+            (*parameters).codec_type = ffmpeg::ffi::AVMediaType::AVMEDIA_TYPE_SUBTITLE;
+            (*parameters).codec_id = ffmpeg::ffi::AVCodecID::AV_CODEC_ID_MOV_TEXT;
+
+            // Freed by FFmpeg along with the stream, so it has to come from its allocator
+            let extradata = ffmpeg::ffi::av_mallocz(
+                TEXT_SAMPLE_ENTRY.len() + ffmpeg::ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize,
+            ) as *mut u8;
+            if extradata.is_null() {
+                bail!("Could not allocate the subtitle stream's extradata");
+            }
+            std::ptr::copy_nonoverlapping(
+                TEXT_SAMPLE_ENTRY.as_ptr(),
+                extradata,
+                TEXT_SAMPLE_ENTRY.len(),
+            );
+            (*parameters).extradata = extradata;
+            (*parameters).extradata_size = TEXT_SAMPLE_ENTRY.len() as i32;
+        }
+
+        Ok(stream.index())
+    }
+
+    /// `mov_text` packets for the entries visible between `clip_start_us` and `clip_end_us`,
+    /// keyed by their start on the capture clock so they can be merged with the other streams.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - Index of the stream returned by [`Self::add_stream`].
+    /// * `time_base` - Time base of that stream once the header is written.
+    pub fn packets(
+        entries: &[SubtitleEntry],
+        clip_start_us: i64,
+        clip_end_us: i64,
+        stream: usize,
+        time_base: Rational,
+    ) -> Vec<(i64, ffmpeg::Packet)> {
+        let micros = Rational::new(1, ONE_MICROS as i32);
+
+        visible(entries, clip_start_us, clip_end_us)
+            .map(|(start_us, end_us, text)| {
+                // A sample is the UTF-8 text prefixed with its length
+                let text = &text.as_bytes()[..text.len().min(u16::MAX as usize)];
+                let mut data = Vec::with_capacity(text.len() + 2);
+                data.extend_from_slice(&(text.len() as u16).to_be_bytes());
+                data.extend_from_slice(text);
+
+                let pts = (start_us - clip_start_us).rescale(micros, time_base);
+                let mut packet = ffmpeg::Packet::copy(&data);
+                packet.set_pts(Some(pts));
+                packet.set_dts(Some(pts));
+                packet.set_duration((end_us - start_us).rescale(micros, time_base));
+                packet.set_stream(stream);
+
+                (start_us, packet)
+            })
+            .collect()
+    }
+
+    /// An ASS script showing the entries visible from `clip_start_us` on, timed relative to it
+    pub fn to_ass(entries: &[SubtitleEntry], clip_start_us: i64) -> String {
+        let mut script = ASS_HEADER.to_string();
+        for (start_us, end_us, text) in visible(entries, clip_start_us, i64::MAX) {
+            let _ = writeln!(
+                script,
+                "Dialogue: 0,{},{},Default,,0,0,0,,{}",
+                ass_time(start_us - clip_start_us),
+                ass_time(end_us - clip_start_us),
+                text.replace('\n', "\\N")
+            );
+        }
+        script
+    }
+}
+
+/// The entries overlapping the clip with their times clamped to it
+fn visible(
+    entries: &[SubtitleEntry],
+    clip_start_us: i64,
+    clip_end_us: i64,
+) -> impl Iterator<Item = (i64, i64, &str)> {
+    entries
+        .iter()
+        .filter(move |entry| entry.end_us > clip_start_us && entry.start_us < clip_end_us)
+        .map(move |entry| {
+            (
+                entry.start_us.max(clip_start_us),
+                entry.end_us.min(clip_end_us),
+                entry.text.as_str(),
+            )
+        })
+}
+
+/// Format micro seconds as ASS's `H:MM:SS.cc`
+fn ass_time(us: i64) -> String {
+    let centis = us.max(0) / 10_000;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        centis / 360_000,
+        centis / 6_000 % 60,
+        centis / 100 % 60,
+        centis % 100
+    )
+}
@@ -1,14 +1,68 @@
-use ffmpeg_next::{self as ffmpeg, Rational};
+use std::{
+    ffi::{c_void, CString},
+    num::NonZeroUsize,
+    ptr,
+    sync::Arc,
+};
+
+use ffmpeg_next::{
+    self as ffmpeg, codec::profile::H264, format::Pixel, software::scaling, Rational, Rescale,
+};
+use log::{debug, error, warn};
+use lru::LruCache;
+use tokio::sync::{broadcast, oneshot};
 
 use crate::{
-    application_config::{load_or_create_config, QualityPreset},
+    application_config::{h264_level_idc, AppConfig, BufferLimit, QualityPreset, Region},
+    encoder_report::VIDEO_ENCODERS,
+    pw_capture::cursor::CursorOverlay,
     RawVideoFrame,
 };
 
-use super::buffer::{VideoBuffer, VideoFrameData};
+pub use super::buffer::ONE_MICROS;
+use super::{
+    buffer::{VideoBuffer, VideoFrameData, GOP_SIZE},
+    decode_until,
+};
+
+/// Packets queued for each receiver of [`VideoEncoder::set_packet_broadcast`] before it falls
+/// behind and has to skip to the next keyframe
+pub const PACKET_BACKLOG: usize = 512;
+
+/// Size of the grid frames are sampled on for scene detection
+const SCENE_GRID_WIDTH: usize = 64;
+const SCENE_GRID_HEIGHT: usize = 36;
+
+/// How much a sample has to change to count towards a scene cut, ignores noise and small
+/// brightness shifts
+const SCENE_SAMPLE_DELTA: u8 = 24;
+
+/// Frames that have to pass after a keyframe before a scene cut forces another, so a flickering
+/// or fast changing picture doesn't turn every frame into a keyframe
+const MIN_SCENE_CUT_DISTANCE: u32 = GOP_SIZE / 4;
+
+/// Frames decoded by [`VideoEncoder::get_frame_at`] kept around for the next request
+const DECODED_FRAME_CACHE: NonZeroUsize = NonZeroUsize::new(10).unwrap();
 
-pub const ONE_MICROS: usize = 1_000_000;
-const GOP_SIZE: u32 = 30;
+/// An open encoder, the pixel format it takes and whether that is the configured chroma format
+type OpenedEncoder = (ffmpeg::codec::encoder::Video, Pixel, bool);
+
+/// `scaling::Context` is not `Send` only because it holds a raw `SwsContext` pointer
+struct Scaler(scaling::Context);
+
+// SAFETY: the `SwsContext` is owned by the `Scaler` alone, nothing else keeps the pointer, and
+// libswscale keeps no thread local state for it, so moving it to another thread is sound. The
+// `Scaler` is only reachable through `&mut VideoEncoder`, which the encoder's mutex hands to one
+// thread at a time, so it is never used from two threads at once. It is not `Sync`.
+unsafe impl Send for Scaler {}
+
+/// Part of the captured monitor to encode, see [`VideoEncoder::set_crop`]
+#[derive(Clone, Copy)]
+struct Crop {
+    region: Region,
+    source_width: u32,
+    source_height: u32,
+}
 
 pub struct VideoEncoder {
     encoder: Option<ffmpeg::codec::encoder::Video>,
@@ -16,42 +70,306 @@ pub struct VideoEncoder {
     width: u32,
     height: u32,
     encoder_name: String,
+
+    /// Pixel format the encoder is opened with
+    pixel_format: Pixel,
+
+    /// Set when `pixel_format` comes from the configured chroma format, in which case the encoder
+    /// isn't reopened with the captured format
+    fixed_pixel_format: bool,
+
+    /// Only created when the captured format differs from `pixel_format`
+    scaler: Option<Scaler>,
+
+    /// Codec of the pre-encoded packets being buffered when the compositor encodes for us
+    passthrough_codec: Option<ffmpeg::codec::Id>,
+
+    /// SPS and PPS of the pre-encoded stream, taken from its latest keyframe that carried them
+    passthrough_extradata: Vec<u8>,
+
+    /// Set when the next submitted frame should be forced to a keyframe
+    force_keyframe: bool,
+
+    /// Notified once the first keyframe after a `request_keyframe()` call is buffered
+    keyframe_waiter: Option<oneshot::Sender<()>>,
+
+    /// Each receives a copy of the next raw frame sent to the encoder
+    frame_waiters: Vec<oneshot::Sender<ffmpeg::util::frame::video::Video>>,
+
+    /// Cut out of every captured frame before encoding when only a region is recorded
+    crop: Option<Crop>,
+
+    /// Replaces the quality preset's bitrate once set through [`Self::set_bitrate_adaptive`], so
+    /// it survives the encoder being reopened
+    bitrate_kbps: Option<u32>,
+
+    /// Buffer the cursor beside the frames instead of drawing it, see [`Self::set_cursor_on_save`]
+    cursor_on_save: bool,
+
+    /// Fraction of the picture that has to change to force a keyframe, see
+    /// [`Self::set_scene_detection`]
+    scene_cut_threshold: Option<f32>,
+
+    /// Downscaled luma of the last encoded frame to compare the next one against
+    scene_thumbnail: Vec<u8>,
+
+    /// Frames sent to the encoder since the last keyframe, see [`MIN_SCENE_CUT_DISTANCE`]
+    frames_since_keyframe: u32,
+
+    /// BGRA pixels of recently decoded buffered frames, keyed by PTS
+    decoded_frames: LruCache<i64, Arc<[u8]>>,
+
+    /// Receives every buffered packet along with its DTS, see [`Self::set_packet_broadcast`]
+    packet_tx: Option<broadcast::Sender<(i64, VideoFrameData)>>,
+
+    /// Settings the encoder is opened and reopened with
+    config: Arc<AppConfig>,
 }
 
 impl VideoEncoder {
+    /// Open `encoder_name` for frames of the given size.
+    ///
+    /// When it's an NVENC encoder and the driver is out of encode sessions, e.g. because another
+    /// application is already encoding, the next encoder in [`VIDEO_ENCODERS`] that opens is used
+    /// instead. [`Self::encoder_name`] tells which one that was.
     pub fn new(
         width: u32,
         height: u32,
         max_buffer_seconds: u32,
         encoder_name: &str,
+        config: Arc<AppConfig>,
     ) -> Result<Self, ffmpeg::Error> {
-        let encoder = Some(Self::create_encoder(width, height, encoder_name)?);
-        let max_time = max_buffer_seconds as usize * ONE_MICROS;
+        let (encoder_name, (encoder, pixel_format, fixed_pixel_format)) =
+            match Self::open(width, height, encoder_name, &config) {
+                Err(e) if is_session_limit(encoder_name, e) => {
+                    error!(
+                        "Could not open {}, the NVIDIA driver limits how many NVENC sessions can \
+                        run at once and another application is probably using them. Falling back \
+                        to another encoder",
+                        encoder_name
+                    );
+                    Self::open_fallback(width, height, &config).ok_or(e)?
+                }
+                opened => (encoder_name.to_string(), opened?),
+            };
+        let limit = BufferLimit::ByDuration {
+            seconds: max_buffer_seconds,
+        };
 
         Ok(Self {
-            encoder,
-            video_buffer: VideoBuffer::new(max_time),
+            encoder: Some(encoder),
+            video_buffer: VideoBuffer::new(limit),
             width,
             height,
-            encoder_name: encoder_name.to_string(),
+            encoder_name,
+            pixel_format,
+            fixed_pixel_format,
+            scaler: None,
+            passthrough_codec: None,
+            passthrough_extradata: Vec::new(),
+            force_keyframe: false,
+            keyframe_waiter: None,
+            frame_waiters: Vec::new(),
+            crop: None,
+            bitrate_kbps: None,
+            cursor_on_save: false,
+            scene_cut_threshold: None,
+            scene_thumbnail: Vec::new(),
+            frames_since_keyframe: 0,
+            decoded_frames: LruCache::new(DECODED_FRAME_CACHE),
+            packet_tx: None,
+            config,
         })
     }
 
-    pub fn process(&mut self, frame: &RawVideoFrame) -> Result<(), ffmpeg::Error> {
-        if let Some(ref mut encoder) = self.encoder {
-            let mut src_frame = ffmpeg::util::frame::video::Video::new(
-                ffmpeg_next::format::Pixel::BGRA,
-                encoder.width(),
-                encoder.height(),
+    /// Name of the encoder in use, which differs from the configured one after a fallback
+    pub fn encoder_name(&self) -> &str {
+        &self.encoder_name
+    }
+
+    /// Width and height of the encoded video
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Try the encoders after NVENC in [`VIDEO_ENCODERS`] in order, returning the first one that
+    /// opens along with its name. VAAPI and QSV are skipped, they only open with a hardware
+    /// device set up for them, which the recorder doesn't do.
+    fn open_fallback(
+        width: u32,
+        height: u32,
+        config: &AppConfig,
+    ) -> Option<(String, OpenedEncoder)> {
+        VIDEO_ENCODERS
+            .iter()
+            .filter(|name| !["nvenc", "vaapi", "qsv"].iter().any(|hw| name.contains(hw)))
+            .filter(|&&name| ffmpeg::codec::encoder::find_by_name(name).is_some())
+            .find_map(|&name| match Self::open(width, height, name, config) {
+                Ok(opened) => {
+                    warn!("Encoding with {} instead", name);
+                    Some((name.to_string(), opened))
+                }
+                Err(e) => {
+                    debug!("Fallback encoder {} did not open: {:?}", name, e);
+                    None
+                }
+            })
+    }
+
+    /// Open the encoder with the configured chroma format, or its default pixel format if it
+    /// can't take that
+    fn open(
+        width: u32,
+        height: u32,
+        encoder_name: &str,
+        config: &AppConfig,
+    ) -> Result<OpenedEncoder, ffmpeg::Error> {
+        let mut chroma_format = config
+            .chroma_format
+            .map(|chroma_format| chroma_format.pixel_format())
+            .filter(|&format| {
+                let supported = Self::supports_pixel_format(encoder_name, format);
+                if !supported {
+                    warn!(
+                        "{} does not accept {:?}, using its default pixel format",
+                        encoder_name, format
+                    );
+                }
+                supported
+            });
+
+        let mut pixel_format =
+            chroma_format.unwrap_or_else(|| Self::default_pixel_format(encoder_name));
+        let encoder =
+            match Self::create_encoder(width, height, encoder_name, pixel_format, None, config) {
+                // The codec may list a format the hardware can't encode, e.g. 4:4:4 on older NVENC
+                Err(e) if chroma_format.is_some() => {
+                    warn!(
+                        "Could not open {} with {:?}, using its default pixel format: {:?}",
+                        encoder_name, pixel_format, e
+                    );
+                    chroma_format = None;
+                    pixel_format = Self::default_pixel_format(encoder_name);
+                    Self::create_encoder(width, height, encoder_name, pixel_format, None, config)?
+                }
+                encoder => encoder?,
+            };
+
+        Ok((encoder, pixel_format, chroma_format.is_some()))
+    }
+
+    pub fn process(&mut self, frame: RawVideoFrame) -> Result<(), ffmpeg::Error> {
+        if let Some(codec_id) = frame.encoded_codec {
+            self.process_encoded(&frame, codec_id);
+            return Ok(());
+        }
+
+        // If the source negotiated a format the encoder takes natively, reopen the encoder
+        // with it so we can skip the scaler entirely
+        if frame.format != self.pixel_format
+            && !self.fixed_pixel_format
+            && self.scaler.is_none()
+            && Self::supports_pixel_format(&self.encoder_name, frame.format)
+        {
+            debug!(
+                "Source format {:?} is supported by the encoder, reopening without scaling",
+                frame.format
             );
+            self.pixel_format = frame.format;
+            self.scaler = None;
+            // Keep the buffer, it may hold what was restored from `persistent_buffer`
+            self.reopen_encoder()?;
+        }
+
+        if let Some(ref mut encoder) = self.encoder {
+            let captured_frame = frame.frame.ok_or(ffmpeg::Error::InvalidData)?;
+            let mut captured_frame = match &self.crop {
+                Some(crop) => crop_frame(&captured_frame, crop)?,
+                None => captured_frame,
+            };
+            if (captured_frame.width(), captured_frame.height())
+                != (encoder.width(), encoder.height())
+            {
+                return Err(ffmpeg::Error::InvalidData);
+            }
+
+            let (offset_x, offset_y) = self
+                .crop
+                .map(|crop| (crop.region.x as i32, crop.region.y as i32))
+                .unwrap_or((0, 0));
+            if self.cursor_on_save {
+                // Stored relative to the encoded frame so saves don't need to know the crop
+                let cursor = frame.cursor.as_ref().map(|cursor| CursorOverlay {
+                    x: cursor.x - offset_x,
+                    y: cursor.y - offset_y,
+                    ..cursor.clone()
+                });
+                self.video_buffer.insert_cursor(frame.timestamp, cursor);
+            } else if let Some(cursor) = &frame.cursor {
+                draw_cursor(&mut captured_frame, cursor, offset_x, offset_y);
+            }
+
+            let mut src_frame = if frame.format == self.pixel_format {
+                captured_frame
+            } else {
+                let scaler = match self.scaler {
+                    Some(ref mut scaler) => scaler,
+                    None => self.scaler.insert(Scaler(scaling::Context::get(
+                        frame.format,
+                        encoder.width(),
+                        encoder.height(),
+                        self.pixel_format,
+                        encoder.width(),
+                        encoder.height(),
+                        scaling::Flags::BILINEAR,
+                    )?)),
+                };
+
+                let mut scaled_frame = ffmpeg::util::frame::video::Video::empty();
+                scaler.0.run(&captured_frame, &mut scaled_frame)?;
+                scaled_frame
+            };
+
+            src_frame.set_pts(Some(
+                frame
+                    .timestamp
+                    .rescale(Rational::new(1, ONE_MICROS as i32), encoder.time_base()),
+            ));
+
+            for waiter in self.frame_waiters.drain(..) {
+                let _ = waiter.send(src_frame.clone());
+            }
+
+            if let Some(threshold) = self.scene_cut_threshold {
+                let thumbnail = scene_thumbnail(&src_frame);
+                let changed = scene_change(&self.scene_thumbnail, &thumbnail);
+                if changed > threshold
+                    && !self.force_keyframe
+                    && self.frames_since_keyframe >= MIN_SCENE_CUT_DISTANCE
+                {
+                    debug!(
+                        "Scene cut at {:?}, {:.0}% of the picture changed",
+                        frame.timestamp,
+                        changed * 100.0
+                    );
+                    self.force_keyframe = true;
+                }
+                self.scene_thumbnail = thumbnail;
+            }
 
-            src_frame.set_pts(Some(frame.timestamp));
-            src_frame.data_mut(0).copy_from_slice(frame.get_bytes());
+            if self.force_keyframe {
+                src_frame.set_kind(ffmpeg::picture::Type::I);
+                self.force_keyframe = false;
+                self.frames_since_keyframe = 0;
+            }
 
-            encoder.send_frame(&src_frame).unwrap();
+            encoder.send_frame(&src_frame)?;
+            self.frames_since_keyframe += 1;
 
             let mut packet = ffmpeg::codec::packet::Packet::empty();
             if encoder.receive_packet(&mut packet).is_ok() {
+                // The buffer stays on the capture clock whatever the encoder counts in
+                packet.rescale_ts(encoder.time_base(), Rational::new(1, ONE_MICROS as i32));
                 if let Some(data) = packet.data() {
                     let frame_data = VideoFrameData::new(
                         data.to_vec(),
@@ -59,36 +377,193 @@ impl VideoEncoder {
                         packet.pts().unwrap_or(0),
                     );
 
-                    self.video_buffer
-                        .insert(packet.dts().unwrap_or(0), frame_data);
+                    self.buffer_frame(packet.dts().unwrap_or(0), frame_data);
+
+                    if packet.is_key() {
+                        // The encoder's own keyframes every `GOP_SIZE` frames count as well
+                        self.frames_since_keyframe = 0;
+                        if let Some(waiter) = self.keyframe_waiter.take() {
+                            let _ = waiter.send(());
+                        }
+                    }
                 };
             }
         }
         Ok(())
     }
 
+    /// Buffer an already encoded packet as is, skipping our encoder entirely
+    fn process_encoded(&mut self, frame: &RawVideoFrame, codec_id: ffmpeg::codec::Id) {
+        if self.passthrough_codec != Some(codec_id) {
+            debug!("Buffering pre-encoded {:?} packets", codec_id);
+            self.passthrough_codec = Some(codec_id);
+            self.passthrough_extradata.clear();
+            self.video_buffer.reset();
+        }
+
+        // No B-frames in a capture stream so decode order matches presentation order
+        let is_key = h264_contains_idr(frame.get_bytes());
+        if is_key {
+            let parameter_sets = h264_parameter_sets(frame.get_bytes());
+            if !parameter_sets.is_empty() {
+                self.passthrough_extradata = parameter_sets;
+            }
+        }
+        let frame_data = VideoFrameData::new(frame.get_bytes().clone(), is_key, frame.timestamp);
+        self.buffer_frame(frame.timestamp, frame_data);
+
+        if is_key {
+            if let Some(waiter) = self.keyframe_waiter.take() {
+                let _ = waiter.send(());
+            }
+        }
+    }
+
+    /// Codec parameters of what's in the buffer, either our encoder's or those of the
+    /// pre-encoded stream
+    pub fn get_parameters(&self) -> Option<ffmpeg::codec::Parameters> {
+        if let Some(codec_id) = self.passthrough_codec {
+            let mut parameters = ffmpeg::codec::Parameters::new();
+            unsafe {
+                let parameters = parameters.as_mut_ptr();
+                (*parameters).codec_type = ffmpeg::ffi::AVMediaType::AVMEDIA_TYPE_VIDEO;
+                (*parameters).codec_id = codec_id.into();
+                (*parameters).width = self.width as i32;
+                (*parameters).height = self.height as i32;
+
+                // MP4 needs the SPS and PPS up front, it can't find them in the packets
+                let size = self.passthrough_extradata.len();
+                if size > 0 {
+                    let extradata = ffmpeg::ffi::av_mallocz(
+                        size + ffmpeg::ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize,
+                    ) as *mut u8;
+                    if !extradata.is_null() {
+                        ptr::copy_nonoverlapping(
+                            self.passthrough_extradata.as_ptr(),
+                            extradata,
+                            size,
+                        );
+                        (*parameters).extradata = extradata;
+                        (*parameters).extradata_size = size as i32;
+                    }
+                }
+            }
+            return Some(parameters);
+        }
+
+        self.encoder.as_ref().map(ffmpeg::codec::Parameters::from)
+    }
+
+    /// Force the next processed frame to be encoded as a keyframe.
+    ///
+    /// The returned receiver resolves once a keyframe has made it into the buffer. Requesting
+    /// a new keyframe before the previous one arrived drops the older receiver's sender.
+    pub fn request_keyframe(&mut self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        self.force_keyframe = true;
+        self.keyframe_waiter = Some(tx);
+        rx
+    }
+
+    /// Leave the cursor out of the encoded frames and keep it in the buffer instead, so each save
+    /// can choose whether to draw it. Only has an effect when the cursor comes as metadata.
+    pub fn set_cursor_on_save(&mut self, cursor_on_save: bool) {
+        self.cursor_on_save = cursor_on_save;
+    }
+
+    /// Force a keyframe whenever more than `threshold` (0 to 1) of the picture changed since the
+    /// previous frame, so cuts such as switching windows start a new GOP. No more often than every
+    /// [`MIN_SCENE_CUT_DISTANCE`] frames.
+    pub fn set_scene_detection(&mut self, threshold: f32) {
+        self.scene_cut_threshold = Some(threshold);
+    }
+
+    /// Only encode `region` of the captured frames, which are `source_width`x`source_height`.
+    ///
+    /// The encoder must have been created with the region's size. Pre-encoded passthrough
+    /// frames are buffered uncropped.
+    pub fn set_crop(&mut self, region: Region, source_width: u32, source_height: u32) {
+        self.crop = Some(Crop {
+            region,
+            source_width,
+            source_height,
+        });
+    }
+
+    /// Get a copy of the next raw frame sent to the encoder, in the encoder's pixel format.
+    ///
+    /// Frames are only copied while someone is waiting, so this costs nothing otherwise.
+    /// Pre-encoded passthrough frames never resolve the receiver.
+    pub fn request_frame(&mut self) -> oneshot::Receiver<ffmpeg::util::frame::video::Video> {
+        let (tx, rx) = oneshot::channel();
+        self.frame_waiters.push(tx);
+        rx
+    }
+
     /// Drain the encoder of any remaining frames it is processing
     pub fn drain(&mut self) -> Result<(), ffmpeg::Error> {
         if let Some(ref mut encoder) = self.encoder {
-            encoder.send_eof()?;
-            let mut packet = ffmpeg::codec::packet::Packet::empty();
-            while encoder.receive_packet(&mut packet).is_ok() {
-                if let Some(data) = packet.data() {
-                    let frame_data = VideoFrameData::new(
-                        data.to_vec(),
-                        packet.is_key(),
-                        packet.pts().unwrap_or(0),
-                    );
-
-                    self.video_buffer
-                        .insert(packet.dts().unwrap_or(0), frame_data);
-                };
-                packet = ffmpeg::codec::packet::Packet::empty();
-            }
+            let packets = Self::flush_encoder(encoder)?;
+            self.buffer_drained(packets);
         }
         Ok(())
     }
 
+    /// Same as [`Self::drain`] but the encoder finishes its frames on the blocking thread pool,
+    /// so awaiting it doesn't hold up the async executor
+    pub async fn drain_async(&mut self) -> Result<(), ffmpeg::Error> {
+        let Some(mut encoder) = self.encoder.take() else {
+            return Ok(());
+        };
+
+        let (encoder, packets) = tokio::task::spawn_blocking(move || {
+            let packets = Self::flush_encoder(&mut encoder);
+            (encoder, packets)
+        })
+        .await
+        .map_err(|_| ffmpeg::Error::Bug)?;
+        self.encoder = Some(encoder);
+
+        self.buffer_drained(packets?);
+        Ok(())
+    }
+
+    /// Signal the end of the stream and collect every packet the encoder still had in flight,
+    /// timestamped in micro seconds like the buffer
+    fn flush_encoder(
+        encoder: &mut ffmpeg::codec::encoder::Video,
+    ) -> Result<Vec<ffmpeg::codec::packet::Packet>, ffmpeg::Error> {
+        encoder.send_eof()?;
+        let mut packets = Vec::new();
+        let mut packet = ffmpeg::codec::packet::Packet::empty();
+        while encoder.receive_packet(&mut packet).is_ok() {
+            packet.rescale_ts(encoder.time_base(), Rational::new(1, ONE_MICROS as i32));
+            packets.push(packet);
+            packet = ffmpeg::codec::packet::Packet::empty();
+        }
+        Ok(packets)
+    }
+
+    fn buffer_drained(&mut self, packets: Vec<ffmpeg::codec::packet::Packet>) {
+        for packet in packets {
+            if let Some(data) = packet.data() {
+                let frame_data =
+                    VideoFrameData::new(data.to_vec(), packet.is_key(), packet.pts().unwrap_or(0));
+
+                self.buffer_frame(packet.dts().unwrap_or(0), frame_data);
+            };
+        }
+    }
+
+    /// Insert an encoded frame into the buffer, sending a copy to whoever else wants packets
+    fn buffer_frame(&mut self, dts: i64, frame_data: VideoFrameData) {
+        if let Some(packet_tx) = &self.packet_tx {
+            // Nobody subscribed yet isn't an error
+            let _ = packet_tx.send((dts, frame_data.clone()));
+        }
+        self.video_buffer.insert(dts, frame_data);
+    }
+
     pub fn reset_encoder(&mut self) -> Result<(), ffmpeg::Error> {
         // Drop the encoder
         self.encoder.take();
@@ -99,26 +574,172 @@ impl VideoEncoder {
             self.width,
             self.height,
             &self.encoder_name,
+            self.pixel_format,
+            self.bitrate_kbps,
+            &self.config,
         )?);
         Ok(())
     }
 
+    /// Change the target bitrate without losing the buffer.
+    ///
+    /// The new bitrate is set on the open encoder first, which x264 and NVENC pick up with the
+    /// next frame. Other encoders ignore it after opening, so they're drained into the buffer and
+    /// reopened with it instead, starting a new GOP.
+    pub fn set_bitrate_adaptive(&mut self, target_kbps: u32) -> Result<(), ffmpeg::Error> {
+        // x264 opened at a constant quality ignores the bitrate until it's reopened with one
+        let had_bitrate = self.bitrate_kbps.replace(target_kbps).is_some();
+        let Some(ref mut encoder) = self.encoder else {
+            return Ok(());
+        };
+
+        if reconfigures_bitrate(&self.encoder_name)
+            && (had_bitrate || self.encoder_name != "libx264")
+        {
+            let ret = unsafe {
+                ffmpeg::ffi::av_opt_set_int(
+                    encoder.as_mut_ptr() as *mut c_void,
+                    c"b".as_ptr(),
+                    target_kbps as i64 * 1000,
+                    0,
+                )
+            };
+            if ret >= 0 {
+                debug!(
+                    "Changed the {} bitrate to {}k",
+                    self.encoder_name, target_kbps
+                );
+                return Ok(());
+            }
+            warn!(
+                "Could not change the {} bitrate in place, reopening it: {:?}",
+                self.encoder_name,
+                ffmpeg::Error::from(ret)
+            );
+        }
+
+        self.reopen_encoder()?;
+        debug!("Reopened {} at {}k", self.encoder_name, target_kbps);
+        Ok(())
+    }
+
+    /// Drain the encoder into the buffer and open it again with the current settings, starting a
+    /// new GOP. Unlike [`Self::reset_encoder`] the buffer is kept.
+    fn reopen_encoder(&mut self) -> Result<(), ffmpeg::Error> {
+        self.drain()?;
+        self.encoder = Some(Self::create_encoder(
+            self.width,
+            self.height,
+            &self.encoder_name,
+            self.pixel_format,
+            self.bitrate_kbps,
+            &self.config,
+        )?);
+        Ok(())
+    }
+
+    /// Whether the named encoder accepts `format` as its input pixel format
+    pub fn supports_pixel_format(encoder_name: &str, format: Pixel) -> bool {
+        ffmpeg::codec::encoder::find_by_name(encoder_name)
+            .and_then(|codec| codec.video().ok())
+            .and_then(|video| video.formats())
+            .map(|mut formats| formats.any(|f| f == format))
+            .unwrap_or(false)
+    }
+
     pub fn get_encoder(&self) -> &Option<ffmpeg::codec::encoder::Video> {
         &self.encoder
     }
 
+    /// Find where a clip starting at `pts_us` has to begin: the DTS of the closest buffered
+    /// keyframe at or before it, or `None` if it is older than the buffer.
+    pub fn seek_to_nearest_keyframe(&self, pts_us: i64) -> Option<i64> {
+        self.video_buffer.keyframe_at_or_before(pts_us)
+    }
+
+    /// Look up the buffered frame shown at `pts_us`, or the last one shown before it. A recently
+    /// decoded frame comes straight from the cache, otherwise its GOP is copied out so it can be
+    /// decoded with [`EncodedFrame::decode`] without holding the encoder.
+    ///
+    /// Returns `None` if `pts_us` is older than the buffer.
+    pub fn get_frame_at(&mut self, pts_us: i64) -> Option<BufferedFrame> {
+        let keyframe = self.seek_to_nearest_keyframe(pts_us)?;
+        // Only the GOP the frame is in is needed to decode it
+        let frames: Vec<VideoFrameData> = self
+            .video_buffer
+            .get_frames()
+            .range(keyframe..)
+            .take_while(|(dts, frame)| **dts == keyframe || !frame.is_key())
+            .map(|(_, frame)| frame.clone())
+            .collect();
+        let shown_pts = frames
+            .iter()
+            .map(|frame| *frame.get_pts())
+            .filter(|pts| *pts <= pts_us)
+            .max()?;
+        if let Some(pixels) = self.decoded_frames.get(&shown_pts) {
+            return Some(BufferedFrame::Decoded(Arc::clone(pixels)));
+        }
+
+        Some(BufferedFrame::Encoded(EncodedFrame {
+            frames,
+            parameters: self.get_parameters()?,
+            shown_pts,
+        }))
+    }
+
+    /// Keep `pixels` decoded from the frame shown at `shown_pts` for the next request
+    pub fn cache_decoded_frame(&mut self, shown_pts: i64, pixels: Arc<[u8]>) {
+        self.decoded_frames.put(shown_pts, pixels);
+    }
+
+    /// Hold the buffered video to `limit` instead of the seconds given to [`Self::new`]. It still
+    /// never holds more than those seconds, audio isn't kept any longer.
+    pub fn set_buffer_limit(&mut self, limit: BufferLimit) {
+        if let BufferLimit::ByDuration { seconds } = self.video_buffer.get_limit() {
+            self.video_buffer.set_max_seconds(seconds);
+        }
+        self.video_buffer.set_limit(limit);
+    }
+
     pub fn get_buffer(&self) -> &VideoBuffer {
         &self.video_buffer
     }
+
+    pub fn get_buffer_mut(&mut self) -> &mut VideoBuffer {
+        &mut self.video_buffer
+    }
+
+    /// Also send every packet that gets buffered on `packet_tx`, as `(dts, frame)` in micro
+    /// seconds, for outputs that want the encoded stream as it's produced
+    pub fn set_packet_broadcast(&mut self, packet_tx: broadcast::Sender<(i64, VideoFrameData)>) {
+        self.packet_tx = Some(packet_tx);
+    }
 }
 
 impl VideoEncoder {
+    /// BGRA is what the capture delivers unless NV12 gets negotiated, so prefer it and only pick
+    /// something else (and scale into it) when the encoder can't take BGRA.
+    fn default_pixel_format(encoder_name: &str) -> Pixel {
+        if Self::supports_pixel_format(encoder_name, Pixel::BGRA) {
+            return Pixel::BGRA;
+        }
+
+        ffmpeg::codec::encoder::find_by_name(encoder_name)
+            .and_then(|codec| codec.video().ok())
+            .and_then(|video| video.formats())
+            .and_then(|mut formats| formats.next())
+            .unwrap_or(Pixel::BGRA)
+    }
+
     fn create_encoder(
         width: u32,
         height: u32,
         encoder_name: &str,
+        pixel_format: Pixel,
+        bitrate_kbps: Option<u32>,
+        config: &AppConfig,
     ) -> Result<ffmpeg::codec::encoder::Video, ffmpeg::Error> {
-        let config = load_or_create_config();
         let encoder_codec = ffmpeg::codec::encoder::find_by_name(encoder_name)
             .ok_or(ffmpeg::Error::EncoderNotFound)?;
 
@@ -128,12 +749,11 @@ impl VideoEncoder {
 
         encoder_ctx.set_width(width);
         encoder_ctx.set_height(height);
-        encoder_ctx.set_format(ffmpeg::format::Pixel::BGRA);
+        encoder_ctx.set_format(pixel_format);
         encoder_ctx.set_frame_rate(Some(Rational::new(1, 60)));
         encoder_ctx.set_bit_rate(16_000_000);
 
-        // These should be part of a config file
-        encoder_ctx.set_time_base(Rational::new(1, 1_000_000));
+        encoder_ctx.set_time_base(Rational::new(1, config.time_base_den as i32));
 
         // Needed to insert I-Frames more frequently so we don't lose full seconds
         // when popping frames from the front
@@ -144,39 +764,69 @@ impl VideoEncoder {
 
         // TODO: Fine tune these presets and show estimated file sizes for each in the
         // README
-        match config.quality {
+        let (nvenc_preset, cq, x264_preset, crf) = match config.quality {
             // 1.5 GB file for a 5 minute recording
-            QualityPreset::LOW => {
-                opts.set("vsync", "vfr");
-                opts.set("rc", "vbr");
-                opts.set("preset", "p2");
-                opts.set("tune", "hq");
-                opts.set("cq", "25");
-                opts.set("b:v", "20M");
-            }
-            QualityPreset::MEDIUM => {
-                opts.set("vsync", "vfr");
-                opts.set("rc", "vbr");
-                opts.set("preset", "p4");
-                opts.set("tune", "hq");
-                opts.set("cq", "18");
-                opts.set("b:v", "40M");
-            }
-            QualityPreset::HIGH => {
-                opts.set("vsync", "vfr");
-                opts.set("rc", "vbr");
-                opts.set("preset", "p7");
-                opts.set("tune", "hq");
-                opts.set("cq", "10");
-                opts.set("b:v", "80M");
-            }
-            QualityPreset::HIGHEST => {
-                opts.set("vsync", "vfr");
-                opts.set("rc", "vbr");
-                opts.set("preset", "p7");
-                opts.set("tune", "hq");
-                opts.set("cq", "1");
-                opts.set("b:v", "120M");
+            QualityPreset::LOW => ("p2", "25", "veryfast", "28"),
+            QualityPreset::MEDIUM => ("p4", "18", "medium", "23"),
+            QualityPreset::HIGH => ("p7", "10", "slow", "18"),
+            QualityPreset::HIGHEST => ("p7", "1", "slow", "12"),
+        };
+        opts.set("vsync", "vfr");
+
+        // Each encoder names its presets differently and rejects the others' values, e.g. x264
+        // fails to open with `preset=p4`. VAAPI and QSV only get the bitrate.
+        if encoder_name.contains("nvenc") {
+            opts.set("rc", "vbr");
+            opts.set("preset", nvenc_preset);
+            opts.set("tune", "hq");
+            opts.set("cq", cq);
+
+            // Make sure keyframes requested via `request_keyframe` are real IDR frames so the
+            // buffer can be cut there
+            opts.set("forced-idr", "1");
+        } else if encoder_name == "libx264" {
+            opts.set("preset", x264_preset);
+            // A constant quality overrides the bitrate, leave it out once one is set
+            if bitrate_kbps.is_none() {
+                opts.set("crf", crf);
+            }
+        }
+
+        if config.closed_gop {
+            encoder_ctx.set_flags(ffmpeg::codec::Flags::CLOSED_GOP);
+            // x264 otherwise adds keyframes at scene cuts, keep every GOP `GOP_SIZE` frames
+            if encoder_name == "libx264" {
+                opts.set(
+                    "x264-params",
+                    &format!("keyint={0}:min-keyint={0}:scenecut=0:open-gop=0", GOP_SIZE),
+                );
+            }
+        }
+
+        let bitrate_kbps = bitrate_kbps.unwrap_or_else(|| config.quality.bitrate_kbps());
+        opts.set("b:v", &format!("{}k", bitrate_kbps));
+
+        if encoder_codec.id() == ffmpeg::codec::Id::H264 {
+            set_h264_profile_level(
+                &mut encoder_ctx,
+                &mut opts,
+                encoder_name,
+                config.h264_profile.as_deref(),
+                config.h264_level.as_deref(),
+            );
+        }
+
+        // Values are left for FFmpeg to reject, only names it doesn't know are caught here since
+        // opening drops them silently
+        for (key, value) in &config.encoder_options {
+            if has_option(&encoder_ctx, key) {
+                debug!("Setting encoder option {}={}", key, value);
+                opts.set(key, value);
+            } else {
+                warn!(
+                    "{} did not recognise option {}={}, ignoring it",
+                    encoder_name, key, value
+                );
             }
         }
 
@@ -186,3 +836,450 @@ impl VideoEncoder {
         Ok(encoder)
     }
 }
+
+/// Ask the encoder for `profile` and `level` through its own options, which every H.264 encoder in
+/// [`VIDEO_ENCODERS`] has, and put them on the context as well so they end up in the stream
+/// parameters and the container tags the stream with them
+fn set_h264_profile_level(
+    encoder_ctx: &mut ffmpeg::codec::encoder::video::Video,
+    opts: &mut ffmpeg::Dictionary,
+    encoder_name: &str,
+    profile: Option<&str>,
+    level: Option<&str>,
+) {
+    if let Some(profile) = profile {
+        let (name, value) = match profile {
+            // VAAPI has no plain baseline, constrained baseline is what devices decode anyway
+            "baseline" if encoder_name.contains("vaapi") => (
+                "constrained_baseline",
+                ffmpeg::codec::Profile::H264(H264::ConstrainedBaseline),
+            ),
+            "baseline" => ("baseline", ffmpeg::codec::Profile::H264(H264::Baseline)),
+            "main" => ("main", ffmpeg::codec::Profile::H264(H264::Main)),
+            _ => ("high", ffmpeg::codec::Profile::H264(H264::High)),
+        };
+        opts.set("profile", name);
+        unsafe {
+            (*encoder_ctx.as_mut_ptr()).profile = value.into();
+        }
+    }
+
+    if let Some(level_idc) = level.and_then(h264_level_idc) {
+        // Written as `4` or `4.1`, which all of them take
+        let level = match level_idc % 10 {
+            0 => (level_idc / 10).to_string(),
+            minor => format!("{}.{}", level_idc / 10, minor),
+        };
+        opts.set("level", &level);
+        unsafe {
+            (*encoder_ctx.as_mut_ptr()).level = level_idc;
+        }
+    }
+}
+
+/// Whether opening an NVENC encoder failed because the driver ran out of encode sessions, which
+/// FFmpeg reports as running out of memory
+fn is_session_limit(encoder_name: &str, error: ffmpeg::Error) -> bool {
+    encoder_name.contains("nvenc")
+        && error
+            == ffmpeg::Error::Other {
+                errno: ffmpeg::util::error::ENOMEM,
+            }
+}
+
+/// Whether the encoder applies a bitrate changed after opening to the frames that follow
+fn reconfigures_bitrate(encoder_name: &str) -> bool {
+    encoder_name == "libx264" || encoder_name.contains("nvenc")
+}
+
+/// Whether the encoder takes `option`, either as an option of every codec or one of its own
+fn has_option(encoder_ctx: &ffmpeg::codec::encoder::video::Video, option: &str) -> bool {
+    let Ok(name) = CString::new(option) else {
+        return false;
+    };
+
+    // The context's private data holds the codec's own options, which are searched as children
+    let found = unsafe {
+        ffmpeg::ffi::av_opt_find(
+            encoder_ctx.as_ptr() as *mut c_void,
+            name.as_ptr(),
+            ptr::null(),
+            0,
+            ffmpeg::ffi::AV_OPT_SEARCH_CHILDREN as i32,
+        )
+    };
+    !found.is_null()
+}
+
+/// Cut the crop region out of a captured frame of the crop's source size.
+///
+/// Only the formats the capture delivers are handled. For NV12 and YUV420P the region's position
+/// must be even since chroma is subsampled.
+fn crop_frame(
+    frame: &ffmpeg::util::frame::video::Video,
+    crop: &Crop,
+) -> Result<ffmpeg::util::frame::video::Video, ffmpeg::Error> {
+    let Crop {
+        region,
+        source_width,
+        source_height,
+    } = *crop;
+    if (frame.width(), frame.height()) != (source_width, source_height) {
+        return Err(ffmpeg::Error::InvalidData);
+    }
+
+    // (bytes per sample, horizontal subsampling, vertical subsampling) of each plane
+    let planes: &[(usize, usize, u32)] = match frame.format() {
+        Pixel::BGRA
+        | Pixel::BGRZ
+        | Pixel::RGBA
+        | Pixel::RGBZ
+        | Pixel::ARGB
+        | Pixel::ZRGB
+        | Pixel::ABGR
+        | Pixel::ZBGR => &[(4, 1, 1)],
+        Pixel::RGB24 | Pixel::BGR24 => &[(3, 1, 1)],
+        Pixel::NV12 => &[(1, 1, 1), (2, 2, 2)],
+        Pixel::YUV420P => &[(1, 1, 1), (1, 2, 2), (1, 2, 2)],
+        _ => return Err(ffmpeg::Error::InvalidData),
+    };
+
+    let mut cropped =
+        ffmpeg::util::frame::video::Video::new(frame.format(), region.width, region.height);
+    for (plane, &(bytes_per_sample, horizontal, subsampling)) in planes.iter().enumerate() {
+        let source_stride = frame.stride(plane);
+        let stride = cropped.stride(plane);
+        let source = frame.data(plane);
+        let data = cropped.data_mut(plane);
+
+        let row_start = region.x as usize / horizontal * bytes_per_sample;
+        let row_len = region.width as usize / horizontal * bytes_per_sample;
+        for row in 0..(region.height / subsampling) as usize {
+            let start =
+                (region.y / subsampling) as usize * source_stride + row * source_stride + row_start;
+            data[row * stride..row * stride + row_len]
+                .copy_from_slice(&source[start..start + row_len]);
+        }
+    }
+
+    Ok(cropped)
+}
+
+/// Blend the cursor onto a captured frame, `offset_x` and `offset_y` being where the frame starts
+/// on the monitor when cropping.
+///
+/// Only BGRA, BGR0, NV12 and YUV420P are handled, the cursor is left out of other formats. For
+/// the 4:2:0 formats each chroma sample takes the colour of the top left cursor pixel it covers.
+pub fn draw_cursor(
+    frame: &mut ffmpeg::util::frame::video::Video,
+    cursor: &CursorOverlay,
+    offset_x: i32,
+    offset_y: i32,
+) {
+    let bitmap = &cursor.bitmap;
+    let frame_width = frame.width() as i32;
+    let frame_height = frame.height() as i32;
+    let format = frame.format();
+
+    for row in 0..bitmap.height as i32 {
+        let y = cursor.y - offset_y + row;
+        if y < 0 || y >= frame_height {
+            continue;
+        }
+
+        for col in 0..bitmap.width as i32 {
+            let x = cursor.x - offset_x + col;
+            if x < 0 || x >= frame_width {
+                continue;
+            }
+
+            let start = (row * bitmap.width as i32 + col) as usize * 4;
+            let [b, g, r, alpha] = [0, 1, 2, 3].map(|i| bitmap.pixels[start + i]);
+            if alpha == 0 {
+                continue;
+            }
+
+            let (x, y) = (x as usize, y as usize);
+            match format {
+                Pixel::BGRA | Pixel::BGRZ => {
+                    let stride = frame.stride(0);
+                    let pixel = &mut frame.data_mut(0)[y * stride + x * 4..];
+                    for (dst, src) in pixel.iter_mut().zip([b, g, r]) {
+                        *dst = blend(*dst, src, alpha);
+                    }
+                }
+                Pixel::NV12 => {
+                    let (luma, cb, cr) = bt601_yuv(r, g, b);
+                    let stride = frame.stride(0);
+                    let dst = &mut frame.data_mut(0)[y * stride + x];
+                    *dst = blend(*dst, luma, alpha);
+
+                    if x % 2 == 0 && y % 2 == 0 {
+                        let stride = frame.stride(1);
+                        let chroma = &mut frame.data_mut(1)[(y / 2) * stride + x..];
+                        chroma[0] = blend(chroma[0], cb, alpha);
+                        chroma[1] = blend(chroma[1], cr, alpha);
+                    }
+                }
+                Pixel::YUV420P => {
+                    let (luma, cb, cr) = bt601_yuv(r, g, b);
+                    let stride = frame.stride(0);
+                    let dst = &mut frame.data_mut(0)[y * stride + x];
+                    *dst = blend(*dst, luma, alpha);
+
+                    if x % 2 == 0 && y % 2 == 0 {
+                        for (plane, value) in [(1, cb), (2, cr)] {
+                            let stride = frame.stride(plane);
+                            let dst = &mut frame.data_mut(plane)[(y / 2) * stride + x / 2];
+                            *dst = blend(*dst, value, alpha);
+                        }
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+/// A buffered frame found by [`VideoEncoder::get_frame_at`]
+pub enum BufferedFrame {
+    /// Tightly packed BGRA rows at the encoded size
+    Decoded(Arc<[u8]>),
+    Encoded(EncodedFrame),
+}
+
+/// The GOP a buffered frame is in, copied out of the buffer to decode it
+pub struct EncodedFrame {
+    frames: Vec<VideoFrameData>,
+    parameters: ffmpeg::codec::Parameters,
+    shown_pts: i64,
+}
+
+impl EncodedFrame {
+    pub fn shown_pts(&self) -> i64 {
+        self.shown_pts
+    }
+
+    /// Decode the frame into tightly packed BGRA rows at the encoded size. Decoding starts at the
+    /// keyframe before it, so frames late in a GOP take longer.
+    ///
+    /// Returns `None` if the frame could not be decoded.
+    pub fn decode(self) -> Option<Arc<[u8]>> {
+        match decode_frame(self.frames.iter(), self.parameters, self.shown_pts) {
+            Ok(Some(pixels)) => Some(pixels.into()),
+            Ok(None) => {
+                warn!(
+                    "The frame at {}us did not come out of the decoder",
+                    self.shown_pts
+                );
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Could not decode the frame at {}us: {:?}",
+                    self.shown_pts, e
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Decode `frames`, starting at a key frame, until the one shown at `pts` comes out and convert it
+/// to BGRA. Returns `None` if it never does.
+fn decode_frame<'a>(
+    frames: impl Iterator<Item = &'a VideoFrameData>,
+    parameters: ffmpeg::codec::Parameters,
+    pts: i64,
+) -> Result<Option<Vec<u8>>, ffmpeg::Error> {
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(parameters)?
+        .decoder()
+        .video()?;
+
+    let mut decoded = ffmpeg::util::frame::video::Video::empty();
+    let found = decode_until(&mut decoder, frames, |frame| {
+        if frame.pts() != Some(pts) {
+            return false;
+        }
+        std::mem::swap(&mut decoded, frame);
+        true
+    })?;
+    if !found {
+        return Ok(None);
+    }
+
+    let (width, height) = (decoded.width(), decoded.height());
+    let mut bgra = ffmpeg::util::frame::video::Video::empty();
+    scaling::Context::get(
+        decoded.format(),
+        width,
+        height,
+        Pixel::BGRA,
+        width,
+        height,
+        scaling::Flags::BILINEAR,
+    )?
+    .run(&decoded, &mut bgra)?;
+
+    let row_bytes = width as usize * 4;
+    let stride = bgra.stride(0);
+    let data = bgra.data(0);
+    Ok(Some(
+        (0..height as usize)
+            .flat_map(|row| &data[row * stride..][..row_bytes])
+            .copied()
+            .collect(),
+    ))
+}
+
+/// Sample the first plane of `frame` on a [`SCENE_GRID_WIDTH`]x[`SCENE_GRID_HEIGHT`] grid. That's
+/// the luma for YUV formats and the first channel of every pixel for packed RGB.
+fn scene_thumbnail(frame: &ffmpeg::util::frame::video::Video) -> Vec<u8> {
+    let width = frame.width() as usize;
+    let height = frame.height() as usize;
+    let stride = frame.stride(0);
+    if width == 0 || height == 0 {
+        return Vec::new();
+    }
+    let bytes_per_pixel = (stride / width).max(1);
+    let data = frame.data(0);
+
+    let mut thumbnail = Vec::with_capacity(SCENE_GRID_WIDTH * SCENE_GRID_HEIGHT);
+    for row in 0..SCENE_GRID_HEIGHT {
+        let y = (row * 2 + 1) * height / (SCENE_GRID_HEIGHT * 2);
+        for column in 0..SCENE_GRID_WIDTH {
+            let x = (column * 2 + 1) * width / (SCENE_GRID_WIDTH * 2);
+            thumbnail.push(
+                data.get(y * stride + x * bytes_per_pixel)
+                    .copied()
+                    .unwrap_or(0),
+            );
+        }
+    }
+    thumbnail
+}
+
+/// Fraction of the samples that differ by more than [`SCENE_SAMPLE_DELTA`] between two
+/// thumbnails, 0 when there is nothing to compare against
+fn scene_change(previous: &[u8], current: &[u8]) -> f32 {
+    if previous.len() != current.len() || current.is_empty() {
+        return 0.0;
+    }
+    let changed = previous
+        .iter()
+        .zip(current)
+        .filter(|(previous, current)| previous.abs_diff(**current) > SCENE_SAMPLE_DELTA)
+        .count();
+    changed as f32 / current.len() as f32
+}
+
+fn blend(dst: u8, src: u8, alpha: u8) -> u8 {
+    ((src as u32 * alpha as u32 + dst as u32 * (255 - alpha as u32) + 127) / 255) as u8
+}
+
+/// Limited range BT.601 conversion of an RGB pixel
+fn bt601_yuv(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = 16 + ((66 * r + 129 * g + 25 * b + 128) >> 8);
+    let u = 128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8);
+    let v = 128 + ((112 * r - 94 * g - 18 * b + 128) >> 8);
+    (y as u8, u as u8, v as u8)
+}
+
+/// Whether an Annex B H.264 packet contains an IDR slice
+fn h264_contains_idr(bytes: &[u8]) -> bool {
+    bytes
+        .windows(4)
+        .any(|w| w[0] == 0 && w[1] == 0 && w[2] == 1 && w[3] & 0x1F == 5)
+}
+
+/// NAL units of an Annex B H.264 packet, without their start codes
+fn h264_nal_units(bytes: &[u8]) -> Vec<&[u8]> {
+    let starts: Vec<usize> = (0..bytes.len().saturating_sub(2))
+        .filter(|&i| bytes[i..i + 3] == [0, 0, 1])
+        .map(|i| i + 3)
+        .collect();
+
+    starts
+        .iter()
+        .enumerate()
+        .map(|(index, &start)| {
+            let end = starts.get(index + 1).map_or(bytes.len(), |&next| next - 3);
+            // A four byte start code leaves its first zero at the end of the previous unit
+            let mut unit = &bytes[start..end];
+            while let [rest @ .., 0] = unit {
+                unit = rest;
+            }
+            unit
+        })
+        .collect()
+}
+
+/// The SPS and PPS of an Annex B H.264 packet, each behind a start code, which is how muxers
+/// take them as extradata. Empty if the packet carries neither.
+fn h264_parameter_sets(bytes: &[u8]) -> Vec<u8> {
+    h264_nal_units(bytes)
+        .into_iter()
+        .filter(|unit| matches!(unit.first().map(|header| header & 0x1F), Some(7 | 8)))
+        .flat_map(|unit| [0, 0, 0, 1].iter().chain(unit))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: [u8; 4] = [0x67, 0x64, 0x00, 0x1f];
+    const PPS: [u8; 3] = [0x68, 0xee, 0x3c];
+    const IDR: [u8; 3] = [0x65, 0x88, 0x84];
+
+    #[test]
+    fn h264_parameter_sets_keeps_only_sps_and_pps() {
+        let mut packet = vec![0, 0, 0, 1];
+        packet.extend_from_slice(&SPS);
+        packet.extend_from_slice(&[0, 0, 1]);
+        packet.extend_from_slice(&PPS);
+        packet.extend_from_slice(&[0, 0, 0, 1]);
+        packet.extend_from_slice(&IDR);
+
+        let mut expected = vec![0, 0, 0, 1];
+        expected.extend_from_slice(&SPS);
+        expected.extend_from_slice(&[0, 0, 0, 1]);
+        expected.extend_from_slice(&PPS);
+
+        assert!(h264_contains_idr(&packet));
+        assert_eq!(h264_parameter_sets(&packet), expected);
+    }
+
+    #[test]
+    fn h264_parameter_sets_is_empty_without_them() {
+        let mut packet = vec![0, 0, 0, 1];
+        packet.extend_from_slice(&IDR);
+
+        assert!(h264_parameter_sets(&packet).is_empty());
+        assert!(h264_parameter_sets(&[]).is_empty());
+    }
+
+    #[test]
+    fn scene_change_counts_samples_past_the_delta() {
+        let previous = [100; 4];
+        let current = [
+            100,
+            100 + SCENE_SAMPLE_DELTA,
+            100 + SCENE_SAMPLE_DELTA + 1,
+            100 - SCENE_SAMPLE_DELTA - 1,
+        ];
+
+        assert_eq!(scene_change(&previous, &previous), 0.0);
+        assert_eq!(scene_change(&previous, &current), 0.5);
+        assert_eq!(scene_change(&[0; 4], &[255; 4]), 1.0);
+    }
+
+    #[test]
+    fn scene_change_is_zero_without_comparable_thumbnails() {
+        assert_eq!(scene_change(&[], &[]), 0.0);
+        assert_eq!(scene_change(&[], &[255; 4]), 0.0);
+        assert_eq!(scene_change(&[0; 4], &[255; 8]), 0.0);
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ffmpeg_next::{self as ffmpeg, format::context::Output, Rational};
+
+use super::buffer::{VideoFrameData, ONE_MICROS};
+
+/// Writes buffered video frames as they are into an output holding just their stream, for HLS
+/// segments, exported GOPs and the stream output
+pub struct VideoMuxer {
+    /// What the muxer picked for the stream once the header was written
+    time_base: Rational,
+
+    /// Buffered timestamps count from here in the output
+    start: i64,
+}
+
+impl VideoMuxer {
+    /// Add the video stream with `parameters` to `output` and write its header. Frames are
+    /// written with their timestamps counting from `start`, see [`timeline_start`].
+    pub fn start(
+        output: &mut Output,
+        parameters: ffmpeg::codec::Parameters,
+        start: i64,
+    ) -> Result<Self> {
+        let mut stream = output.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+        stream.set_time_base(Rational::new(1, ONE_MICROS as i32));
+        stream.set_parameters(parameters);
+        output.write_header()?;
+
+        Ok(Self {
+            time_base: output
+                .stream(0)
+                .context("Could not get the output stream")?
+                .time_base(),
+            start,
+        })
+    }
+
+    /// Write the buffered `frame` decoded at `dts`
+    pub fn write(&self, output: &mut Output, dts: i64, frame: &VideoFrameData) -> Result<()> {
+        let mut packet = ffmpeg::codec::packet::Packet::copy(frame.get_raw_bytes());
+        packet.set_pts(Some(frame.get_pts() - self.start));
+        packet.set_dts(Some(dts - self.start));
+        if frame.is_key() {
+            packet.set_flags(ffmpeg::codec::packet::Flags::KEY);
+        }
+        packet.set_stream(0);
+        packet.rescale_ts(Rational::new(1, ONE_MICROS as i32), self.time_base);
+        packet.write_interleaved(output)?;
+        Ok(())
+    }
+}
+
+/// Where the timestamps of an output starting with `frame` decoded at `dts` should count from.
+/// With B-frames the decode timestamps run behind the presentation ones, so this starts early
+/// enough that none are negative.
+pub fn timeline_start(dts: i64, frame: &VideoFrameData) -> i64 {
+    (*frame.get_pts()).min(dts)
+}
+
+/// Write `frames` to a new MPEG-TS file at `path`, counting timestamps from `start`
+pub fn write_ts_file<'a>(
+    path: &Path,
+    parameters: ffmpeg::codec::Parameters,
+    start: i64,
+    frames: impl IntoIterator<Item = (i64, &'a VideoFrameData)>,
+) -> Result<()> {
+    let mut output = ffmpeg::format::output_as(path, "mpegts")?;
+    let muxer = VideoMuxer::start(&mut output, parameters, start)?;
+    for (dts, frame) in frames {
+        muxer.write(&mut output, dts, frame)?;
+    }
+    output.write_trailer()?;
+    Ok(())
+}
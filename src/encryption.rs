@@ -0,0 +1,302 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use aes_gcm::{
+    aead::{
+        generic_array::GenericArray,
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        OsRng,
+    },
+    Aes256Gcm, KeyInit,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+
+use crate::application_config::AppConfig;
+
+/// Environment variable the passphrase is read from when no key file is configured
+pub const KEY_ENV_VAR: &str = "SCREEN_RECORDER_KEY";
+
+/// Extension appended to encrypted clips, e.g. `clip_1700000000.mp4.enc`
+pub const EXTENSION: &str = "enc";
+
+/// Start of every encrypted file, the last byte is the format version
+const MAGIC: &[u8; 6] = b"SRENC\x01";
+const SALT_LEN: usize = 16;
+
+/// The 12 byte GCM nonce minus the 5 bytes the STREAM construction uses as chunk counter
+const NONCE_PREFIX_LEN: usize = 7;
+
+/// Plaintext is encrypted in chunks of this size so clips never have to fit in memory
+const CHUNK_SIZE: usize = 1024 * 1024;
+const TAG_LEN: usize = 16;
+
+/// Passphrase clips are encrypted with
+pub struct ClipKey(Vec<u8>);
+
+impl ClipKey {
+    /// Read the passphrase from `encryption_key_file`, or `SCREEN_RECORDER_KEY` if unset
+    pub fn load(config: &AppConfig) -> Result<Self> {
+        let passphrase = match &config.encryption_key_file {
+            Some(path) => fs::read(path)
+                .with_context(|| format!("Could not read the encryption key from {:?}", path))?,
+            None => std::env::var(KEY_ENV_VAR)
+                .with_context(|| {
+                    format!(
+                        "Set encryption_key_file or {} to encrypt clips",
+                        KEY_ENV_VAR
+                    )
+                })?
+                .into_bytes(),
+        };
+
+        // Key files are usually written with a trailing newline
+        let passphrase = passphrase.trim_ascii_end().to_vec();
+        if passphrase.is_empty() {
+            bail!("The encryption key is empty");
+        }
+
+        Ok(Self(passphrase))
+    }
+
+    /// Derive the AES-256 key for a file from the passphrase and the file's salt
+    fn cipher(&self, salt: &[u8]) -> Result<Aes256Gcm> {
+        let mut key = [0u8; 32];
+        argon2::Argon2::default()
+            .hash_password_into(&self.0, salt, &mut key)
+            .map_err(|e| anyhow!("Could not derive the encryption key: {}", e))?;
+
+        Ok(Aes256Gcm::new(GenericArray::from_slice(&key)))
+    }
+}
+
+/// Write a clip with `write`, encrypting it into `filename` if `key` is set.
+///
+/// Muxers need to seek back into what they wrote, so the plaintext is staged in a file only the
+/// user can read in the runtime directory first, which lives in memory on most systems, and
+/// removed once encrypted.
+pub fn write_clip<T>(
+    filename: &str,
+    key: Option<&ClipKey>,
+    write: impl FnOnce(&str) -> Result<T>,
+) -> Result<T> {
+    let Some(key) = key else {
+        return write(filename);
+    };
+
+    let extension = Path::new(filename)
+        .file_stem()
+        .map(Path::new)
+        .and_then(|stem| stem.extension())
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("mp4");
+    // Created with mode 0600 and removed once dropped
+    let temp_file = tempfile::Builder::new()
+        .prefix("screen-recorder-")
+        .suffix(&format!(".{}", extension))
+        .tempfile_in(staging_dir(Path::new(filename)))
+        .context("Could not create the file to stage the clip in")?;
+    let staged = temp_file.path();
+
+    write(&staged.to_string_lossy())
+        .and_then(|result| encrypt_file(staged, Path::new(filename), key).map(|_| result))
+}
+
+/// Encrypt `input` into `output` with AES-256-GCM in chunks (the STREAM construction), with the
+/// key derived from `key` with Argon2id and a random salt stored in the file header
+pub fn encrypt_file(input: &Path, output: &Path, key: &ClipKey) -> Result<()> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce_prefix);
+
+    let mut reader = BufReader::new(File::open(input)?);
+    let mut writer = BufWriter::new(File::create(output)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&salt)?;
+    writer.write_all(&nonce_prefix)?;
+
+    let mut encryptor = Some(EncryptorBE32::from_aead(
+        key.cipher(&salt)?,
+        GenericArray::from_slice(&nonce_prefix),
+    ));
+    for_each_chunk(&mut reader, CHUNK_SIZE, |chunk, last| {
+        let encrypted = if last {
+            encryptor
+                .take()
+                .context("Encryptor already finished")?
+                .encrypt_last(chunk)
+        } else {
+            encryptor
+                .as_mut()
+                .context("Encryptor already finished")?
+                .encrypt_next(chunk)
+        };
+        writer.write_all(&encrypted.map_err(|_| anyhow!("Could not encrypt the clip"))?)?;
+        Ok(())
+    })?;
+    writer.flush()?;
+
+    debug!("Encrypted {:?} into {:?}", input, output);
+    Ok(())
+}
+
+/// Decrypt a file written by [`encrypt_file`]. Fails without writing anything useful if the key
+/// is wrong or the file was modified, as every chunk is authenticated.
+pub fn decrypt_file(input: &Path, output: &Path, key: &ClipKey) -> Result<()> {
+    let mut reader = BufReader::new(File::open(input)?);
+
+    let mut magic = [0u8; MAGIC.len()];
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    reader
+        .read_exact(&mut magic)
+        .and_then(|_| reader.read_exact(&mut salt))
+        .and_then(|_| reader.read_exact(&mut nonce_prefix))
+        .context("File is too short to be an encrypted clip")?;
+    if &magic != MAGIC {
+        bail!("{:?} is not an encrypted clip", input);
+    }
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut decryptor = Some(DecryptorBE32::from_aead(
+        key.cipher(&salt)?,
+        GenericArray::from_slice(&nonce_prefix),
+    ));
+    let result = for_each_chunk(&mut reader, CHUNK_SIZE + TAG_LEN, |chunk, last| {
+        let decrypted = if last {
+            decryptor
+                .take()
+                .context("Decryptor already finished")?
+                .decrypt_last(chunk)
+        } else {
+            decryptor
+                .as_mut()
+                .context("Decryptor already finished")?
+                .decrypt_next(chunk)
+        };
+        let decrypted =
+            decrypted.map_err(|_| anyhow!("Wrong key, or the file is corrupted or truncated"))?;
+        writer.write_all(&decrypted)?;
+        Ok(())
+    })
+    .and_then(|_| writer.flush().map_err(Into::into));
+
+    if result.is_err() {
+        let _ = fs::remove_file(output);
+    }
+    result
+}
+
+/// The default output of `decrypt`, the input without its `.enc` extension
+pub fn decrypted_path(input: &Path) -> Result<PathBuf> {
+    match input.extension() {
+        Some(extension) if extension == EXTENSION => Ok(input.with_extension("")),
+        _ => bail!(
+            "{:?} doesn't end in .{}, pass the output path as well",
+            input,
+            EXTENSION
+        ),
+    }
+}
+
+/// Where plaintext clips are muxed before being encrypted into `output`, next to it when there's
+/// no runtime directory rather than in a shared `/tmp`
+fn staging_dir(output: &Path) -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .or_else(|| output.parent().map(Path::to_path_buf))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+/// Call `f` with every `chunk_size` piece of `reader`, flagging the last one. An empty reader
+/// still produces one empty last chunk.
+fn for_each_chunk(
+    reader: &mut impl Read,
+    chunk_size: usize,
+    mut f: impl FnMut(&[u8], bool) -> Result<()>,
+) -> Result<()> {
+    let mut current = vec![0u8; chunk_size];
+    let mut next = vec![0u8; chunk_size];
+    let mut current_len = read_full(reader, &mut current)?;
+    loop {
+        // Only a short read marks the end, a full chunk may be followed by nothing
+        let next_len = if current_len == chunk_size {
+            read_full(reader, &mut next)?
+        } else {
+            0
+        };
+        let last = next_len == 0;
+        f(&current[..current_len], last)?;
+        if last {
+            return Ok(());
+        }
+
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+    }
+}
+
+/// Fill `buffer` as far as the reader allows, returning how much was read
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bit over one chunk, so the last chunk follows a full one
+    fn plaintext() -> Vec<u8> {
+        (0..CHUNK_SIZE + 100).map(|i| i as u8).collect()
+    }
+
+    fn write_encrypted(dir: &Path, key: &ClipKey) -> PathBuf {
+        let encrypted = dir.join("clip.mp4.enc");
+        write_clip(&encrypted.to_string_lossy(), Some(key), |target| {
+            Ok(fs::write(target, plaintext())?)
+        })
+        .unwrap();
+        encrypted
+    }
+
+    #[test]
+    fn write_clip_encrypts_what_decrypt_file_restores() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ClipKey(b"passphrase".to_vec());
+        let encrypted = write_encrypted(dir.path(), &key);
+        assert_ne!(fs::read(&encrypted).unwrap(), plaintext());
+
+        let decrypted = dir.path().join("clip.mp4");
+        decrypt_file(&encrypted, &decrypted, &key).unwrap();
+        assert_eq!(fs::read(&decrypted).unwrap(), plaintext());
+    }
+
+    #[test]
+    fn decrypt_file_rejects_a_modified_clip() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = ClipKey(b"passphrase".to_vec());
+        let encrypted = write_encrypted(dir.path(), &key);
+
+        let mut bytes = fs::read(&encrypted).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&encrypted, bytes).unwrap();
+
+        let decrypted = dir.path().join("clip.mp4");
+        assert!(decrypt_file(&encrypted, &decrypted, &key).is_err());
+        assert!(!decrypted.exists());
+    }
+}
@@ -0,0 +1,62 @@
+use log::debug;
+use serde_json::Value;
+use tokio::process::Command;
+
+/// Whether the focused window is fullscreen.
+///
+/// The screen cast portal has no notion of windows so this asks the compositor directly, which
+/// only works on Hyprland and Sway. Returns `None` on anything else or if the query fails.
+pub async fn focused_window_is_fullscreen() -> Option<bool> {
+    if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() {
+        let window = query_json("hyprctl", &["activewindow", "-j"]).await?;
+
+        // Older Hyprland reports a bool, newer versions the fullscreen mode with 0 being none.
+        // Without a focused window this is an empty object.
+        let fullscreen = &window["fullscreen"];
+        return Some(
+            fullscreen
+                .as_bool()
+                .or_else(|| fullscreen.as_u64().map(|mode| mode != 0))
+                .unwrap_or(false),
+        );
+    }
+
+    if std::env::var_os("SWAYSOCK").is_some() {
+        let tree = query_json("swaymsg", &["-t", "get_tree"]).await?;
+        return Some(focused_sway_node(&tree).is_some_and(|node| {
+            node["fullscreen_mode"]
+                .as_u64()
+                .is_some_and(|mode| mode != 0)
+        }));
+    }
+
+    None
+}
+
+async fn query_json(program: &str, args: &[&str]) -> Option<Value> {
+    let output = match Command::new(program).args(args).output().await {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            debug!("{} exited with {}", program, output.status);
+            return None;
+        }
+        Err(e) => {
+            debug!("Could not run {}: {:?}", program, e);
+            return None;
+        }
+    };
+
+    serde_json::from_slice(&output.stdout).ok()
+}
+
+fn focused_sway_node(node: &Value) -> Option<&Value> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(node);
+    }
+
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .find_map(focused_sway_node)
+}
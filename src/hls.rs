@@ -0,0 +1,204 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{
+    application_config::HlsConfig,
+    encoders::{
+        buffer::VideoFrameData,
+        video_encoder::{VideoEncoder, ONE_MICROS},
+        video_muxer,
+    },
+};
+
+/// Segments listed in the playlist, older ones are deleted
+const PLAYLIST_SEGMENTS: usize = 6;
+
+/// A segment being collected, starting at a keyframe
+struct Segment {
+    frames: Vec<(i64, VideoFrameData)>,
+    start_pts: i64,
+}
+
+/// A segment that was written and is listed in the playlist
+struct WrittenSegment {
+    path: PathBuf,
+    duration_us: i64,
+}
+
+/// Writes the packets the video encoder broadcasts as MPEG-TS segments with a live `m3u8`
+/// playlist, see `hls_output`
+pub struct HlsWriter {
+    config: HlsConfig,
+    video_encoder: Arc<Mutex<VideoEncoder>>,
+    sequence: u64,
+    segments: VecDeque<WrittenSegment>,
+
+    /// Timestamps in the segments count from here, so they continue from one to the next
+    timeline_start: Option<i64>,
+}
+
+impl HlsWriter {
+    pub fn new(config: HlsConfig, video_encoder: Arc<Mutex<VideoEncoder>>) -> Self {
+        Self {
+            config,
+            video_encoder,
+            sequence: 0,
+            segments: VecDeque::new(),
+            timeline_start: None,
+        }
+    }
+
+    /// Write segments from the packets on `packet_rx` on its own thread until `stop` is set
+    pub fn spawn(
+        mut self,
+        mut packet_rx: broadcast::Receiver<(i64, VideoFrameData)>,
+        stop: Arc<AtomicBool>,
+    ) {
+        std::thread::spawn(move || {
+            let dir = self.segment_dir();
+            if let Err(e) = std::fs::create_dir_all(&dir) {
+                error!("Could not create the HLS directory {:?}: {:?}", dir, e);
+                return;
+            }
+            info!("Writing HLS to {:?}", self.config.playlist_path);
+
+            let mut segment: Option<Segment> = None;
+            while !stop.load(std::sync::atomic::Ordering::Acquire) {
+                let (dts, frame) = match packet_rx.blocking_recv() {
+                    Ok(packet) => packet,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // A segment with holes won't decode, start over at the next keyframe
+                        warn!(
+                            "HLS output fell behind by {} packets, skipping ahead",
+                            skipped
+                        );
+                        segment = None;
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if frame.is_key() {
+                    let segment_us = self.config.segment_duration_secs as i64 * ONE_MICROS as i64;
+                    if let Some(done) =
+                        segment.take_if(|s| frame.get_pts() - s.start_pts >= segment_us)
+                    {
+                        let duration_us = frame.get_pts() - done.start_pts;
+                        if let Err(e) = self.write_segment(done, duration_us) {
+                            error!("Could not write HLS segment: {:?}", e);
+                        }
+                    }
+                    segment.get_or_insert_with(|| Segment {
+                        frames: Vec::new(),
+                        start_pts: *frame.get_pts(),
+                    });
+                }
+
+                // Anything before the first keyframe can't be decoded on its own
+                if let Some(segment) = &mut segment {
+                    segment.frames.push((dts, frame));
+                }
+            }
+        });
+    }
+
+    fn segment_dir(&self) -> PathBuf {
+        self.config
+            .playlist_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+
+    fn write_segment(&mut self, segment: Segment, duration_us: i64) -> Result<()> {
+        // The encoder is reopened when the bitrate changes, take its current parameters
+        let parameters = self
+            .video_encoder
+            .blocking_lock()
+            .get_parameters()
+            .context("Could not get video parameters")?;
+
+        // Every segment counts from the first, so the playlist plays as one timeline
+        let timeline_start = *self.timeline_start.get_or_insert_with(|| {
+            segment
+                .frames
+                .first()
+                .map_or(segment.start_pts, |(dts, frame)| {
+                    video_muxer::timeline_start(*dts, frame)
+                })
+        });
+
+        self.sequence += 1;
+        let path = self
+            .segment_dir()
+            .join(format!("segment_{:06}.ts", self.sequence));
+        video_muxer::write_ts_file(
+            &path,
+            parameters,
+            timeline_start,
+            segment.frames.iter().map(|(dts, frame)| (*dts, frame)),
+        )?;
+
+        self.segments
+            .push_back(WrittenSegment { path, duration_us });
+        let removed: Vec<_> = self
+            .segments
+            .drain(..self.segments.len().saturating_sub(PLAYLIST_SEGMENTS))
+            .collect();
+        self.write_playlist()?;
+
+        // Only once they're out of the playlist, players may still be fetching them otherwise
+        for segment in removed {
+            if let Err(e) = std::fs::remove_file(&segment.path) {
+                debug!(
+                    "Could not remove old HLS segment {:?}: {:?}",
+                    segment.path, e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the playlist through a rename, so players never read a half written one
+    fn write_playlist(&self) -> Result<()> {
+        let target_duration = self
+            .segments
+            .iter()
+            .map(|segment| (segment.duration_us as f64 / ONE_MICROS as f64).ceil() as u64)
+            .max()
+            .unwrap_or(0);
+        let first_sequence = self.sequence + 1 - self.segments.len() as u64;
+
+        let mut playlist = String::new();
+        writeln!(playlist, "#EXTM3U")?;
+        writeln!(playlist, "#EXT-X-VERSION:3")?;
+        writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target_duration)?;
+        writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first_sequence)?;
+        for segment in &self.segments {
+            let name = segment
+                .path
+                .file_name()
+                .context("HLS segment has no file name")?;
+            writeln!(
+                playlist,
+                "#EXTINF:{:.3},",
+                segment.duration_us as f64 / ONE_MICROS as f64
+            )?;
+            writeln!(playlist, "{}", name.to_string_lossy())?;
+        }
+
+        let playlist_path = &self.config.playlist_path;
+        let temp_path = playlist_path.with_extension("m3u8.tmp");
+        std::fs::write(&temp_path, playlist)?;
+        std::fs::rename(&temp_path, playlist_path)?;
+        Ok(())
+    }
+}
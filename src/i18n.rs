@@ -0,0 +1,121 @@
+use std::fmt::{Display, Write};
+
+#[cfg(feature = "i18n")]
+use gettextrs::{
+    bind_textdomain_codeset, bindtextdomain, gettext, setlocale, textdomain, LocaleCategory,
+};
+#[cfg(feature = "i18n")]
+use log::warn;
+
+/// Name the translations are installed under, `<locale dir>/<lang>/LC_MESSAGES/<domain>.mo`
+#[cfg(feature = "i18n")]
+const DOMAIN: &str = "auto-screen-recorder";
+
+/// Translate a user facing message, substituting the arguments into the translated text.
+///
+/// Translations may refer to arguments by position (`{0}`, `{1}`) when their word order differs
+/// from English.
+#[macro_export]
+macro_rules! tr {
+    ($msgid:literal) => {
+        $crate::i18n::translate($msgid)
+    };
+    ($msgid:literal, $($arg:expr),+ $(,)?) => {
+        $crate::i18n::fill(
+            &$crate::i18n::translate($msgid),
+            &[$(&$arg as &dyn std::fmt::Display),+],
+        )
+    };
+}
+pub use crate::tr;
+
+/// Pick the language of user facing messages from the environment (`LANG`). Without the `i18n`
+/// feature messages stay in English.
+pub fn init() {
+    #[cfg(feature = "i18n")]
+    {
+        setlocale(LocaleCategory::LcAll, "");
+        let result = bindtextdomain(DOMAIN, env!("LOCALE_DIR"))
+            .and_then(|_| bind_textdomain_codeset(DOMAIN, "UTF-8"))
+            .and_then(|_| textdomain(DOMAIN));
+        if let Err(e) = result {
+            warn!(
+                "Could not load translations, messages stay in English: {:?}",
+                e
+            );
+        }
+    }
+}
+
+/// The message in the current language, or as is if there's no translation for it
+pub fn translate(msgid: &str) -> String {
+    #[cfg(feature = "i18n")]
+    {
+        gettext(msgid)
+    }
+
+    #[cfg(not(feature = "i18n"))]
+    {
+        msgid.to_string()
+    }
+}
+
+/// Replace the `{}` placeholders in `message` with `args` in order, and `{N}` with the Nth
+/// argument. Placeholders without a matching argument are left as they are.
+pub fn fill(message: &str, args: &[&dyn Display]) -> String {
+    let mut filled = String::with_capacity(message.len());
+    let mut next = 0;
+    let mut rest = message;
+    while let Some(open) = rest.find('{') {
+        let Some(close) = rest[open..].find('}').map(|close| open + close) else {
+            break;
+        };
+
+        filled.push_str(&rest[..open]);
+        let placeholder = &rest[open + 1..close];
+        let index = if placeholder.is_empty() {
+            next += 1;
+            Some(next - 1)
+        } else {
+            placeholder.parse::<usize>().ok()
+        };
+        match index.and_then(|index| args.get(index)) {
+            Some(arg) => {
+                let _ = write!(filled, "{}", arg);
+            }
+            None => filled.push_str(&rest[open..=close]),
+        }
+        rest = &rest[close + 1..];
+    }
+    filled.push_str(rest);
+
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_substitutes_arguments_in_order() {
+        assert_eq!(
+            fill("saved {} in {}s", &[&"clip.mp4", &3]),
+            "saved clip.mp4 in 3s"
+        );
+        assert_eq!(fill("no placeholders", &[&1]), "no placeholders");
+    }
+
+    #[test]
+    fn fill_substitutes_arguments_by_position() {
+        assert_eq!(fill("{1} {0}", &[&"world", &"hello"]), "hello world");
+        assert_eq!(fill("{0} and {0}", &[&"again"]), "again and again");
+    }
+
+    #[test]
+    fn fill_keeps_placeholders_without_an_argument() {
+        assert_eq!(fill("{} {} {}", &[&1, &2]), "1 2 {}");
+        assert_eq!(fill("{5} {name}", &[&1]), "{5} {name}");
+        assert_eq!(fill("unclosed {", &[&1]), "unclosed {");
+        assert_eq!(fill("", &[]), "");
+    }
+}
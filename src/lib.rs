@@ -0,0 +1,288 @@
+//! The recorder's capture, encoding and D-Bus service. The `auto-screen-recorder` binary drives
+//! them from `main.rs`, the benchmarks in `benches/` use them directly.
+
+pub mod application_config;
+pub mod batch;
+pub mod benchmark;
+pub mod checksum;
+pub mod clip_library;
+pub mod concat;
+pub mod dbus;
+pub mod encoder_report;
+pub mod encoders;
+pub mod encryption;
+pub mod fullscreen;
+pub mod hls;
+pub mod i18n;
+pub mod pipe_output;
+pub mod preview_server;
+pub mod pw_capture;
+pub mod stream_output;
+pub mod transcode;
+
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize},
+    time::{Duration, Instant},
+};
+
+use dbus::StatusUpdate;
+use encoders::buffer::MIN_SAVE_GOPS;
+use ffmpeg_next as ffmpeg;
+use log::info;
+use pw_capture::cursor::CursorOverlay;
+use tokio::sync::mpsc;
+
+/// A save was requested before the buffer held enough complete GOPs to cut a clip from
+#[derive(Debug)]
+pub struct BufferNotReady;
+
+impl std::fmt::Display for BufferNotReady {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&tr!(
+            "Buffer not yet ready, wait for at least one complete GOP"
+        ))
+    }
+}
+
+impl std::error::Error for BufferNotReady {}
+
+pub struct RawAudioFrame {
+    samples: Vec<f32>,
+    timestamp: i64,
+}
+
+impl RawAudioFrame {
+    pub fn new(samples: Vec<f32>, timestamp: i64) -> Self {
+        Self { samples, timestamp }
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn get_samples_mut(&mut self) -> &mut Vec<f32> {
+        &mut self.samples
+    }
+
+    pub fn get_samples(&mut self) -> &Vec<f32> {
+        &self.samples
+    }
+}
+
+pub struct RawVideoFrame {
+    /// The packet when the compositor sends already encoded frames, empty otherwise
+    bytes: Vec<u8>,
+
+    /// Raw pixels, copied straight out of the PipeWire buffer into a frame the encoder takes
+    frame: Option<ffmpeg::util::frame::video::Video>,
+    timestamp: i64,
+    format: ffmpeg::format::Pixel,
+
+    /// Set when `bytes` is an already encoded packet of this codec rather than raw pixels
+    encoded_codec: Option<ffmpeg::codec::Id>,
+
+    /// Cursor to draw over the frame when the compositor sends it as metadata
+    cursor: Option<CursorOverlay>,
+}
+
+impl RawVideoFrame {
+    pub fn get_bytes(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+}
+
+pub struct Terminate;
+
+/// Tracks whether both encoders have processed their first frame, after which a save produces
+/// a clip
+#[derive(Default)]
+pub struct Readiness {
+    video_processed: AtomicBool,
+    audio_processed: AtomicBool,
+    ready: AtomicBool,
+
+    /// GOPs in the video buffer, a save needs [`MIN_SAVE_GOPS`]
+    gop_count: AtomicUsize,
+
+    /// Milliseconds of video in the buffer, updated with `gop_count`
+    buffered_ms: AtomicU64,
+
+    /// Whether captured frames go into the buffer, i.e. capture is armed and not paused
+    capturing: AtomicBool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    pub fn gop_count(&self) -> usize {
+        self.gop_count.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Whether enough GOPs are buffered for a save, see [`MIN_SAVE_GOPS`]
+    pub fn buffer_ready(&self) -> bool {
+        self.gop_count() >= MIN_SAVE_GOPS
+    }
+
+    pub fn buffered_ms(&self) -> u64 {
+        self.buffered_ms.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capturing.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Record what the video buffer holds after a frame went into it
+    pub fn set_buffer_state(&self, gop_count: usize, buffered_ms: u64) {
+        self.gop_count
+            .store(gop_count, std::sync::atomic::Ordering::Relaxed);
+        self.buffered_ms
+            .store(buffered_ms, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn set_capturing(&self, capturing: bool) {
+        self.capturing
+            .store(capturing, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Record that a stream's frame was encoded, reporting readiness the first time both have
+    pub fn frame_processed(&self, video: bool, status_tx: &mpsc::UnboundedSender<StatusUpdate>) {
+        let processed = if video {
+            &self.video_processed
+        } else {
+            &self.audio_processed
+        };
+        processed.store(true, std::sync::atomic::Ordering::Release);
+
+        if self
+            .video_processed
+            .load(std::sync::atomic::Ordering::Acquire)
+            && self
+                .audio_processed
+                .load(std::sync::atomic::Ordering::Acquire)
+            && !self.ready.swap(true, std::sync::atomic::Ordering::AcqRel)
+        {
+            info!("Encoders are open and processing frames, ready to save clips");
+            let _ = status_tx.send(StatusUpdate::new(
+                "ready",
+                "encoders open and first frames processed",
+            ));
+        }
+    }
+}
+
+/// Frames lost before reaching the encoder, split by where they were dropped
+#[derive(Default)]
+pub struct CaptureStats {
+    /// Skipped by the compositor, found from gaps in the PipeWire buffer sequence numbers
+    pub frames_dropped_compositor: AtomicU64,
+
+    /// Dropped because the video ring buffer to the encoder was full
+    pub frames_dropped_encoder: AtomicU64,
+
+    /// Audio and video frames skipped because they failed to encode, see `on_encode_error`
+    pub frames_encode_failed: AtomicU64,
+
+    /// When each frame of the last `FPS_WINDOW` arrived from the compositor
+    frame_arrivals: std::sync::Mutex<VecDeque<Instant>>,
+}
+
+impl CaptureStats {
+    /// How far back delivered frames are counted for the capture frame rate
+    const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+    /// Record a frame the compositor delivered, whether or not it gets encoded
+    pub fn frame_delivered(&self) {
+        let now = Instant::now();
+        let mut arrivals = self.frame_arrivals.lock().unwrap();
+        arrivals.push_back(now);
+        while arrivals
+            .front()
+            .is_some_and(|arrival| now.duration_since(*arrival) > Self::FPS_WINDOW)
+        {
+            arrivals.pop_front();
+        }
+    }
+
+    /// Frames per second the compositor actually delivered over the last second, which drops
+    /// below the negotiated rate when it can't keep up, e.g. under heavy GPU load
+    pub fn capture_fps(&self) -> f64 {
+        let now = Instant::now();
+        let delivered = self
+            .frame_arrivals
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|arrival| now.duration_since(**arrival) <= Self::FPS_WINDOW)
+            .count();
+
+        delivered as f64 / Self::FPS_WINDOW.as_secs_f64()
+    }
+}
+
+/// Returns `filename`, with `_1`, `_2`, ... appended to its name if that name is taken, so saves
+/// within the same second don't overwrite each other. The file is created empty to claim the name
+/// right away since muxing happens in the background.
+pub fn claim_filename(filename: &str) -> std::io::Result<String> {
+    let path = Path::new(filename);
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    // Split at the first dot so the suffix goes before all extensions, e.g. `.mp4.enc`
+    let (stem, extension) = match name.split_once('.') {
+        Some((stem, extension)) => (stem, format!(".{}", extension)),
+        None => (name.as_ref(), String::new()),
+    };
+
+    let mut suffix = 0;
+    loop {
+        let candidate = if suffix == 0 {
+            filename.to_string()
+        } else {
+            path.with_file_name(format!("{}_{}{}", stem, suffix, extension))
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => suffix += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_filename_gives_saves_in_the_same_second_distinct_names() {
+        let dir = std::env::temp_dir().join(format!("screen-recorder-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("clip_1700000000.mp4");
+        let filename = filename.to_string_lossy();
+
+        let first = claim_filename(&filename).unwrap();
+        let second = claim_filename(&filename).unwrap();
+
+        assert_eq!(first, filename);
+        assert_eq!(second, dir.join("clip_1700000000_1.mp4").to_string_lossy());
+        assert!(Path::new(&first).exists());
+        assert!(Path::new(&second).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
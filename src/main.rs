@@ -1,97 +1,268 @@
-mod application_config;
-mod dbus;
-mod encoders;
-mod pw_capture;
+mod cli;
+mod self_test;
 
 use std::{
-    sync::{atomic::AtomicBool, Arc},
-    time::{Duration, SystemTime},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicI32},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{Context, Error, Result};
-use application_config::load_or_create_config;
+use anyhow::{anyhow, bail, Context, Error, Result};
+use application_config::{
+    load_or_create_config, AppConfig, EncodePreset, FirstFrameOffset, OnEncodeError,
+    OnVideoReadyTimeout, PortalPersist, Region, ShortAudio, SubtitleMode,
+};
+use auto_screen_recorder::{
+    application_config, batch, benchmark, checksum, claim_filename, clip_library, dbus,
+    encoder_report, encoders, encryption, fullscreen, hls, i18n, pipe_output, preview_server,
+    pw_capture, stream_output, transcode, BufferNotReady, CaptureStats, RawAudioFrame,
+    RawVideoFrame, Readiness, Terminate,
+};
+use bytes::Bytes;
+use clap::Parser;
+use cli::{Cli, Command};
+use clip_library::ClipLibrary;
+use dbus::{ClipOptions, ClipService, GameClip, SaveRequest, StatusUpdate};
 use encoders::{
+    audio_analyzer::{AudioAnalyzer, AudioSpectrum},
     audio_encoder::AudioEncoder,
-    buffer::{AudioBuffer, VideoBuffer},
-    video_encoder::VideoEncoder,
+    buffer::{self, AudioBuffer, VideoBuffer, MIN_SAVE_GOPS},
+    mjpeg_encoder::MjpegEncoder,
+    mjpeg_stream_encoder::MjpegStreamEncoder,
+    packet_dump, persistence,
+    subtitle_encoder::{SubtitleEncoder, SubtitleEntry, SubtitleTrack},
+    video_encoder::{BufferedFrame, VideoEncoder, ONE_MICROS, PACKET_BACKLOG},
 };
-use ffmpeg_next::{self as ffmpeg};
+use encryption::ClipKey;
+use ffmpeg_next::{self as ffmpeg, Rational, Rescale};
+use hls::HlsWriter;
+use i18n::tr;
 use log::{debug, error, info, trace, warn, LevelFilter};
+use pipe_output::PipeOutput;
 use pipewire::{self as pw};
-use portal_screencast::{CursorMode, ScreenCast, SourceType};
-use pw_capture::{audio_stream::AudioCapture, video_stream::VideoCapture};
+use portal_screencast::{
+    ActiveScreenCast, CursorMode, PersistMode, PortalError, ScreenCast, ScreenCastStream,
+    SourceType,
+};
+use pw_capture::{
+    audio_stream::{self, AudioCapture},
+    desktop_capture::{self, DesktopCapture},
+    mic_agc::MicAgc,
+    node_monitor::{NodeMonitor, NodeStateUpdate},
+    noise_gate::NoiseGate,
+    timestamp_synchronizer::TimestampSynchronizer,
+    video_stream::{self, VideoCapture},
+};
 use ringbuf::{
     traits::{Consumer, Producer, Split},
     HeapRb,
 };
-use tokio::sync::{mpsc, Mutex};
+use stream_output::StreamWriter;
+use tokio::sync::{broadcast, mpsc, oneshot, Mutex};
 use zbus::connection;
 
 const VIDEO_STREAM: usize = 0;
 const AUDIO_STREAM: usize = 1;
 
-pub struct RawAudioFrame {
-    samples: Vec<f32>,
-    timestamp: i64,
-}
+/// Upper bound on how long a save waits for a requested keyframe
+const KEYFRAME_WAIT_TIMEOUT: Duration = Duration::from_millis(500);
 
-impl RawAudioFrame {
-    pub fn get_samples_mut(&mut self) -> &mut Vec<f32> {
-        &mut self.samples
-    }
+/// Upper bound on how long `GetCurrentFrame` waits for the next captured frame
+const FRAME_WAIT_TIMEOUT: Duration = Duration::from_millis(500);
 
-    pub fn get_samples(&mut self) -> &Vec<f32> {
-        &self.samples
-    }
-}
+/// How often the `BufferedDuration` property is refreshed, and how far it has to move for a
+/// `PropertiesChanged` signal
+const BUFFERED_DURATION_INTERVAL: Duration = Duration::from_millis(500);
 
-pub struct RawVideoFrame {
-    bytes: Vec<u8>,
-    timestamp: i64,
-}
+/// How often the focused window is checked when only recording fullscreen windows
+const FULLSCREEN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-impl RawVideoFrame {
-    pub fn get_bytes(&self) -> &Vec<u8> {
-        &self.bytes
-    }
-}
+/// Encoded preview frames kept for slow clients before they start skipping
+const PREVIEW_STREAM_FRAMES: usize = 4;
+
+/// Audio frames queued for the spectrum analyzer before the oldest are skipped
+const ANALYZER_FRAMES: usize = 16;
+
+/// Audio is shifted to line up with video when their first packets are further apart than this
+const AV_SYNC_THRESHOLD_US: i64 = 10_000;
+
+/// Constant quality clips with burned in subtitles or the cursor are re-encoded at, what the
+/// MEDIUM preset uses
+const OVERLAY_CRF: u32 = 18;
 
-pub struct Terminate;
+/// Reconnects to the portal in a row after video capture failed, before giving up on it
+const MAX_PORTAL_RECONNECTS: u32 = 3;
+
+/// Longest clip title kept in the file name, the full title still goes in the metadata
+const MAX_TITLE_FILENAME_CHARS: usize = 64;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    let cli = Cli::parse();
     let _ = simple_logging::log_to_file("logs.txt", LevelFilter::Debug);
+    i18n::init();
 
-    let config = load_or_create_config();
+    // Shared with the encoders and every save, changes to the file apply after a restart
+    let config = Arc::new(load_or_create_config());
     debug!("CONFIG: {:?}", config);
 
-    let mut screen_cast = ScreenCast::new()?;
-    screen_cast.set_source_types(SourceType::MONITOR);
-    screen_cast.set_cursor_mode(CursorMode::EMBEDDED);
-    let screen_cast = screen_cast.start(None)?;
+    match cli.command {
+        Some(Command::SelfTest) => {
+            let passed = self_test::run(&config)?;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Some(Command::ListEncoders) => return encoder_report::run(),
+        Some(Command::Benchmark) => return benchmark::run(&config),
+        Some(Command::Reencode {
+            input_dir,
+            output_dir,
+            preset,
+        }) => return batch::run(&config, &input_dir, &output_dir, &preset),
+        Some(Command::Decrypt { input, output }) => {
+            let output = match output {
+                Some(output) => output,
+                None => encryption::decrypted_path(&input)?,
+            };
+            encryption::decrypt_file(&input, &output, &ClipKey::load(&config)?)?;
+            println!("Decrypted {} to {}", input.display(), output.display());
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if config.memory_check {
+        check_buffer_memory(&config);
+    }
+
+    let mut source = match PortalSession::start(&config).await {
+        Ok(Some(session)) => CaptureSource::Portal(session),
+        Ok(None) => {
+            info!("Screen selection was cancelled, exiting");
+            println!("Screen selection was cancelled, nothing to record");
+            return Ok(());
+        }
+        Err(e) if desktop_capture::x11_available() => {
+            warn!(
+                "Screen cast portal unavailable, capturing the X11 root window instead: {:?}",
+                e
+            );
+            CaptureSource::X11
+        }
+        Err(e) => return Err(e),
+    };
+
+    let (stream_node, (width, height), cursor_metadata) = match &source {
+        CaptureSource::Portal(session) => (
+            session.stream().pipewire_node(),
+            session.stream().size(),
+            session.cursor_metadata,
+        ),
+        // No portal node to follow, audio and the node monitor fall back to the defaults
+        CaptureSource::X11 => (
+            pw::sys::PW_ID_ANY,
+            DesktopCapture::x11_screen_size()?,
+            false,
+        ),
+    };
 
-    let fd = screen_cast.pipewire_fd();
-    let stream = screen_cast.streams().next().unwrap();
-    let stream_node = stream.pipewire_node();
-    let (width, height) = stream.size();
+    let region = capture_region(cli.region, &config, width, height)?;
+    let (video_width, video_height) = region
+        .map(|region| (region.width, region.height))
+        .unwrap_or((width, height));
 
     let (save_tx, mut save_rx) = mpsc::channel(1);
-    let clip_service = dbus::ClipService::new(save_tx);
+    let (keyframe_save_tx, mut keyframe_save_rx) = mpsc::channel::<ClipOptions>(1);
+    let (status_tx, mut status_rx) = mpsc::unbounded_channel::<StatusUpdate>();
+    let (encode_error_tx, mut encode_error_rx) = mpsc::unbounded_channel::<Error>();
+    let (capture_failure_tx, mut capture_failure_rx) = mpsc::unbounded_channel::<CaptureFailure>();
+    // Clips are written to the working directory
+    let clip_library = Arc::new(ClipLibrary::new(std::env::current_dir()?));
+    let readiness = Arc::new(Readiness::default());
+    let capture_stats = Arc::new(CaptureStats::default());
+    let audio_spectrum = Arc::new(AudioSpectrum::default());
+    let mic_gain = Arc::new(AtomicI32::new(0));
+    let clip_service = ClipService::new(
+        save_tx,
+        Arc::clone(&clip_library),
+        Arc::clone(&readiness),
+        Arc::clone(&capture_stats),
+        Arc::clone(&audio_spectrum),
+        Arc::clone(&mic_gain),
+        Duration::from_millis(config.save_debounce_ms),
+        Duration::from_secs(config.save_sync_timeout_secs),
+        config.segment_dir.clone(),
+    );
 
     debug!("Creating dbus connection");
-    let _connection = connection::Builder::session()?
+    let connection = connection::Builder::session()?
         .name("com.rust.GameClip")?
         .serve_at("/com/rust/GameClip", clip_service)?
         .build()
         .await?;
+    let clip_service_ref = connection
+        .object_server()
+        .interface::<_, ClipService>("/com/rust/GameClip")
+        .await?;
 
-    // Video
-    let video_encoder = Arc::new(Mutex::new(VideoEncoder::new(
-        width,
-        height,
+    let mut video_encoder = VideoEncoder::new(
+        video_width,
+        video_height,
         config.max_seconds,
         &config.encoder,
-    )?));
+        Arc::clone(&config),
+    )?;
+    {
+        let clip_service = clip_service_ref.get().await;
+        clip_service.set_active_video_encoder(video_encoder.encoder_name());
+        if let Err(e) = clip_service
+            .active_video_encoder_changed(clip_service_ref.signal_emitter())
+            .await
+        {
+            warn!("Could not signal the active video encoder: {:?}", e);
+        }
+    }
+    if let Some(region) = region {
+        video_encoder.set_crop(region, width, height);
+    }
+    video_encoder.set_cursor_on_save(config.cursor_on_save && cursor_metadata);
+    if config.scene_detection {
+        video_encoder.set_scene_detection(config.scene_cut_threshold);
+    }
+    if let Some(limit) = config.buffer_limit {
+        video_encoder.set_buffer_limit(limit);
+    }
+    let mut audio_encoder = AudioEncoder::new(Arc::clone(&config))?;
+
+    if config.persistent_buffer {
+        match persistence::restore_buffers(
+            &config.persist_buffer_path,
+            video_width,
+            video_height,
+            config.time_base_den,
+            video_encoder.get_buffer_mut(),
+            audio_encoder.get_buffer_mut(),
+        ) {
+            Ok(true) => info!(
+                "Restored replay buffer from {:?}",
+                config.persist_buffer_path
+            ),
+            Ok(false) => {}
+            Err(e) => {
+                warn!(
+                    "Could not restore persisted replay buffer, starting empty: {:?}",
+                    e
+                );
+                video_encoder.get_buffer_mut().reset();
+                audio_encoder.get_buffer_mut().reset();
+            }
+        }
+    }
+
+    // Video
+    let video_encoder = Arc::new(Mutex::new(video_encoder));
     let video_encoder_clone = Arc::clone(&video_encoder);
     let video_ready = Arc::new(AtomicBool::new(false));
     let vr_clone = Arc::clone(&video_ready);
@@ -100,7 +271,7 @@ async fn main() -> Result<(), Error> {
     let (mut video_ring_sender, mut video_ring_receiver) = video_ring_buffer.split();
 
     // Audio
-    let audio_encoder = Arc::new(Mutex::new(AudioEncoder::new(config.max_seconds)?));
+    let audio_encoder = Arc::new(Mutex::new(audio_encoder));
     let audio_encoder_clone = Arc::clone(&audio_encoder);
     let audio_ready = Arc::new(AtomicBool::new(false));
     let (audio_sender, mut audio_receiver) = mpsc::channel::<RawAudioFrame>(10);
@@ -108,158 +279,862 @@ async fn main() -> Result<(), Error> {
     let (mut audio_ring_sender, mut audio_ring_receiver) = audio_ring_buffer.split();
     let ar_clone = Arc::clone(&audio_ready);
 
+    // Captured samples are also copied to the spectrum analyzer, which works on its own
+    let analyzer_sender = config.audio_spectrum.then(|| {
+        let (analyzer_sender, analyzer_receiver) = broadcast::channel(ANALYZER_FRAMES);
+        tokio::spawn(AudioAnalyzer::new(Arc::clone(&audio_spectrum)).run(analyzer_receiver));
+        analyzer_sender
+    });
+
     pw::init();
     ffmpeg::log::set_level(ffmpeg_next::log::Level::Info);
     ffmpeg::init()?;
 
-    let current_time = SystemTime::now();
+    // Audio and video frames are both timestamped from here
+    let timestamps = Arc::new(TimestampSynchronizer::new());
 
     // Create audio worker thread
     let stop = Arc::new(AtomicBool::new(false));
     let stop_audio_clone = Arc::clone(&stop);
-    let audio_worker = std::thread::spawn(move || loop {
-        if stop_audio_clone.load(std::sync::atomic::Ordering::Acquire) {
-            break;
-        }
+    let audio_status_tx = status_tx.clone();
+    let audio_readiness = Arc::clone(&readiness);
+    let audio_capture_stats = Arc::clone(&capture_stats);
+    let audio_encode_error_tx = encode_error_tx.clone();
+    let on_encode_error = config.on_encode_error;
+    let audio_worker = std::thread::spawn(move || {
+        let mut failing = false;
+        loop {
+            if stop_audio_clone.load(std::sync::atomic::Ordering::Acquire) {
+                break;
+            }
 
-        while let Some(mut raw_frame) = audio_ring_receiver.try_pop() {
-            let now = SystemTime::now();
-            if let Err(e) = audio_encoder_clone.blocking_lock().process(&mut raw_frame) {
-                error!(
-                    "Error processing audio frame at {:?}: {:?}",
-                    raw_frame.timestamp, e
+            while let Some(mut raw_frame) = audio_ring_receiver.try_pop() {
+                let now = SystemTime::now();
+                let result = audio_encoder_clone.blocking_lock().process(&mut raw_frame);
+                if let Err(e) = &result {
+                    if !handle_encode_error(
+                        on_encode_error,
+                        "audio",
+                        raw_frame.get_timestamp(),
+                        *e,
+                        &audio_capture_stats,
+                        &audio_encode_error_tx,
+                    ) {
+                        return;
+                    }
+                }
+                report_encoder_status(&audio_status_tx, "audio", &result, &mut failing);
+                if result.is_ok() && !audio_readiness.is_ready() {
+                    audio_readiness.frame_processed(false, &audio_status_tx);
+                }
+                trace!(
+                    "Took {:?} to process this audio frame at {:?}",
+                    now.elapsed(),
+                    raw_frame.get_timestamp()
                 );
             }
-            trace!(
-                "Took {:?} to process this audio frame at {:?}",
-                now.elapsed(),
-                raw_frame.timestamp
-            );
+            std::thread::sleep(Duration::from_nanos(100));
         }
-        std::thread::sleep(Duration::from_nanos(100));
     });
 
     // Create video worker
     let stop_video_clone = Arc::clone(&stop);
-    let video_worder = std::thread::spawn(move || loop {
-        if stop_video_clone.load(std::sync::atomic::Ordering::Acquire) {
-            break;
-        }
+    let video_status_tx = status_tx.clone();
+    let audio_trim_clone = Arc::clone(&audio_encoder);
+    let video_readiness = Arc::clone(&readiness);
+    let video_capture_stats = Arc::clone(&capture_stats);
+    let video_worder = std::thread::spawn(move || {
+        let mut failing = false;
+        let mut video_oldest_pts = None;
+        loop {
+            if stop_video_clone.load(std::sync::atomic::Ordering::Acquire) {
+                break;
+            }
 
-        while let Some(raw_frame) = video_ring_receiver.try_pop() {
-            let now = SystemTime::now();
-            if let Err(e) = video_encoder_clone.blocking_lock().process(&raw_frame) {
-                error!(
-                    "Error processing video frame at {:?}: {:?}",
-                    raw_frame.timestamp, e
+            while let Some(raw_frame) = video_ring_receiver.try_pop() {
+                let now = SystemTime::now();
+                let timestamp = raw_frame.get_timestamp();
+                let result = video_encoder_clone.blocking_lock().process(raw_frame);
+                if let Err(e) = &result {
+                    if !handle_encode_error(
+                        on_encode_error,
+                        "video",
+                        timestamp,
+                        *e,
+                        &video_capture_stats,
+                        &encode_error_tx,
+                    ) {
+                        return;
+                    }
+                }
+                report_encoder_status(&video_status_tx, "video", &result, &mut failing);
+                if result.is_ok() && !video_readiness.is_ready() {
+                    video_readiness.frame_processed(true, &video_status_tx);
+                }
+
+                // Keep audio aligned with whatever the video buffer trimmed. The video lock is
+                // released before taking the audio one so this can't deadlock with a save.
+                let (oldest_pts, gop_count, duration_us) = {
+                    let video_lock = video_encoder_clone.blocking_lock();
+                    let video_buffer = video_lock.get_buffer();
+                    (
+                        video_buffer.oldest_pts(),
+                        video_buffer.get_gop_count(),
+                        video_buffer.duration_us(),
+                    )
+                };
+                video_readiness.set_buffer_state(gop_count, duration_us.max(0) as u64 / 1000);
+                if let Some(oldest_pts) = oldest_pts.filter(|_| oldest_pts != video_oldest_pts) {
+                    video_oldest_pts = Some(oldest_pts);
+                    let trimmed = audio_trim_clone
+                        .blocking_lock()
+                        .get_buffer_mut()
+                        .trim_before(oldest_pts);
+                    if trimmed > 0 {
+                        trace!("Trimmed {} audio frames before {}", trimmed, oldest_pts);
+                    }
+                }
+
+                trace!(
+                    "Took {:?} to process this video frame at {:?}",
+                    now.elapsed(),
+                    timestamp
                 );
             }
-
-            trace!(
-                "Took {:?} to process this video frame at {:?}",
-                now.elapsed(),
-                raw_frame.timestamp
-            );
+            std::thread::sleep(Duration::from_nanos(100));
         }
-        std::thread::sleep(Duration::from_nanos(100));
     });
 
     let saving = Arc::new(AtomicBool::new(false));
 
-    let (pw_video_sender, pw_video_recv) = pw::channel::channel::<Terminate>();
-    let saving_video_clone = Arc::clone(&saving);
-    let pw_video_worker = std::thread::spawn(move || {
-        debug!("Starting video stream");
-        let _video = VideoCapture::run(
-            fd,
-            stream_node,
-            video_sender,
-            video_ready,
-            audio_ready,
-            current_time,
-            pw_video_recv,
-            saving_video_clone,
+    if config.hls_output.is_some() || config.stream_output.is_some() {
+        let (packet_tx, _) = broadcast::channel(PACKET_BACKLOG);
+        if let Some(hls_config) = config.hls_output.clone() {
+            HlsWriter::new(hls_config, Arc::clone(&video_encoder))
+                .spawn(packet_tx.subscribe(), Arc::clone(&stop));
+        }
+        if let Some(target) = config.stream_output.clone() {
+            StreamWriter::new(target, Arc::clone(&video_encoder))
+                .spawn(packet_tx.subscribe(), Arc::clone(&stop));
+        }
+        video_encoder.lock().await.set_packet_broadcast(packet_tx);
+    }
+
+    if let Some(address) = config.preview_stream_address.clone() {
+        let (frames_tx, _) = broadcast::channel(PREVIEW_STREAM_FRAMES);
+        spawn_preview_stream(
+            Arc::clone(&video_encoder),
+            Arc::clone(&stop),
+            config.preview_stream_quality,
+            frames_tx.clone(),
+        );
+        let audio_spectrum = Arc::clone(&audio_spectrum);
+        tokio::spawn(async move {
+            if let Err(e) = preview_server::serve(address, frames_tx, audio_spectrum).await {
+                error!("Preview stream server stopped: {:?}", e);
+            }
+        });
+    }
+
+    // Only offer NV12 to the compositor when the encoder can take it without scaling and the
+    // configured chroma format doesn't keep more colour than NV12 has
+    let allow_nv12 =
+        VideoEncoder::supports_pixel_format(&config.encoder, ffmpeg::format::Pixel::NV12)
+            && config
+                .chroma_format
+                .is_none_or(|chroma_format| chroma_format.is_subsampled());
+
+    let audio_node_latency = config.audio_node_latency.clone();
+    let audio_sources = config.audio_sources.clone();
+    let audio_capture_status_tx = status_tx.clone();
+    let noise_gate = config.mic_noise_gate_db.map(|threshold_db| {
+        NoiseGate::new(
+            threshold_db,
+            config.mic_noise_gate_attack_ms,
+            config.mic_noise_gate_release_ms,
         )
-        .unwrap();
     });
+    let mic_agc = config
+        .mic_agc
+        .then(|| MicAgc::new(config.mic_target_db, Arc::clone(&mic_gain)));
+
+    let mut video_capture = VideoCaptureParams {
+        stream_node,
+        video_sender,
+        video_ready,
+        audio_ready,
+        timestamps: Arc::clone(&timestamps),
+        saving: Arc::clone(&saving),
+        allow_nv12,
+        node_latency: config.video_node_latency.clone(),
+        buffers: config.pw_video_buffers,
+        cursor_metadata,
+        capture_stats: Arc::clone(&capture_stats),
+        status_tx: status_tx.clone(),
+        failure_tx: capture_failure_tx.clone(),
+    };
+    let (mut pw_video_worker, mut pw_video_sender) =
+        spawn_video_capture(&source, video_capture.clone());
 
     let (pw_audio_sender, pw_audio_recv) = pw::channel::channel::<Terminate>();
     let saving_audio_clone = Arc::clone(&saving);
-    let pw_audio_worker = std::thread::spawn(move || {
+    let audio_timestamps = Arc::clone(&timestamps);
+    let pw_audio_worker = spawn_capture("audio", capture_failure_tx.clone(), move || {
         debug!("Starting audio stream");
-        let _audio = AudioCapture::run(
+        AudioCapture::run(
             stream_node,
             audio_sender,
             vr_clone,
             ar_clone,
             config.use_mic,
-            current_time,
+            audio_sources,
+            audio_timestamps,
             pw_audio_recv,
             saving_audio_clone,
+            audio_node_latency,
+            audio_capture_status_tx,
+            noise_gate,
+            mic_agc,
+            config.audio_prebuffer,
+            config.pw_sample_format,
         )
-        .unwrap();
     });
 
+    let (node_state_tx, mut node_state_rx) = mpsc::unbounded_channel::<NodeStateUpdate>();
+    let (pw_monitor_sender, pw_monitor_recv) = pw::channel::channel::<Terminate>();
+    let pw_monitor_worker = std::thread::spawn(move || {
+        debug!("Starting node monitor");
+        if let Err(e) = NodeMonitor::run(
+            stream_node,
+            vec![video_stream::STREAM_NAME, audio_stream::STREAM_NAME],
+            pw_monitor_recv,
+            node_state_tx,
+        ) {
+            error!("Node monitor stopped: {:?}", e);
+        }
+    });
+
+    let video_timeout = Duration::from_secs(config.video_timeout_secs);
+    let mut health_check = tokio::time::interval(Duration::from_secs(1));
+    let mut last_video_frame = Instant::now();
+    let mut video_restarts = 0;
+    let mut portal_reconnects = 0;
+
+    // Going through the portal can wait on the user, it runs off the main loop, see
+    // `spawn_portal_reconnect`
+    let (portal_tx, mut portal_rx) = mpsc::unbounded_channel::<PortalReconnect>();
+    let mut portal_reconnecting = false;
+
+    let mut fullscreen_check = tokio::time::interval(FULLSCREEN_CHECK_INTERVAL);
+    let mut paused = false;
+
+    // Set with the `Pause` and `Resume` D-Bus methods, independent of `fullscreen_only`
+    let mut paused_by_request = false;
+
+    // Nothing is buffered until armed, which happens after the delay or on request
+    let mut armed = config.arm_delay_seconds == 0;
+    let arm_timer = tokio::time::sleep(Duration::from_secs(config.arm_delay_seconds));
+    tokio::pin!(arm_timer);
+    if !armed {
+        info!("Capture arms in {}s", config.arm_delay_seconds);
+        let _ = status_tx.send(StatusUpdate::new("paused", "waiting to be armed"));
+    }
+    let mut fullscreen_query_failed = false;
+
+    let mut buffer_heartbeat =
+        tokio::time::interval(Duration::from_secs(config.buffer_heartbeat_secs.max(1)));
+
+    // Rewritten from a copy of the buffers while capturing, see `persist_interval_secs`
+    let persist_periodically = config.persistent_buffer && config.persist_interval_secs > 0;
+    let persist_period = Duration::from_secs(config.persist_interval_secs.max(1));
+    let mut persist_timer =
+        tokio::time::interval_at(tokio::time::Instant::now() + persist_period, persist_period);
+    let mut persist_task: Option<tokio::task::JoinHandle<()>> = None;
+
+    let mut subtitle_track = SubtitleTrack::new(config.max_seconds);
+
+    let mut buffered_duration = tokio::time::interval(BUFFERED_DURATION_INTERVAL);
+    let mut emitted_buffered_ms = 0;
+    let mut emitted_buffer_ready = false;
+
+    // Audio waits for video to be ready, don't let it wait forever if video capture is broken
+    let video_ready_timeout = Duration::from_secs(config.video_ready_timeout_secs);
+    let video_ready_timer = tokio::time::sleep(video_ready_timeout);
+    tokio::pin!(video_ready_timer);
+    let mut video_ready_checked = video_ready_timeout.is_zero();
+
+    // Pinged from this loop, so systemd restarts the recorder if it stops running with
+    // `WatchdogSec` set
+    let mut watchdog_usec = 0;
+    let watchdog_enabled = sd_notify::watchdog_enabled(false, &mut watchdog_usec);
+    let mut watchdog = tokio::time::interval(Duration::from_micros((watchdog_usec / 2).max(1)));
+    if watchdog_enabled {
+        info!("Pinging the systemd watchdog every {}us", watchdog_usec / 2);
+    }
+
+    // Set when `on_encode_error = "abort"`, `on_video_ready_timeout = "shutdown"` or
+    // `exit_on_capture_failure` stops the recorder, exiting with it once shut down
+    let mut exit_error = None;
+
     // Main event loop
     loop {
+        readiness.set_capturing(armed && !paused && !paused_by_request);
+
         tokio::select! {
-            _ = save_rx.recv() => {
-                // Stop capturing video and audio while we save by taking out the locks
-                saving.store(true, std::sync::atomic::Ordering::Release);
-                let (mut video_lock, mut audio_lock) = tokio::join!(
-                    video_encoder.lock(),
-                    audio_encoder.lock()
-                );
+            Some(request) = save_rx.recv() => match request {
+                SaveRequest::Clip(options) => {
+                    if config.keyframe_on_save {
+                        // Wait for a fresh keyframe before saving so the clip reaches up to now
+                        let keyframe_rx = video_encoder.lock().await.request_keyframe();
+                        let keyframe_save_tx = keyframe_save_tx.clone();
+                        tokio::spawn(async move {
+                            if tokio::time::timeout(KEYFRAME_WAIT_TIMEOUT, keyframe_rx).await.is_err() {
+                                warn!("Timed out waiting for the requested keyframe, saving anyway");
+                            }
+                            let _ = keyframe_save_tx.send(options).await;
+                        });
+                    } else if let Err(e) = save_clip(
+                        &video_encoder,
+                        &audio_encoder,
+                        &saving,
+                        &status_tx,
+                        &clip_library,
+                        &config,
+                        options,
+                        subtitle_track.entries(),
+                    )
+                    .await
+                    {
+                        error!("Could not save clip: {:?}", e);
+                        clip_library.record_save("", Err(e.to_string()));
+                        let _ = status_tx
+                            .send(StatusUpdate::new("error", tr!("save failed: {}", e)));
+                    }
+                }
+                SaveRequest::AudioOnly(reply) => {
+                    if let Err(e) = save_audio_only(&audio_encoder, reply).await {
+                        error!("Could not snapshot audio buffer: {:?}", e);
+                    }
+                }
+                SaveRequest::CurrentFrame(quality, reply) => {
+                    send_current_frame(&video_encoder, quality, reply).await;
+                }
+                SaveRequest::BufferedFrame(ms_ago, quality, reply) => {
+                    send_buffered_frame(&video_encoder, ms_ago, quality, reply).await;
+                }
+                SaveRequest::EstimateSize(seconds, reply) => {
+                    let estimate = estimate_clip_size(&video_encoder, &audio_encoder, seconds).await;
+                    let _ = reply.send(estimate);
+                }
+                SaveRequest::Preset(preset, reply) => {
+                    if let Err(e) = save_clip_with_preset(
+                        &video_encoder,
+                        &audio_encoder,
+                        &saving,
+                        &status_tx,
+                        &clip_library,
+                        &config,
+                        preset,
+                        reply,
+                    )
+                    .await
+                    {
+                        error!("Could not save clip with preset: {:?}", e);
+                        clip_library.record_save("", Err(e.to_string()));
+                    }
+                }
+                SaveRequest::SaveTo(command, reply) => {
+                    if let Err(e) = save_to_command(
+                        &video_encoder,
+                        &audio_encoder,
+                        &saving,
+                        &config,
+                        command,
+                        reply,
+                    )
+                    .await
+                    {
+                        error!("Could not save clip to command: {:?}", e);
+                    }
+                }
+                SaveRequest::BufferStats(reply) => {
+                    let video_lock = video_encoder.lock().await;
+                    let video_buffer = video_lock.get_buffer();
+                    let (i_frames, p_frames, b_frames) = video_buffer.frame_count_by_type();
+                    let buffered_us = video_buffer.duration_us();
+                    let _ = reply.send((
+                        i_frames as u64,
+                        p_frames as u64,
+                        b_frames as u64,
+                        buffered_us as u64 / 1000,
+                    ));
+                }
+                SaveRequest::Quality(reply) => {
+                    let video_lock = video_encoder.lock().await;
+                    let parameters = video_lock.get_parameters();
+                    let video_buffer = quality_samples(video_lock.get_buffer());
+                    drop(video_lock);
+
+                    tokio::task::spawn_blocking(move || {
+                        let quality = parameters
+                            .context("Could not get video parameters")
+                            .and_then(|parameters| estimate_quality(&video_buffer, &parameters));
+                        let _ = reply.send(quality.map_err(|e| e.to_string()));
+                    });
+                }
+                SaveRequest::Subtitle(entry) => {
+                    debug!("Adding subtitle {:?}", entry);
+                    subtitle_track.add(entry);
+                }
+                SaveRequest::Arm => {
+                    if !armed {
+                        armed = true;
+                        arm_capture(paused, paused_by_request, &status_tx);
+                    }
+                }
+                SaveRequest::Pause => {
+                    if !paused_by_request {
+                        paused_by_request = true;
+                        info!("Capture paused on request");
+                        if armed {
+                            let _ = status_tx.send(StatusUpdate::new("paused", "on request"));
+                        }
+                    }
+                }
+                SaveRequest::Resume => {
+                    if paused_by_request {
+                        paused_by_request = false;
+                        info!("Capture resumed on request");
+                        if armed {
+                            let status = if paused {
+                                StatusUpdate::new("paused", "resumed, no fullscreen window focused")
+                            } else {
+                                StatusUpdate::new("recording", "resumed")
+                            };
+                            let _ = status_tx.send(status);
+                        }
+                    }
+                }
+                SaveRequest::SetBitrate(kbps, reply) => {
+                    let result = video_encoder.lock().await.set_bitrate_adaptive(kbps);
+                    if let Err(e) = &result {
+                        error!("Could not change the bitrate to {}k: {:?}", kbps, e);
+                    }
+                    let _ = reply.send(result.map_err(|e| e.to_string()));
+                }
+                SaveRequest::ConcatList(manifest, reply) => {
+                    let video_lock = video_encoder.lock().await;
+                    let parameters = video_lock.get_parameters();
+                    // Only the complete GOPs get exported, and the key frame ending the last one
+                    let video_buffer = match video_lock.get_buffer().get_last_gop_start() {
+                        Some(&end) => video_lock.get_buffer().copy_range(..=end),
+                        None => VideoBuffer::new(video_lock.get_buffer().get_limit()),
+                    };
+                    drop(video_lock);
 
-                // Drain both encoders of any remaining frames being processed
-                video_lock.drain()?;
-                audio_lock.drain()?;
+                    let segment_dir = config.segment_dir.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let result = parameters
+                            .context("Could not get video parameters")
+                            .and_then(|parameters| {
+                                video_buffer.export_as_concat_list(
+                                    &manifest,
+                                    &segment_dir,
+                                    &parameters,
+                                )
+                            });
+                        if let Err(e) = &result {
+                            error!("Could not export concat list: {:?}", e);
+                        }
+                        let _ = reply.send(result.map_err(|e| e.to_string()));
+                    });
+                }
+                SaveRequest::ReconnectPortal(reply) => {
+                    let CaptureSource::Portal(session) = &source else {
+                        let reason = "Not capturing through the screen cast portal";
+                        let _ = reply.send(Err(reason.into()));
+                        continue;
+                    };
+                    if portal_reconnecting {
+                        let reason = "Already reconnecting to the screen cast portal";
+                        let _ = reply.send(Err(reason.into()));
+                        continue;
+                    }
 
-                let filename = format!("clip_{}.mp4", chrono::Local::now().timestamp());
-                let video_buffer = video_lock.get_buffer();
-                let video_encoder = video_lock
-                    .get_encoder()
-                    .as_ref()
-                    .context("Could not get video encoder")?;
+                    // Capture carries on with the old session until the new one is there
+                    portal_reconnecting = true;
+                    spawn_portal_reconnect(
+                        session.restore_token.clone(),
+                        (width, height),
+                        Arc::clone(&config),
+                        portal_tx.clone(),
+                        Some(reply),
+                        None,
+                    );
+                }
+            },
+            _ = &mut arm_timer, if !armed => {
+                armed = true;
+                arm_capture(paused, paused_by_request, &status_tx);
+            },
+            Some(options) = keyframe_save_rx.recv() => {
+                if let Err(e) = save_clip(
+                    &video_encoder,
+                    &audio_encoder,
+                    &saving,
+                    &status_tx,
+                    &clip_library,
+                    &config,
+                    options,
+                    subtitle_track.entries(),
+                )
+                .await
+                {
+                    error!("Could not save clip: {:?}", e);
+                    clip_library.record_save("", Err(e.to_string()));
+                    let _ = status_tx
+                        .send(StatusUpdate::new("error", tr!("save failed: {}", e)));
+                }
+            },
+            Some(update) = status_rx.recv() => {
+                debug!("Status changed: {} ({})", update.state, update.detail);
+                if let Err(e) = ClipService::status_changed(
+                    clip_service_ref.signal_emitter(),
+                    &update.state,
+                    &update.detail,
+                )
+                .await
+                {
+                    error!("Could not emit status change: {:?}", e);
+                }
+            },
+            Some(update) = node_state_rx.recv() => {
+                if let Err(e) = ClipService::node_state_changed(
+                    clip_service_ref.signal_emitter(),
+                    &update.node,
+                    &update.state,
+                )
+                .await
+                {
+                    error!("Could not emit node state change: {:?}", e);
+                }
+            },
+            _ = fullscreen_check.tick(), if config.fullscreen_only => {
+                let Some(fullscreen) = fullscreen::focused_window_is_fullscreen().await else {
+                    if !fullscreen_query_failed {
+                        warn!("Could not query the focused window, fullscreen_only needs Hyprland or Sway");
+                        fullscreen_query_failed = true;
+                    }
+                    continue;
+                };
 
-                let audio_buffer = audio_lock.get_buffer();
-                let audio_encoder = audio_lock
-                    .get_encoder()
-                    .as_ref()
-                    .context("Could not get audio encoder")?;
+                // The buffer is left as is while paused so a save right after resuming still
+                // includes what was recorded before
+                if paused == fullscreen {
+                    paused = !fullscreen;
+                    let (state, detail) = if paused {
+                        ("paused", "no fullscreen window focused")
+                    } else {
+                        ("recording", "fullscreen window focused")
+                    };
+                    info!("Capture {}: {}", state, detail);
+                    if armed && !paused_by_request {
+                        let _ = status_tx.send(StatusUpdate::new(state, detail));
+                    }
+                }
+            },
+            _ = buffered_duration.tick() => {
+                // Published by the video thread, so this never waits on the encoder
+                let buffered_ms = readiness.buffered_ms();
+                let buffer_ready = readiness.buffer_ready();
+                if buffer_ready != emitted_buffer_ready {
+                    emitted_buffer_ready = buffer_ready;
+                    if let Err(e) = clip_service_ref
+                        .get()
+                        .await
+                        .buffer_ready_changed(clip_service_ref.signal_emitter())
+                        .await
+                    {
+                        error!("Could not emit buffer ready change: {:?}", e);
+                    }
+                }
 
-                save_buffer(&filename, video_buffer, video_encoder, audio_buffer, audio_encoder)?;
+                if buffered_ms.abs_diff(emitted_buffered_ms)
+                    > BUFFERED_DURATION_INTERVAL.as_millis() as u64
+                {
+                    emitted_buffered_ms = buffered_ms;
+                    if let Err(e) = clip_service_ref
+                        .get()
+                        .await
+                        .buffered_duration_changed(clip_service_ref.signal_emitter())
+                        .await
+                    {
+                        error!("Could not emit buffered duration change: {:?}", e);
+                    }
+                }
+            },
+            _ = watchdog.tick(), if watchdog_enabled => {
+                if let Err(e) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+                    warn!("Could not ping the systemd watchdog: {:?}", e);
+                }
+            },
+            _ = buffer_heartbeat.tick(), if config.buffer_heartbeat_secs > 0 => {
+                log_buffer_heartbeat(&video_encoder, &audio_encoder, &capture_stats).await;
+            },
+            _ = persist_timer.tick(), if persist_periodically => {
+                if persist_task.as_ref().is_some_and(|task| !task.is_finished()) {
+                    debug!("Still persisting the replay buffer, skipping this write");
+                } else {
+                    persist_task = Some(
+                        spawn_persist(
+                            &video_encoder,
+                            &audio_encoder,
+                            config.persist_buffer_path.clone(),
+                            video_width,
+                            video_height,
+                            config.time_base_den,
+                        )
+                        .await,
+                    );
+                }
+            },
+            _ = health_check.tick(), if !video_timeout.is_zero() => {
+                // Frames are held back while saving and until audio streams, and stop while the
+                // portal is gone, none of them is a stall
+                if saving.load(std::sync::atomic::Ordering::Acquire)
+                    || !video_capture.audio_ready.load(std::sync::atomic::Ordering::Acquire)
+                    || portal_reconnecting
+                {
+                    last_video_frame = Instant::now();
+                    continue;
+                }
+                if last_video_frame.elapsed() < video_timeout {
+                    continue;
+                }
 
-                video_lock.reset_encoder()?;
-                audio_lock.reset_encoder()?;
+                video_restarts += 1;
+                error!(
+                    "VideoStreamTimeout: no video frames for {:?}, restarting capture (attempt {})",
+                    video_timeout, video_restarts
+                );
+                let _ = status_tx.send(StatusUpdate::new(
+                    "error",
+                    format!("video stream timed out, restarting (attempt {})", video_restarts),
+                ));
 
-                drop(video_lock);
-                drop(audio_lock);
-                saving.store(false, std::sync::atomic::Ordering::Release);
+                // The old loop exits on its own once terminated, join it off the main loop
+                let _ = pw_video_sender.send(Terminate);
+                let old_worker = pw_video_worker;
+                tokio::task::spawn_blocking(move || old_worker.join());
 
-                debug!("Done saving!");
+                (pw_video_worker, pw_video_sender) =
+                    spawn_video_capture(&source, video_capture.clone());
+                last_video_frame = Instant::now();
             },
             Some(raw_frame) = video_receiver.recv() => {
+                last_video_frame = Instant::now();
+                if video_restarts > 0 {
+                    info!("Video capture recovered after {} restart(s)", video_restarts);
+                    let _ = status_tx.send(StatusUpdate::new("recording", "video stream recovered"));
+                    if let Err(e) = ClipService::capture_restarted(
+                        clip_service_ref.signal_emitter(),
+                        video_restarts,
+                    )
+                    .await
+                    {
+                        error!("Could not emit capture restarted: {:?}", e);
+                    }
+                    video_restarts = 0;
+                }
+                portal_reconnects = 0;
+
+                if paused || paused_by_request || !armed {
+                    continue;
+                }
+
                 // Send the data to the worker thread and exit as to not block this one
                 if let Err(_) = video_ring_sender.try_push(raw_frame) {
+                    capture_stats
+                        .frames_dropped_encoder
+                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                     warn!("Trying to push but the video ring buff is full. Consider increasing the max");
                 }
             },
-            Some(raw_frame) = audio_receiver.recv() => {
+            Some(mut raw_frame) = audio_receiver.recv() => {
+                // The spectrum shows what is playing even while the buffer is paused
+                if let Some(analyzer_sender) = &analyzer_sender {
+                    let _ = analyzer_sender.send(Arc::from(raw_frame.get_samples().as_slice()));
+                }
+
+                if paused || paused_by_request || !armed {
+                    continue;
+                }
+
                 // Send the data to the worker thread and exit as to not block this one
                 if let Err(_) = audio_ring_sender.try_push(raw_frame) {
                     warn!("Trying to push but the audio ring buff is full. Consider increasing the max");
                 }
             },
+            Some(e) = encode_error_rx.recv() => {
+                error!("Stopping as a frame failed to encode: {:?}", e);
+                stop.store(true, std::sync::atomic::Ordering::Release);
+                let _ = pw_video_sender.send(Terminate);
+                let _ = pw_audio_sender.send(Terminate);
+                let _ = pw_monitor_sender.send(Terminate);
+                exit_error = Some(e);
+                break;
+            },
+            Some(failure) = capture_failure_rx.recv() => {
+                let _ = status_tx.send(StatusUpdate::new(
+                    "error",
+                    format!("{} capture stopped: {}", failure.thread, failure.reason),
+                ));
+
+                // The portal session usually dies with the compositor, try getting a new one. If
+                // that fails the failure comes back here, until out of attempts.
+                if let CaptureSource::Portal(session) = &source {
+                    if failure.thread == "video"
+                        && portal_reconnects < MAX_PORTAL_RECONNECTS
+                        && !portal_reconnecting
+                    {
+                        portal_reconnects += 1;
+                        warn!(
+                            "Reconnecting to the screen cast portal (attempt {})",
+                            portal_reconnects
+                        );
+                        portal_reconnecting = true;
+                        spawn_portal_reconnect(
+                            session.restore_token.clone(),
+                            (width, height),
+                            Arc::clone(&config),
+                            portal_tx.clone(),
+                            None,
+                            Some(failure),
+                        );
+                        continue;
+                    }
+                }
+                if !config.exit_on_capture_failure {
+                    warn!("Capture of {} stopped, carrying on without it", failure.thread);
+                    continue;
+                }
+
+                stop.store(true, std::sync::atomic::Ordering::Release);
+                let _ = pw_video_sender.send(Terminate);
+                let _ = pw_audio_sender.send(Terminate);
+                let _ = pw_monitor_sender.send(Terminate);
+                exit_error = Some(anyhow!(
+                    "The {} capture thread stopped: {}",
+                    failure.thread,
+                    failure.reason
+                ));
+                break;
+            },
+            Some(reconnect) = portal_rx.recv() => {
+                portal_reconnecting = false;
+                let CaptureSource::Portal(session) = &mut source else {
+                    continue;
+                };
+
+                let result = reconnect.session.map(|new_session| {
+                    let old_session = std::mem::replace(session, new_session);
+                    // Closing it talks to the portal too, keep that off the main loop as well
+                    tokio::task::spawn_blocking(move || drop(old_session));
+                    session.stream().pipewire_node()
+                });
+                match &result {
+                    Ok(stream_node) => {
+                        video_capture.stream_node = *stream_node;
+
+                        // The old loop exits on its own once terminated, or already returned if
+                        // it failed, join it off the main loop
+                        let _ = pw_video_sender.send(Terminate);
+                        let old_worker = pw_video_worker;
+                        tokio::task::spawn_blocking(move || old_worker.join());
+
+                        (pw_video_worker, pw_video_sender) =
+                            spawn_video_capture(&source, video_capture.clone());
+                        last_video_frame = Instant::now();
+                    }
+                    Err(e) => {
+                        error!("Could not reconnect to the screen cast portal: {:?}", e);
+                        if let Some(failure) = reconnect.failure {
+                            let _ = capture_failure_tx.send(failure);
+                        }
+                    }
+                }
+                if let Some(reply) = reconnect.reply {
+                    let _ = reply.send(result.map(|_| ()).map_err(|e| e.to_string()));
+                }
+            },
+            _ = &mut video_ready_timer, if !video_ready_checked => {
+                video_ready_checked = true;
+                if video_capture.video_ready.load(std::sync::atomic::Ordering::Acquire) {
+                    continue;
+                }
+
+                error!(
+                    "VideoReadyTimeout: video capture isn't streaming after {:?}, \
+                     no video is being buffered. Check the logs above for why",
+                    video_ready_timeout
+                );
+                match config.on_video_ready_timeout {
+                    OnVideoReadyTimeout::AudioOnly => {
+                        warn!("Buffering audio without video");
+                        let _ = status_tx.send(StatusUpdate::new(
+                            "error",
+                            "video capture never started, buffering audio only",
+                        ));
+                        // Only audio reads this, video capture still updates it if it starts later
+                        video_capture
+                            .video_ready
+                            .store(true, std::sync::atomic::Ordering::Release);
+                    }
+                    OnVideoReadyTimeout::Shutdown => {
+                        stop.store(true, std::sync::atomic::Ordering::Release);
+                        let _ = pw_video_sender.send(Terminate);
+                        let _ = pw_audio_sender.send(Terminate);
+                        let _ = pw_monitor_sender.send(Terminate);
+                        exit_error = Some(anyhow!(
+                            "Video capture wasn't ready within {:?}",
+                            video_ready_timeout
+                        ));
+                        break;
+                    }
+                }
+            },
             _ = tokio::signal::ctrl_c() => {
                 info!("Shutting down");
                 stop.store(true, std::sync::atomic::Ordering::Release);
                 let _ = pw_video_sender.send(Terminate);
                 let _ = pw_audio_sender.send(Terminate);
+                let _ = pw_monitor_sender.send(Terminate);
+                // The last write below replaces whatever a periodic one was writing
+                if let Some(task) = persist_task.take() {
+                    let _ = task.await;
+                }
                 let (mut video_lock, mut audio_lock) = tokio::join!(
                     video_encoder.lock(),
                     audio_encoder.lock()
                 );
+
+                if config.persistent_buffer {
+                    let (video_drained, audio_drained) =
+                        tokio::join!(video_lock.drain_async(), audio_lock.drain_async());
+                    video_drained?;
+                    audio_drained?;
+                    if let Err(e) = persistence::persist_buffers(
+                        &config.persist_buffer_path,
+                        video_width,
+                        video_height,
+                        config.time_base_den,
+                        video_lock.get_buffer(),
+                        audio_lock.get_buffer(),
+                    ) {
+                        error!("Could not persist replay buffer: {:?}", e);
+                    }
+                }
+
                 video_lock.reset_encoder()?;
                 audio_lock.reset_encoder()?;
 
@@ -272,116 +1147,1425 @@ async fn main() -> Result<(), Error> {
     let _ = video_worder.join();
     let _ = pw_video_worker.join();
     let _ = pw_audio_worker.join();
+    let _ = pw_monitor_worker.join();
     debug!("Done shutting down!");
-    Ok(())
+    match exit_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
 
-fn save_buffer(
-    filename: &str,
-    video_buffer: &VideoBuffer,
-    video_encoder: &ffmpeg::codec::encoder::Video,
-    audio_buffer: &AudioBuffer,
-    audio_encoder: &ffmpeg::codec::encoder::Audio,
-) -> Result<()> {
-    let mut output = ffmpeg::format::output(&filename)?;
+/// Feed the preview stream from its own thread, grabbing the next raw frame from the video
+/// encoder whenever the previous one is done encoding. Idles while no client is connected.
+fn spawn_preview_stream(
+    video_encoder: Arc<Mutex<VideoEncoder>>,
+    stop: Arc<AtomicBool>,
+    quality: u8,
+    frames_tx: broadcast::Sender<Bytes>,
+) {
+    std::thread::spawn(move || {
+        let mut stream_encoder = MjpegStreamEncoder::new(quality, frames_tx);
+        while !stop.load(std::sync::atomic::Ordering::Acquire) {
+            if !stream_encoder.has_clients() {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
 
-    let video_codec = video_encoder
-        .codec()
-        .context("Could not find expected video codec")?;
+            let frame_rx = video_encoder.blocking_lock().request_frame();
+            match frame_rx.blocking_recv() {
+                Ok(frame) => {
+                    if let Err(e) = stream_encoder.process(&frame) {
+                        error!("Error encoding preview frame: {:?}", e);
+                    }
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    });
+}
 
-    let mut video_stream = output.add_stream(video_codec)?;
-    video_stream.set_time_base(video_encoder.time_base());
-    video_stream.set_parameters(&video_encoder);
+/// Where video frames come from
+enum CaptureSource {
+    /// The portal's screen cast stream
+    Portal(PortalSession),
+    /// The X11 root window, when the portal isn't available
+    X11,
+}
 
-    let audio_codec = audio_encoder
-        .codec()
-        .context("Could not find expected audio codec")?;
+/// Everything needed to (re)start the video capture thread
+#[derive(Clone)]
+struct VideoCaptureParams {
+    stream_node: u32,
+    video_sender: mpsc::Sender<RawVideoFrame>,
+    video_ready: Arc<AtomicBool>,
+    audio_ready: Arc<AtomicBool>,
+    timestamps: Arc<TimestampSynchronizer>,
+    saving: Arc<AtomicBool>,
+    allow_nv12: bool,
+    node_latency: Option<String>,
+    buffers: u32,
+    cursor_metadata: bool,
+    capture_stats: Arc<CaptureStats>,
+    status_tx: mpsc::UnboundedSender<StatusUpdate>,
+    failure_tx: mpsc::UnboundedSender<CaptureFailure>,
+}
 
-    let mut audio_stream = output.add_stream(audio_codec)?;
-    audio_stream.set_time_base(audio_encoder.time_base());
-    audio_stream.set_parameters(&audio_encoder);
+/// A capture thread that stopped on its own by failing or panicking, see [`spawn_capture`]
+#[derive(Debug)]
+struct CaptureFailure {
+    thread: &'static str,
+    reason: String,
+}
 
-    output.write_header()?;
+/// Run `capture` on its own thread. If it fails or panics the main loop is told through
+/// `failure_tx`, rather than the thread quietly disappearing while nothing is captured anymore.
+/// Returning `Ok` means it was terminated and isn't reported.
+fn spawn_capture<E: std::fmt::Debug>(
+    thread: &'static str,
+    failure_tx: mpsc::UnboundedSender<CaptureFailure>,
+    capture: impl FnOnce() -> Result<(), E> + Send + 'static,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let reason = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(capture)) {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => format!("{:?}", e),
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+                format!("panicked: {}", message)
+            }
+        };
+        error!("The {} capture thread stopped: {}", thread, reason);
+        let _ = failure_tx.send(CaptureFailure { thread, reason });
+    })
+}
 
-    let last_keyframe = video_buffer
-        .get_last_gop_start()
-        .context("Could not get last keyframe dts")?;
+/// Run the video capture on its own thread, returning it along with the sender to terminate it
+fn spawn_video_capture(
+    source: &CaptureSource,
+    params: VideoCaptureParams,
+) -> (std::thread::JoinHandle<()>, pw::channel::Sender<Terminate>) {
+    let fd = match source {
+        CaptureSource::Portal(session) => Some(session.screen_cast.pipewire_fd()),
+        CaptureSource::X11 => None,
+    };
+    let (pw_video_sender, pw_video_recv) = pw::channel::channel::<Terminate>();
+    let failure_tx = params.failure_tx.clone();
+    let worker = spawn_capture("video", failure_tx, move || -> Result<()> {
+        let Some(fd) = fd else {
+            debug!("Starting X11 capture");
+            return DesktopCapture::run_x11(
+                params.video_sender,
+                params.video_ready,
+                params.audio_ready,
+                params.timestamps,
+                pw_video_recv,
+                params.saving,
+                params.capture_stats,
+                params.status_tx,
+            );
+        };
 
-    let newest_video_pts = video_buffer
-        .get_frames()
-        .get(last_keyframe)
-        .context("Could not get last keyframe")?
-        .get_pts();
+        debug!("Starting video stream");
+        VideoCapture::run(
+            fd,
+            params.stream_node,
+            params.video_sender,
+            params.video_ready,
+            params.audio_ready,
+            params.timestamps,
+            pw_video_recv,
+            params.saving,
+            params.allow_nv12,
+            params.node_latency,
+            params.buffers,
+            params.cursor_metadata,
+            params.capture_stats,
+            params.status_tx,
+        )?;
+        Ok(())
+    });
 
-    let oldest_capture_time = audio_buffer.get_capture_times();
+    (worker, pw_video_sender)
+}
 
-    // Write video
-    let mut first_pts_offset = video_buffer
-        .oldest_pts()
-        .context("Could not get oldest pts when muxing.")?;
-    let mut first_offset = false;
-    debug!("VIDEO SAVE START");
-    for (dts, frame_data) in video_buffer.get_frames().range(..=last_keyframe) {
-        // If video starts before audio try and catch up as much as possible
-        // (At worst a 20ms gap)
-        if &oldest_capture_time[0] > frame_data.get_pts() {
-            continue;
-        }
-        if first_offset == false {
-            first_pts_offset = *frame_data.get_pts();
-            first_offset = true;
-        }
-        let pts_offset = frame_data.get_pts() - first_pts_offset;
-        let mut dts_offset = dts - first_pts_offset;
+/// A new portal session from [`spawn_portal_reconnect`], for the main loop to restart video capture
+/// with
+struct PortalReconnect {
+    session: Result<PortalSession>,
 
-        debug!(
-            "Capture Timestamp: {:?}, PTS offset: {:?}",
-            frame_data.get_pts(),
-            pts_offset
-        );
-        if dts_offset < 0 {
-            dts_offset = 0;
+    /// Answers `ReconnectPortal` once capture restarted, when that's what asked for it
+    reply: Option<oneshot::Sender<Result<(), String>>>,
+
+    /// The capture failure that set it off, handled again if no session came back
+    failure: Option<CaptureFailure>,
+}
+
+/// Start a new portal session, restoring the previous selection with `restore_token` if the portal
+/// allows it. The picker can wait on the user and `portal_cancel_retry_secs` retries for as long
+/// as they cancel it, so it runs on a blocking thread and the session comes back on `done_tx`.
+/// Frames keep being encoded at `size`, a monitor that changed resolution is only warned about.
+fn spawn_portal_reconnect(
+    restore_token: Option<String>,
+    size: (u32, u32),
+    config: Arc<AppConfig>,
+    done_tx: mpsc::UnboundedSender<PortalReconnect>,
+    reply: Option<oneshot::Sender<Result<(), String>>>,
+    failure: Option<CaptureFailure>,
+) {
+    tokio::task::spawn_blocking(move || {
+        let session = tokio::runtime::Handle::current()
+            .block_on(PortalSession::open(&config, restore_token.as_deref()))
+            .and_then(|session| session.context("Screen selection was cancelled"));
+
+        if let Ok(session) = &session {
+            let stream = session.stream();
+            if stream.size() != size {
+                warn!(
+                    "The monitor is now {}x{} but keeps being recorded at {}x{}",
+                    stream.width(),
+                    stream.height(),
+                    size.0,
+                    size.1
+                );
+            }
+            info!(
+                "Reconnected to the screen cast portal, capturing node {}",
+                stream.pipewire_node()
+            );
         }
 
-        let mut packet = ffmpeg::codec::packet::Packet::copy(&frame_data.get_raw_bytes());
-        packet.set_pts(Some(pts_offset));
-        packet.set_dts(Some(dts_offset));
+        let _ = done_tx.send(PortalReconnect {
+            session,
+            reply,
+            failure,
+        });
+    });
+}
 
-        packet.set_stream(VIDEO_STREAM);
+/// The portal's screen cast, kept along with what's needed to start it again if the session dies,
+/// e.g. when the compositor restarts
+struct PortalSession {
+    screen_cast: ActiveScreenCast,
+    /// Whether the cursor comes as metadata
+    cursor_metadata: bool,
+    /// Lets the portal restore the monitor picked last time instead of asking again
+    restore_token: Option<String>,
+}
 
-        packet
-            .write_interleaved(&mut output)
-            .expect("Could not write video interleaved");
+impl PortalSession {
+    /// Ask the portal for a monitor to capture. Returns `None` if the user cancels the picker and
+    /// `portal_cancel_retry_secs` is 0, otherwise the picker is shown again after that delay.
+    async fn start(config: &AppConfig) -> Result<Option<Self>> {
+        // Only a permanent grant outlives the recorder
+        let restore_token = match config.portal_persist {
+            PortalPersist::Permanent => {
+                std::fs::read_to_string(&config.portal_restore_token_path).ok()
+            }
+            PortalPersist::None | PortalPersist::App => None,
+        };
+        Self::open(config, restore_token.as_deref().map(str::trim)).await
     }
-    debug!("VIDEO SAVE END");
 
-    // Write audio
-    let oldest_frame_offset = audio_buffer
-        .oldest_pts()
-        .context("Could not get oldest chunk")?;
+    /// The captured monitor's stream
+    fn stream(&self) -> &ScreenCastStream {
+        self.screen_cast.streams().next().unwrap()
+    }
 
-    debug!("AUDIO SAVE START");
-    let mut iter = 0;
-    for (pts, frame) in audio_buffer.get_frames() {
-        // Don't write any more audio if we would exceed video (clip to max video)
-        if &oldest_capture_time[iter] > newest_video_pts {
-            debug!(
-                "Oldest capture time {:?}, in time scale: {:?}",
-                oldest_capture_time[iter], pts
-            );
-            break;
+    async fn open(config: &AppConfig, restore_token: Option<&str>) -> Result<Option<Self>> {
+        loop {
+            let mut screen_cast = ScreenCast::new()?;
+            screen_cast.set_source_types(SourceType::MONITOR);
+            let cursor_metadata = config.cursor_metadata && cursor_metadata_supported(&screen_cast);
+            screen_cast.set_cursor_mode(if cursor_metadata {
+                CursorMode::METADATA
+            } else {
+                CursorMode::EMBEDDED
+            });
+            screen_cast.set_persist_mode(match config.portal_persist {
+                PortalPersist::None => PersistMode::DoNot,
+                PortalPersist::App => PersistMode::Application,
+                PortalPersist::Permanent => PersistMode::Permanent,
+            });
+            if let Some(token) = restore_token {
+                screen_cast.set_restore_token(token);
+            }
+
+            match screen_cast.start(None) {
+                Ok(screen_cast) => {
+                    if config.portal_persist == PortalPersist::Permanent {
+                        save_restore_token(config, screen_cast.restore_token());
+                    }
+                    return Ok(Some(Self {
+                        restore_token: screen_cast.restore_token().map(String::from),
+                        screen_cast,
+                        cursor_metadata,
+                    }));
+                }
+                Err(PortalError::Cancelled) if config.portal_cancel_retry_secs > 0 => {
+                    warn!(
+                        "Screen selection was cancelled, asking again in {}s",
+                        config.portal_cancel_retry_secs
+                    );
+                    tokio::time::sleep(Duration::from_secs(config.portal_cancel_retry_secs)).await;
+                }
+                Err(PortalError::Cancelled) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
         }
-        // If audio starts before video try and catch up as much as possible
-        // (At worst a 20ms gap)
-        if &oldest_capture_time[iter] < &first_pts_offset {
-            debug!("Audio timestamp is before video, skipping writing this one");
+    }
+}
+
+/// Keep the token for a `permanent` portal grant for the next run, failing only gets logged
+fn save_restore_token(config: &AppConfig, token: Option<&str>) {
+    let Some(token) = token else {
+        warn!("The portal did not hand out a restore token, the picker shows again next time");
+        return;
+    };
+
+    let path = &config.portal_restore_token_path;
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(path, token));
+    if let Err(e) = written {
+        warn!(
+            "Could not save the portal restore token to {:?}: {:?}",
+            path, e
+        );
+    }
+}
+
+/// Whether the portal can send the cursor as metadata, warning that it falls back to an embedded
+/// cursor if not
+fn cursor_metadata_supported(screen_cast: &ScreenCast) -> bool {
+    match screen_cast.cursor_modes() {
+        Ok(modes) if modes.contains(CursorMode::METADATA) => true,
+        Ok(_) => {
+            warn!(
+                "The portal can't send the cursor as metadata, falling back to an embedded cursor"
+            );
+            false
+        }
+        Err(e) => {
+            warn!(
+                "Could not query the portal's cursor modes, falling back to an embedded cursor: {:?}",
+                e
+            );
+            false
+        }
+    }
+}
+
+/// The region to record from `--region` or the config, checked against the monitor size
+fn capture_region(
+    region: Option<String>,
+    config: &AppConfig,
+    width: u32,
+    height: u32,
+) -> Result<Option<Region>> {
+    let Some(geometry) = region.or_else(|| config.capture_region.clone()) else {
+        return Ok(None);
+    };
+
+    let mut region = Region::parse(&geometry)
+        .with_context(|| format!("Invalid region {:?}, expected x,y WxH", geometry))?;
+    if !region.fits_within(width, height) {
+        bail!(
+            "Region {:?} does not fit within the {}x{} monitor",
+            geometry,
+            width,
+            height
+        );
+    }
+
+    // Encoders and NV12's subsampled chroma want even positions and sizes
+    region.x &= !1;
+    region.y &= !1;
+    region.width &= !1;
+    region.height &= !1;
+    if region.width == 0 || region.height == 0 {
+        bail!("Region {:?} is too small", geometry);
+    }
+
+    info!(
+        "Capturing region {:?} of the {}x{} monitor",
+        region, width, height
+    );
+    Ok(Some(region))
+}
+
+/// Report an encoder starting or stopping to fail, only on transitions so a broken encoder
+/// doesn't flood the bus
+fn report_encoder_status(
+    status_tx: &mpsc::UnboundedSender<StatusUpdate>,
+    encoder: &str,
+    result: &Result<(), ffmpeg::Error>,
+    failing: &mut bool,
+) {
+    match (result, *failing) {
+        (Err(e), false) => {
+            *failing = true;
+            let _ = status_tx.send(StatusUpdate::new(
+                "error",
+                format!("{} encoder failing: {}", encoder, e),
+            ));
+        }
+        (Ok(()), true) => {
+            *failing = false;
+            let _ = status_tx.send(StatusUpdate::new(
+                "recording",
+                format!("{} encoder recovered", encoder),
+            ));
+        }
+        _ => {}
+    }
+}
+
+/// Deal with a frame that failed to encode as `on_encode_error` says. Returns `false` if the
+/// worker should stop, in which case the error was handed to the main loop to exit with.
+fn handle_encode_error(
+    on_encode_error: OnEncodeError,
+    encoder: &str,
+    timestamp: i64,
+    error: ffmpeg::Error,
+    capture_stats: &CaptureStats,
+    encode_error_tx: &mpsc::UnboundedSender<Error>,
+) -> bool {
+    match on_encode_error {
+        OnEncodeError::Skip => {
+            let skipped = capture_stats
+                .frames_encode_failed
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                + 1;
+            warn!(
+                "Skipping {} frame at {:?} that failed to encode ({} skipped so far): {:?}",
+                encoder, timestamp, skipped, error
+            );
+            true
+        }
+        OnEncodeError::Abort => {
+            let error = Error::new(error).context(format!(
+                "Could not encode the {} frame at {:?}",
+                encoder, timestamp
+            ));
+            let _ = encode_error_tx.send(error);
+            false
+        }
+    }
+}
+
+/// Estimate how much memory the replay buffer will use and warn if it's more than half of
+/// what's currently available.
+fn check_buffer_memory(config: &AppConfig) {
+    let bitrate_kbps = config.quality.bitrate_kbps() as u64;
+
+    // 10% headroom for container/packet overhead
+    let estimated_bytes = (config.max_seconds as u64 * bitrate_kbps * 1000 / 8) * 11 / 10;
+
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    let available_bytes = system.available_memory();
+
+    debug!(
+        "Estimated replay buffer size: {} MiB, available memory: {} MiB",
+        estimated_bytes / 1024 / 1024,
+        available_bytes / 1024 / 1024
+    );
+
+    if estimated_bytes > available_bytes / 2 {
+        warn!(
+            "Replay buffer may use up to {} MiB ({}s at {} kbps) but only {} MiB of memory is available. \
+            Consider lowering max_seconds or the quality preset.",
+            estimated_bytes / 1024 / 1024,
+            config.max_seconds,
+            bitrate_kbps,
+            available_bytes / 1024 / 1024
+        );
+    }
+}
+
+/// Point in time copy of both buffers along with what's needed to mux them, so the muxing can
+/// happen without holding the encoder locks.
+struct ClipSnapshot {
+    video_buffer: VideoBuffer,
+    video_parameters: ffmpeg::codec::Parameters,
+    video_time_base: Rational,
+    audio_buffer: AudioBuffer,
+    audio_parameters: ffmpeg::codec::Parameters,
+    audio_time_base: Rational,
+
+    /// Muxed as a subtitle track when not empty
+    subtitles: Vec<SubtitleEntry>,
+
+    /// DTS of the last video frame to write when [`Self::keep_last`] cut the clip short,
+    /// otherwise it ends where the last GOP starts
+    end_dts: Option<i64>,
+
+    /// Settings the clip is muxed with
+    config: Arc<AppConfig>,
+}
+
+impl ClipSnapshot {
+    /// Moves the buffers out of the encoders, which are reset right after so copying every frame
+    /// while capture waits on the locks would be wasted
+    fn new(
+        video_encoder: &mut VideoEncoder,
+        audio_encoder: &mut AudioEncoder,
+        config: Arc<AppConfig>,
+    ) -> Result<Self> {
+        video_encoder
+            .get_encoder()
+            .as_ref()
+            .context("Could not get video encoder")?;
+
+        let audio = audio_encoder
+            .get_encoder()
+            .as_ref()
+            .context("Could not get audio encoder")?;
+        let audio_parameters = ffmpeg::codec::Parameters::from(audio);
+        let audio_time_base = audio.time_base();
+        let video_parameters = video_encoder
+            .get_parameters()
+            .context("Could not get video parameters")?;
+
+        Ok(Self {
+            video_buffer: video_encoder.get_buffer_mut().take(),
+            video_parameters,
+            // The video buffer is kept in micro seconds whatever the encoder's time base
+            video_time_base: Rational::new(1, ONE_MICROS as i32),
+            audio_buffer: audio_encoder.get_buffer_mut().take(),
+            audio_parameters,
+            audio_time_base,
+            subtitles: Vec::new(),
+            end_dts: None,
+            config,
+        })
+    }
+
+    /// Only keep the last `seconds` of what a clip would contain, starting at the keyframe before
+    /// and ending `seconds` after it, and the audio captured in between. Shorter buffers are kept
+    /// whole.
+    fn keep_last(&mut self, seconds: u32) {
+        // Clips end where the last GOP starts
+        let Some(end) = self
+            .video_buffer
+            .get_last_gop_start()
+            .and_then(|dts| self.video_buffer.get_frames().get(dts))
+            .map(|frame| *frame.get_pts())
+        else {
+            return;
+        };
+
+        let duration = seconds as i64 * ONE_MICROS as i64;
+        self.video_buffer.trim_before(end - duration);
+        let Some(start) = self.video_buffer.oldest_pts() else {
+            return;
+        };
+        self.audio_buffer.trim_before(start);
+
+        // Starting at the keyframe before would make the clip longer than asked for
+        let clip_end = end.min(start + duration);
+        self.end_dts = self.video_buffer.last_dts_shown_by(clip_end);
+        info!(
+            "Keeping {:.3}s of the requested {}s",
+            (clip_end - start) as f64 / ONE_MICROS as f64,
+            seconds
+        );
+    }
+}
+
+async fn save_clip(
+    video_encoder: &Mutex<VideoEncoder>,
+    audio_encoder: &Mutex<AudioEncoder>,
+    saving: &AtomicBool,
+    status_tx: &mpsc::UnboundedSender<StatusUpdate>,
+    clip_library: &Arc<ClipLibrary>,
+    config: &Arc<AppConfig>,
+    options: ClipOptions,
+    subtitles: &[SubtitleEntry],
+) -> Result<()> {
+    let ClipOptions {
+        title,
+        description,
+        seconds,
+        with_cursor,
+        done,
+    } = options;
+    let mut snapshot = match snapshot_buffers(video_encoder, audio_encoder, saving, config).await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            reply_save(done, Err(e.to_string()));
+            return Err(e);
+        }
+    };
+    if seconds > 0 {
+        snapshot.keep_last(seconds);
+    }
+
+    let mut burned_subtitles = Vec::new();
+    match config.subtitle_mode {
+        SubtitleMode::SoftSubtitle => snapshot.subtitles = subtitles.to_vec(),
+        SubtitleMode::Burned => burned_subtitles = subtitles.to_vec(),
+        _ => {}
+    }
+    // Only buffered with `cursor_on_save`, otherwise it's already in the frames
+    let burned_cursor = with_cursor && snapshot.video_buffer.has_cursors();
+
+    let key = if config.encrypt_clips {
+        match ClipKey::load(config) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                reply_save(done, Err(e.to_string()));
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+    // The dump would hold the clip unencrypted
+    let dump_packets = config.dump_raw_packets && key.is_none();
+    if config.dump_raw_packets && !dump_packets {
+        warn!("Not dumping raw packets of an encrypted clip");
+    }
+
+    // Mux in the background so capture never waits on disk I/O
+    let timestamp = chrono::Local::now().timestamp();
+    let filename = match title.as_deref().map(sanitize_title) {
+        Some(suffix) if !suffix.is_empty() => format!("clip_{}_{}.mp4", timestamp, suffix),
+        _ => format!("clip_{}.mp4", timestamp),
+    };
+    let filename = match claim_filename(&encrypted_name(filename, key.is_some())) {
+        Ok(filename) => filename,
+        Err(e) => {
+            reply_save(done, Err(e.to_string()));
+            return Err(e.into());
+        }
+    };
+    let status_tx = status_tx.clone();
+    let clip_library = Arc::clone(clip_library);
+    let config = Arc::clone(config);
+    tokio::task::spawn_blocking(move || {
+        // Dumped first so the packets are there even when muxing fails
+        if dump_packets {
+            dump_raw_packets(&filename, &snapshot);
+        }
+
+        let result = encryption::write_clip(&filename, key.as_ref(), |target| {
+            if burned_subtitles.is_empty() && !burned_cursor {
+                save_buffer(target, &snapshot, title.as_deref(), description.as_deref())
+            } else {
+                save_with_overlays(
+                    target,
+                    &snapshot,
+                    title.as_deref(),
+                    description.as_deref(),
+                    &burned_subtitles,
+                    burned_cursor,
+                    &config.encoder,
+                )
+            }
+        });
+
+        match result {
+            Ok(result) => {
+                if config.clip_checksums {
+                    write_checksum(&filename);
+                }
+                clip_library.record_save(&filename, Ok(result.av_sync_offset_us));
+                debug!("Done saving {}!", filename);
+                reply_save(done, Ok(filename.clone()));
+                if config.sound_on_save {
+                    play_save_sound(&config.save_sound_command);
+                }
+
+                if cfg!(feature = "compute_ssim") {
+                    match estimate_quality(&snapshot.video_buffer, &snapshot.video_parameters) {
+                        Ok(quality) => info!("Estimated quality of {}: {:.3}", filename, quality),
+                        Err(e) => warn!("Could not estimate quality of {}: {:?}", filename, e),
+                    }
+                }
+            }
+            Err(e) => {
+                release_filename(&filename);
+                clip_library.record_save(&filename, Err(e.to_string()));
+                error!("Could not save {}: {:?}", filename, e);
+                reply_save(done, Err(e.to_string()));
+                let _ = status_tx.send(StatusUpdate::new(
+                    "error",
+                    format!("saving {} failed: {}", filename, e),
+                ));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Tell whoever waits on a save through `SaveClipSync`, if anyone, how it ended
+fn reply_save(
+    done: Option<oneshot::Sender<Result<String, String>>>,
+    result: Result<String, String>,
+) {
+    if let Some(done) = done {
+        let _ = done.send(result);
+    }
+}
+
+/// Write the snapshot's packets next to the clip for `dump_raw_packets`, failing only gets logged
+fn dump_raw_packets(filename: &str, snapshot: &ClipSnapshot) {
+    let path = format!("{}.{}", filename, packet_dump::EXTENSION);
+    if let Err(e) = packet_dump::write(
+        Path::new(&path),
+        &snapshot.video_buffer,
+        snapshot.video_time_base,
+        &snapshot.audio_buffer,
+        snapshot.audio_time_base,
+    ) {
+        warn!("Could not dump the packets of {}: {:?}", filename, e);
+    }
+}
+
+/// Write the SHA-256 of the finished clip next to it for `clip_checksums`, failing only gets logged
+fn write_checksum(filename: &str) {
+    match checksum::write_sidecar(Path::new(filename)) {
+        Ok(sidecar) => debug!("Wrote checksum of {} to {}", filename, sidecar.display()),
+        Err(e) => warn!("Could not write the checksum of {}: {:?}", filename, e),
+    }
+}
+
+/// Run `save_sound_command` for `sound_on_save` and wait for the sound to finish, failing only
+/// gets logged
+fn play_save_sound(command: &str) {
+    match std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::null())
+        .status()
+    {
+        Ok(status) if !status.success() => {
+            warn!("Save sound command {:?} exited with {}", command, status)
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Could not run save sound command {:?}: {:?}", command, e),
+    }
+}
+
+/// Perceived quality of `video_buffer` from 0 to 1, only available with the `compute_ssim` feature
+#[cfg(feature = "compute_ssim")]
+fn estimate_quality(
+    video_buffer: &VideoBuffer,
+    parameters: &ffmpeg::codec::Parameters,
+) -> Result<f32> {
+    video_buffer.compute_crf_estimate(parameters)
+}
+
+#[cfg(not(feature = "compute_ssim"))]
+fn estimate_quality(_: &VideoBuffer, _: &ffmpeg::codec::Parameters) -> Result<f32> {
+    bail!(tr!("Built without the compute_ssim feature"))
+}
+
+/// The part of `video_buffer` [`estimate_quality`] looks at, copied so it can be estimated
+/// without holding the encoder lock
+#[cfg(feature = "compute_ssim")]
+fn quality_samples(video_buffer: &VideoBuffer) -> VideoBuffer {
+    video_buffer.quality_samples()
+}
+
+#[cfg(not(feature = "compute_ssim"))]
+fn quality_samples(video_buffer: &VideoBuffer) -> VideoBuffer {
+    // Nothing gets estimated
+    VideoBuffer::new(video_buffer.get_limit())
+}
+
+/// Save the clip to a temporary file first, then re-encode it into `filename` with `subtitles`
+/// and, if `with_cursor`, the cursors kept in the buffer drawn onto the video
+fn save_with_overlays(
+    filename: &str,
+    snapshot: &ClipSnapshot,
+    title: Option<&str>,
+    description: Option<&str>,
+    subtitles: &[SubtitleEntry],
+    with_cursor: bool,
+    encoder_name: &str,
+) -> Result<SaveResult> {
+    // Removed along with its contents once dropped, and never shared between concurrent saves
+    let temp_dir = tempfile::Builder::new()
+        .prefix("screen-recorder-")
+        .tempdir()?;
+    let muxed = temp_dir.path().join("clip.mp4");
+    let script = temp_dir.path().join("subtitles.ass");
+
+    let result =
+        save_buffer(&muxed.to_string_lossy(), snapshot, title, description).and_then(|result| {
+            if !subtitles.is_empty() {
+                std::fs::write(
+                    &script,
+                    SubtitleEncoder::to_ass(subtitles, result.clip_start_us),
+                )?;
+            }
+
+            // The live encoder's settings aren't known here, keep the quality close to the MEDIUM
+            // preset
+            let preset = EncodePreset {
+                video_crf: Some(OVERLAY_CRF),
+                ..Default::default()
+            };
+            transcode::transcode(
+                &muxed,
+                Path::new(filename),
+                &preset,
+                encoder_name,
+                (!subtitles.is_empty()).then_some(script.as_path()),
+                with_cursor.then_some((&snapshot.video_buffer, result.clip_start_us)),
+            )?;
+            Ok(result)
+        });
+
+    result
+}
+
+/// Save a clip re-encoded with the named preset from the config, replying with the file name it
+/// will be written to. The buffer is muxed to a temporary file first which then gets transcoded.
+async fn save_clip_with_preset(
+    video_encoder: &Mutex<VideoEncoder>,
+    audio_encoder: &Mutex<AudioEncoder>,
+    saving: &AtomicBool,
+    status_tx: &mpsc::UnboundedSender<StatusUpdate>,
+    clip_library: &Arc<ClipLibrary>,
+    config: &Arc<AppConfig>,
+    preset_name: String,
+    reply: oneshot::Sender<Result<String, String>>,
+) -> Result<()> {
+    let Some(preset) = config.presets.get(&preset_name).cloned() else {
+        let _ = reply.send(Err(tr!("Unknown preset \"{}\"", preset_name)));
+        return Ok(());
+    };
+
+    let key = if config.encrypt_clips {
+        match ClipKey::load(config) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                let _ = reply.send(Err(e.to_string()));
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+
+    let snapshot = match snapshot_buffers(video_encoder, audio_encoder, saving, config).await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            let _ = reply.send(Err(e.to_string()));
+            return Err(e);
+        }
+    };
+
+    let timestamp = chrono::Local::now().timestamp();
+    let filename = format!(
+        "clip_{}_{}.{}",
+        timestamp,
+        sanitize_title(&preset_name),
+        preset.output_format.as_deref().unwrap_or("mp4")
+    );
+    let filename = match claim_filename(&encrypted_name(filename, key.is_some())) {
+        Ok(filename) => filename,
+        Err(e) => {
+            let _ = reply.send(Err(e.to_string()));
+            return Err(e.into());
+        }
+    };
+    let _ = reply.send(Ok(filename.clone()));
+
+    let status_tx = status_tx.clone();
+    let clip_library = Arc::clone(clip_library);
+    let config = Arc::clone(config);
+    tokio::task::spawn_blocking(move || {
+        let result = encryption::write_clip(&filename, key.as_ref(), |target| {
+            // Removed once dropped, and never shared between concurrent saves
+            let temp_file = tempfile::Builder::new()
+                .prefix("screen-recorder-")
+                .suffix(".mp4")
+                .tempfile()?;
+            let muxed = temp_file.path();
+            let result = save_buffer(&muxed.to_string_lossy(), &snapshot, None, None)?;
+            let cursors = snapshot
+                .video_buffer
+                .has_cursors()
+                .then_some((&snapshot.video_buffer, result.clip_start_us));
+            transcode::transcode(
+                muxed,
+                Path::new(target),
+                &preset,
+                &config.encoder,
+                None,
+                cursors,
+            )?;
+            Ok(result)
+        });
+
+        match result {
+            Ok(result) => {
+                if config.clip_checksums {
+                    write_checksum(&filename);
+                }
+                clip_library.record_save(&filename, Ok(result.av_sync_offset_us));
+                debug!("Done saving {} with preset {}!", filename, preset_name);
+                if config.sound_on_save {
+                    play_save_sound(&config.save_sound_command);
+                }
+            }
+            Err(e) => {
+                release_filename(&filename);
+                clip_library.record_save(&filename, Err(e.to_string()));
+                error!("Could not save {}: {:?}", filename, e);
+                let _ = status_tx.send(StatusUpdate::new(
+                    "error",
+                    format!("saving {} failed: {}", filename, e),
+                ));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Drain both encoders and snapshot their buffers, pausing capture only for as long as it takes
+async fn snapshot_buffers(
+    video_encoder: &Mutex<VideoEncoder>,
+    audio_encoder: &Mutex<AudioEncoder>,
+    saving: &AtomicBool,
+    config: &Arc<AppConfig>,
+) -> Result<ClipSnapshot> {
+    saving.store(true, std::sync::atomic::Ordering::Release);
+    let (mut video_lock, mut audio_lock) = tokio::join!(video_encoder.lock(), audio_encoder.lock());
+
+    if video_lock.get_buffer().get_gop_count() < MIN_SAVE_GOPS {
+        saving.store(false, std::sync::atomic::Ordering::Release);
+        bail!(BufferNotReady);
+    }
+
+    // Drain both encoders of any remaining frames being processed
+    let (video_drained, audio_drained) =
+        tokio::join!(video_lock.drain_async(), audio_lock.drain_async());
+    video_drained?;
+    audio_drained?;
+
+    let snapshot = ClipSnapshot::new(&mut video_lock, &mut audio_lock, Arc::clone(config))?;
+
+    video_lock.reset_encoder()?;
+    audio_lock.reset_encoder()?;
+
+    drop(video_lock);
+    drop(audio_lock);
+    saving.store(false, std::sync::atomic::Ordering::Release);
+
+    Ok(snapshot)
+}
+
+/// `filename` with the extension of encrypted clips appended if `encrypted`
+fn encrypted_name(filename: String, encrypted: bool) -> String {
+    if encrypted {
+        format!("{}.{}", filename, encryption::EXTENSION)
+    } else {
+        filename
+    }
+}
+
+/// Remove what was written to a name from [`claim_filename`] when the save failed, so no empty or
+/// half written clip is left behind
+fn release_filename(filename: &str) {
+    if let Err(e) = std::fs::remove_file(filename) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!(
+                "Could not remove {} after the failed save: {:?}",
+                filename, e
+            );
+        }
+    }
+}
+
+/// Turn a clip title into something safe to put in a file name
+fn sanitize_title(title: &str) -> String {
+    title
+        .trim()
+        .chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .filter(|c| c.is_alphanumeric() || matches!(c, '_' | '-'))
+        .take(MAX_TITLE_FILENAME_CHARS)
+        .collect()
+}
+
+/// Report that capture was armed, it stays paused if `Pause` or `fullscreen_only` paused it
+/// meanwhile
+fn arm_capture(
+    paused: bool,
+    paused_by_request: bool,
+    status_tx: &mpsc::UnboundedSender<StatusUpdate>,
+) {
+    info!("Capture armed");
+    let status = if paused_by_request {
+        StatusUpdate::new("paused", "armed, paused on request")
+    } else if paused {
+        StatusUpdate::new("paused", "armed, no fullscreen window focused")
+    } else {
+        StatusUpdate::new("recording", "armed")
+    };
+    let _ = status_tx.send(status);
+}
+
+/// Write a copy of both buffers to `path` on the blocking pool, see `persist_interval_secs`.
+/// Frames still inside the encoders are left out, draining them would restart the encoders.
+async fn spawn_persist(
+    video_encoder: &Mutex<VideoEncoder>,
+    audio_encoder: &Mutex<AudioEncoder>,
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    audio_time_base_den: u32,
+) -> tokio::task::JoinHandle<()> {
+    // Video frames share their data, so capture only waits on copying the index and the audio
+    let (video_buffer, audio_buffer) = {
+        let (video_lock, audio_lock) = tokio::join!(video_encoder.lock(), audio_encoder.lock());
+        (
+            video_lock.get_buffer().clone(),
+            audio_lock.get_buffer().clone(),
+        )
+    };
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = persistence::persist_buffers(
+            &path,
+            width,
+            height,
+            audio_time_base_den,
+            &video_buffer,
+            &audio_buffer,
+        ) {
+            error!("Could not persist replay buffer: {:?}", e);
+        }
+    })
+}
+
+/// Log how much is buffered, to tell whether short clips come from the buffer not holding
+/// `max_seconds`
+async fn log_buffer_heartbeat(
+    video_encoder: &Mutex<VideoEncoder>,
+    audio_encoder: &Mutex<AudioEncoder>,
+    capture_stats: &CaptureStats,
+) {
+    let (video_us, frames, keyframes) = {
+        let video_lock = video_encoder.lock().await;
+        let video_buffer = video_lock.get_buffer();
+        (
+            video_buffer.duration_us(),
+            video_buffer.frame_count(),
+            video_buffer.get_gop_count(),
+        )
+    };
+    let audio_us = audio_encoder.lock().await.get_buffer().duration_us();
+
+    info!(
+        "Buffered {:.1}s of video ({} frames, {} keyframes) and {:.1}s of audio, capturing at {:.1} fps, dropped frames: {} compositor, {} encoder, {} failed to encode",
+        video_us as f64 / ONE_MICROS as f64,
+        frames,
+        keyframes,
+        audio_us as f64 / ONE_MICROS as f64,
+        capture_stats.capture_fps(),
+        capture_stats
+            .frames_dropped_compositor
+            .load(std::sync::atomic::Ordering::Relaxed),
+        capture_stats
+            .frames_dropped_encoder
+            .load(std::sync::atomic::Ordering::Relaxed),
+        capture_stats
+            .frames_encode_failed
+            .load(std::sync::atomic::Ordering::Relaxed)
+    );
+}
+
+/// Estimate the size of a clip of the last `seconds` from the buffered packet sizes plus the
+/// container overhead
+async fn estimate_clip_size(
+    video_encoder: &Mutex<VideoEncoder>,
+    audio_encoder: &Mutex<AudioEncoder>,
+    seconds: u32,
+) -> u64 {
+    let (video_lock, audio_lock) = tokio::join!(video_encoder.lock(), audio_encoder.lock());
+    buffer::estimate_clip_size(video_lock.get_buffer(), audio_lock.get_buffer(), seconds)
+}
+
+/// Reply with a JPEG of the next frame the video encoder receives. Encoding happens in the
+/// background so the main loop isn't held up.
+async fn send_current_frame(
+    video_encoder: &Mutex<VideoEncoder>,
+    quality: u8,
+    reply: oneshot::Sender<Result<Vec<u8>, String>>,
+) {
+    let frame_rx = video_encoder.lock().await.request_frame();
+
+    tokio::spawn(async move {
+        let result = match tokio::time::timeout(FRAME_WAIT_TIMEOUT, frame_rx).await {
+            Ok(Ok(frame)) => {
+                tokio::task::spawn_blocking(move || MjpegEncoder::encode(&frame, quality))
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|encoded| encoded.map_err(|e| e.to_string()))
+            }
+            _ => Err("No raw video frame captured in time".to_string()),
+        };
+
+        if let Err(e) = &result {
+            error!("Could not get current frame: {}", e);
+        }
+        let _ = reply.send(result);
+    });
+}
+
+/// Decode the buffered frame shown `ms_ago` before the newest one and send it as a JPEG
+async fn send_buffered_frame(
+    video_encoder: &Arc<Mutex<VideoEncoder>>,
+    ms_ago: u32,
+    quality: u8,
+    reply: oneshot::Sender<Result<Vec<u8>, String>>,
+) {
+    let mut video_lock = video_encoder.lock().await;
+    let (width, height) = video_lock.size();
+    let buffered = video_lock
+        .get_buffer()
+        .newest_pts()
+        .and_then(|newest| video_lock.get_frame_at(newest - ms_ago as i64 * 1000));
+    drop(video_lock);
+
+    let Some(buffered) = buffered else {
+        let _ = reply.send(Err(format!("No buffered frame {}ms ago", ms_ago)));
+        return;
+    };
+
+    let video_encoder = Arc::clone(video_encoder);
+    tokio::task::spawn_blocking(move || {
+        // Decoding a whole GOP is slow, only take the encoder again to cache the result
+        let pixels = match buffered {
+            BufferedFrame::Decoded(pixels) => pixels,
+            BufferedFrame::Encoded(encoded) => {
+                let shown_pts = encoded.shown_pts();
+                let Some(pixels) = encoded.decode() else {
+                    let _ = reply.send(Err(format!("Could not decode the frame {}ms ago", ms_ago)));
+                    return;
+                };
+                video_encoder
+                    .blocking_lock()
+                    .cache_decoded_frame(shown_pts, Arc::clone(&pixels));
+                pixels
+            }
+        };
+
+        let mut frame = ffmpeg::frame::Video::new(ffmpeg::format::Pixel::BGRA, width, height);
+        let stride = frame.stride(0);
+        let row_bytes = width as usize * 4;
+        for (row, pixels) in pixels.chunks_exact(row_bytes).enumerate() {
+            frame.data_mut(0)[row * stride..][..row_bytes].copy_from_slice(pixels);
+        }
+
+        let result = MjpegEncoder::encode(&frame, quality).map_err(|e| e.to_string());
+        if let Err(e) = &result {
+            error!("Could not encode buffered frame: {}", e);
+        }
+        let _ = reply.send(result);
+    });
+}
+
+/// Save only the buffered audio to a WAV file without interrupting capture, replying with the
+/// written file name once done.
+async fn save_audio_only(
+    audio_encoder: &Mutex<AudioEncoder>,
+    reply: oneshot::Sender<Result<String, String>>,
+) -> Result<()> {
+    // Snapshot the buffer so capture can keep going while we decode and write
+    let (audio_buffer, audio_parameters, audio_time_base) = {
+        let audio_lock = audio_encoder.lock().await;
+        let encoder = audio_lock
+            .get_encoder()
+            .as_ref()
+            .context("Could not get audio encoder")?;
+        (
+            audio_lock.get_buffer().clone(),
+            ffmpeg::codec::Parameters::from(encoder),
+            encoder.time_base(),
+        )
+    };
+
+    let filename = claim_filename(&format!("audio_{}.wav", chrono::Local::now().timestamp()))?;
+    tokio::task::spawn_blocking(move || {
+        let result = save_audio_buffer(&filename, &audio_buffer, audio_parameters, audio_time_base)
+            .map(|_| filename)
+            .map_err(|e| e.to_string());
+
+        match &result {
+            Ok(filename) => debug!("Done saving audio to {}", filename),
+            Err(e) => error!("Could not save audio: {}", e),
+        }
+        let _ = reply.send(result);
+    });
+
+    Ok(())
+}
+
+/// Run `command` with `sh -c` and stream a clip into its stdin, replying with its exit status
+/// once it exits
+async fn save_to_command(
+    video_encoder: &Mutex<VideoEncoder>,
+    audio_encoder: &Mutex<AudioEncoder>,
+    saving: &AtomicBool,
+    config: &Arc<AppConfig>,
+    command: String,
+    reply: oneshot::Sender<Result<String, String>>,
+) -> Result<()> {
+    let snapshot = snapshot_buffers(video_encoder, audio_encoder, saving, config).await?;
+
+    tokio::task::spawn_blocking(move || {
+        let result = pipe_to_command(&command, &snapshot).map_err(|e| format!("{:#}", e));
+        match &result {
+            Ok(status) => info!("Saved clip to {:?}, it exited with {}", command, status),
+            Err(e) => error!("Could not save clip to {:?}: {}", command, e),
+        }
+        let _ = reply.send(result);
+    });
+
+    Ok(())
+}
+
+fn pipe_to_command(command: &str, snapshot: &ClipSnapshot) -> Result<String> {
+    let mut child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Could not run {:?}", command))?;
+    let stdin = child
+        .stdin
+        .take()
+        .context("Could not open the command's stdin")?;
+
+    // The pipe has to be closed, by dropping the output, before the command sees the end of
+    // the clip and exits
+    let muxed = PipeOutput::new(stdin, pipe_output::FORMAT).and_then(|mut pipe| {
+        mux_buffer(pipe.output(), command, snapshot, None, None).map_err(|e| pipe.explain(e))
+    });
+    let status = child.wait()?;
+    // A command that exits early breaks the pipe, its exit status usually tells why
+    muxed.with_context(|| format!("{:?} exited with {}", command, status))?;
+
+    Ok(status.to_string())
+}
+
+/// Diagnostics of a written clip
+pub struct SaveResult {
+    /// How much later the first audio packet was captured than the first video frame. Audio is
+    /// shifted by this much when it exceeds [`AV_SYNC_THRESHOLD_US`].
+    pub av_sync_offset_us: i64,
+
+    /// Capture time of the clip's first video frame in micro seconds
+    pub clip_start_us: i64,
+}
+
+fn save_buffer(
+    filename: &str,
+    snapshot: &ClipSnapshot,
+    title: Option<&str>,
+    description: Option<&str>,
+) -> Result<SaveResult> {
+    let mut output = ffmpeg::format::output(&filename)?;
+    mux_buffer(&mut output, filename, snapshot, title, description)
+}
+
+/// Mux the snapshot into `output`, `name` is what the clip is called in the logs. Empty metadata
+/// values are left out.
+fn mux_buffer(
+    output: &mut ffmpeg::format::context::Output,
+    name: &str,
+    snapshot: &ClipSnapshot,
+    title: Option<&str>,
+    description: Option<&str>,
+) -> Result<SaveResult> {
+    let video_buffer = &snapshot.video_buffer;
+    let audio_buffer = &snapshot.audio_buffer;
+
+    let video_codec = ffmpeg::codec::encoder::find(snapshot.video_parameters.id())
+        .context("Could not find expected video codec")?;
+
+    let config = &snapshot.config;
+    let mut video_stream = output.add_stream(video_codec)?;
+    video_stream.set_time_base(Rational::new(1, config.output_time_base_den as i32));
+    video_stream.set_parameters(snapshot.video_parameters.clone());
+
+    if config.vfr_output {
+        // Leave the frame rate unknown rather than a nominal one players would pace frames at
+        video_stream.set_avg_frame_rate(Rational::new(0, 1));
+        video_stream.set_rate(Rational::new(0, 1));
+    }
+
+    let audio_codec = ffmpeg::codec::encoder::find(snapshot.audio_parameters.id())
+        .context("Could not find expected audio codec")?;
+
+    let mut audio_stream = output.add_stream(audio_codec)?;
+    audio_stream.set_time_base(snapshot.audio_time_base);
+    audio_stream.set_parameters(snapshot.audio_parameters.clone());
+
+    let subtitle_stream = if snapshot.subtitles.is_empty() {
+        None
+    } else {
+        Some(SubtitleEncoder::add_stream(output)?)
+    };
+
+    let mut metadata = ffmpeg::Dictionary::new();
+    for (key, value) in [("title", title), ("description", description)] {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            metadata.set(key, value);
+        }
+    }
+    if metadata.iter().next().is_some() {
+        output.set_metadata(metadata);
+    }
+
+    let mut muxer_options = ffmpeg::Dictionary::new();
+    if config.vfr_output && output.format().name() == "mp4" {
+        // MP4 otherwise picks a track timescale of its own, keep the stream's precision
+        muxer_options.set(
+            "video_track_timescale",
+            &config.output_time_base_den.to_string(),
+        );
+    }
+    for (key, value) in &config.muxer_options {
+        muxer_options.set(key, value);
+    }
+
+    let unused_options = output.write_header_with(muxer_options)?;
+    for (key, value) in unused_options.iter() {
+        warn!(
+            "Muxer did not recognise option {}={}, ignoring it",
+            key, value
+        );
+    }
+
+    // Saves are refused before a full GOP is buffered, but don't fail obscurely if one gets here
+    let last_keyframe = video_buffer.get_last_gop_start().ok_or(BufferNotReady)?;
+    let end_dts = snapshot.end_dts.unwrap_or(*last_keyframe);
+
+    let newest_video_pts = video_buffer
+        .get_frames()
+        .range(..=end_dts)
+        .map(|(_, frame_data)| *frame_data.get_pts())
+        .max()
+        .context("Could not get last video frame")?;
+
+    let oldest_capture_time = audio_buffer.get_capture_times();
+
+    // If video starts before audio try and catch up as much as possible
+    // (At worst a gap of one `audio_frame_ms` frame)
+    let retained_frames: Vec<_> = video_buffer
+        .get_frames()
+        .range(..=end_dts)
+        .filter(|(_, frame_data)| {
+            oldest_capture_time
+                .first()
+                .map_or(true, |oldest| frame_data.get_pts() >= oldest)
+        })
+        .collect();
+
+    // With B-frames the first frame decoded isn't the first one shown, go by the earliest
+    // presentation time so the clip doesn't start with a gap before its first frame
+    let first_pts_offset = match retained_frames
+        .iter()
+        .map(|(_, frame_data)| *frame_data.get_pts())
+        .min()
+    {
+        Some(pts) => pts,
+        None => video_buffer
+            .oldest_pts()
+            .context("Could not get oldest pts when muxing.")?,
+    };
+    let timeline_start = match config.first_frame_offset {
+        FirstFrameOffset::Zero => first_pts_offset,
+        FirstFrameOffset::Preserve => retained_frames
+            .first()
+            .map_or(first_pts_offset, |(dts, _)| first_pts_offset.min(**dts)),
+    };
+
+    // Collect video packets, keyed by DTS in micro seconds
+    let mut video_packets = Vec::new();
+    for (dts, frame_data) in retained_frames {
+        let pts_offset = frame_data.get_pts() - timeline_start;
+        let dts_offset = dts - timeline_start;
+
+        debug!(
+            "Capture Timestamp: {:?}, PTS offset: {:?}",
+            frame_data.get_pts(),
+            pts_offset
+        );
+
+        let mut packet = ffmpeg::codec::packet::Packet::copy(frame_data.get_raw_bytes());
+        packet.set_pts(Some(pts_offset));
+        packet.set_dts(Some(dts_offset));
+
+        packet.set_stream(VIDEO_STREAM);
+
+        video_packets.push((*dts, packet));
+    }
+
+    if config.vfr_output {
+        set_frame_durations(&mut video_packets);
+    }
+
+    // The muxer may not have kept the encoder's time base for the stream
+    let video_stream_time_base = output
+        .stream(VIDEO_STREAM)
+        .context("Could not get video stream")?
+        .time_base();
+    let moved = rescale_video_packets(
+        &mut video_packets,
+        snapshot.video_time_base,
+        video_stream_time_base,
+    );
+    if moved > 0 {
+        warn!(
+            "Video time base {} is coarser than the frame interval, moved {} DTS",
+            video_stream_time_base, moved
+        );
+    }
+
+    // Collect audio packets, keyed by capture time in micro seconds. Audio is buffered before
+    // video starts streaming, so the timeline starts at the first packet kept rather than the
+    // oldest one buffered.
+    let mut oldest_frame_offset = None;
+    let mut first_audio_capture_time = None;
+    let mut audio_packets = Vec::new();
+    for (iter, (pts, frame)) in audio_buffer.get_frames().iter().enumerate() {
+        // Don't write any more audio if we would exceed video (clip to max video)
+        if oldest_capture_time[iter] > newest_video_pts {
+            debug!(
+                "Oldest capture time {:?}, in time scale: {:?}",
+                oldest_capture_time[iter], pts
+            );
+            break;
+        }
+        // If audio starts before video try and catch up as much as possible
+        // (At worst a gap of one `audio_frame_ms` frame)
+        if &oldest_capture_time[iter] < &first_pts_offset {
+            debug!("Audio timestamp is before video, skipping writing this one");
             debug!(
                 "Capture time: {:?}, Video time: {:?}",
                 oldest_capture_time[iter], first_pts_offset
             );
+            continue;
         }
 
-        let offset = pts - oldest_frame_offset;
+        let offset = pts - *oldest_frame_offset.get_or_insert(*pts);
+        first_audio_capture_time.get_or_insert(oldest_capture_time[iter]);
 
         debug!(
             "PTS IN MICROS: {:?}, PTS IN TIME SCALE: {:?}",
@@ -394,15 +2578,350 @@ fn save_buffer(
 
         packet.set_stream(AUDIO_STREAM);
 
-        packet
-            .write_interleaved(&mut output)
-            .expect("Could not write audio interleaved");
+        audio_packets.push((oldest_capture_time[iter], packet));
+    }
+
+    // Both streams start at 0 but the PipeWire streams don't deliver their first packets at the
+    // same time, shift audio so it lines up with video again
+    let av_sync_offset_us = match first_audio_capture_time {
+        Some(first_audio_capture_time) => first_audio_capture_time - first_pts_offset,
+        None => {
+            warn!("No audio captured during {}, it gets silence instead", name);
+            0
+        }
+    };
+    info!("A/V sync offset of {}: {}us", name, av_sync_offset_us);
+
+    // Audio starts at 0 along with the first frame shown, which is later than 0 when the
+    // reorder delay is preserved
+    let mut audio_shift_us = first_pts_offset - timeline_start;
+    if av_sync_offset_us.abs() > AV_SYNC_THRESHOLD_US {
+        audio_shift_us += av_sync_offset_us;
+    }
+    if audio_shift_us != 0 {
+        let shift = audio_shift_us.rescale(
+            Rational::new(1, ONE_MICROS as i32),
+            snapshot.audio_time_base,
+        );
+        for (_, packet) in audio_packets.iter_mut() {
+            packet.set_pts(packet.pts().map(|pts| pts + shift));
+            packet.set_dts(packet.dts().map(|dts| dts + shift));
+        }
+    }
+
+    // The audio stream can stop short of the last frame, make the difference visible in the log
+    let micros = Rational::new(1, ONE_MICROS as i32);
+    let audio_frame_us = (config.audio_frame_ms * 1000.0) as i64;
+    let video_end_us = newest_video_pts - timeline_start;
+    let audio_end_us = audio_packets
+        .last()
+        .and_then(|(_, packet)| packet.pts())
+        .map_or(0, |pts| {
+            pts.rescale(snapshot.audio_time_base, micros) + audio_frame_us
+        });
+    info!(
+        "A/V duration delta of {}: {}us (video {}us, audio {}us)",
+        name,
+        video_end_us - audio_end_us,
+        video_end_us,
+        audio_end_us
+    );
+
+    let missing_frames = (video_end_us - audio_end_us) / audio_frame_us;
+    // Without any audio the whole clip is padded, players handle a silent track better than an
+    // empty one
+    let pad_silence = config.short_audio == ShortAudio::PadSilence || audio_packets.is_empty();
+    if pad_silence && missing_frames > 0 {
+        match AudioEncoder::encode_silence(missing_frames as usize, config) {
+            Ok(silence) => {
+                debug!("Padding {} with {} frames of silence", name, silence.len());
+                for (index, data) in silence.iter().enumerate() {
+                    let start_us = audio_end_us + index as i64 * audio_frame_us;
+                    let pts = start_us.rescale(micros, snapshot.audio_time_base);
+
+                    let mut packet = ffmpeg::codec::packet::Packet::copy(data);
+                    packet.set_pts(Some(pts));
+                    packet.set_dts(Some(pts));
+                    packet.set_stream(AUDIO_STREAM);
+
+                    // Keyed like the video packets so the merge below slots them in at the end
+                    audio_packets.push((timeline_start + start_us, packet));
+                }
+            }
+            Err(e) => warn!("Could not pad the audio of {} with silence: {:?}", name, e),
+        }
+    }
+
+    let mut subtitle_packets = match subtitle_stream {
+        Some(index) => {
+            let time_base = output
+                .stream(index)
+                .context("Could not get subtitle stream")?
+                .time_base();
+            SubtitleEncoder::packets(
+                &snapshot.subtitles,
+                timeline_start,
+                newest_video_pts,
+                index,
+                time_base,
+            )
+        }
+        None => Vec::new(),
+    }
+    .into_iter()
+    .peekable();
+
+    // Both sequences are already sorted, merge them so the muxer receives packets in timestamp
+    // order instead of having to buffer the whole video stream before any audio shows up
+    debug!(
+        "INTERLEAVED SAVE START: {} video, {} audio packets",
+        video_packets.len(),
+        audio_packets.len()
+    );
+    let mut video_packets = video_packets.into_iter().peekable();
+    let mut audio_packets = audio_packets.into_iter().peekable();
+    loop {
+        let next = match (video_packets.peek(), audio_packets.peek()) {
+            (Some((video_time, _)), Some((audio_time, _))) if audio_time < video_time => {
+                audio_packets.next()
+            }
+            (Some(_), _) => video_packets.next(),
+            (None, _) => audio_packets.next(),
+        };
+
+        let Some((time, mut packet)) = next else {
+            break;
+        };
+
+        // Subtitles are sparse, slot each one in before the first packet that comes after it
+        while let Some((_, mut subtitle)) =
+            subtitle_packets.next_if(|(subtitle_time, _)| *subtitle_time <= time)
+        {
+            subtitle.write_interleaved(output)?;
+        }
+
+        packet.write_interleaved(output)?;
+    }
+    for (_, mut subtitle) in subtitle_packets {
+        subtitle.write_interleaved(output)?;
+    }
+    debug!("INTERLEAVED SAVE END");
+
+    output.write_trailer()?;
+
+    Ok(SaveResult {
+        av_sync_offset_us,
+        clip_start_us: first_pts_offset,
+    })
+}
+
+/// Give each video packet the time until the next frame is shown as its duration, so the clip
+/// keeps the real capture intervals, the last frame included. Packets are in decode order.
+fn set_frame_durations(packets: &mut [(i64, ffmpeg::Packet)]) {
+    let mut presentation_times: Vec<i64> = packets
+        .iter()
+        .filter_map(|(_, packet)| packet.pts())
+        .collect();
+    presentation_times.sort_unstable();
+
+    // Nothing comes after the last frame, assume it lasts as long as the one before it
+    let last_duration = match presentation_times[..] {
+        [.., previous, last] => last - previous,
+        _ => 0,
+    };
+
+    for (_, packet) in packets.iter_mut() {
+        let Some(pts) = packet.pts() else {
+            continue;
+        };
+        let next = presentation_times.partition_point(|time| *time <= pts);
+        let duration = presentation_times
+            .get(next)
+            .map_or(last_duration, |next_pts| next_pts - pts);
+        packet.set_duration(duration);
+    }
+}
+
+/// Rescale the timestamps of video packets in decode order from `from` to `to`. A time base
+/// coarser than the frame interval rounds neighbouring frames to the same DTS, which muxers reject,
+/// so each DTS is moved past the one before it and its PTS kept at or after it.
+///
+/// Returns how many DTS had to be moved.
+fn rescale_video_packets(
+    packets: &mut [(i64, ffmpeg::Packet)],
+    from: Rational,
+    to: Rational,
+) -> usize {
+    let mut previous_dts = None;
+    let mut moved = 0;
+    for (_, packet) in packets.iter_mut() {
+        packet.rescale_ts(from, to);
+        let Some(mut dts) = packet.dts() else {
+            continue;
+        };
+        if let Some(previous) = previous_dts.filter(|previous| dts <= *previous) {
+            dts = previous + 1;
+            packet.set_dts(Some(dts));
+            moved += 1;
+        }
+        if packet.pts().is_some_and(|pts| pts < dts) {
+            packet.set_pts(Some(dts));
+        }
+        previous_dts = Some(dts);
+    }
+    moved
+}
+
+/// Decode the buffered audio and write it out losslessly as `pcm_f32le` in a WAV container.
+fn save_audio_buffer(
+    filename: &str,
+    audio_buffer: &AudioBuffer,
+    audio_parameters: ffmpeg::codec::Parameters,
+    audio_time_base: Rational,
+) -> Result<()> {
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(audio_parameters)?
+        .decoder()
+        .audio()?;
+
+    let pcm_codec = ffmpeg::codec::encoder::find(ffmpeg::codec::Id::PCM_F32LE)
+        .context("Could not find pcm_f32le encoder")?;
+    let mut pcm_ctx = ffmpeg::codec::context::Context::new_with_codec(pcm_codec)
+        .encoder()
+        .audio()?;
+
+    let pcm_format = ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Packed);
+    pcm_ctx.set_rate(decoder.rate() as i32);
+    pcm_ctx.set_channel_layout(decoder.channel_layout());
+    pcm_ctx.set_format(pcm_format);
+    pcm_ctx.set_time_base(Rational::new(1, decoder.rate() as i32));
+    let mut pcm_encoder = pcm_ctx.open()?;
 
-        iter += 1;
+    let mut output = ffmpeg::format::output(&filename)?;
+    let mut audio_stream = output.add_stream(pcm_codec)?;
+    audio_stream.set_time_base(pcm_encoder.time_base());
+    audio_stream.set_parameters(&pcm_encoder);
+
+    output.write_header()?;
+
+    let oldest_pts = audio_buffer
+        .oldest_pts()
+        .context("Could not get oldest chunk")?;
+
+    let mut resampler: Option<ffmpeg::software::resampling::Context> = None;
+    let mut decoded = ffmpeg::frame::Audio::empty();
+
+    debug!("AUDIO ONLY SAVE START");
+    for (pts, frame) in audio_buffer.get_frames() {
+        // Decoded frames are passed on to the PCM encoder as they are, count in its samples
+        let pts = (pts - oldest_pts).rescale(audio_time_base, pcm_encoder.time_base());
+        let mut packet = ffmpeg::codec::packet::Packet::copy(frame);
+        packet.set_pts(Some(pts));
+        packet.set_dts(Some(pts));
+        decoder.send_packet(&packet)?;
+
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            // Opus decodes to planar samples, convert them to what the PCM encoder takes
+            let resampler = match resampler {
+                Some(ref mut resampler) => resampler,
+                None => resampler.insert(ffmpeg::software::resampling::Context::get(
+                    decoded.format(),
+                    decoded.channel_layout(),
+                    decoded.rate(),
+                    pcm_format,
+                    decoded.channel_layout(),
+                    decoded.rate(),
+                )?),
+            };
+
+            let mut converted = ffmpeg::frame::Audio::empty();
+            resampler.run(&decoded, &mut converted)?;
+            converted.set_pts(decoded.pts());
+
+            pcm_encoder.send_frame(&converted)?;
+            write_encoded_audio(&mut pcm_encoder, &mut output)?;
+        }
     }
-    debug!("AUDIO SAVE END");
+
+    pcm_encoder.send_eof()?;
+    write_encoded_audio(&mut pcm_encoder, &mut output)?;
+    debug!("AUDIO ONLY SAVE END");
 
     output.write_trailer()?;
 
     Ok(())
 }
+
+fn write_encoded_audio(
+    encoder: &mut ffmpeg::codec::encoder::Audio,
+    output: &mut ffmpeg::format::context::Output,
+) -> Result<()> {
+    let stream_time_base = output
+        .stream(0)
+        .context("Could not get audio stream")?
+        .time_base();
+
+    let mut packet = ffmpeg::codec::packet::Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.rescale_ts(encoder.time_base(), stream_time_base);
+        packet.set_stream(0);
+        packet.write_interleaved(output)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Video packets at the given (DTS, PTS) in micro seconds, in decode order
+    fn video_packets(timestamps: &[(i64, i64)]) -> Vec<(i64, ffmpeg::Packet)> {
+        timestamps
+            .iter()
+            .map(|&(dts, pts)| {
+                let mut packet = ffmpeg::Packet::empty();
+                packet.set_dts(Some(dts));
+                packet.set_pts(Some(pts));
+                (dts, packet)
+            })
+            .collect()
+    }
+
+    fn timestamps(packets: &[(i64, ffmpeg::Packet)]) -> Vec<(i64, i64)> {
+        packets
+            .iter()
+            .map(|(_, packet)| (packet.dts().unwrap(), packet.pts().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn rescale_video_packets_keeps_a_fine_time_base_as_is() {
+        let mut packets = video_packets(&[(0, 16_000), (16_000, 50_000), (33_000, 33_000)]);
+
+        let moved = rescale_video_packets(
+            &mut packets,
+            Rational::new(1, ONE_MICROS as i32),
+            Rational::new(1, 90_000),
+        );
+        assert_eq!(moved, 0);
+        assert_eq!(
+            timestamps(&packets),
+            vec![(0, 1_440), (1_440, 4_500), (2_970, 2_970)]
+        );
+    }
+
+    #[test]
+    fn rescale_video_packets_keeps_dts_increasing_in_a_coarse_time_base() {
+        // Frames 4ms apart in a 10ms time base round onto each other
+        let mut packets =
+            video_packets(&[(0, 0), (4_000, 4_000), (8_000, 8_000), (12_000, 12_000)]);
+
+        let moved = rescale_video_packets(
+            &mut packets,
+            Rational::new(1, ONE_MICROS as i32),
+            Rational::new(1, 100),
+        );
+        assert_eq!(moved, 3);
+        assert_eq!(timestamps(&packets), vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+    }
+}
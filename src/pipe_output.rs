@@ -0,0 +1,139 @@
+use std::{
+    ffi::{c_int, c_void, CString},
+    io::{self, Write},
+    ptr,
+};
+
+use anyhow::{bail, Result};
+use ffmpeg_next::{self as ffmpeg, format::context::Output};
+
+/// Size of the buffer FFmpeg collects muxed data in before handing it to the pipe
+const IO_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Container clips are streamed in, MP4 needs to seek back into what it wrote
+pub const FORMAT: &str = "matroska";
+
+/// A muxer writing into a pipe through a custom `AVIOContext`, e.g. the stdin of a child process
+/// so a clip can be handed to `rclone rcat` without a temporary file.
+///
+/// Dropping it closes the pipe, which tells the reader the stream is complete.
+pub struct PipeOutput {
+    output: Output,
+
+    /// Boxed so the pointer FFmpeg holds to it stays valid when `PipeOutput` moves
+    writer: Box<PipeWriter>,
+}
+
+struct PipeWriter {
+    pipe: Box<dyn Write + Send>,
+
+    /// Why the last write failed, FFmpeg only passes an error code along
+    error: Option<io::Error>,
+}
+
+impl PipeOutput {
+    pub fn new(pipe: impl Write + Send + 'static, format: &str) -> Result<Self> {
+        let format_name = CString::new(format)?;
+        let mut writer = Box::new(PipeWriter {
+            pipe: Box::new(pipe),
+            error: None,
+        });
+
+        unsafe {
+            let mut ctx = ptr::null_mut();
+            let ret = ffmpeg::ffi::avformat_alloc_output_context2(
+                &mut ctx,
+                ptr::null(),
+                format_name.as_ptr(),
+                ptr::null(),
+            );
+            if ret < 0 || ctx.is_null() {
+                bail!(
+                    "Could not create a {} muxer: {}",
+                    format,
+                    ffmpeg::Error::from(ret)
+                );
+            }
+            let mut output = Output::wrap(ctx);
+
+            let buffer = ffmpeg::ffi::av_malloc(IO_BUFFER_SIZE) as *mut u8;
+            if buffer.is_null() {
+                bail!(ffmpeg::Error::from(ffmpeg::ffi::AVERROR(
+                    ffmpeg::util::error::ENOMEM
+                )));
+            }
+            let pb = ffmpeg::ffi::avio_alloc_context(
+                buffer,
+                IO_BUFFER_SIZE as c_int,
+                1,
+                writer.as_mut() as *mut PipeWriter as *mut c_void,
+                None,
+                Some(write_packet),
+                None,
+            );
+            if pb.is_null() {
+                ffmpeg::ffi::av_free(buffer as *mut c_void);
+                bail!(ffmpeg::Error::from(ffmpeg::ffi::AVERROR(
+                    ffmpeg::util::error::ENOMEM
+                )));
+            }
+
+            let ctx = output.as_mut_ptr();
+            (*ctx).pb = pb;
+            (*ctx).flags |= ffmpeg::ffi::AVFMT_FLAG_CUSTOM_IO as c_int;
+
+            Ok(Self { output, writer })
+        }
+    }
+
+    pub fn output(&mut self) -> &mut Output {
+        &mut self.output
+    }
+
+    /// Whether the last write failed because the reader closed its end of the pipe
+    pub fn reader_closed(&self) -> bool {
+        self.writer
+            .error
+            .as_ref()
+            .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+    }
+
+    /// Turn an error from muxing into the reason writing to the pipe failed, if that's what
+    /// caused it, e.g. the command exiting before it read the whole clip
+    pub fn explain(&mut self, error: anyhow::Error) -> anyhow::Error {
+        match self.writer.error.take() {
+            Some(io_error) => error.context(io_error),
+            None => error,
+        }
+    }
+}
+
+impl Drop for PipeOutput {
+    fn drop(&mut self) {
+        // `Output` closes its `pb` as if FFmpeg had opened it, which would treat the writer as
+        // a URLContext. Free the custom context here first so it only frees the muxer.
+        unsafe {
+            let ctx = self.output.as_mut_ptr();
+            let mut pb = (*ctx).pb;
+            (*ctx).pb = ptr::null_mut();
+            if !pb.is_null() {
+                ffmpeg::ffi::avio_flush(pb);
+                ffmpeg::ffi::av_freep(&mut (*pb).buffer as *mut *mut u8 as *mut c_void);
+                ffmpeg::ffi::avio_context_free(&mut pb);
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn write_packet(opaque: *mut c_void, buf: *const u8, buf_size: c_int) -> c_int {
+    let writer = &mut *(opaque as *mut PipeWriter);
+    let data = std::slice::from_raw_parts(buf, buf_size.max(0) as usize);
+    match writer.pipe.write_all(data) {
+        Ok(()) => buf_size,
+        Err(e) => {
+            let code = ffmpeg::ffi::AVERROR(e.raw_os_error().unwrap_or(ffmpeg::util::error::EIO));
+            writer.error = Some(e);
+            code
+        }
+    }
+}
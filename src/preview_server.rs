@@ -0,0 +1,105 @@
+use std::{convert::Infallible, sync::Arc};
+
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, info};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+use crate::encoders::audio_analyzer::{AudioSpectrum, ANALYZE_INTERVAL};
+
+const BOUNDARY: &str = "frame";
+
+#[derive(Clone)]
+struct PreviewState {
+    frames_tx: broadcast::Sender<Bytes>,
+    audio_spectrum: Arc<AudioSpectrum>,
+}
+
+/// Serve the JPEG frames broadcast on `frames_tx` as a `multipart/x-mixed-replace` stream at `/`.
+///
+/// Any browser or player that understands MJPEG over HTTP can open it. The audio spectrum is sent
+/// as a JSON array of bins over a WebSocket at `/spectrum` every time it's recomputed.
+pub async fn serve(
+    address: String,
+    frames_tx: broadcast::Sender<Bytes>,
+    audio_spectrum: Arc<AudioSpectrum>,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(&address).await?;
+    info!("Serving preview stream on http://{}/", address);
+
+    let app = Router::new()
+        .route("/", get(stream))
+        .route("/spectrum", get(spectrum))
+        .with_state(PreviewState {
+            frames_tx,
+            audio_spectrum,
+        });
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+async fn stream(State(state): State<PreviewState>) -> impl IntoResponse {
+    // Clients that can't keep up just skip the frames they missed
+    let parts = BroadcastStream::new(state.frames_tx.subscribe())
+        .filter_map(|frame| frame.ok())
+        .map(|jpeg| Ok::<_, Infallible>(multipart_part(&jpeg)));
+
+    Response::builder()
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/x-mixed-replace; boundary={}", BOUNDARY),
+        )
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from_stream(parts))
+        .unwrap()
+}
+
+async fn spectrum(ws: WebSocketUpgrade, State(state): State<PreviewState>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| send_spectrum(socket, state.audio_spectrum))
+}
+
+async fn send_spectrum(mut socket: WebSocket, audio_spectrum: Arc<AudioSpectrum>) {
+    let mut interval = tokio::time::interval(ANALYZE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let bins = match serde_json::to_string(&audio_spectrum.get()) {
+            Ok(bins) => bins,
+            Err(e) => {
+                debug!("Could not serialise the audio spectrum: {:?}", e);
+                continue;
+            }
+        };
+        if socket.send(Message::Text(bins.into())).await.is_err() {
+            debug!("Spectrum client disconnected");
+            break;
+        }
+    }
+}
+
+fn multipart_part(jpeg: &[u8]) -> Bytes {
+    let header = format!(
+        "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+        BOUNDARY,
+        jpeg.len()
+    );
+
+    let mut part = BytesMut::with_capacity(header.len() + jpeg.len() + 2);
+    part.put_slice(header.as_bytes());
+    part.put_slice(jpeg);
+    part.put_slice(b"\r\n");
+    part.freeze()
+}
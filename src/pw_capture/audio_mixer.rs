@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+
+/// Interleaved samples per second of the mixed format, 48kHz stereo
+const SAMPLES_PER_SECOND: i64 = 48_000 * 2;
+
+/// How many interleaved samples one source may run ahead before the sources behind it are treated
+/// as silent, 100ms of 48kHz stereo
+const MAX_LAG_SAMPLES: usize = SAMPLES_PER_SECOND as usize / 10;
+
+/// Samples of one source waiting to be mixed
+#[derive(Clone, Default)]
+struct SourceQueue {
+    samples: VecDeque<f32>,
+
+    /// Capture time in micro seconds of the first queued sample
+    start_us: i64,
+}
+
+/// Sums the samples of several audio streams running on the same loop.
+///
+/// Streams deliver their buffers independently, so samples are queued per source until every
+/// source has caught up. A source that stops sending, e.g. an application that went quiet, is
+/// padded with silence once another one is `MAX_LAG_SAMPLES` ahead.
+pub struct AudioMixer {
+    queues: Vec<SourceQueue>,
+}
+
+impl AudioMixer {
+    pub fn new(sources: usize) -> Self {
+        Self {
+            queues: vec![SourceQueue::default(); sources],
+        }
+    }
+
+    /// Queue the interleaved `samples` of `source` captured at `timestamp_us`, returning the next
+    /// mixed samples if any are ready. With a single source its samples are returned straight
+    /// away.
+    ///
+    /// Mixed samples come with the capture time of the earliest sample in them, which is that of
+    /// the first buffer contributing to the mix rather than the one completing it.
+    pub fn push(
+        &mut self,
+        source: usize,
+        samples: &[f32],
+        timestamp_us: i64,
+    ) -> Option<(Vec<f32>, i64)> {
+        let queue = &mut self.queues[source];
+        if queue.samples.is_empty() {
+            queue.start_us = timestamp_us;
+        }
+        queue.samples.extend(samples);
+
+        let shortest = self.queues.iter().map(|queue| queue.samples.len()).min()?;
+        let longest = self.queues.iter().map(|queue| queue.samples.len()).max()?;
+        let len = if longest > MAX_LAG_SAMPLES {
+            longest
+        } else {
+            shortest
+        };
+        if len == 0 {
+            return None;
+        }
+
+        let timestamp_us = self
+            .queues
+            .iter()
+            .filter(|queue| !queue.samples.is_empty())
+            .map(|queue| queue.start_us)
+            .min()?;
+
+        let mut mixed = vec![0.0; len];
+        for queue in &mut self.queues {
+            let available = len.min(queue.samples.len());
+            for (mixed, sample) in mixed.iter_mut().zip(queue.samples.drain(..available)) {
+                *mixed += sample;
+            }
+            // What's left over was captured after the samples just mixed
+            queue.start_us += available as i64 * 1_000_000 / SAMPLES_PER_SECOND;
+        }
+
+        // Several loud sources can add up past full scale
+        for sample in &mut mixed {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+
+        Some((mixed, timestamp_us))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_source_passes_through() {
+        let mut mixer = AudioMixer::new(1);
+
+        assert_eq!(
+            mixer.push(0, &[0.25, -0.5], 1_000),
+            Some((vec![0.25, -0.5], 1_000))
+        );
+        assert_eq!(mixer.push(0, &[], 2_000), None);
+    }
+
+    #[test]
+    fn mix_is_timestamped_from_the_first_contributing_buffer() {
+        let mut mixer = AudioMixer::new(2);
+
+        assert_eq!(mixer.push(0, &[0.25; 4], 1_000), None);
+        let (mixed, timestamp) = mixer.push(1, &[0.5; 2], 1_500).unwrap();
+        assert_eq!(mixed, vec![0.75; 2]);
+        assert_eq!(timestamp, 1_000);
+
+        // Two samples of source 0 are left, captured after the two just mixed
+        let (mixed, timestamp) = mixer.push(1, &[0.5; 2], 3_000).unwrap();
+        assert_eq!(mixed, vec![0.75; 2]);
+        assert_eq!(timestamp, 1_000 + 2 * 1_000_000 / SAMPLES_PER_SECOND);
+    }
+
+    #[test]
+    fn lagging_source_is_padded_with_silence() {
+        let mut mixer = AudioMixer::new(2);
+
+        let (mixed, timestamp) = mixer.push(0, &vec![0.5; MAX_LAG_SAMPLES + 2], 0).unwrap();
+        assert_eq!(mixed.len(), MAX_LAG_SAMPLES + 2);
+        assert!(mixed.iter().all(|&sample| sample == 0.5));
+        assert_eq!(timestamp, 0);
+    }
+
+    #[test]
+    fn loud_sources_are_clipped_to_full_scale() {
+        let mut mixer = AudioMixer::new(2);
+
+        mixer.push(0, &[0.75, -0.75], 0);
+        let (mixed, _) = mixer.push(1, &[0.75, -0.75], 0).unwrap();
+        assert_eq!(mixed, vec![1.0, -1.0]);
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
+    cell::{Cell, RefCell},
     process::Command,
+    rc::Rc,
     sync::{atomic::AtomicBool, Arc},
-    time::SystemTime,
 };
 
 use anyhow::Result;
@@ -21,33 +22,98 @@ use pipewire::{
 };
 use tokio::sync::mpsc;
 
-use crate::{RawAudioFrame, Terminate};
+use crate::{application_config::SampleFormat, dbus::StatusUpdate, RawAudioFrame, Terminate};
 
-#[derive(Clone, Copy)]
+use super::{
+    audio_mixer::AudioMixer, mic_agc::MicAgc, noise_gate::NoiseGate, stream_status,
+    timestamp_synchronizer::TimestampSynchronizer,
+};
+
+/// Name of the capture streams, which PipeWire also uses as their `node.name`
+pub const STREAM_NAME: &str = "auto-screen-recorder-audio";
+
+/// Format every source is converted to when several are mixed, what the audio encoder takes
+const MIX_RATE: i32 = 48_000;
+const MIX_CHANNELS: i32 = 2;
+
+#[derive(Clone)]
 struct UserData {
     audio_format: spa::param::audio::AudioInfoRaw,
+
+    /// Applied to the captured samples when recording the microphone
+    noise_gate: Option<NoiseGate>,
+
+    /// Applied after the noise gate when recording the microphone
+    mic_agc: Option<MicAgc>,
 }
 
 impl Default for UserData {
     fn default() -> Self {
         Self {
             audio_format: Default::default(),
+            noise_gate: None,
+            mic_agc: None,
         }
     }
 }
 
 pub struct AudioCapture;
 
+/// A node an audio stream is connected to
+enum AudioTarget {
+    /// A node by id
+    Node(u32),
+
+    /// A node by `node.name`, or any other value PipeWire accepts as `target.object`
+    Name(String),
+}
+
+impl AudioTarget {
+    fn parse(source: &str) -> Self {
+        match source.parse() {
+            Ok(id) => Self::Node(id),
+            Err(_) => Self::Name(source.to_string()),
+        }
+    }
+}
+
+/// What the callbacks of every audio stream share
+#[derive(Clone)]
+struct StreamContext {
+    process_audio_channel: mpsc::Sender<RawAudioFrame>,
+    video_ready: Arc<AtomicBool>,
+    audio_ready: Arc<AtomicBool>,
+    timestamps: Arc<TimestampSynchronizer>,
+    saving: Arc<AtomicBool>,
+    node_latency: String,
+    status_tx: mpsc::UnboundedSender<StatusUpdate>,
+    prebuffer: bool,
+    sample_format: SampleFormat,
+    mixer: Rc<RefCell<AudioMixer>>,
+
+    /// Number of streams currently streaming, audio is ready while any is
+    streaming: Rc<Cell<usize>>,
+}
+
 impl AudioCapture {
+    /// Capture `sources` mixed together, each a node id or name. Without any sources the default
+    /// sink is captured, or the microphone with `use_mic`.
     pub fn run(
         stream_node: u32,
         process_audio_channel: mpsc::Sender<RawAudioFrame>,
         video_ready: Arc<AtomicBool>,
         audio_ready: Arc<AtomicBool>,
         use_mic: bool,
-        start_time: SystemTime,
+        sources: Vec<String>,
+        timestamps: Arc<TimestampSynchronizer>,
         termination_recv: pw::channel::Receiver<Terminate>,
         saving: Arc<AtomicBool>,
+        node_latency: String,
+        status_tx: mpsc::UnboundedSender<StatusUpdate>,
+        noise_gate: Option<NoiseGate>,
+        mic_agc: Option<MicAgc>,
+        prebuffer: bool,
+        sample_format: SampleFormat,
     ) -> Result<(), pw::Error> {
         let pw_loop = MainLoop::new(None)?;
         let terminate_loop = pw_loop.clone();
@@ -67,28 +133,102 @@ impl AudioCapture {
             .done(|d, _| info!("DONE: {0}", d))
             .register();
 
-        let data = UserData::default();
+        let targets = if sources.is_empty() {
+            let sink_id_to_use = if !use_mic {
+                get_default_sink_node_id()
+            } else {
+                Some(stream_node)
+            };
+            debug!("Default sink id: {:?}", sink_id_to_use);
+            vec![sink_id_to_use.map(AudioTarget::Node)]
+        } else {
+            sources
+                .iter()
+                .map(|source| Some(AudioTarget::parse(source)))
+                .collect()
+        };
 
-        // Audio Stream
-        let audio_stream = pw::stream::Stream::new(
-            &audio_core,
-            "auto-screen-recorder-audio",
-            properties! {
+        let context = StreamContext {
+            process_audio_channel,
+            video_ready,
+            audio_ready,
+            timestamps,
+            saving,
+            node_latency,
+            status_tx,
+            prebuffer,
+            sample_format,
+            mixer: Rc::new(RefCell::new(AudioMixer::new(targets.len()))),
+            streaming: Rc::new(Cell::new(0)),
+        };
+
+        // Sources can have different native formats, they need to match to be mixed
+        let fixed_format = targets.len() > 1;
+        let mic_only = use_mic && sources.is_empty();
+        let data = UserData {
+            noise_gate: noise_gate.filter(|_| mic_only),
+            mic_agc: mic_agc.filter(|_| mic_only),
+            ..Default::default()
+        };
+
+        // Streams stop once dropped so keep them around until the loop quits
+        let mut streams = Vec::with_capacity(targets.len());
+        for (index, target) in targets.into_iter().enumerate() {
+            streams.push(Self::connect_stream(
+                &audio_core,
+                index,
+                target,
+                fixed_format,
+                data.clone(),
+                context.clone(),
+            )?);
+        }
+
+        pw_loop.run();
+        Ok(())
+    }
+
+    fn connect_stream(
+        audio_core: &pw::core::Core,
+        index: usize,
+        target: Option<AudioTarget>,
+        fixed_format: bool,
+        data: UserData,
+        context: StreamContext,
+    ) -> Result<(pw::stream::Stream, pw::stream::StreamListener<UserData>), pw::Error> {
+        let mut audio_properties = properties! {
             *pw::keys::MEDIA_TYPE => "Audio",
             *pw::keys::MEDIA_CATEGORY => "Capture",
             *pw::keys::MEDIA_ROLE => "Music",
-            *pw::keys::NODE_LATENCY => "1024/48000",
-            },
-        )?;
+            *pw::keys::NODE_LATENCY => context.node_latency.clone(),
+        };
+        if let Some(AudioTarget::Name(name)) = &target {
+            // `pw::keys::TARGET_OBJECT` is behind a feature of the bindings
+            audio_properties.insert("target.object", name.as_str());
+        }
 
-        let _audio_stream_shared_data_listener = audio_stream
+        // Audio Stream
+        let audio_stream = pw::stream::Stream::new(audio_core, STREAM_NAME, audio_properties)?;
+
+        let state_context = context.clone();
+        let audio_stream_shared_data_listener = audio_stream
             .add_local_listener_with_user_data(data)
             .state_changed(move |_, _, old, new| {
-                debug!("Audio Stream State Changed: {0:?} -> {1:?}", old, new);
-                audio_ready.store(
-                    new == StreamState::Streaming,
-                    std::sync::atomic::Ordering::Release,
+                debug!(
+                    "Audio Stream {0} State Changed: {1:?} -> {2:?}",
+                    index, old, new
                 );
+                let _ = state_context.status_tx.send(stream_status("audio", &new));
+
+                let streaming = &state_context.streaming;
+                if new == StreamState::Streaming {
+                    streaming.set(streaming.get() + 1);
+                } else if old == StreamState::Streaming {
+                    streaming.set(streaming.get().saturating_sub(1));
+                }
+                state_context
+                    .audio_ready
+                    .store(streaming.get() > 0, std::sync::atomic::Ordering::Release);
             })
             .param_changed(|_, udata, id, param| {
                 let Some(param) = param else {
@@ -121,12 +261,18 @@ impl AudioCapture {
                     udata.audio_format.format().as_raw()
                 );
             })
-            .process(move |stream, _| match stream.dequeue_buffer() {
+            .process(move |stream, udata| match stream.dequeue_buffer() {
                 None => debug!("Out of audio buffers"),
                 Some(mut buffer) => {
-                    // Wait until video is streaming before we try to process
-                    if !video_ready.load(std::sync::atomic::Ordering::Acquire)
-                        || saving.load(std::sync::atomic::Ordering::Acquire)
+                    // Unless pre-buffering, wait until video is streaming before we try to
+                    // process. Both share `timestamps` so pre-buffered audio lines up with video
+                    // and is trimmed when saving.
+                    let waiting_for_video = !context.prebuffer
+                        && !context
+                            .video_ready
+                            .load(std::sync::atomic::Ordering::Acquire);
+                    if waiting_for_video
+                        || context.saving.load(std::sync::atomic::Ordering::Acquire)
                     {
                         return;
                     }
@@ -136,22 +282,43 @@ impl AudioCapture {
                         return;
                     }
 
-                    let time_us = if let Ok(elapsed) = start_time.elapsed() {
-                        elapsed.as_micros() as i64
-                    } else {
-                        0
-                    };
+                    let time_us = context.timestamps.elapsed_us();
 
                     let data = &mut datas[0];
-                    let n_samples = data.chunk().size() / (std::mem::size_of::<f32>()) as u32;
+                    let size = data.chunk().size() as usize;
 
                     if let Some(samples) = data.data() {
-                        let samples_f32: &[f32] = bytemuck::cast_slice(samples);
-                        let audio_samples = &samples_f32[..n_samples as usize];
-                        process_audio_channel
+                        let mut audio_samples =
+                            to_f32(&samples[..size.min(samples.len())], context.sample_format);
+                        if let Some(noise_gate) = udata.noise_gate.as_mut() {
+                            noise_gate.process(
+                                &mut audio_samples,
+                                udata.audio_format.channels() as usize,
+                                udata.audio_format.rate(),
+                            );
+                        }
+                        if let Some(mic_agc) = udata.mic_agc.as_mut() {
+                            mic_agc.process(
+                                &mut audio_samples,
+                                udata.audio_format.channels() as usize,
+                                udata.audio_format.rate(),
+                            );
+                        }
+
+                        let Some((mixed, timestamp)) =
+                            context
+                                .mixer
+                                .borrow_mut()
+                                .push(index, &audio_samples, time_us)
+                        else {
+                            return;
+                        };
+
+                        context
+                            .process_audio_channel
                             .blocking_send(RawAudioFrame {
-                                samples: audio_samples.to_vec(),
-                                timestamp: time_us,
+                                samples: mixed,
+                                timestamp,
                             })
                             .unwrap();
                     }
@@ -159,7 +326,7 @@ impl AudioCapture {
             })
             .register()?;
 
-        let audio_spa_obj = pw::spa::pod::object! {
+        let mut audio_spa_obj = pw::spa::pod::object! {
             pw::spa::utils::SpaTypes::ObjectParamFormat,
             pw::spa::param::ParamType::EnumFormat,
             pw::spa::pod::property!(
@@ -175,10 +342,21 @@ impl AudioCapture {
             pw::spa::pod::property!(
                 pw::spa::param::format::FormatProperties::AudioFormat,
                 Id,
-                pw::spa::param::audio::AudioFormat::F32LE
+                spa_audio_format(context.sample_format)
             )
         };
 
+        if fixed_format {
+            audio_spa_obj.properties.push(spa::pod::Property::new(
+                spa::sys::SPA_FORMAT_AUDIO_rate,
+                spa::pod::Value::Int(MIX_RATE),
+            ));
+            audio_spa_obj.properties.push(spa::pod::Property::new(
+                spa::sys::SPA_FORMAT_AUDIO_channels,
+                spa::pod::Value::Int(MIX_CHANNELS),
+            ));
+        }
+
         let audio_spa_values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
             std::io::Cursor::new(Vec::new()),
             &pw::spa::pod::Value::Object(audio_spa_obj),
@@ -189,24 +367,52 @@ impl AudioCapture {
 
         let mut audio_params = [Pod::from_bytes(&audio_spa_values).unwrap()];
 
-        let sink_id_to_use = if !use_mic {
-            get_default_sink_node_id()
-        } else {
-            Some(stream_node)
+        let target_id = match target {
+            Some(AudioTarget::Node(id)) => Some(id),
+            _ => None,
         };
 
-        debug!("Default sink id: {:?}", sink_id_to_use);
+        debug!("Audio stream {} target: {:?}", index, target_id);
         audio_stream.connect(
             Direction::Input,
-            sink_id_to_use,
+            target_id,
             StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS | StreamFlags::RT_PROCESS,
             &mut audio_params,
         )?;
 
         debug!("Audio Stream: {:?}", audio_stream);
 
-        pw_loop.run();
-        Ok(())
+        Ok((audio_stream, audio_stream_shared_data_listener))
+    }
+}
+
+fn spa_audio_format(format: SampleFormat) -> spa::param::audio::AudioFormat {
+    match format {
+        SampleFormat::F32 => spa::param::audio::AudioFormat::F32LE,
+        SampleFormat::S16 => spa::param::audio::AudioFormat::S16LE,
+        SampleFormat::S24 => spa::param::audio::AudioFormat::S24LE,
+    }
+}
+
+/// Convert interleaved little endian samples in `format` to floats from -1 to 1
+fn to_f32(bytes: &[u8], format: SampleFormat) -> Vec<f32> {
+    match format {
+        // Read sample by sample, the buffer isn't guaranteed to be aligned for f32
+        SampleFormat::F32 => bytes
+            .chunks_exact(4)
+            .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+            .collect(),
+        SampleFormat::S16 => bytes
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32_768.0)
+            .collect(),
+        SampleFormat::S24 => bytes
+            .chunks_exact(3)
+            .map(|sample| {
+                // Shift into the top of an i32 and back to sign extend
+                (i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8) as f32 / 8_388_608.0
+            })
+            .collect(),
     }
 }
 
@@ -223,3 +429,68 @@ fn get_default_sink_node_id() -> Option<u32> {
 
     cleaned.trim().parse::<u32>().ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_f32_reads_floats_as_they_are() {
+        let bytes: Vec<u8> = [0.5f32, -1.0, 0.0]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        assert_eq!(to_f32(&bytes, SampleFormat::F32), vec![0.5, -1.0, 0.0]);
+
+        // Not aligned to 4 bytes
+        assert_eq!(to_f32(&bytes[..6], SampleFormat::F32), vec![0.5]);
+        let mut unaligned = vec![0];
+        unaligned.extend(&bytes);
+        assert_eq!(
+            to_f32(&unaligned[1..], SampleFormat::F32),
+            vec![0.5, -1.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn to_f32_scales_s16_to_full_scale() {
+        let bytes: Vec<u8> = [0i16, i16::MIN, i16::MAX, 16_384]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        assert_eq!(
+            to_f32(&bytes, SampleFormat::S16),
+            vec![0.0, -1.0, 32_767.0 / 32_768.0, 0.5]
+        );
+    }
+
+    #[test]
+    fn to_f32_sign_extends_s24() {
+        let bytes = [
+            0x00, 0x00, 0x00, // 0
+            0x00, 0x00, 0x80, // -8388608
+            0xff, 0xff, 0x7f, // 8388607
+            0xff, 0xff, 0xff, // -1
+            0x00, 0x00, 0x40, // 4194304
+        ];
+        assert_eq!(
+            to_f32(&bytes, SampleFormat::S24),
+            vec![
+                0.0,
+                -1.0,
+                8_388_607.0 / 8_388_608.0,
+                -1.0 / 8_388_608.0,
+                0.5
+            ]
+        );
+    }
+
+    #[test]
+    fn to_f32_drops_incomplete_samples() {
+        for format in [SampleFormat::F32, SampleFormat::S16, SampleFormat::S24] {
+            assert!(to_f32(&[], format).is_empty());
+            assert!(to_f32(&[0x01], format).is_empty());
+        }
+        assert_eq!(to_f32(&[0, 0, 0, 0, 0], SampleFormat::S24).len(), 1);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{mem::size_of, sync::Arc};
+
+use log::debug;
+use pipewire::spa::{self, param::video::VideoFormat, sys as spa_sys};
+
+use super::find_meta;
+
+/// Cursor bitmap sizes, in pixels per side, the buffer metadata is sized for
+const DEFAULT_CURSOR_SIZE: i32 = 64;
+const MAX_CURSOR_SIZE: i32 = 512;
+
+/// A cursor image as straight alpha BGRA
+#[derive(Debug)]
+pub struct CursorBitmap {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// The cursor to draw over a captured frame
+#[derive(Clone, Debug)]
+pub struct CursorOverlay {
+    /// Top left corner of the bitmap on the monitor, the hotspot is already accounted for
+    pub x: i32,
+    pub y: i32,
+    pub bitmap: Arc<CursorBitmap>,
+}
+
+/// Follows the cursor metadata the compositor attaches to buffers with `CursorMode::METADATA`.
+///
+/// The bitmap is only sent when the cursor image changes so the last one is kept around.
+#[derive(Default)]
+pub struct CursorTracker {
+    bitmap: Option<Arc<CursorBitmap>>,
+}
+
+impl CursorTracker {
+    /// Read the cursor metadata of `buffer`, returning `None` if no cursor should be drawn.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must be null or point to a buffer currently dequeued from the stream.
+    pub unsafe fn update(&mut self, buffer: *const spa_sys::spa_buffer) -> Option<CursorOverlay> {
+        let meta = find_meta(buffer, spa_sys::SPA_META_Cursor)?;
+        let meta_size = meta.size as usize;
+        if meta_size < size_of::<spa_sys::spa_meta_cursor>() {
+            return None;
+        }
+
+        let cursor = &*(meta.data as *const spa_sys::spa_meta_cursor);
+        // An id of 0 means the cursor isn't on the captured monitor
+        if cursor.id == 0 {
+            return None;
+        }
+
+        let bitmap_offset = cursor.bitmap_offset as usize;
+        if bitmap_offset >= size_of::<spa_sys::spa_meta_cursor>()
+            && bitmap_offset + size_of::<spa_sys::spa_meta_bitmap>() <= meta_size
+        {
+            let bitmap =
+                &*((meta.data as *const u8).add(bitmap_offset) as *const spa_sys::spa_meta_bitmap);
+            self.bitmap = read_bitmap(bitmap, meta_size - bitmap_offset).map(Arc::new);
+        }
+
+        Some(CursorOverlay {
+            x: cursor.position.x - cursor.hotspot.x,
+            y: cursor.position.y - cursor.hotspot.y,
+            bitmap: Arc::clone(self.bitmap.as_ref()?),
+        })
+    }
+}
+
+/// The `SPA_PARAM_Meta` param asking the compositor to attach the cursor to every buffer
+pub fn cursor_meta_param() -> Vec<u8> {
+    let size_property = spa::pod::Property::new(
+        spa_sys::SPA_PARAM_META_size,
+        spa::pod::Value::Choice(spa::pod::ChoiceValue::Int(spa::utils::Choice(
+            spa::utils::ChoiceFlags::empty(),
+            spa::utils::ChoiceEnum::Range {
+                default: cursor_meta_size(DEFAULT_CURSOR_SIZE),
+                min: cursor_meta_size(1),
+                max: cursor_meta_size(MAX_CURSOR_SIZE),
+            },
+        ))),
+    );
+
+    let meta_obj = spa::pod::object!(
+        spa::utils::SpaTypes::ObjectParamMeta,
+        spa::param::ParamType::Meta,
+        spa::pod::Property::new(
+            spa_sys::SPA_PARAM_META_type,
+            spa::pod::Value::Id(spa::utils::Id(spa_sys::SPA_META_Cursor)),
+        ),
+        size_property,
+    );
+
+    spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(meta_obj),
+    )
+    .unwrap()
+    .0
+    .into_inner()
+}
+
+/// Bytes needed for the cursor metadata with a square BGRA bitmap of `size` pixels per side
+fn cursor_meta_size(size: i32) -> i32 {
+    (size_of::<spa_sys::spa_meta_cursor>() + size_of::<spa_sys::spa_meta_bitmap>()) as i32
+        + size * size * 4
+}
+
+/// Copy the bitmap out of the metadata, `available` being the bytes left in the metadata from
+/// the start of the bitmap. A zero sized bitmap means the cursor is hidden.
+unsafe fn read_bitmap(bitmap: &spa_sys::spa_meta_bitmap, available: usize) -> Option<CursorBitmap> {
+    let width = bitmap.size.width as usize;
+    let height = bitmap.size.height as usize;
+    let stride = bitmap.stride.max(0) as usize;
+    if width == 0 || height == 0 || stride < width * 4 {
+        return None;
+    }
+
+    let offset = bitmap.offset as usize;
+    if offset + stride * (height - 1) + width * 4 > available {
+        debug!("Cursor bitmap does not fit in its metadata, ignoring it");
+        return None;
+    }
+
+    // Byte positions of blue, green, red and alpha within a pixel
+    let channels = match VideoFormat::from_raw(bitmap.format) {
+        VideoFormat::BGRA => [0, 1, 2, 3],
+        VideoFormat::RGBA => [2, 1, 0, 3],
+        VideoFormat::ARGB => [3, 2, 1, 0],
+        VideoFormat::ABGR => [1, 2, 3, 0],
+        format => {
+            debug!("Unsupported cursor bitmap format {:?}", format);
+            return None;
+        }
+    };
+
+    let data = (bitmap as *const spa_sys::spa_meta_bitmap as *const u8).add(offset);
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for row in 0..height {
+        let row = std::slice::from_raw_parts(data.add(row * stride), width * 4);
+        for pixel in row.chunks_exact(4) {
+            pixels.extend(channels.iter().map(|&channel| pixel[channel]));
+        }
+    }
+
+    Some(CursorBitmap {
+        width: width as u32,
+        height: height as u32,
+        pixels,
+    })
+}
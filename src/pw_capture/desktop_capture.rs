@@ -0,0 +1,201 @@
+use std::{
+    fs::File,
+    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use ffmpeg_next::{self as ffmpeg, format::Pixel};
+use log::{debug, error, info, trace};
+use memmap2::{MmapMut, MmapOptions};
+use pipewire::{self as pw, main_loop::MainLoop};
+use tokio::sync::mpsc;
+use x11rb::{
+    connection::{Connection, RequestConnection},
+    protocol::{
+        shm::{self, ConnectionExt as _},
+        xproto::{ImageFormat, Window},
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{dbus::StatusUpdate, CaptureStats, RawVideoFrame, Terminate};
+
+use super::timestamp_synchronizer::TimestampSynchronizer;
+
+/// How often the root window is grabbed, X11 has no way to tell when something changed
+const X11_FPS: u64 = 60;
+
+/// Captures the desktop without the screen cast portal, for sessions where it isn't available
+/// such as plain X11 or some XWayland setups.
+pub struct DesktopCapture;
+
+/// The shared memory segment the X server copies the root window into
+struct ShmImage {
+    segment: shm::Seg,
+    memory: MmapMut,
+}
+
+/// Whether an X server to capture from is configured for this session
+pub fn x11_available() -> bool {
+    std::env::var_os("DISPLAY").is_some_and(|display| !display.is_empty())
+}
+
+impl DesktopCapture {
+    /// Size of the X11 root window, which spans every monitor
+    pub fn x11_screen_size() -> Result<(u32, u32)> {
+        let (conn, screen_num) = x11rb::connect(None).context("Could not connect to X11")?;
+        let screen = &conn.setup().roots[screen_num];
+        Ok((
+            screen.width_in_pixels as u32,
+            screen.height_in_pixels as u32,
+        ))
+    }
+
+    /// Grab the X11 root window through MIT-SHM [`X11_FPS`] times a second until
+    /// `termination_recv` receives, sending every frame to `process_video_callback` like
+    /// [`super::video_stream::VideoCapture::run`] does.
+    ///
+    /// Frames come as BGRx on the 24 bit visuals X servers use, without the cursor.
+    pub fn run_x11(
+        process_video_callback: mpsc::Sender<RawVideoFrame>,
+        video_ready: Arc<AtomicBool>,
+        audio_ready: Arc<AtomicBool>,
+        timestamps: Arc<TimestampSynchronizer>,
+        termination_recv: pw::channel::Receiver<Terminate>,
+        saving: Arc<AtomicBool>,
+        capture_stats: Arc<CaptureStats>,
+        status_tx: mpsc::UnboundedSender<StatusUpdate>,
+    ) -> Result<()> {
+        let (conn, screen_num) = x11rb::connect(None).context("Could not connect to X11")?;
+        if conn
+            .extension_information(shm::X11_EXTENSION_NAME)?
+            .is_none()
+        {
+            bail!("The X server doesn't support the MIT-SHM extension");
+        }
+
+        let screen = &conn.setup().roots[screen_num];
+        let root = screen.root;
+        let (width, height) = (screen.width_in_pixels, screen.height_in_pixels);
+        let bits_per_pixel = conn
+            .setup()
+            .pixmap_formats
+            .iter()
+            .find(|format| format.depth == screen.root_depth)
+            .map(|format| format.bits_per_pixel);
+        if screen.root_depth != 24 || bits_per_pixel != Some(32) {
+            bail!(
+                "Unsupported X11 root window depth {} ({:?} bits per pixel), expected 24 bit colour in 32 bit pixels",
+                screen.root_depth,
+                bits_per_pixel
+            );
+        }
+
+        let image = ShmImage::new(&conn, width as usize * height as usize * 4)?;
+        info!(
+            "Capturing the X11 root window ({}x{}) at {} fps",
+            width, height, X11_FPS
+        );
+
+        let pw_loop = MainLoop::new(None)?;
+        let terminate_loop = pw_loop.clone();
+        let _recv = termination_recv.attach(pw_loop.loop_(), move |_| {
+            debug!("Terminating X11 capture loop");
+            terminate_loop.quit();
+        });
+
+        video_ready.store(true, std::sync::atomic::Ordering::Release);
+        let _ = status_tx.send(StatusUpdate::new("recording", "X11 capture started"));
+
+        let timer = pw_loop.loop_().add_timer(move |_| {
+            // Wait until audio is streaming before we try to process
+            if !audio_ready.load(std::sync::atomic::Ordering::Acquire)
+                || saving.load(std::sync::atomic::Ordering::Acquire)
+            {
+                return;
+            }
+
+            let grab_start = Instant::now();
+            let frame = match image.grab(&conn, root, width, height) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!("Could not grab the X11 root window: {:?}", e);
+                    return;
+                }
+            };
+            capture_stats.frame_delivered();
+            trace!("Took {:?} to grab this X11 frame", grab_start.elapsed());
+
+            let time_us = timestamps.elapsed_us();
+
+            if let Err(err) = process_video_callback.blocking_send(RawVideoFrame {
+                bytes: Vec::new(),
+                frame: Some(frame),
+                timestamp: time_us,
+                format: Pixel::BGRZ,
+                encoded_codec: None,
+                cursor: None,
+            }) {
+                error!("Error sending video frame: {:?}", err);
+            }
+        });
+        let interval = Duration::from_micros(1_000_000 / X11_FPS);
+        timer
+            .update_timer(Some(interval), Some(interval))
+            .into_result()?;
+
+        pw_loop.run();
+
+        video_ready.store(false, std::sync::atomic::Ordering::Release);
+        Ok(())
+    }
+}
+
+impl ShmImage {
+    /// Have the X server create a segment of `size` bytes and map it
+    fn new(conn: &RustConnection, size: usize) -> Result<Self> {
+        let segment = conn.generate_id()?;
+        let reply = conn
+            .shm_create_segment(segment, size as u32, false)?
+            .reply()
+            .context("The X server could not create a shared memory segment")?;
+        let file = File::from(reply.shm_fd);
+        let memory = unsafe { MmapOptions::new().len(size).map_mut(&file)? };
+
+        Ok(Self { segment, memory })
+    }
+
+    /// Copy the root window into the segment and from there into a frame the encoder takes
+    fn grab(
+        &self,
+        conn: &RustConnection,
+        root: Window,
+        width: u16,
+        height: u16,
+    ) -> Result<ffmpeg::util::frame::video::Video> {
+        conn.shm_get_image(
+            root,
+            0,
+            0,
+            width,
+            height,
+            !0,
+            ImageFormat::Z_PIXMAP.into(),
+            self.segment,
+            0,
+        )?
+        .reply()?;
+
+        let mut frame =
+            ffmpeg::util::frame::video::Video::new(Pixel::BGRZ, width as u32, height as u32);
+        let row_len = width as usize * 4;
+        let stride = frame.stride(0);
+        let data = frame.data_mut(0);
+        for (row, source) in self.memory.chunks_exact(row_len).enumerate() {
+            data[row * stride..row * stride + row_len].copy_from_slice(source);
+        }
+
+        Ok(frame)
+    }
+}
@@ -0,0 +1,45 @@
+use ffmpeg_next as ffmpeg;
+
+/// Copy one plane of a captured frame into `plane` of `frame`, honouring the strides on both
+/// sides.
+///
+/// `source` holds the plane's rows `source_stride` bytes apart starting at `start`. Only the
+/// frame's row size is copied from each, any padding after it is skipped.
+pub fn copy_plane(
+    source: &[u8],
+    start: usize,
+    source_stride: usize,
+    frame: &mut ffmpeg::util::frame::video::Video,
+    plane: usize,
+) -> Result<(), ffmpeg::Error> {
+    let row_size = row_sizes(frame)?[plane];
+    let rows = frame.plane_height(plane) as usize;
+    let stride = frame.stride(plane);
+    let destination = frame.data_mut(plane);
+    for row in 0..rows {
+        let offset = start + row * source_stride;
+        let src = source
+            .get(offset..offset + row_size)
+            .ok_or(ffmpeg::Error::InvalidData)?;
+        destination[row * stride..row * stride + row_size].copy_from_slice(src);
+    }
+
+    Ok(())
+}
+
+/// Bytes of pixel data in a row of each plane of `frame`, without the padding its strides add
+pub fn row_sizes(frame: &ffmpeg::util::frame::video::Video) -> Result<[usize; 4], ffmpeg::Error> {
+    let mut row_sizes = [0i32; 4];
+    let ret = unsafe {
+        ffmpeg::ffi::av_image_fill_linesizes(
+            row_sizes.as_mut_ptr(),
+            frame.format().into(),
+            frame.width() as i32,
+        )
+    };
+    if ret < 0 {
+        return Err(ffmpeg::Error::from(ret));
+    }
+
+    Ok(row_sizes.map(|row_size| row_size as usize))
+}
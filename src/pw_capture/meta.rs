@@ -0,0 +1,20 @@
+use pipewire::spa::sys as spa_sys;
+
+/// Find the metadata of `meta_type` attached to `buffer`, if the compositor filled it in
+///
+/// # Safety
+///
+/// `buffer` must be null or point to a buffer currently dequeued from a stream.
+pub(super) unsafe fn find_meta<'a>(
+    buffer: *const spa_sys::spa_buffer,
+    meta_type: u32,
+) -> Option<&'a spa_sys::spa_meta> {
+    let buffer = buffer.as_ref()?;
+    if buffer.metas.is_null() {
+        return None;
+    }
+
+    std::slice::from_raw_parts(buffer.metas, buffer.n_metas as usize)
+        .iter()
+        .find(|meta| meta.type_ == meta_type && !meta.data.is_null())
+}
@@ -0,0 +1,141 @@
+use std::sync::{atomic::AtomicI32, Arc};
+
+/// Largest correction the gain control applies either way
+const MAX_GAIN_DB: f32 = 20.0;
+
+/// How long the gain takes to rise towards a louder target, slow so breaths and pauses don't pump
+const ATTACK_MS: f32 = 100.0;
+
+/// How long the gain takes to fall once the signal gets too loud, fast so it doesn't clip
+const RELEASE_MS: f32 = 20.0;
+
+/// Chunks quieter than this are treated as silence and leave the gain alone, otherwise pauses
+/// would ramp it up to the limit and boost the noise floor
+const SILENCE_DB: f32 = -60.0;
+
+/// Keeps the microphone level around a target by adjusting its gain from the RMS of each chunk.
+#[derive(Clone, Debug)]
+pub struct MicAgc {
+    target_db: f32,
+
+    /// Gain applied to the next chunk
+    gain_db: f32,
+
+    /// The current gain in hundredths of a dB, for front-ends to show
+    shared_gain: Arc<AtomicI32>,
+}
+
+impl MicAgc {
+    /// # Arguments
+    ///
+    /// * `target_db` - RMS level in dBFS to keep the microphone at, e.g. `-12.0`.
+    /// * `shared_gain` - Updated with the gain applied, in hundredths of a dB.
+    pub fn new(target_db: f32, shared_gain: Arc<AtomicI32>) -> Self {
+        shared_gain.store(0, std::sync::atomic::Ordering::Relaxed);
+        Self {
+            target_db,
+            gain_db: 0.0,
+            shared_gain,
+        }
+    }
+
+    /// Apply the current gain to interleaved `samples` in place, then correct it towards the
+    /// target from their level for the next chunk.
+    pub fn process(&mut self, samples: &mut [f32], channels: usize, rate: u32) {
+        if samples.is_empty() {
+            return;
+        }
+
+        let sum_squares: f32 = samples.iter().map(|sample| sample * sample).sum();
+        let rms_db = 10.0
+            * (sum_squares / samples.len() as f32)
+                .max(f32::MIN_POSITIVE)
+                .log10();
+
+        let gain = 10f32.powf(self.gain_db / 20.0);
+        for sample in samples.iter_mut() {
+            *sample = (*sample * gain).clamp(-1.0, 1.0);
+        }
+
+        if rms_db < SILENCE_DB {
+            return;
+        }
+
+        let wanted_db = (self.target_db - rms_db).clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+        let time_ms = if wanted_db > self.gain_db {
+            ATTACK_MS
+        } else {
+            RELEASE_MS
+        };
+
+        // Move a share of the way there depending on how much time the chunk covers
+        let chunk_ms = (samples.len() / channels.max(1)) as f32 * 1000.0 / rate.max(1) as f32;
+        let step = 1.0 - (-chunk_ms / time_ms).exp();
+        self.gain_db += (wanted_db - self.gain_db) * step;
+
+        self.shared_gain.store(
+            (self.gain_db * 100.0).round() as i32,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agc() -> (MicAgc, Arc<AtomicI32>) {
+        let shared_gain = Arc::new(AtomicI32::new(0));
+        (MicAgc::new(-12.0, shared_gain.clone()), shared_gain)
+    }
+
+    fn gain(shared_gain: &AtomicI32) -> i32 {
+        shared_gain.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    #[test]
+    fn silence_leaves_the_gain_alone() {
+        let (mut agc, shared_gain) = agc();
+        for _ in 0..50 {
+            let mut samples = [0.0; 960];
+            agc.process(&mut samples, 2, 48000);
+            assert!(samples.iter().all(|sample| *sample == 0.0));
+        }
+        assert_eq!(gain(&shared_gain), 0);
+    }
+
+    #[test]
+    fn quiet_input_is_boosted_up_to_the_limit() {
+        let (mut agc, shared_gain) = agc();
+        for _ in 0..200 {
+            let mut samples = [0.01; 960];
+            agc.process(&mut samples, 2, 48000);
+        }
+        assert!(gain(&shared_gain) > 0);
+        assert!(gain(&shared_gain) <= (MAX_GAIN_DB * 100.0) as i32);
+    }
+
+    #[test]
+    fn boosted_output_is_clipped_to_full_scale() {
+        let (mut agc, shared_gain) = agc();
+        for _ in 0..200 {
+            let mut samples = [0.01; 960];
+            agc.process(&mut samples, 2, 48000);
+        }
+        assert!(gain(&shared_gain) > 0);
+
+        let mut samples = [0.9, -0.9].repeat(480);
+        agc.process(&mut samples, 2, 48000);
+        assert!(samples.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+
+        // The loud chunk pulls the gain back down for the next one
+        assert!(gain(&shared_gain) < (MAX_GAIN_DB * 100.0) as i32);
+    }
+
+    #[test]
+    fn empty_input_is_ignored() {
+        let (mut agc, shared_gain) = agc();
+        agc.process(&mut [], 2, 48000);
+        assert_eq!(gain(&shared_gain), 0);
+    }
+}
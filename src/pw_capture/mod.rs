@@ -1,2 +1,36 @@
 pub mod video_stream;
+pub mod audio_mixer;
 pub mod audio_stream;
+pub mod cursor;
+pub mod desktop_capture;
+pub mod frame_copy;
+mod meta;
+pub mod mic_agc;
+pub mod node_monitor;
+pub mod noise_gate;
+pub mod timestamp_synchronizer;
+
+use pipewire::stream::StreamState;
+
+use crate::dbus::StatusUpdate;
+
+use meta::find_meta;
+
+/// Translate a PipeWire stream state change into a status for front-ends
+pub fn stream_status(stream: &str, state: &StreamState) -> StatusUpdate {
+    match state {
+        StreamState::Streaming => {
+            StatusUpdate::new("recording", format!("{} stream started", stream))
+        }
+        StreamState::Paused => StatusUpdate::new("paused", format!("{} stream paused", stream)),
+        StreamState::Connecting => {
+            StatusUpdate::new("connecting", format!("{} stream connecting", stream))
+        }
+        StreamState::Unconnected => {
+            StatusUpdate::new("error", format!("{} stream disconnected", stream))
+        }
+        StreamState::Error(e) => {
+            StatusUpdate::new("error", format!("{} stream error: {}", stream, e))
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use log::{debug, info, warn};
+use pipewire::{
+    self as pw,
+    context::Context,
+    main_loop::MainLoop,
+    node::{Node, NodeListener, NodeState},
+    types::ObjectType,
+};
+use tokio::sync::mpsc;
+
+use crate::Terminate;
+
+/// A PipeWire node the capture depends on changed state, e.g. from `running` to `suspended` when
+/// the compositor stops producing frames
+#[derive(Debug)]
+pub struct NodeStateUpdate {
+    pub node: String,
+    pub state: String,
+}
+
+/// A node being watched, its proxy and listener have to be kept alive for the updates to arrive
+struct WatchedNode {
+    label: String,
+    _node: Node,
+    _listener: NodeListener,
+}
+
+/// Watches the state of the compositor's screencast node and the recorder's own streams in the
+/// PipeWire graph.
+///
+/// This tells a compositor that stopped sending frames apart from an encoder that stopped taking
+/// them when frames go missing, which the stream states alone don't.
+pub struct NodeMonitor;
+
+impl NodeMonitor {
+    /// Run until `termination_recv` receives, sending every state transition to `state_tx`.
+    ///
+    /// # Arguments
+    ///
+    /// * `video_source_node` - Id of the screencast node the portal handed out.
+    /// * `stream_names` - `node.name` of the streams to watch. Streams that get recreated, e.g.
+    ///   when the video capture restarts, are picked up again.
+    pub fn run(
+        video_source_node: u32,
+        stream_names: Vec<&'static str>,
+        termination_recv: pw::channel::Receiver<Terminate>,
+        state_tx: mpsc::UnboundedSender<NodeStateUpdate>,
+    ) -> Result<(), pw::Error> {
+        let pw_loop = MainLoop::new(None)?;
+        let terminate_loop = pw_loop.clone();
+
+        let _recv = termination_recv.attach(pw_loop.loop_(), move |_| {
+            debug!("Terminating node monitor loop");
+            terminate_loop.quit();
+        });
+
+        let pw_context = Context::new(&pw_loop)?;
+        let core = pw_context.connect(None)?;
+        let registry = Rc::new(core.get_registry()?);
+        let registry_weak = Rc::downgrade(&registry);
+
+        let watched: Rc<RefCell<HashMap<u32, WatchedNode>>> = Rc::default();
+        let watched_removed = Rc::clone(&watched);
+        let removed_tx = state_tx.clone();
+
+        let _registry_listener = registry
+            .add_listener_local()
+            .global(move |global| {
+                if global.type_ != ObjectType::Node {
+                    return;
+                }
+
+                let name = global.props.and_then(|props| props.get("node.name"));
+                let label = if global.id == video_source_node {
+                    "video source".to_string()
+                } else {
+                    match name.filter(|name| stream_names.iter().any(|stream| stream == name)) {
+                        Some(name) => name.to_string(),
+                        None => return,
+                    }
+                };
+
+                let Some(registry) = registry_weak.upgrade() else {
+                    return;
+                };
+                let node: Node = match registry.bind(global) {
+                    Ok(node) => node,
+                    Err(e) => {
+                        warn!("Could not watch the {} node: {:?}", label, e);
+                        return;
+                    }
+                };
+
+                debug!("Watching the state of the {} node ({})", label, global.id);
+                let id = global.id;
+                let node_label = label.clone();
+                let state_tx = state_tx.clone();
+                let last_state = RefCell::new(None::<String>);
+                let listener = node
+                    .add_listener_local()
+                    .info(move |node_info| {
+                        let state = state_name(&node_info.state());
+                        let previous = last_state.replace(Some(state.clone()));
+                        if previous.as_ref() == Some(&state) {
+                            return;
+                        }
+
+                        info!(
+                            "{} node ({}) state: {} -> {}",
+                            node_label,
+                            id,
+                            previous.as_deref().unwrap_or("unknown"),
+                            state
+                        );
+                        let _ = state_tx.send(NodeStateUpdate {
+                            node: node_label.clone(),
+                            state,
+                        });
+                    })
+                    .register();
+
+                watched.borrow_mut().insert(
+                    id,
+                    WatchedNode {
+                        label,
+                        _node: node,
+                        _listener: listener,
+                    },
+                );
+            })
+            .global_remove(move |id| {
+                if let Some(node) = watched_removed.borrow_mut().remove(&id) {
+                    info!("{} node ({}) was removed", node.label, id);
+                    let _ = removed_tx.send(NodeStateUpdate {
+                        node: node.label,
+                        state: "removed".to_string(),
+                    });
+                }
+            })
+            .register();
+
+        pw_loop.run();
+        Ok(())
+    }
+}
+
+fn state_name(state: &NodeState) -> String {
+    match state {
+        NodeState::Creating => "creating".to_string(),
+        NodeState::Suspended => "suspended".to_string(),
+        NodeState::Idle => "idle".to_string(),
+        NodeState::Running => "running".to_string(),
+        NodeState::Error(e) => format!("error: {}", e),
+    }
+}
@@ -0,0 +1,61 @@
+/// Silences audio below a threshold, opening and closing gradually so the gate doesn't click.
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseGate {
+    /// Linear amplitude the signal has to reach to open the gate
+    threshold: f32,
+    attack_ms: u32,
+    release_ms: u32,
+
+    /// Current gain applied to the signal, 0 when closed and 1 when fully open
+    gain: f32,
+}
+
+impl NoiseGate {
+    /// # Arguments
+    ///
+    /// * `threshold_db` - Level in dBFS below which audio is silenced, e.g. `-40.0`.
+    /// * `attack_ms` - How long the gate takes to fully open once the signal is loud enough.
+    /// * `release_ms` - How long the gate takes to fully close once the signal drops below.
+    pub fn new(threshold_db: f32, attack_ms: u32, release_ms: u32) -> Self {
+        Self {
+            threshold: 10f32.powf(threshold_db / 20.0),
+            attack_ms,
+            release_ms,
+            gain: 0.0,
+        }
+    }
+
+    /// Gate interleaved `samples` in place.
+    ///
+    /// Channels of a frame share the gain, so the gate opens if any of them is loud enough.
+    pub fn process(&mut self, samples: &mut [f32], channels: usize, rate: u32) {
+        let attack_step = Self::step(self.attack_ms, rate);
+        let release_step = Self::step(self.release_ms, rate);
+
+        for frame in samples.chunks_mut(channels.max(1)) {
+            let peak = frame
+                .iter()
+                .fold(0f32, |peak, sample| peak.max(sample.abs()));
+
+            self.gain = if peak >= self.threshold {
+                (self.gain + attack_step).min(1.0)
+            } else {
+                (self.gain - release_step).max(0.0)
+            };
+
+            for sample in frame.iter_mut() {
+                *sample *= self.gain;
+            }
+        }
+    }
+
+    /// Gain change per sample frame to ramp fully in `ms`, an instant change for 0
+    fn step(ms: u32, rate: u32) -> f32 {
+        let frames = ms as f32 * rate as f32 / 1000.0;
+        if frames < 1.0 {
+            1.0
+        } else {
+            1.0 / frames
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::Instant;
+
+/// The clock audio and video frames are timestamped with, started once so both captures count
+/// from the same instant and their timestamps line up.
+///
+/// Monotonic unlike `SystemTime`, so the system clock being adjusted while recording can't make
+/// timestamps jump or go backwards.
+#[derive(Debug)]
+pub struct TimestampSynchronizer {
+    start: Instant,
+}
+
+impl TimestampSynchronizer {
+    /// Start the clock, capture timestamps count from now
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+
+    /// Micro seconds since the clock was started
+    pub fn elapsed_us(&self) -> i64 {
+        self.start.elapsed().as_micros() as i64
+    }
+}
+
+impl Default for TimestampSynchronizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
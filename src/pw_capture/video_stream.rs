@@ -1,10 +1,16 @@
 use std::{
+    cell::RefCell,
+    mem::size_of,
     os::fd::{FromRawFd, OwnedFd, RawFd},
+    ptr::NonNull,
+    rc::Rc,
     sync::{atomic::AtomicBool, Arc},
-    time::SystemTime,
+    time::Instant,
 };
 
-use log::{debug, error, info};
+use anyhow::{bail, Result};
+use ffmpeg_next::{self as ffmpeg, format::Pixel};
+use log::{debug, error, info, trace, warn};
 use pipewire::{
     self as pw,
     context::Context,
@@ -17,19 +23,81 @@ use pw::{properties::properties, spa};
 use spa::pod::Pod;
 use tokio::sync::mpsc;
 
-use crate::{RawVideoFrame, Terminate};
+use crate::{
+    application_config::{MAX_PW_VIDEO_BUFFERS, MIN_PW_VIDEO_BUFFERS},
+    dbus::StatusUpdate,
+    CaptureStats, RawVideoFrame, Terminate,
+};
+
+use super::{
+    cursor::{cursor_meta_param, CursorTracker},
+    find_meta,
+    frame_copy::{copy_plane, row_sizes},
+    stream_status,
+    timestamp_synchronizer::TimestampSynchronizer,
+};
+
+/// Name of the capture stream, which PipeWire also uses as its `node.name`
+pub const STREAM_NAME: &str = "auto-screen-recorder-video";
 
 pub struct VideoCapture;
 
+/// A buffer dequeued from the stream that is queued back once dropped.
+///
+/// `pw::buffer::Buffer` only exposes the buffer's datas but the cursor is sent in its metadata,
+/// so the raw buffer is used instead.
+struct RawBuffer<'s> {
+    stream: &'s Stream,
+    buffer: NonNull<pw::sys::pw_buffer>,
+}
+
+impl<'s> RawBuffer<'s> {
+    fn dequeue(stream: &'s Stream) -> Option<Self> {
+        NonNull::new(unsafe { stream.dequeue_raw_buffer() }).map(|buffer| Self { stream, buffer })
+    }
+
+    fn spa_buffer(&self) -> *mut spa::sys::spa_buffer {
+        unsafe { self.buffer.as_ref().buffer }
+    }
+
+    fn datas_mut(&mut self) -> &mut [spa::buffer::Data] {
+        let buffer = self.spa_buffer();
+        unsafe {
+            if buffer.is_null() || (*buffer).datas.is_null() {
+                return &mut [];
+            }
+            // `Data` is a transparent wrapper around `spa_data`
+            std::slice::from_raw_parts_mut(
+                (*buffer).datas as *mut spa::buffer::Data,
+                (*buffer).n_datas as usize,
+            )
+        }
+    }
+}
+
+impl Drop for RawBuffer<'_> {
+    fn drop(&mut self) {
+        unsafe { self.stream.queue_raw_buffer(self.buffer.as_ptr()) };
+    }
+}
+
 #[derive(Clone, Copy)]
 struct UserData {
     video_format: spa::param::video::VideoInfoRaw,
+
+    /// `video_format`'s layout in FFmpeg terms, unset while the negotiated format isn't supported
+    pixel_format: Option<Pixel>,
+
+    /// Set when the compositor sends already encoded H.264 instead of raw frames
+    pre_encoded: bool,
 }
 
 impl Default for UserData {
     fn default() -> Self {
         Self {
             video_format: Default::default(),
+            pixel_format: None,
+            pre_encoded: false,
         }
     }
 }
@@ -41,10 +109,16 @@ impl VideoCapture {
         process_video_callback: mpsc::Sender<RawVideoFrame>,
         video_ready: Arc<AtomicBool>,
         audio_ready: Arc<AtomicBool>,
-        start_time: SystemTime,
+        timestamps: Arc<TimestampSynchronizer>,
         termination_recv: pw::channel::Receiver<Terminate>,
         saving: Arc<AtomicBool>,
-    ) -> Result<(), pipewire::Error> {
+        allow_nv12: bool,
+        node_latency: Option<String>,
+        buffers: u32,
+        cursor_metadata: bool,
+        capture_stats: Arc<CaptureStats>,
+        status_tx: mpsc::UnboundedSender<StatusUpdate>,
+    ) -> Result<()> {
         let pw_loop = MainLoop::new(None)?;
         let terminate_loop = pw_loop.clone();
 
@@ -57,6 +131,12 @@ impl VideoCapture {
         let core = pw_context.connect_fd(unsafe { OwnedFd::from_raw_fd(pipewire_fd) }, None)?;
 
         let data = UserData::default();
+        // Set once the stream errors, e.g. when the compositor restarts, to stop the loop with it
+        let stream_error = Rc::new(RefCell::new(None));
+        let state_stream_error = Rc::clone(&stream_error);
+        let error_loop = pw_loop.clone();
+        let mut cursor_tracker = CursorTracker::default();
+        let mut last_sequence = None;
 
         let _listener = core
             .add_listener_local()
@@ -66,26 +146,32 @@ impl VideoCapture {
             .register();
 
         // Set up video stream
-        let video_stream = Stream::new(
-            &core,
-            "auto-screen-recorder-video",
-            properties! {
-                *pw::keys::MEDIA_TYPE => "Video",
-                *pw::keys::MEDIA_CATEGORY => "Capture",
-                *pw::keys::MEDIA_ROLE => "Screen",
-            },
-        )?;
+        let mut video_properties = properties! {
+            *pw::keys::MEDIA_TYPE => "Video",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Screen",
+        };
+        if let Some(latency) = node_latency {
+            video_properties.insert(*pw::keys::NODE_LATENCY, latency);
+        }
+
+        let video_stream = Stream::new(&core, STREAM_NAME, video_properties)?;
 
         let _video_stream_shared_data_listener = video_stream
             .add_local_listener_with_user_data(data)
             .state_changed(move |_, _, old, new| {
                 debug!("Video Stream State Changed: {0:?} -> {1:?}", old, new);
+                let _ = status_tx.send(stream_status("video", &new));
                 video_ready.store(
                     new == StreamState::Streaming,
                     std::sync::atomic::Ordering::Release,
                 );
+                if let StreamState::Error(e) = new {
+                    *state_stream_error.borrow_mut() = Some(e);
+                    error_loop.quit();
+                }
             })
-            .param_changed(|_, user_data, id, param| {
+            .param_changed(move |stream, user_data, id, param| {
                 let Some(param) = param else {
                     return;
                 };
@@ -99,9 +185,20 @@ impl VideoCapture {
                         Err(_) => return,
                     };
 
-                if media_type != pw::spa::param::format::MediaType::Video
-                    || media_subtype != pw::spa::param::format::MediaSubtype::Raw
-                {
+                if media_type != pw::spa::param::format::MediaType::Video {
+                    return;
+                }
+
+                // Some compositors export window captures pre-encoded, those packets can go
+                // straight into the buffer. The spa bindings don't expose an H.265 subtype yet
+                // so only H.264 is handled.
+                user_data.pre_encoded = media_subtype == pw::spa::param::format::MediaSubtype::H264;
+                if user_data.pre_encoded {
+                    debug!("Compositor negotiated pre-encoded H.264, bypassing the encoder");
+                    return;
+                }
+
+                if media_subtype != pw::spa::param::format::MediaSubtype::Raw {
                     return;
                 }
 
@@ -115,6 +212,14 @@ impl VideoCapture {
                     user_data.video_format.format().as_raw(),
                     user_data.video_format.format()
                 );
+                user_data.pixel_format =
+                    spa_format_to_ffmpeg_pixel(user_data.video_format.format());
+                if user_data.pixel_format.is_none() {
+                    error!(
+                        "Negotiated unsupported video format {:?}, dropping frames",
+                        user_data.video_format.format()
+                    );
+                }
                 debug!(
                     "  size: {}x{}",
                     user_data.video_format.size().width,
@@ -125,11 +230,48 @@ impl VideoCapture {
                     user_data.video_format.framerate().num,
                     user_data.video_format.framerate().denom
                 );
+
+                let buffers_values = buffers_param(buffers);
+                // The header carries the sequence numbers used to spot frames the compositor dropped
+                let header_values = header_meta_param();
+                let cursor_values = cursor_metadata.then(cursor_meta_param);
+                let mut buffer_params: Vec<&Pod> = [&buffers_values, &header_values]
+                    .into_iter()
+                    .chain(cursor_values.as_ref())
+                    .map(|values| Pod::from_bytes(values).unwrap())
+                    .collect();
+                if let Err(e) = stream.update_params(&mut buffer_params) {
+                    error!("Could not request buffer params: {:?}", e);
+                }
             })
-            .process(move |stream, _| {
-                match stream.dequeue_buffer() {
+            .process(move |stream, user_data| {
+                match RawBuffer::dequeue(stream) {
                     None => debug!("out of buffers"),
                     Some(mut buffer) => {
+                        capture_stats.frame_delivered();
+
+                        // The cursor bitmap is only sent when it changes so always read it, even
+                        // for frames that get dropped
+                        let cursor = if cursor_metadata {
+                            unsafe { cursor_tracker.update(buffer.spa_buffer()) }
+                        } else {
+                            None
+                        };
+
+                        // Read before dropping frames ourselves so only gaps left by the
+                        // compositor are counted
+                        if let Some(sequence) = unsafe { buffer_sequence(buffer.spa_buffer()) } {
+                            if let Some(last) = last_sequence.replace(sequence) {
+                                if sequence > last.saturating_add(1) {
+                                    let dropped = sequence - last - 1;
+                                    warn!("Compositor dropped {} video frames", dropped);
+                                    capture_stats
+                                        .frames_dropped_compositor
+                                        .fetch_add(dropped, std::sync::atomic::Ordering::Relaxed);
+                                }
+                            }
+                        }
+
                         // Wait until audio is streaming before we try to process
                         if !audio_ready.load(std::sync::atomic::Ordering::Acquire)
                             || saving.load(std::sync::atomic::Ordering::Acquire)
@@ -142,21 +284,56 @@ impl VideoCapture {
                             return;
                         }
 
-                        let time_us = if let Ok(elapsed) = start_time.elapsed() {
-                            elapsed.as_micros() as i64
-                        } else {
-                            0
+                        let time_us = timestamps.elapsed_us();
+
+                        if user_data.pre_encoded {
+                            let data = &mut datas[0];
+                            let size = data.chunk().size() as usize;
+                            if let Some(packet) = data.data() {
+                                let packet = &packet[..size.min(packet.len())];
+                                if let Err(err) =
+                                    process_video_callback.blocking_send(RawVideoFrame {
+                                        bytes: packet.to_vec(),
+                                        frame: None,
+                                        timestamp: time_us,
+                                        format: Pixel::None,
+                                        encoded_codec: Some(ffmpeg_next::codec::Id::H264),
+                                        cursor: None,
+                                    })
+                                {
+                                    error!("Error sending encoded video packet: {:?}", err);
+                                }
+                            }
+                            return;
+                        }
+
+                        let Some(format) = user_data.pixel_format else {
+                            return;
                         };
 
-                        // send frame data to encoder
-                        let data = &mut datas[0];
-                        if let Some(frame) = data.data() {
-                            if let Err(err) = process_video_callback.blocking_send(RawVideoFrame {
-                                bytes: frame.to_vec(),
-                                timestamp: time_us,
-                            }) {
-                                error!("Error sending video frame: {:?}", err);
+                        // The buffer goes back to the compositor once this callback returns, so
+                        // copy it once, straight into the frame the encoder takes
+                        let size = user_data.video_format.size();
+                        let copy_start = Instant::now();
+                        let frame = match copy_to_frame(datas, format, size.width, size.height) {
+                            Ok(frame) => frame,
+                            Err(e) => {
+                                debug!("Could not copy video frame: {:?}", e);
+                                return;
                             }
+                        };
+                        trace!("Took {:?} to copy this video frame", copy_start.elapsed());
+
+                        // send frame data to encoder
+                        if let Err(err) = process_video_callback.blocking_send(RawVideoFrame {
+                            bytes: Vec::new(),
+                            frame: Some(frame),
+                            timestamp: time_us,
+                            format,
+                            encoded_codec: None,
+                            cursor,
+                        }) {
+                            error!("Error sending video frame: {:?}", err);
                         }
                     }
                 }
@@ -167,6 +344,35 @@ impl VideoCapture {
             .add_local_listener_with_user_data(data)
             .register()?;
 
+        let mut video_formats = vec![
+            pw::spa::param::video::VideoFormat::xRGB,
+            pw::spa::param::video::VideoFormat::RGB,
+            pw::spa::param::video::VideoFormat::BGRA,
+            pw::spa::param::video::VideoFormat::RGBA,
+            pw::spa::param::video::VideoFormat::RGBx,
+            pw::spa::param::video::VideoFormat::BGRx,
+            pw::spa::param::video::VideoFormat::I420,
+        ];
+
+        // Prefer NV12 when the encoder accepts it so no conversion is needed on our side
+        if allow_nv12 {
+            video_formats.insert(0, pw::spa::param::video::VideoFormat::NV12);
+        }
+
+        let video_format_property = pw::spa::pod::Property::new(
+            pw::spa::param::format::FormatProperties::VideoFormat.as_raw(),
+            pw::spa::pod::Value::Choice(pw::spa::pod::ChoiceValue::Id(pw::spa::utils::Choice(
+                pw::spa::utils::ChoiceFlags::empty(),
+                pw::spa::utils::ChoiceEnum::Enum {
+                    default: pw::spa::utils::Id(video_formats[0].as_raw()),
+                    alternatives: video_formats[1..]
+                        .iter()
+                        .map(|format| pw::spa::utils::Id(format.as_raw()))
+                        .collect(),
+                },
+            ))),
+        );
+
         let video_spa_obj = pw::spa::pod::object!(
             pw::spa::utils::SpaTypes::ObjectParamFormat,
             pw::spa::param::ParamType::EnumFormat,
@@ -180,19 +386,7 @@ impl VideoCapture {
                 Id,
                 pw::spa::param::format::MediaSubtype::Raw
             ),
-            pw::spa::pod::property!(
-                pw::spa::param::format::FormatProperties::VideoFormat,
-                Choice,
-                Enum,
-                Id,
-                pw::spa::param::video::VideoFormat::xRGB,
-                pw::spa::param::video::VideoFormat::RGB,
-                pw::spa::param::video::VideoFormat::RGB,
-                pw::spa::param::video::VideoFormat::RGBA,
-                pw::spa::param::video::VideoFormat::RGBx,
-                pw::spa::param::video::VideoFormat::BGRx,
-                pw::spa::param::video::VideoFormat::I420,
-            ),
+            video_format_property,
             pw::spa::pod::property!(
                 pw::spa::param::format::FormatProperties::VideoSize,
                 Choice,
@@ -222,6 +416,40 @@ impl VideoCapture {
             ),
         );
 
+        // Offered after raw video so it's only picked when the compositor can't give us raw frames
+        let encoded_spa_obj = pw::spa::pod::object!(
+            pw::spa::utils::SpaTypes::ObjectParamFormat,
+            pw::spa::param::ParamType::EnumFormat,
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaType,
+                Id,
+                pw::spa::param::format::MediaType::Video
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::MediaSubtype,
+                Id,
+                pw::spa::param::format::MediaSubtype::H264
+            ),
+            pw::spa::pod::property!(
+                pw::spa::param::format::FormatProperties::VideoSize,
+                Choice,
+                Range,
+                Rectangle,
+                pw::spa::utils::Rectangle {
+                    width: 2560,
+                    height: 1440
+                }, // Default
+                pw::spa::utils::Rectangle {
+                    width: 1,
+                    height: 1
+                }, // Min
+                pw::spa::utils::Rectangle {
+                    width: 4096,
+                    height: 4096
+                } // Max
+            ),
+        );
+
         let video_spa_values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
             std::io::Cursor::new(Vec::new()),
             &pw::spa::pod::Value::Object(video_spa_obj),
@@ -230,7 +458,18 @@ impl VideoCapture {
         .0
         .into_inner();
 
-        let mut video_params = [Pod::from_bytes(&video_spa_values).unwrap()];
+        let encoded_spa_values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+            std::io::Cursor::new(Vec::new()),
+            &pw::spa::pod::Value::Object(encoded_spa_obj),
+        )
+        .unwrap()
+        .0
+        .into_inner();
+
+        let mut video_params = [
+            Pod::from_bytes(&video_spa_values).unwrap(),
+            Pod::from_bytes(&encoded_spa_values).unwrap(),
+        ];
 
         video_stream.connect(
             Direction::Input,
@@ -242,6 +481,193 @@ impl VideoCapture {
         debug!("Video Stream: {0:?}", video_stream);
 
         pw_loop.run();
-        Ok(())
+        match stream_error.take() {
+            Some(e) => bail!("Video stream error: {}", e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The `SPA_PARAM_Buffers` param preferring `buffers` buffers, the compositor can still pick
+/// another count within the range the config allows
+fn buffers_param(buffers: u32) -> Vec<u8> {
+    let buffers_obj = spa::pod::object!(
+        spa::utils::SpaTypes::ObjectParamBuffers,
+        spa::param::ParamType::Buffers,
+        spa::pod::Property::new(
+            spa::sys::SPA_PARAM_BUFFERS_buffers,
+            spa::pod::Value::Choice(spa::pod::ChoiceValue::Int(spa::utils::Choice(
+                spa::utils::ChoiceFlags::empty(),
+                spa::utils::ChoiceEnum::Range {
+                    default: buffers as i32,
+                    min: MIN_PW_VIDEO_BUFFERS as i32,
+                    max: MAX_PW_VIDEO_BUFFERS as i32,
+                },
+            ))),
+        ),
+    );
+
+    spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(buffers_obj),
+    )
+    .unwrap()
+    .0
+    .into_inner()
+}
+
+/// The `SPA_PARAM_Meta` param asking the compositor to attach a header to every buffer
+fn header_meta_param() -> Vec<u8> {
+    let meta_obj = spa::pod::object!(
+        spa::utils::SpaTypes::ObjectParamMeta,
+        spa::param::ParamType::Meta,
+        spa::pod::Property::new(
+            spa::sys::SPA_PARAM_META_type,
+            spa::pod::Value::Id(spa::utils::Id(spa::sys::SPA_META_Header)),
+        ),
+        spa::pod::Property::new(
+            spa::sys::SPA_PARAM_META_size,
+            spa::pod::Value::Int(size_of::<spa::sys::spa_meta_header>() as i32),
+        ),
+    );
+
+    spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(meta_obj),
+    )
+    .unwrap()
+    .0
+    .into_inner()
+}
+
+/// Sequence number from the buffer's header metadata, if the compositor sent one
+///
+/// # Safety
+///
+/// `buffer` must be null or point to a buffer currently dequeued from the stream.
+unsafe fn buffer_sequence(buffer: *const spa::sys::spa_buffer) -> Option<u64> {
+    let meta = find_meta(buffer, spa::sys::SPA_META_Header)?;
+    if (meta.size as usize) < size_of::<spa::sys::spa_meta_header>() {
+        return None;
+    }
+
+    Some((*(meta.data as *const spa::sys::spa_meta_header)).seq)
+}
+
+/// The FFmpeg pixel format with the same memory layout as a raw SPA video format, `None` for
+/// formats the capture doesn't handle
+pub fn spa_format_to_ffmpeg_pixel(format: spa::param::video::VideoFormat) -> Option<Pixel> {
+    use spa::param::video::VideoFormat;
+
+    // SPA names packed formats by byte order in memory, as FFmpeg does. `x` is padding, which
+    // FFmpeg calls `0`, or `Z` in the bindings.
+    let pixel = match format {
+        VideoFormat::BGRA => Pixel::BGRA,
+        VideoFormat::BGRx => Pixel::BGRZ,
+        VideoFormat::RGBA => Pixel::RGBA,
+        VideoFormat::RGBx => Pixel::RGBZ,
+        VideoFormat::ARGB => Pixel::ARGB,
+        VideoFormat::xRGB => Pixel::ZRGB,
+        VideoFormat::ABGR => Pixel::ABGR,
+        VideoFormat::xBGR => Pixel::ZBGR,
+        VideoFormat::RGB => Pixel::RGB24,
+        VideoFormat::BGR => Pixel::BGR24,
+        VideoFormat::NV12 => Pixel::NV12,
+        VideoFormat::I420 => Pixel::YUV420P,
+        _ => return None,
+    };
+
+    Some(pixel)
+}
+
+/// Copy a raw frame out of the buffer's datas into a newly allocated frame for the encoder,
+/// honouring the strides on both sides.
+///
+/// Planar formats may come as one data per plane or with all planes packed into the first one.
+fn copy_to_frame(
+    datas: &mut [spa::buffer::Data],
+    format: Pixel,
+    width: u32,
+    height: u32,
+) -> Result<ffmpeg::util::frame::video::Video, ffmpeg::Error> {
+    let mut frame = ffmpeg::util::frame::video::Video::new(format, width, height);
+    let row_sizes = row_sizes(&frame)?;
+
+    // Where the next plane starts in the first data when the planes are packed together
+    let mut packed_offset = 0;
+    for plane in 0..frame.planes() {
+        let row_size = row_sizes[plane];
+        let rows = frame.plane_height(plane) as usize;
+        let separate = plane < datas.len();
+        let data = &mut datas[if separate { plane } else { 0 }];
+
+        // Packed planes only come with the first plane's stride, chroma planes of formats
+        // subsampled horizontally have proportionally shorter rows
+        let source_stride = match data.chunk().stride() {
+            stride if stride > 0 && separate => stride as usize,
+            stride if stride > 0 => stride as usize * row_size / row_sizes[0],
+            _ => row_size,
+        };
+        let start = data.chunk().offset() as usize + if separate { 0 } else { packed_offset };
+        let source = data.data().ok_or(ffmpeg::Error::InvalidData)?;
+        copy_plane(source, start, source_stride, &mut frame, plane)?;
+
+        packed_offset += source_stride * rows;
+    }
+
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spa::param::video::VideoFormat;
+
+    #[test]
+    fn packed_formats_keep_their_byte_order() {
+        let expected = [
+            (VideoFormat::BGRA, Pixel::BGRA),
+            (VideoFormat::BGRx, Pixel::BGRZ),
+            (VideoFormat::RGBA, Pixel::RGBA),
+            (VideoFormat::RGBx, Pixel::RGBZ),
+            (VideoFormat::ARGB, Pixel::ARGB),
+            (VideoFormat::xRGB, Pixel::ZRGB),
+            (VideoFormat::ABGR, Pixel::ABGR),
+            (VideoFormat::xBGR, Pixel::ZBGR),
+            (VideoFormat::RGB, Pixel::RGB24),
+            (VideoFormat::BGR, Pixel::BGR24),
+        ];
+        for (format, pixel) in expected {
+            assert_eq!(
+                spa_format_to_ffmpeg_pixel(format),
+                Some(pixel),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn planar_formats_are_mapped() {
+        assert_eq!(
+            spa_format_to_ffmpeg_pixel(VideoFormat::NV12),
+            Some(Pixel::NV12)
+        );
+        assert_eq!(
+            spa_format_to_ffmpeg_pixel(VideoFormat::I420),
+            Some(Pixel::YUV420P)
+        );
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        for format in [
+            VideoFormat::Unknown,
+            VideoFormat::Encoded,
+            VideoFormat::YUY2,
+            VideoFormat::RGB16,
+        ] {
+            assert_eq!(spa_format_to_ffmpeg_pixel(format), None, "{:?}", format);
+        }
     }
 }
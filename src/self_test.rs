@@ -0,0 +1,235 @@
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use ffmpeg_next as ffmpeg;
+use log::debug;
+use pipewire as pw;
+use portal_screencast::{CursorMode, ScreenCast, SourceType};
+use tokio::sync::mpsc;
+
+use crate::{
+    application_config::AppConfig,
+    encoders::{audio_encoder::AudioEncoder, video_encoder::VideoEncoder},
+    pw_capture::{timestamp_synchronizer::TimestampSynchronizer, video_stream::VideoCapture},
+    save_buffer, CaptureStats, ClipSnapshot, RawAudioFrame, Terminate,
+};
+
+/// Number of captured frames to encode before muxing the test clip, enough for two GOPs
+const TEST_FRAMES: usize = 60;
+
+/// How long to wait for the compositor to deliver frames
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TEST_CLIP: &str = "self_test.mp4";
+
+/// Runs every stage of a recording session once and prints a pass/fail report.
+///
+/// Returns `Ok(true)` if all checks passed.
+pub fn run(config: &Arc<AppConfig>) -> Result<bool> {
+    let mut report = Report::default();
+
+    let screen_cast = report.check("portal grants a screen cast stream", || {
+        let mut screen_cast = ScreenCast::new()?;
+        screen_cast.set_source_types(SourceType::MONITOR);
+        screen_cast.set_cursor_mode(CursorMode::EMBEDDED);
+        let screen_cast = screen_cast.start(None)?;
+        let stream = screen_cast
+            .streams()
+            .next()
+            .context("Portal returned no streams")?;
+        let (width, height) = stream.size();
+        let detail = format!("node {} at {}x{}", stream.pipewire_node(), width, height);
+        Ok((screen_cast, detail))
+    });
+
+    let Some(screen_cast) = screen_cast else {
+        return Ok(report.finish());
+    };
+
+    let stream = screen_cast.streams().next().unwrap();
+    let stream_node = stream.pipewire_node();
+    let (width, height) = stream.size();
+
+    pw::init();
+    ffmpeg::log::set_level(ffmpeg_next::log::Level::Error);
+    ffmpeg::init()?;
+
+    let video_encoder = report.check("video encoder opens", || {
+        let encoder = VideoEncoder::new(
+            width,
+            height,
+            config.max_seconds,
+            &config.encoder,
+            Arc::clone(config),
+        )?;
+        Ok((encoder, config.encoder.clone()))
+    });
+    let audio_encoder = report.check("audio encoder opens", || {
+        Ok((AudioEncoder::new(Arc::clone(config))?, "opus".to_string()))
+    });
+
+    let (Some(mut video_encoder), Some(mut audio_encoder)) = (video_encoder, audio_encoder) else {
+        let _ = screen_cast.close();
+        return Ok(report.finish());
+    };
+
+    // Only the video stream is connected, the audio encoder gets silence instead
+    let (video_sender, mut video_receiver) = mpsc::channel(TEST_FRAMES);
+    let (pw_video_sender, pw_video_recv) = pw::channel::channel::<Terminate>();
+    let (status_tx, _status_rx) = mpsc::unbounded_channel();
+    let fd = screen_cast.pipewire_fd();
+    let timestamps = Arc::new(TimestampSynchronizer::new());
+    let allow_nv12 =
+        VideoEncoder::supports_pixel_format(&config.encoder, ffmpeg::format::Pixel::NV12)
+            && config
+                .chroma_format
+                .is_none_or(|chroma_format| chroma_format.is_subsampled());
+    let video_node_latency = config.video_node_latency.clone();
+    let pw_video_buffers = config.pw_video_buffers;
+    let video_worker = std::thread::spawn(move || {
+        VideoCapture::run(
+            fd,
+            stream_node,
+            video_sender,
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(true)),
+            timestamps,
+            pw_video_recv,
+            Arc::new(AtomicBool::new(false)),
+            allow_nv12,
+            video_node_latency,
+            pw_video_buffers,
+            false,
+            Arc::new(CaptureStats::default()),
+            status_tx,
+        )
+    });
+
+    let frames = report.check("PipeWire delivers frames", || {
+        let deadline = SystemTime::now() + FRAME_TIMEOUT;
+        let mut frames = Vec::new();
+        while frames.len() < TEST_FRAMES && SystemTime::now() < deadline {
+            match video_receiver.try_recv() {
+                Ok(frame) => frames.push(frame),
+                Err(mpsc::error::TryRecvError::Empty) => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(mpsc::error::TryRecvError::Disconnected) => break,
+            }
+        }
+
+        if frames.is_empty() {
+            bail!("No frames within {:?}", FRAME_TIMEOUT);
+        }
+        let detail = format!("{} frames", frames.len());
+        Ok((frames, detail))
+    });
+
+    // Dropping the receiver unblocks the capture thread if it's waiting on a full channel
+    drop(video_receiver);
+    let _ = pw_video_sender.send(Terminate);
+    match video_worker.join() {
+        Ok(Err(e)) => debug!("Video capture exited with {:?}", e),
+        Err(_) => debug!("Video capture thread panicked"),
+        Ok(Ok(())) => {}
+    }
+    let _ = screen_cast.close();
+
+    let Some(frames) = frames else {
+        return Ok(report.finish());
+    };
+
+    let encoded = report.check("frames encode", || {
+        let first_timestamp = frames[0].get_timestamp();
+        let last_timestamp = frames[frames.len() - 1].get_timestamp();
+        for frame in frames {
+            video_encoder.process(frame)?;
+        }
+        video_encoder.drain()?;
+
+        // Cover the captured span with silence so the clip has an audio track
+        let mut timestamp = first_timestamp;
+        while timestamp <= last_timestamp {
+            audio_encoder.process(&mut RawAudioFrame::new(vec![0.0; 960 * 2], timestamp))?;
+            timestamp += 20_000;
+        }
+        audio_encoder.drain()?;
+
+        let video_frames = video_encoder.get_buffer().get_frames().len();
+        if video_frames == 0 {
+            bail!("Encoder produced no packets");
+        }
+        Ok(((), format!("{} video packets", video_frames)))
+    });
+
+    if encoded.is_none() {
+        return Ok(report.finish());
+    }
+
+    let muxed = report.check("test clip muxes", || {
+        let snapshot =
+            ClipSnapshot::new(&mut video_encoder, &mut audio_encoder, Arc::clone(config))?;
+        let result = save_buffer(TEST_CLIP, &snapshot, None, None)?;
+        let detail = format!(
+            "{}, A/V sync offset {}us",
+            TEST_CLIP, result.av_sync_offset_us
+        );
+        Ok(((), detail))
+    });
+
+    if muxed.is_some() {
+        report.check("test clip probes", || {
+            let input = ffmpeg::format::input(&TEST_CLIP)?;
+            let streams = input.streams().count();
+            if streams != 2 {
+                bail!("Expected 2 streams, found {}", streams);
+            }
+            if input.duration() <= 0 {
+                bail!("Clip has no duration");
+            }
+            let detail = format!(
+                "{} streams, {} ms",
+                streams,
+                input.duration() * 1000 / ffmpeg::ffi::AV_TIME_BASE as i64
+            );
+            Ok(((), detail))
+        });
+    }
+    let _ = std::fs::remove_file(TEST_CLIP);
+
+    Ok(report.finish())
+}
+
+#[derive(Default)]
+struct Report {
+    failed: bool,
+}
+
+impl Report {
+    /// Runs a single check and prints its outcome, returning its value if it passed
+    fn check<T>(&mut self, name: &str, check: impl FnOnce() -> Result<(T, String)>) -> Option<T> {
+        match check() {
+            Ok((value, detail)) => {
+                println!("[PASS] {}: {}", name, detail);
+                Some(value)
+            }
+            Err(e) => {
+                println!("[FAIL] {}: {:#}", name, e);
+                self.failed = true;
+                None
+            }
+        }
+    }
+
+    fn finish(self) -> bool {
+        if self.failed {
+            println!("Self test failed");
+        } else {
+            println!("Self test passed");
+        }
+        !self.failed
+    }
+}
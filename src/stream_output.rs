@@ -0,0 +1,148 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::{atomic::AtomicBool, Arc},
+};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{
+    encoders::{
+        buffer::VideoFrameData,
+        video_encoder::VideoEncoder,
+        video_muxer::{self, VideoMuxer},
+    },
+    pipe_output::{self, PipeOutput},
+};
+
+/// `stream_output` value that writes to stdout instead of a file
+const STDOUT: &str = "-";
+
+/// Muxes the packets the video encoder broadcasts into one continuous stream on stdout or a named
+/// pipe, see `stream_output`
+pub struct StreamWriter {
+    target: String,
+    video_encoder: Arc<Mutex<VideoEncoder>>,
+}
+
+impl StreamWriter {
+    pub fn new(target: String, video_encoder: Arc<Mutex<VideoEncoder>>) -> Self {
+        Self {
+            target,
+            video_encoder,
+        }
+    }
+
+    /// Write the packets on `packet_rx` on its own thread until `stop` is set or the reader goes
+    /// away. Opening a named pipe waits for a reader to open the other end.
+    pub fn spawn(
+        self,
+        mut packet_rx: broadcast::Receiver<(i64, VideoFrameData)>,
+        stop: Arc<AtomicBool>,
+    ) {
+        std::thread::spawn(move || {
+            let pipe: Box<dyn Write + Send> = if self.target == STDOUT {
+                Box::new(std::io::stdout())
+            } else {
+                match OpenOptions::new().write(true).open(&self.target) {
+                    Ok(file) => Box::new(file),
+                    Err(e) => {
+                        error!("Could not open the stream output {}: {:?}", self.target, e);
+                        return;
+                    }
+                }
+            };
+            info!("Streaming video to {}", self.target);
+
+            // Skip what queued up while waiting for a reader, it wants the capture as it is now
+            packet_rx = packet_rx.resubscribe();
+
+            let mut pipe = match PipeOutput::new(pipe, pipe_output::FORMAT) {
+                Ok(pipe) => pipe,
+                Err(e) => {
+                    error!("Could not create the stream output: {:?}", e);
+                    return;
+                }
+            };
+
+            let mut muxer: Option<VideoMuxer> = None;
+            let mut skip_to_keyframe = true;
+            while !stop.load(std::sync::atomic::Ordering::Acquire) {
+                let (dts, frame) = match packet_rx.blocking_recv() {
+                    Ok(packet) => packet,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        // The reader can't decode past the hole until the next keyframe
+                        warn!(
+                            "Stream output fell behind by {} packets, skipping ahead",
+                            skipped
+                        );
+                        skip_to_keyframe = true;
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if skip_to_keyframe {
+                    if !frame.is_key() {
+                        continue;
+                    }
+                    skip_to_keyframe = false;
+                }
+
+                let written = match &muxer {
+                    Some(muxer) => muxer.write(pipe.output(), dts, &frame),
+                    None => self
+                        .write_header(&mut pipe, dts, &frame)
+                        .and_then(|started| {
+                            muxer.insert(started).write(pipe.output(), dts, &frame)
+                        }),
+                };
+                if let Err(e) = written {
+                    if pipe.reader_closed() {
+                        info!("Stream output reader went away, stopping");
+                    } else {
+                        error!(
+                            "Could not write to the stream output: {:?}",
+                            pipe.explain(e)
+                        );
+                    }
+                    return;
+                }
+            }
+
+            if muxer.is_some() {
+                if let Err(e) = pipe.output().write_trailer() {
+                    if !pipe.reader_closed() {
+                        error!(
+                            "Could not finish the stream output: {:?}",
+                            pipe.explain(e.into())
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    /// Write the header of the stream starting at the keyframe `frame`
+    fn write_header(
+        &self,
+        pipe: &mut PipeOutput,
+        dts: i64,
+        frame: &VideoFrameData,
+    ) -> Result<VideoMuxer> {
+        // The encoder is reopened when the bitrate changes, take its current parameters
+        let parameters = self
+            .video_encoder
+            .blocking_lock()
+            .get_parameters()
+            .context("Could not get video parameters")?;
+
+        VideoMuxer::start(
+            pipe.output(),
+            parameters,
+            video_muxer::timeline_start(dts, frame),
+        )
+    }
+}
@@ -0,0 +1,762 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use ffmpeg_next::{
+    self as ffmpeg, format::Pixel, media::Type, software::resampling, software::scaling, Rational,
+    Rescale,
+};
+use log::debug;
+
+use crate::{
+    application_config::EncodePreset,
+    encoders::{
+        buffer::VideoBuffer,
+        video_encoder::{draw_cursor, ONE_MICROS},
+    },
+    pw_capture::cursor::CursorOverlay,
+};
+
+/// Output stream indexes, matching the layout written by `save_buffer`
+const VIDEO_STREAM: usize = 0;
+const AUDIO_STREAM: usize = 1;
+
+/// MPEG-TS timestamps count in the 90kHz MPEG clock
+const MPEG_TS_CLOCK: i32 = 90_000;
+
+/// Bitrate audio is re-encoded at when the container needs another codec and the preset doesn't
+/// set one
+const DEFAULT_AUDIO_BITRATE_KBPS: u32 = 128;
+
+/// Re-encode the clip at `input` into `output` with the settings of `preset`.
+///
+/// Video is encoded with `encoder_name`, with the ASS script at `subtitles` burned in if given.
+/// `cursors` draws the cursors recorded in a buffer onto the frames, along with the capture time
+/// in micro seconds of the clip's first frame. Audio keeps its codec and is only re-encoded if the
+/// preset sets an audio bitrate, otherwise its packets are copied as is. MPEG-TS has no standard
+/// mapping for Opus, so audio is always re-encoded as AAC there.
+pub fn transcode(
+    input: &Path,
+    output: &Path,
+    preset: &EncodePreset,
+    encoder_name: &str,
+    subtitles: Option<&Path>,
+    cursors: Option<(&VideoBuffer, i64)>,
+) -> Result<()> {
+    let mut input_ctx = ffmpeg::format::input(&input)?;
+    let mut output_ctx = ffmpeg::format::output(&output)?;
+
+    let input_video = input_ctx
+        .streams()
+        .best(Type::Video)
+        .context("Clip has no video stream")?;
+    let video_index = input_video.index();
+    let mut video = VideoTranscoder::new(
+        &input_video,
+        &mut output_ctx,
+        preset,
+        encoder_name,
+        subtitles,
+        cursors,
+    )?;
+
+    let input_audio = input_ctx
+        .streams()
+        .best(Type::Audio)
+        .context("Clip has no audio stream")?;
+    let audio_index = input_audio.index();
+    let mut audio = AudioTranscoder::new(&input_audio, &mut output_ctx, preset)?;
+
+    // Keep the clip's title and description
+    output_ctx.set_metadata(input_ctx.metadata().to_owned());
+    output_ctx.write_header()?;
+
+    debug!("TRANSCODE START: {:?} -> {:?}", input, output);
+    for (stream, packet) in input_ctx.packets() {
+        if stream.index() == video_index {
+            video.send_packet(&packet, &mut output_ctx)?;
+        } else if stream.index() == audio_index {
+            audio.send_packet(packet, &mut output_ctx)?;
+        }
+    }
+
+    video.finish(&mut output_ctx)?;
+    audio.finish(&mut output_ctx)?;
+    debug!("TRANSCODE END");
+
+    output_ctx.write_trailer()?;
+
+    Ok(())
+}
+
+struct VideoTranscoder<'a> {
+    decoder: ffmpeg::decoder::Video,
+    encoder: ffmpeg::encoder::Video,
+
+    /// Only created when the size or pixel format changes
+    scaler: Option<scaling::Context>,
+
+    /// Filter graph drawing the subtitles onto decoded frames
+    subtitles: Option<ffmpeg::filter::Graph>,
+    input_time_base: Rational,
+
+    /// Buffer to take the cursors from and the capture time of the first frame
+    cursors: Option<(&'a VideoBuffer, i64)>,
+
+    /// Timestamp of the first decoded frame, which is shown at the clip's start
+    first_pts: Option<i64>,
+}
+
+impl<'a> VideoTranscoder<'a> {
+    fn new(
+        input: &ffmpeg::format::stream::Stream,
+        output_ctx: &mut ffmpeg::format::context::Output,
+        preset: &EncodePreset,
+        encoder_name: &str,
+        subtitles: Option<&Path>,
+        cursors: Option<(&'a VideoBuffer, i64)>,
+    ) -> Result<Self> {
+        let decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())?
+            .decoder()
+            .video()?;
+
+        let subtitles = subtitles
+            .map(|subtitles| subtitle_filter(&decoder, input.time_base(), subtitles))
+            .transpose()?;
+
+        let codec = ffmpeg::codec::encoder::find_by_name(encoder_name)
+            .context("Could not find the configured video encoder")?;
+        let formats: Vec<Pixel> = codec
+            .video()?
+            .formats()
+            .map(|formats| formats.collect())
+            .unwrap_or_default();
+        let pixel_format = if formats.is_empty() || formats.contains(&decoder.format()) {
+            decoder.format()
+        } else {
+            formats[0]
+        };
+
+        // Encoders want even dimensions for 4:2:0
+        let scale = preset.scale_factor.unwrap_or(1.0);
+        let width = ((decoder.width() as f32 * scale).round() as u32 / 2 * 2).max(2);
+        let height = ((decoder.height() as f32 * scale).round() as u32 / 2 * 2).max(2);
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        encoder_ctx.set_width(width);
+        encoder_ctx.set_height(height);
+        encoder_ctx.set_format(pixel_format);
+        // Encode in the muxer's clock so timestamps are only rounded once
+        let time_base = if is_mpeg_ts(output_ctx) {
+            Rational::new(1, MPEG_TS_CLOCK)
+        } else {
+            input.time_base()
+        };
+        encoder_ctx.set_time_base(time_base);
+        if input.avg_frame_rate().numerator() > 0 {
+            encoder_ctx.set_frame_rate(Some(input.avg_frame_rate()));
+        }
+        if output_ctx
+            .format()
+            .flags()
+            .contains(ffmpeg::format::Flags::GLOBAL_HEADER)
+        {
+            encoder_ctx.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+        }
+
+        let mut opts = ffmpeg::Dictionary::new();
+        if let Some(bitrate_kbps) = preset.video_bitrate_kbps {
+            encoder_ctx.set_bit_rate(bitrate_kbps as usize * 1000);
+        }
+        if let Some(crf) = preset.video_crf {
+            // NVENC calls its constant quality mode cq
+            if encoder_name.contains("nvenc") {
+                opts.set("rc", "vbr");
+                opts.set("cq", &crf.to_string());
+            } else {
+                opts.set("crf", &crf.to_string());
+            }
+        }
+
+        let encoder = encoder_ctx.open_with(opts)?;
+        debug!(
+            "Transcoding video with {} at {}x{} as {:?}",
+            encoder_name, width, height, pixel_format
+        );
+
+        let mut output_stream = output_ctx.add_stream(codec)?;
+        output_stream.set_parameters(&encoder);
+
+        let scaler = if (width, height, pixel_format)
+            != (decoder.width(), decoder.height(), decoder.format())
+        {
+            Some(scaling::Context::get(
+                decoder.format(),
+                decoder.width(),
+                decoder.height(),
+                pixel_format,
+                width,
+                height,
+                scaling::Flags::BILINEAR,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            decoder,
+            encoder,
+            scaler,
+            subtitles,
+            input_time_base: input.time_base(),
+            cursors,
+            first_pts: None,
+        })
+    }
+
+    fn send_packet(
+        &mut self,
+        packet: &ffmpeg::Packet,
+        output_ctx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        self.decoder.send_packet(packet)?;
+        self.encode_decoded(output_ctx)
+    }
+
+    fn finish(&mut self, output_ctx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        self.decoder.send_eof()?;
+        self.encode_decoded(output_ctx)?;
+        if let Some(graph) = self.subtitles.as_mut() {
+            for frame in burn_subtitles(graph, None)? {
+                self.encode_frame(&frame, output_ctx)?;
+            }
+        }
+        self.encoder.send_eof()?;
+        self.write_encoded(output_ctx)
+    }
+
+    fn encode_decoded(&mut self, output_ctx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let mut decoded = ffmpeg::frame::Video::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            decoded.set_pts(decoded.timestamp());
+
+            let mut with_cursor = None;
+            if let Some(cursor) = self.cursor_for(&decoded) {
+                // The decoder may still reference the frame, draw onto a copy
+                let mut frame = decoded.clone();
+                draw_cursor(&mut frame, cursor, 0, 0);
+                with_cursor = Some(frame);
+            }
+            let frame = with_cursor.as_ref().unwrap_or(&decoded);
+
+            match self.subtitles.as_mut() {
+                Some(graph) => {
+                    for frame in burn_subtitles(graph, Some(frame))? {
+                        self.encode_frame(&frame, output_ctx)?;
+                    }
+                }
+                None => self.encode_frame(frame, output_ctx)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The cursor recorded for the capture time of `decoded`, if cursors are drawn
+    fn cursor_for(&mut self, decoded: &ffmpeg::frame::Video) -> Option<&'a CursorOverlay> {
+        let (buffer, clip_start_us) = self.cursors?;
+        let pts = decoded.pts()?;
+        let since_start = pts - *self.first_pts.get_or_insert(pts);
+        buffer.cursor_at(
+            clip_start_us
+                + since_start.rescale(self.input_time_base, Rational::new(1, ONE_MICROS as i32)),
+        )
+    }
+
+    fn encode_frame(
+        &mut self,
+        decoded: &ffmpeg::frame::Video,
+        output_ctx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        let mut frame = match self.scaler {
+            Some(ref mut scaler) => {
+                let mut scaled = ffmpeg::frame::Video::empty();
+                scaler.run(decoded, &mut scaled)?;
+                scaled
+            }
+            None => decoded.clone(),
+        };
+
+        // Let the encoder place its own keyframes instead of copying the source's
+        frame.set_pts(
+            decoded
+                .pts()
+                .map(|pts| pts.rescale(self.input_time_base, self.encoder.time_base())),
+        );
+        frame.set_kind(ffmpeg::picture::Type::None);
+
+        self.encoder.send_frame(&frame)?;
+        self.write_encoded(output_ctx)
+    }
+
+    fn write_encoded(&mut self, output_ctx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let stream_time_base = output_ctx
+            .stream(VIDEO_STREAM)
+            .context("Could not get video stream")?
+            .time_base();
+
+        let mut packet = ffmpeg::Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.rescale_ts(self.encoder.time_base(), stream_time_base);
+            packet.set_stream(VIDEO_STREAM);
+            packet.write_interleaved(output_ctx)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A filter graph drawing the ASS script at `subtitles` onto frames from `decoder`, handing them
+/// back in the decoder's pixel format
+fn subtitle_filter(
+    decoder: &ffmpeg::decoder::Video,
+    time_base: Rational,
+    subtitles: &Path,
+) -> Result<ffmpeg::filter::Graph> {
+    let pixel_format = decoder
+        .format()
+        .descriptor()
+        .context("Clip has an unknown pixel format")?
+        .name();
+
+    let mut graph = ffmpeg::filter::Graph::new();
+    let args = format!(
+        "video_size={}x{}:pix_fmt={}:time_base={}:pixel_aspect=1/1",
+        decoder.width(),
+        decoder.height(),
+        pixel_format,
+        time_base
+    );
+    graph.add(
+        &ffmpeg::filter::find("buffer").context("Could not find the buffer filter")?,
+        "in",
+        &args,
+    )?;
+    graph.add(
+        &ffmpeg::filter::find("buffersink").context("Could not find the buffersink filter")?,
+        "out",
+        "",
+    )?;
+
+    // Needs FFmpeg built with libass
+    graph.output("in", 0)?.input("out", 0)?.parse(&format!(
+        "subtitles=filename='{}',format={}",
+        subtitles.display(),
+        pixel_format
+    ))?;
+    graph.validate()?;
+
+    Ok(graph)
+}
+
+/// Send `frame` through the subtitle filter and collect what comes out, `None` flushes it
+fn burn_subtitles(
+    graph: &mut ffmpeg::filter::Graph,
+    frame: Option<&ffmpeg::frame::Video>,
+) -> Result<Vec<ffmpeg::frame::Video>> {
+    {
+        let mut source = graph.get("in").context("Subtitle filter has no input")?;
+        match frame {
+            Some(frame) => source.source().add(frame)?,
+            None => source.source().flush()?,
+        }
+    }
+
+    let mut frames = Vec::new();
+    let mut burned = ffmpeg::frame::Video::empty();
+    while graph
+        .get("out")
+        .context("Subtitle filter has no output")?
+        .sink()
+        .frame(&mut burned)
+        .is_ok()
+    {
+        frames.push(std::mem::replace(
+            &mut burned,
+            ffmpeg::frame::Video::empty(),
+        ));
+    }
+
+    Ok(frames)
+}
+
+/// Re-encodes audio at the preset's bitrate, or copies it if the preset doesn't set one and the
+/// container takes its codec
+struct AudioTranscoder {
+    reencode: Option<AudioReencoder>,
+    input_time_base: Rational,
+}
+
+struct AudioReencoder {
+    decoder: ffmpeg::decoder::Audio,
+    encoder: ffmpeg::encoder::Audio,
+    resampler: Option<resampling::Context>,
+
+    /// Decoded samples are regrouped into the encoder's frame size, AAC takes 1024 per frame while
+    /// Opus decodes to 960
+    fifo: SampleFifo,
+}
+
+impl AudioTranscoder {
+    fn new(
+        input: &ffmpeg::format::stream::Stream,
+        output_ctx: &mut ffmpeg::format::context::Output,
+        preset: &EncodePreset,
+    ) -> Result<Self> {
+        let input_time_base = input.time_base();
+        let input_codec = input.parameters().id();
+        let codec_id = if is_mpeg_ts(output_ctx) {
+            ffmpeg::codec::Id::AAC
+        } else {
+            input_codec
+        };
+
+        let bitrate_kbps = match preset.audio_bitrate_kbps {
+            Some(bitrate_kbps) => bitrate_kbps,
+            None if codec_id != input_codec => DEFAULT_AUDIO_BITRATE_KBPS,
+            None => {
+                let mut output_stream =
+                    output_ctx.add_stream(ffmpeg::encoder::find(ffmpeg::codec::Id::None))?;
+                output_stream.set_parameters(input.parameters());
+                // Let the muxer pick the tag for the new container
+                unsafe {
+                    (*output_stream.parameters().as_mut_ptr()).codec_tag = 0;
+                }
+
+                return Ok(Self {
+                    reencode: None,
+                    input_time_base,
+                });
+            }
+        };
+
+        let decoder = ffmpeg::codec::context::Context::from_parameters(input.parameters())?
+            .decoder()
+            .audio()?;
+
+        let codec = ffmpeg::codec::encoder::find(codec_id)
+            .with_context(|| format!("Could not find an audio encoder for {:?}", codec_id))?;
+        let sample_format = codec
+            .audio()?
+            .formats()
+            .and_then(|mut formats| formats.next())
+            .unwrap_or(decoder.format());
+
+        let mut encoder_ctx = ffmpeg::codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .audio()?;
+        encoder_ctx.set_rate(decoder.rate() as i32);
+        encoder_ctx.set_channel_layout(decoder.channel_layout());
+        encoder_ctx.set_format(sample_format);
+        encoder_ctx.set_time_base(Rational::new(1, decoder.rate() as i32));
+        encoder_ctx.set_bit_rate(bitrate_kbps as usize * 1000);
+        if output_ctx
+            .format()
+            .flags()
+            .contains(ffmpeg::format::Flags::GLOBAL_HEADER)
+        {
+            encoder_ctx.set_flags(ffmpeg::codec::Flags::GLOBAL_HEADER);
+        }
+
+        let encoder = encoder_ctx.open()?;
+        debug!(
+            "Transcoding audio with {} at {}kbps",
+            codec.name(),
+            bitrate_kbps
+        );
+
+        let mut output_stream = output_ctx.add_stream(codec)?;
+        output_stream.set_parameters(&encoder);
+
+        let resampler = if decoder.format() != sample_format {
+            Some(resampling::Context::get(
+                decoder.format(),
+                decoder.channel_layout(),
+                decoder.rate(),
+                sample_format,
+                decoder.channel_layout(),
+                decoder.rate(),
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            reencode: Some(AudioReencoder {
+                fifo: SampleFifo::new(&encoder),
+                decoder,
+                encoder,
+                resampler,
+            }),
+            input_time_base,
+        })
+    }
+
+    fn send_packet(
+        &mut self,
+        mut packet: ffmpeg::Packet,
+        output_ctx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        let Some(reencode) = self.reencode.as_mut() else {
+            let stream_time_base = output_ctx
+                .stream(AUDIO_STREAM)
+                .context("Could not get audio stream")?
+                .time_base();
+            packet.rescale_ts(self.input_time_base, stream_time_base);
+            packet.set_position(-1);
+            packet.set_stream(AUDIO_STREAM);
+            packet.write_interleaved(output_ctx)?;
+            return Ok(());
+        };
+
+        reencode.decoder.send_packet(&packet)?;
+        reencode.encode_decoded(self.input_time_base, output_ctx)
+    }
+
+    fn finish(&mut self, output_ctx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let Some(reencode) = self.reencode.as_mut() else {
+            return Ok(());
+        };
+
+        reencode.decoder.send_eof()?;
+        reencode.encode_decoded(self.input_time_base, output_ctx)?;
+        reencode.encode_fifo(true, output_ctx)?;
+        reencode.encoder.send_eof()?;
+        reencode.write_encoded(output_ctx)
+    }
+}
+
+impl AudioReencoder {
+    fn encode_decoded(
+        &mut self,
+        input_time_base: Rational,
+        output_ctx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        let mut decoded = ffmpeg::frame::Audio::empty();
+        while self.decoder.receive_frame(&mut decoded).is_ok() {
+            let mut frame = match self.resampler {
+                Some(ref mut resampler) => {
+                    let mut converted = ffmpeg::frame::Audio::empty();
+                    resampler.run(&decoded, &mut converted)?;
+                    converted
+                }
+                None => decoded.clone(),
+            };
+
+            let pts = decoded
+                .timestamp()
+                .map(|pts| pts.rescale(input_time_base, self.encoder.time_base()));
+            self.fifo.push(&frame, pts);
+            self.encode_fifo(false, output_ctx)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send whole frames from the FIFO to the encoder, and what's left over as well with `flush`
+    fn encode_fifo(
+        &mut self,
+        flush: bool,
+        output_ctx: &mut ffmpeg::format::context::Output,
+    ) -> Result<()> {
+        // Encoders that take any number of samples per frame report a frame size of 0
+        let frame_size = match self.encoder.frame_size() as usize {
+            0 => self.fifo.len(),
+            frame_size => frame_size,
+        };
+
+        while let Some(frame) = self.fifo.pop(frame_size, flush) {
+            self.encoder.send_frame(&frame)?;
+            self.write_encoded(output_ctx)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_encoded(&mut self, output_ctx: &mut ffmpeg::format::context::Output) -> Result<()> {
+        let stream_time_base = output_ctx
+            .stream(AUDIO_STREAM)
+            .context("Could not get audio stream")?
+            .time_base();
+
+        let mut packet = ffmpeg::Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.rescale_ts(self.encoder.time_base(), stream_time_base);
+            packet.set_stream(AUDIO_STREAM);
+            packet.write_interleaved(output_ctx)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decoded samples waiting for a whole encoder frame, one buffer per plane
+struct SampleFifo {
+    format: ffmpeg::format::Sample,
+    channel_layout: ffmpeg::channel_layout::ChannelLayout,
+    rate: u32,
+    planes: Vec<Vec<u8>>,
+
+    /// Bytes one sample takes in each plane
+    sample_bytes: usize,
+
+    /// Timestamp of the first sample waiting, in the encoder's time base of 1 / rate
+    pts: Option<i64>,
+}
+
+impl SampleFifo {
+    fn new(encoder: &ffmpeg::encoder::Audio) -> Self {
+        let format = encoder.format();
+        let channels = (encoder.channels() as usize).max(1);
+        let (planes, sample_bytes) = if format.is_planar() {
+            (channels, format.bytes())
+        } else {
+            (1, format.bytes() * channels)
+        };
+
+        Self {
+            format,
+            channel_layout: encoder.channel_layout(),
+            rate: encoder.rate(),
+            planes: vec![Vec::new(); planes],
+            sample_bytes,
+            pts: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.planes[0].len() / self.sample_bytes
+    }
+
+    fn push(&mut self, frame: &ffmpeg::frame::Audio, pts: Option<i64>) {
+        if self.len() == 0 {
+            self.pts = pts;
+        }
+
+        // Planes can be padded past the last sample
+        let bytes = frame.samples() * self.sample_bytes;
+        for (index, plane) in self.planes.iter_mut().enumerate() {
+            plane.extend_from_slice(&frame.data(index)[..bytes]);
+        }
+    }
+
+    /// Take a frame of `samples` from the front, or fewer with `partial` if that's all there is
+    fn pop(&mut self, samples: usize, partial: bool) -> Option<ffmpeg::frame::Audio> {
+        let available = self.len();
+        if available == 0 || (available < samples && !partial) {
+            return None;
+        }
+
+        let samples = samples.min(available);
+        let bytes = samples * self.sample_bytes;
+        let mut frame = ffmpeg::frame::Audio::new(self.format, samples, self.channel_layout);
+        for (index, plane) in self.planes.iter_mut().enumerate() {
+            frame.data_mut(index)[..bytes].copy_from_slice(&plane[..bytes]);
+            plane.drain(..bytes);
+        }
+        frame.set_rate(self.rate);
+        frame.set_pts(self.pts);
+        self.pts = self.pts.map(|pts| pts + samples as i64);
+
+        Some(frame)
+    }
+}
+
+fn is_mpeg_ts(output_ctx: &ffmpeg::format::context::Output) -> bool {
+    output_ctx.format().name() == "mpegts"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: ffmpeg::format::Sample =
+        ffmpeg::format::Sample::F32(ffmpeg::format::sample::Type::Packed);
+
+    /// A FIFO of packed stereo float samples at 48kHz
+    fn fifo() -> SampleFifo {
+        SampleFifo {
+            format: FORMAT,
+            channel_layout: ffmpeg::channel_layout::ChannelLayout::STEREO,
+            rate: 48000,
+            planes: vec![Vec::new()],
+            sample_bytes: FORMAT.bytes() * 2,
+            pts: None,
+        }
+    }
+
+    fn frame(values: &[f32]) -> ffmpeg::frame::Audio {
+        let mut frame = ffmpeg::frame::Audio::new(
+            FORMAT,
+            values.len() / 2,
+            ffmpeg::channel_layout::ChannelLayout::STEREO,
+        );
+        for (index, value) in values.iter().enumerate() {
+            frame.data_mut(0)[index * 4..(index + 1) * 4].copy_from_slice(&value.to_ne_bytes());
+        }
+        frame
+    }
+
+    fn values(frame: &ffmpeg::frame::Audio) -> Vec<f32> {
+        frame.data(0)[..frame.samples() * 8]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_ne_bytes(bytes.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn pop_waits_for_a_whole_frame() {
+        let mut fifo = fifo();
+        fifo.push(&frame(&[0.1, 0.2, 0.3, 0.4]), Some(100));
+        assert_eq!(fifo.len(), 2);
+        assert!(fifo.pop(3, false).is_none());
+
+        fifo.push(&frame(&[0.5, 0.6, 0.7, 0.8]), Some(102));
+        let popped = fifo.pop(3, false).unwrap();
+        assert_eq!(values(&popped), [0.1, 0.2, 0.3, 0.4, 0.5, 0.6]);
+        assert_eq!(popped.pts(), Some(100));
+        assert_eq!(popped.rate(), 48000);
+        assert_eq!(fifo.len(), 1);
+    }
+
+    #[test]
+    fn pts_follows_the_samples_taken() {
+        let mut fifo = fifo();
+        fifo.push(&frame(&[0.0; 8]), Some(1000));
+        assert_eq!(fifo.pop(1, false).unwrap().pts(), Some(1000));
+        assert_eq!(fifo.pop(2, false).unwrap().pts(), Some(1001));
+        assert_eq!(fifo.pop(1, false).unwrap().pts(), Some(1003));
+        assert_eq!(fifo.len(), 0);
+    }
+
+    #[test]
+    fn partial_pop_drains_the_rest() {
+        let mut fifo = fifo();
+        fifo.push(&frame(&[0.1, 0.2, 0.3, 0.4]), Some(0));
+        let popped = fifo.pop(1024, true).unwrap();
+        assert_eq!(popped.samples(), 2);
+        assert_eq!(values(&popped), [0.1, 0.2, 0.3, 0.4]);
+        assert!(fifo.pop(1024, true).is_none());
+    }
+
+    #[test]
+    fn pts_restarts_from_the_next_push_once_empty() {
+        let mut fifo = fifo();
+        fifo.push(&frame(&[0.0; 4]), Some(0));
+        fifo.pop(2, false).unwrap();
+
+        fifo.push(&frame(&[0.0; 4]), Some(5000));
+        assert_eq!(fifo.pop(2, false).unwrap().pts(), Some(5000));
+    }
+}